@@ -20,8 +20,7 @@ fn rand_key() -> String {
 fn rand_value() -> Bytes {
     let mut rng = rand::thread_rng();
     let len = rng.gen_range(1..8);
-    let mut vec = Vec::new();
-    vec.resize(len, 0);
+    let mut vec = vec![0; len];
 
     rng.fill_bytes(&mut vec[..]);
     vec.into()
@@ -110,7 +109,7 @@ fn main() {
                 } else {
                     let key = loop {
                         let key = rand_key();
-                        if del_keys.get(&key).is_none() && exist_keys.insert(key.clone()) {
+                        if !del_keys.contains(&key) && exist_keys.insert(key.clone()) {
                             break key;
                         }
                     };
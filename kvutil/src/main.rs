@@ -1,7 +1,8 @@
 use std::{
     borrow::Borrow,
-    io::Read,
-    path::{Path, PathBuf},
+    io::{self, Read, Write},
+    ops::Bound::{Included, Unbounded},
+    path::PathBuf,
 };
 
 use bytes::Bytes;
@@ -12,11 +13,14 @@ use storage::{
         fs::{local::LocalFileBasedPersistBackend, ExtReader},
         Backend,
     },
-    iterator::KvIteratorItem,
+    iterator::{KvIteratorItem, ScanStatus},
     key::{InternalKey, KeyType, Value},
     kv::{
         manifest::{ManifestLogSerializer, VersionSet},
-        sst::SSTReader,
+        sst::{
+            format::{open_reader, FormatMeta, ReaderOptions},
+            SSTReader,
+        },
         superversion::Lifetime,
     },
     log::LogReplayer,
@@ -27,85 +31,138 @@ mod opt;
 fn main() {
     let cli = opt::Cli::parse();
     let base = cli.path.unwrap_or_else(|| "./".to_owned());
-    let backend = Backend::new(LocalFileBasedPersistBackend::default());
+    let backend = Backend::new(LocalFileBasedPersistBackend);
     match cli.command {
-        opt::Commands::Sst { subcommand, file } => sst(base, subcommand, file, &backend),
+        opt::Commands::Sst { subcommand, file } => {
+            sst(base, subcommand, file, &backend, &mut io::stdout().lock()).unwrap()
+        }
         opt::Commands::Manifest { subcommand } => manifest(base, subcommand, &backend),
         opt::Commands::Wal => wal(),
         opt::Commands::Db => db(),
     };
 }
 
-fn sst(base: String, command: opt::SSTCommands, file: String, backend: &Backend) {
+fn sst<W: Write>(
+    base: String,
+    command: opt::SSTCommands,
+    file: String,
+    backend: &Backend,
+    out: &mut W,
+) -> io::Result<()> {
     let path = PathBuf::from(base).join("sst").join(file);
+    // the format is picked by the magic of the footer
+    let reader = open_reader(&path, backend, &ReaderOptions::default()).unwrap();
     match command {
-        opt::SSTCommands::Summary => sst_summary(&path, backend),
-        opt::SSTCommands::Dump(opts) => sst_dump(&path, opts.noval, backend),
-        opt::SSTCommands::Get(opts) => sst_get(&path, opts.key.into(), opts.noval, backend),
+        opt::SSTCommands::Summary => sst_summary(reader.as_ref(), out),
+        opt::SSTCommands::Dump(opts) => sst_dump(reader.as_ref(), opts.noval, out),
+        opt::SSTCommands::Get(opts) => sst_get(reader.as_ref(), opts.key.into(), opts.noval, out),
     }
 }
 
-fn sst_summary(path: &Path, backend: &Backend) {
-    let reader = storage::kv::sst::raw_sst::RawSSTReader::new(path, backend, false).unwrap();
-    let meta = reader.meta();
-    let min = reader.get_index(0).unwrap().0;
-    let max = reader.get_index(meta.total_keys - 1).unwrap().0;
-
-    println!(
-        "seq:level {}:{}, keys {}, version {}, index offset {}\nmin {:?}\nmax {:?}",
-        meta.number, meta.level, meta.total_keys, meta.version, meta.index_offset, min, max
-    )
+fn sst_summary<W: Write>(reader: &dyn SSTReader, out: &mut W) -> io::Result<()> {
+    match reader.format_meta() {
+        FormatMeta::Raw(meta) => writeln!(
+            out,
+            "raw sst, seq:level {}:{}, keys {}, version {}, index offset {}",
+            meta.number, meta.level, meta.total_keys, meta.version, meta.index_offset
+        )?,
+        FormatMeta::Block(meta) => writeln!(
+            out,
+            "block sst, seq:level {}:{}, keys {}, version {}, index offset {}, index size {}",
+            meta.number,
+            meta.level,
+            meta.total_keys,
+            meta.version,
+            meta.index_offset,
+            meta.index_size
+        )?,
+    }
+    let lifetime = Lifetime::default();
+    let status = ScanStatus::default();
+    let opt = GetOption::default();
+    let min = reader
+        .scan(&opt, Unbounded, Unbounded, &lifetime, &status)
+        .next();
+    let max = reader
+        .scan_rev(&opt, Unbounded, Unbounded, &lifetime, &status)
+        .next();
+    writeln!(
+        out,
+        "min {:?}\nmax {:?}",
+        min.map(|e| e.0),
+        max.map(|e| e.0)
+    )?;
+    if let Err(e) = status.get() {
+        eprintln!("scan stops at {:?}", e);
+    }
+    Ok(())
 }
 
-fn sst_dump(path: &Path, noval: bool, backend: &Backend) {
-    let reader = storage::kv::sst::raw_sst::RawSSTReader::new(path, backend, false).unwrap();
+fn sst_dump<W: Write>(reader: &dyn SSTReader, noval: bool, out: &mut W) -> io::Result<()> {
     let lifetime = Lifetime::default();
+    let status = ScanStatus::default();
     let iter = reader.scan(
         &GetOption::default(),
-        std::ops::Bound::Unbounded,
-        std::ops::Bound::Unbounded,
+        Unbounded,
+        Unbounded,
         &lifetime,
+        &status,
     );
     for (key, value) in iter {
-        print_item(key, value, noval)
+        print_item(key, value, noval, out)?;
+    }
+    if let Err(e) = status.get() {
+        eprintln!("scan stops at {:?}", e);
     }
+    Ok(())
 }
 
-fn sst_get(path: &Path, key: Bytes, noval: bool, backend: &Backend) {
-    let reader = storage::kv::sst::raw_sst::RawSSTReader::new(path, backend, false).unwrap();
+fn sst_get<W: Write>(
+    reader: &dyn SSTReader,
+    key: Bytes,
+    noval: bool,
+    out: &mut W,
+) -> io::Result<()> {
     let lifetime = Lifetime::default();
+    let status = ScanStatus::default();
     let iter = reader.scan(
         &GetOption::default(),
-        std::ops::Bound::Included(key.clone()),
-        std::ops::Bound::Included(key),
+        Included(key.clone()),
+        Included(key),
         &lifetime,
+        &status,
     );
     for (key, value) in iter {
-        print_item(key, value, noval)
+        print_item(key, value, noval, out)?;
+    }
+    if let Err(e) = status.get() {
+        eprintln!("scan stops at {:?}", e);
     }
+    Ok(())
 }
 
-fn print_item(key: InternalKey, value: Value, noval: bool) {
+fn print_item<W: Write>(
+    key: InternalKey,
+    value: Value,
+    noval: bool,
+    out: &mut W,
+) -> io::Result<()> {
+    let state = if key.key_type() == KeyType::Del {
+        "del"
+    } else {
+        "exist"
+    };
     if noval {
-        if key.key_type() == KeyType::Del {
-            println!("del,{},{:?}", key.seq(), key.user_key_slice())
-        } else {
-            println!("exist,{},{:?}", key.seq(), key.user_key_slice());
-        }
-    } else if key.key_type() == KeyType::Del {
-        println!(
-            "del,{},{:?},{:?}",
-            key.seq(),
-            key.user_key_slice(),
-            value.data()
-        )
+        writeln!(out, "{},{},{:?}", state, key.seq(), key.user_key_slice())
     } else {
-        println!(
-            "exist,{},{:?},{:?}",
+        writeln!(
+            out,
+            "{},{},{:?},{:?}",
+            state,
             key.seq(),
             key.user_key_slice(),
             value.data()
-        );
+        )
     }
 }
 
@@ -137,7 +194,7 @@ fn dump_manifest(base: String, show_step: bool, backend: &Backend) {
     name.set_extension("log");
 
     let final_state = {
-        let r = LogReplayer::new(&backend, ManifestLogSerializer);
+        let r = LogReplayer::new(backend, ManifestLogSerializer::default());
         let mut state = VersionSet::default();
         for edit in r.iter(name).unwrap() {
             let edit = edit.unwrap();
@@ -154,3 +211,58 @@ fn dump_manifest(base: String, show_step: bool, backend: &Backend) {
 fn wal() {}
 
 fn db() {}
+
+#[cfg(test)]
+mod test {
+    use storage::{
+        backend::fs::memory::MemoryBasedPersistBackend,
+        kv::sst::{block_sst::BlockSSTWriter, compression::Compression, SSTWriter},
+    };
+
+    use super::*;
+
+    #[test]
+    pub fn block_sst() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let name = PathBuf::from("/db/sst/7.sst");
+        let mut writer = BlockSSTWriter::new(&backend, name, 64, 10, Compression::Lz4);
+        let iter = (0..100).map(|i| {
+            (
+                InternalKey::new(format!("key{:03}", i), i + 1, KeyType::Set),
+                Bytes::from(format!("value{}", i)).into(),
+            )
+        });
+        writer.write(1, 7, iter).unwrap();
+        drop(writer);
+
+        let run = |command: opt::SSTCommands| -> String {
+            let mut out = Vec::new();
+            sst(
+                "/db".to_owned(),
+                command,
+                "7.sst".to_owned(),
+                &backend,
+                &mut out,
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let summary = run(opt::SSTCommands::Summary);
+        assert!(summary.starts_with("block sst, seq:level 7:1, keys 100, version 1"));
+        assert!(summary.contains("key000"));
+        assert!(summary.contains("key099"));
+
+        let dump = run(opt::SSTCommands::Dump(opt::SSTDumpOptions { noval: true }));
+        assert_eq!(dump.lines().count(), 100);
+
+        let get = run(opt::SSTCommands::Get(opt::SSTGetOptions {
+            noval: false,
+            key: "key042".to_owned(),
+        }));
+        assert_eq!(
+            get.trim_end(),
+            format!("exist,43,{:?},{:?}", b"key042", b"value42")
+        );
+    }
+}
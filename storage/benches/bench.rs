@@ -18,14 +18,13 @@ fn rand_key() -> String {
 fn rand_value() -> Bytes {
     let mut rng = rand::thread_rng();
     let len = rng.gen_range(1..8);
-    let mut vec = Vec::new();
-    vec.resize(len, 0);
+    let mut vec = vec![0; len];
 
     rng.fill_bytes(&mut vec[..]);
     vec.into()
 }
 
-fn kv_read(c: &mut Criterion) {}
+fn kv_read(_c: &mut Criterion) {}
 
 fn kv_write(c: &mut Criterion) {
     c.bench_function("write", |b| {
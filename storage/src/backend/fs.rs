@@ -1,12 +1,10 @@
 use std::{
     fmt::Debug,
     io::{Read, Seek, Write},
-    marker::PhantomData,
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use crate::err::Result;
-use integer_encoding::{VarInt, VarIntReader};
 use positioned_io::ReadAt;
 
 pub struct UsageTotal {
@@ -84,6 +82,8 @@ pub trait PersistBackend: Send + Sync + Debug {
     fn usage_total(&self) -> UsageTotal;
     fn make_sure_dir(&self, path: &Path) -> Result<()>;
     fn rename(&self, src: &Path, dst: &Path) -> Result<()>;
+    /// files in the directory, sub directories are not visited
+    fn list(&self, path: &Path) -> Result<Vec<PathBuf>>;
 }
 
 pub mod local;
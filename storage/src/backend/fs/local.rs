@@ -1,11 +1,10 @@
 use bytes::Buf;
-use positioned_io::RandomAccessFile;
 
 use super::*;
 use crate::err::StorageError;
 use std::{
     fs::{self, File},
-    io::{self, Cursor, Seek},
+    io::{self},
     path::PathBuf,
 };
 
@@ -132,4 +131,15 @@ impl PersistBackend for LocalFileBasedPersistBackend {
         fs::rename(src, dst)?;
         Ok(())
     }
+
+    fn list(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
 }
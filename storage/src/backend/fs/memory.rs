@@ -1,6 +1,6 @@
 use std::{
     collections::HashMap,
-    io::{self, Cursor},
+    io,
     sync::{Arc, Mutex},
 };
 
@@ -22,7 +22,6 @@ impl MemoryBasedPersistBackend {
 }
 
 pub struct ReadableMemoryBasedPersist {
-    cursor: Cursor<Bytes>,
     bytes: Bytes,
 }
 
@@ -100,10 +99,8 @@ impl PersistBackend for MemoryBasedPersistBackend {
         let bytes = files
             .get(path.to_str().unwrap())
             .ok_or(io::Error::new(io::ErrorKind::NotFound, "file not exist"))?;
-        let cursor = Cursor::new(bytes.clone());
 
         Ok(Box::new(ReadableMemoryBasedPersist {
-            cursor,
             bytes: bytes.clone(),
         }))
     }
@@ -153,4 +150,13 @@ impl PersistBackend for MemoryBasedPersistBackend {
 
         Ok(())
     }
+
+    fn list(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let files = self.files.lock().unwrap();
+        Ok(files
+            .keys()
+            .map(PathBuf::from)
+            .filter(|file| file.parent() == Some(path))
+            .collect())
+    }
 }
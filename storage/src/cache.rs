@@ -1,22 +1,130 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
 
+use bytes::Bytes;
 use lru::LruCache;
 
 use crate::{
     backend::Backend,
-    kv::sst::{raw_sst::RawSSTReader, SSTReader},
+    err::Result,
+    kv::sst::{
+        format::{self, ReaderOptions},
+        SSTReader,
+    },
     util::fname,
     Config,
 };
 
+const BLOCK_CACHE_SHARDS: usize = 16;
+
+struct BlockCacheShard {
+    lru: LruCache<(u64, u64), Bytes>,
+    usage: u64,
+}
+
+/// lru cache of uncompressed sst blocks, keyed by sst number and block offset
+pub struct BlockCache {
+    shards: Vec<Mutex<BlockCacheShard>>,
+    shard_capacity: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    /// capacity in bytes, 0 disables the cache
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            shards: (0..BLOCK_CACHE_SHARDS)
+                .map(|_| {
+                    Mutex::new(BlockCacheShard {
+                        lru: LruCache::unbounded(),
+                        usage: 0,
+                    })
+                })
+                .collect(),
+            shard_capacity: capacity / BLOCK_CACHE_SHARDS as u64,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, number: u64, offset: u64) -> &Mutex<BlockCacheShard> {
+        let hash = (number ^ offset.rotate_left(32)).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        &self.shards[(hash >> 60) as usize % BLOCK_CACHE_SHARDS]
+    }
+
+    pub fn get(&self, number: u64, offset: u64) -> Option<Bytes> {
+        let data = self
+            .shard(number, offset)
+            .lock()
+            .unwrap()
+            .lru
+            .get(&(number, offset))
+            .cloned();
+        if data.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        data
+    }
+
+    pub fn insert(&self, number: u64, offset: u64, data: Bytes) {
+        let charge = data.len() as u64;
+        // blocks larger than a shard are never cached
+        if charge > self.shard_capacity {
+            return;
+        }
+        let mut shard = self.shard(number, offset).lock().unwrap();
+        if let Some(old) = shard.lru.put((number, offset), data) {
+            shard.usage -= old.len() as u64;
+        }
+        shard.usage += charge;
+        while shard.usage > self.shard_capacity {
+            match shard.lru.pop_lru() {
+                Some((_, evicted)) => shard.usage -= evicted.len() as u64,
+                None => break,
+            }
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// bytes of cached blocks
+    pub fn usage(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().usage)
+            .sum()
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.shard_capacity * BLOCK_CACHE_SHARDS as u64
+    }
+}
+
 pub struct Cache {
     opened_sst: Mutex<LruCache<u64, Arc<dyn SSTReader + Send + Sync>>>,
+    block_cache: Arc<BlockCache>,
 }
 
 impl Cache {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
+        let capacity = NonZeroUsize::new(config.table_cache_size).unwrap_or(NonZeroUsize::MIN);
         Self {
-            opened_sst: Mutex::new(LruCache::new(200.try_into().unwrap())),
+            opened_sst: Mutex::new(LruCache::new(capacity)),
+            block_cache: Arc::new(BlockCache::new(config.block_cache_size)),
         }
     }
 }
@@ -27,15 +135,108 @@ impl Cache {
         config: &Config,
         seq: u64,
         backend: &Backend,
-    ) -> Arc<dyn SSTReader + Send + Sync> {
+    ) -> Result<Arc<dyn SSTReader + Send + Sync>> {
+        if let Some(reader) = self.opened_sst.lock().unwrap().get(&seq) {
+            return Ok(reader.clone());
+        }
+        // open without the lock, the file may be opened twice by concurrent readers
         let sst_path = fname::sst_name(config, seq);
+        let opt = ReaderOptions::new(config).with_block_cache(self.block_cache.clone());
+        let reader = format::open_reader(&sst_path, backend, &opt).map_err(|e| e.with_sst(seq))?;
+        self.opened_sst.lock().unwrap().put(seq, reader.clone());
+        Ok(reader)
+    }
 
-        self.opened_sst
-            .lock()
-            .unwrap()
-            .get_or_insert(seq, || {
-                Arc::new(RawSSTReader::new(&sst_path, backend, config.enable_mmap).unwrap())
-            })
-            .clone()
+    /// close the file if it is opened, readers which are iterating keep it alive
+    pub fn remove_opened_sst(&self, seq: u64) {
+        self.opened_sst.lock().unwrap().pop(&seq);
+    }
+
+    /// opened sst files and the capacity
+    pub fn opened_sst_usage(&self) -> (usize, usize) {
+        let opened_sst = self.opened_sst.lock().unwrap();
+        (opened_sst.len(), opened_sst.cap().get())
+    }
+
+    pub fn block_cache(&self) -> &BlockCache {
+        &self.block_cache
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        backend::fs::memory::MemoryBasedPersistBackend,
+        iterator::ScanStatus,
+        key::{InternalKey, KeyType},
+        kv::{
+            sst::{format::FormatWriter, SSTWriter},
+            superversion::Lifetime,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    pub fn table_cache_capacity() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let config = Config {
+            path: "/nanokv".into(),
+            table_cache_size: 2,
+            ..Default::default()
+        };
+        let cache = Cache::new(&config);
+        // missing file is an error
+        assert!(cache.get_opened_sst(&config, 1, &backend).is_err());
+
+        for number in 1..=3 {
+            let mut writer =
+                FormatWriter::new(&config, &backend, fname::sst_name(&config, number), 0);
+            let iter = (0..100).map(|i| {
+                (
+                    InternalKey::new(format!("key{:03}", i), i, KeyType::Set),
+                    Bytes::from("value").into(),
+                )
+            });
+            writer.write(0, number, iter).unwrap();
+        }
+
+        let lifetime = Lifetime::default();
+        let reader = cache.get_opened_sst(&config, 1, &backend).unwrap();
+        let iter = reader.raw_scan(&lifetime, &ScanStatus::default());
+        drop(reader);
+        cache.get_opened_sst(&config, 2, &backend).unwrap();
+        cache.get_opened_sst(&config, 3, &backend).unwrap();
+        assert_eq!(cache.opened_sst_usage(), (2, 2));
+
+        // evicted reader is kept alive by the iterator
+        assert_eq!(iter.count(), 100);
+
+        cache.remove_opened_sst(3);
+        assert_eq!(cache.opened_sst_usage(), (1, 2));
+    }
+
+    #[test]
+    pub fn block_cache_evict() {
+        // 1024 bytes per shard
+        let cache = BlockCache::new(1024 * BLOCK_CACHE_SHARDS as u64);
+        let block = Bytes::from(vec![0u8; 100]);
+        for offset in 0..1000 {
+            cache.insert(1, offset * 100, block.clone());
+        }
+        assert!(cache.usage() <= cache.capacity());
+        assert!(cache.usage() > 0);
+
+        // the newest block is kept
+        assert!(cache.get(1, 999 * 100).is_some());
+        assert!(cache.get(2, 0).is_none());
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 1);
+
+        // disabled cache
+        let cache = BlockCache::new(0);
+        cache.insert(1, 0, block);
+        assert!(cache.get(1, 0).is_none());
+        assert_eq!(cache.usage(), 0);
     }
 }
@@ -1,33 +1,47 @@
-use std::sync::{
-    atomic::{AtomicBool, AtomicU32, Ordering},
-    Arc,
+use std::{
+    collections::VecDeque,
+    iter::Peekable,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
+use bytes::Bytes;
 use log::info;
-use rand::RngCore;
 use threadpool::ThreadPool;
 
 use crate::{
     backend::Backend,
-    iterator::{MergedIter, ScanIter},
+    comparator::ComparatorRef,
+    err::Result,
+    iterator::{KvIteratorItem, MergedIter, ScanIter, ScanStatus},
+    key::{InternalKey, KeyType, Value},
     kv::{
-        manifest::{FileMetaData, Version, MAX_LEVEL},
-        sst::{self, SSTReader, SSTWriter},
-        superversion::{Lifetime, SuperVersion},
+        manifest::{
+            FileMetaData, FileStatistics, Run, Version, VersionEdit, VersionRef, MAX_LEVEL,
+        },
+        sst::{self, format::ReaderOptions, SSTWriter},
+        superversion::Lifetime,
     },
+    merge::MergeOperatorRef,
+    range_del::RangeTombstone,
     util::fname::{self},
-    Config,
+    CompactionStrategy, Config,
 };
 
 use super::CompactSerializer;
 
-pub type CompactSSTFiles = Vec<FileMetaData>;
+type CommitFn = dyn Fn(Vec<VersionEdit>) -> Result<VersionRef> + Sync + Send + 'static;
+type AllocateFn = dyn Fn() -> u64 + Sync + Send + 'static;
+type SnapshotsFn = dyn Fn() -> Vec<u64> + Sync + Send + 'static;
 
 pub struct MajorCompactionTaskPool {
     pool: ThreadPool,
     config: Arc<Config>,
-    f: Arc<dyn Fn(CompactSSTFiles, Vec<u64>) + Sync + Send + 'static>,
-    factor: AtomicU32,
+    f: Arc<CommitFn>,
+    allocate: Arc<AllocateFn>,
+    snapshots: Arc<SnapshotsFn>,
     backend: &'static Backend,
     stop: Arc<AtomicBool>,
 }
@@ -36,78 +50,465 @@ pub struct MajorCompactionTaskPool {
 pub struct CompactInfo {
     level_bottom: u32,
     level_top: u32,
-    compact_bottom: Vec<u64>,
-    compact_top: Vec<u64>,
-    number: u64,
+    compact_bottom: Vec<Arc<FileStatistics>>,
+    compact_top: Vec<Arc<FileStatistics>>,
+    /// output is a new run of `level_top` instead of being merged into its last run
+    new_run: bool,
+    /// no older data overlaps with compaction files, tombstones can be dropped
+    bottommost: bool,
+}
+
+impl CompactInfo {
+    fn files(&self) -> impl Iterator<Item = &Arc<FileStatistics>> {
+        self.compact_bottom.iter().chain(self.compact_top.iter())
+    }
+
+    /// release picked files, files can be picked by next compaction
+    fn cancel(&self) {
+        for fs in self.files() {
+            fs.set_using();
+        }
+    }
+}
+
+/// entries of one output file, the file is full when it reaches the target size or keys.
+/// versions of the same user key are never split into different files,
+/// nor are keys covered by a range tombstone of the file
+struct OutputIter<'a, I: Iterator> {
+    iter: &'a mut Peekable<I>,
+    comparator: ComparatorRef,
+    last_key: Option<Bytes>,
+    max_end: Option<Bytes>,
+    size: u64,
+    keys: u64,
+    target_size: u64,
+    target_keys: u64,
+}
+
+impl<'a, I> OutputIter<'a, I>
+where
+    I: Iterator<Item = (InternalKey, Value)>,
+{
+    fn new(iter: &'a mut Peekable<I>, config: &Config) -> Self {
+        Self {
+            iter,
+            comparator: config.comparator.clone(),
+            last_key: None,
+            max_end: None,
+            size: 0,
+            keys: 0,
+            target_size: config.target_file_size,
+            target_keys: config.target_file_keys,
+        }
+    }
+
+    fn full(&self) -> bool {
+        (self.target_size > 0 && self.size >= self.target_size)
+            || (self.target_keys > 0 && self.keys >= self.target_keys)
+    }
+}
+
+impl<'a, I> Iterator for OutputIter<'a, I>
+where
+    I: Iterator<Item = (InternalKey, Value)>,
+{
+    type Item = (InternalKey, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.full() {
+            let (key, _) = self.iter.peek()?;
+            // the max key of the file is the end of its tombstones
+            let covered = self.max_end.as_ref().is_some_and(|end| {
+                self.comparator.compare(end, key.user_key_slice()) != std::cmp::Ordering::Less
+            });
+            let same_key = self
+                .last_key
+                .as_ref()
+                .is_some_and(|last| self.comparator.equal(last, key.user_key_slice()));
+            if !same_key && !covered {
+                return None;
+            }
+        }
+        let (key, value) = self.iter.next()?;
+        if key.key_type() == KeyType::RangeDel
+            && self.max_end.as_ref().is_none_or(|end| {
+                self.comparator.compare(end, value.data()) == std::cmp::Ordering::Less
+            })
+        {
+            self.max_end = Some(value.internal());
+        }
+        self.size += (key.len() + value.data().len()) as u64;
+        self.keys += 1;
+        if !self
+            .last_key
+            .as_ref()
+            .is_some_and(|last| self.comparator.equal(last, key.user_key_slice()))
+        {
+            self.last_key = Some(key.user_key());
+        }
+        Some((key, value))
+    }
+}
+
+/// the oldest snapshot which can see the version
+fn snapshot_stripe(snapshots: &[u64], seq: u64) -> usize {
+    snapshots.partition_point(|s| *s < seq)
+}
+
+/// drops versions invisible to all live snapshots. versions are grouped into stripes
+/// by snapshots, only the newest version of each stripe is visible and kept.
+/// merge operands of a stripe are combined with its base value, or with each other
+/// if the base is in an older stripe.
+/// versions covered by a newer range tombstone of the same stripe are dropped
+struct VersionFilter<I>
+where
+    I: Iterator,
+{
+    iter: Peekable<I>,
+    /// live snapshot sequences in ascending order
+    snapshots: Vec<u64>,
+    bottommost: bool,
+    operator: Option<MergeOperatorRef>,
+    comparator: ComparatorRef,
+    last_key: Option<Bytes>,
+    last_stripe: usize,
+    // combined operands from new to old
+    pending: VecDeque<(InternalKey, Value)>,
+    // range tombstones which start before the current key
+    active: Vec<RangeTombstone>,
 }
 
+impl<I> VersionFilter<I>
+where
+    I: Iterator<Item = (InternalKey, Value)>,
+{
+    fn new(
+        iter: I,
+        snapshots: Vec<u64>,
+        bottommost: bool,
+        operator: Option<MergeOperatorRef>,
+        comparator: ComparatorRef,
+    ) -> Self {
+        Self {
+            iter: iter.peekable(),
+            snapshots,
+            bottommost,
+            operator,
+            comparator,
+            last_key: None,
+            last_stripe: 0,
+            pending: VecDeque::new(),
+            active: Vec::new(),
+        }
+    }
+
+    fn covered(&self, key: &InternalKey) -> bool {
+        let stripe = snapshot_stripe(&self.snapshots, key.seq());
+        self.active.iter().any(|t| {
+            t.seq > key.seq()
+                && snapshot_stripe(&self.snapshots, t.seq) == stripe
+                && t.covers(&self.comparator, key.user_key_slice())
+        })
+    }
+
+    fn merge(&mut self, key: InternalKey, value: Value, operator: &MergeOperatorRef) {
+        let stripe = self.last_stripe;
+        let snapshots = &self.snapshots;
+        let comparator = &self.comparator;
+        let mut operands = vec![(key.clone(), value)];
+        // Some(None) if the base is deleted
+        let mut base = None;
+        while let Some((older, value)) = self.iter.next_if(|(older, _)| {
+            comparator.equal(older.user_key_slice(), key.user_key_slice())
+                && older.key_type() != KeyType::RangeDel
+                && snapshot_stripe(snapshots, older.seq()) == stripe
+        }) {
+            if self.covered(&older) {
+                base = Some(None);
+                break;
+            }
+            match older.key_type() {
+                KeyType::Merge => operands.push((older, value)),
+                KeyType::Set => {
+                    base = Some(Some(value));
+                    break;
+                }
+                KeyType::Del | KeyType::RangeDel => {
+                    base = Some(None);
+                    break;
+                }
+            }
+        }
+        // the key has no older version in bottommost compaction
+        if base.is_none()
+            && self.bottommost
+            && self.iter.peek().is_none_or(|(next, _)| {
+                !comparator.equal(next.user_key_slice(), key.user_key_slice())
+            })
+        {
+            base = Some(None);
+        }
+
+        match base {
+            Some(base) => {
+                let operands: Vec<Bytes> =
+                    operands.iter().rev().map(|(_, v)| v.internal()).collect();
+                let value = operator.full_merge(
+                    key.user_key_slice(),
+                    base.as_ref().map(|v| v.data()),
+                    &operands,
+                );
+                self.pending.push_back((
+                    InternalKey::new(key.user_key_slice(), key.seq(), KeyType::Set),
+                    Value::from(value),
+                ));
+            }
+            None => self
+                .pending
+                .extend(operator.partial_merge_all(key.user_key_slice(), operands)),
+        }
+    }
+}
+
+impl<I> Iterator for VersionFilter<I>
+where
+    I: Iterator<Item = (InternalKey, Value)>,
+{
+    type Item = (InternalKey, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Some(item);
+            }
+            let (key, value) = self.iter.next()?;
+            let stripe = snapshot_stripe(&self.snapshots, key.seq());
+
+            let comparator = &self.comparator;
+            self.active.retain(|t| {
+                comparator.compare(key.user_key_slice(), &t.end) == std::cmp::Ordering::Less
+            });
+            if key.key_type() == KeyType::RangeDel {
+                self.active.push(RangeTombstone::from_entry(&key, &value));
+                // covered versions are dropped with it
+                if self.bottommost && stripe == 0 {
+                    continue;
+                }
+                break Some((key, value));
+            }
+
+            if self
+                .last_key
+                .as_ref()
+                .is_some_and(|last| comparator.equal(last, key.user_key_slice()))
+            {
+                if stripe == self.last_stripe {
+                    // shadowed by a newer version
+                    continue;
+                }
+            } else {
+                self.last_key = Some(key.user_key());
+            }
+            self.last_stripe = stripe;
+
+            if self.covered(&key) {
+                continue;
+            }
+
+            if key.is_merge() {
+                if let Some(operator) = self.operator.clone() {
+                    self.merge(key, value, &operator);
+                    continue;
+                }
+            }
+
+            // older versions are in the same stripe and dropped, nothing is left to delete
+            if self.bottommost && stripe == 0 && key.deleted() {
+                continue;
+            }
+            break Some((key, value));
+        }
+    }
+}
+
+/// None if the compaction is stopped, input files are released on error
 fn major_compaction(
     info: CompactInfo,
     config: Arc<Config>,
-    f: Arc<dyn Fn(CompactSSTFiles, Vec<u64>) + Sync + Send + 'static>,
+    allocate: Arc<AllocateFn>,
+    snapshots: Arc<SnapshotsFn>,
+    f: Arc<CommitFn>,
     backend: &'static Backend,
     stop_flag: Arc<AtomicBool>,
-) {
+) -> Result<Option<VersionRef>> {
     if stop_flag.load(Ordering::SeqCst) {
-        return;
+        info.cancel();
+        return Ok(None);
     }
 
-    info!("do major compaction {:?}", info);
+    info!(
+        "do major compaction level {} {:?} -> level {} {:?}",
+        info.level_bottom,
+        info.compact_bottom
+            .iter()
+            .map(|fs| fs.meta().number)
+            .collect::<Vec<_>>(),
+        info.level_top,
+        info.compact_top
+            .iter()
+            .map(|fs| fs.meta().number)
+            .collect::<Vec<_>>(),
+    );
 
-    let removal = info
-        .compact_bottom
-        .iter()
-        .chain(info.compact_top.iter())
-        .cloned()
-        .collect();
-    let mut additional = Vec::new();
+    let mut reader = Vec::new();
+    for fs in info.files() {
+        match sst::format::open_reader(
+            &fname::sst_name(&config, fs.meta().number),
+            backend,
+            &ReaderOptions::new(&config),
+        ) {
+            Ok(r) => reader.push(r),
+            Err(e) => {
+                log::warn!("major compact open sst {} fail {:?}", fs.meta().number, e);
+                info.cancel();
+                return Err(e);
+            }
+        }
+    }
 
-    let reader: Vec<_> = info
-        .compact_bottom
+    let comparator = &config.comparator;
+    let snapshots = snapshots();
+    let tombstones: Vec<RangeTombstone> = reader
         .iter()
-        .chain(info.compact_top.iter())
-        .cloned()
-        .map(|seq| {
-            sst::raw_sst::RawSSTReader::new(
-                &fname::sst_name(&config, seq),
-                backend,
-                config.enable_mmap,
-            )
-            .unwrap()
-        })
+        .flat_map(|r| r.range_tombstones().iter().cloned())
         .collect();
 
     let mut iters = Vec::new();
+    let mut statuses = Vec::new();
     let lifetime = Lifetime::default();
 
-    for file_reader in &reader {
-        iters.push(file_reader.raw_scan(&lifetime))
+    for (fs, file_reader) in info.files().zip(&reader) {
+        // all versions in the file are deleted by a newer tombstone
+        let meta = fs.meta();
+        let stripe = snapshot_stripe(&snapshots, meta.min_ver);
+        if tombstones.iter().any(|t| {
+            t.seq > meta.max_ver
+                && snapshot_stripe(&snapshots, t.seq) == stripe
+                && t.covers(comparator, &meta.min)
+                && comparator.compare(&meta.max, &t.end) == std::cmp::Ordering::Less
+        }) {
+            info!(
+                "major compaction drop sst {} by range tombstones",
+                meta.number
+            );
+            continue;
+        }
+        // iterators stop at corrupted data and record it in the status
+        let status = ScanStatus::default();
+        iters.push(file_reader.raw_scan(&lifetime, &status));
+        statuses.push(status);
     }
+    // tombstones are merged with entries in order of internal keys
+    let mut entries: Vec<_> = tombstones.iter().map(RangeTombstone::to_entry).collect();
+    entries.sort_by(|a, b| comparator.compare_item(&a.0, &b.0));
+    iters.push(ScanIter::new(entries.into_iter()));
+
+    let mut iter = VersionFilter::new(
+        MergedIter::new_all_versions(iters, comparator.clone()),
+        snapshots,
+        info.bottommost,
+        config.merge_operator.clone(),
+        comparator.clone(),
+    )
+    .peekable();
+    let mut outputs: Vec<FileMetaData> = Vec::new();
 
-    let mut writer =
-        sst::raw_sst::RawSSTWriter::new(backend, fname::sst_name(&config, info.number));
+    // roll over to a new file when the output file is full
+    while iter.peek().is_some() {
+        if stop_flag.load(Ordering::SeqCst) {
+            remove_outputs(&config, backend, &outputs);
+            info.cancel();
+            return Ok(None);
+        }
+        let number = allocate();
+        let mut writer = sst::format::FormatWriter::new(
+            &config,
+            backend,
+            fname::sst_name(&config, number),
+            info.level_top,
+        );
 
-    let iter = ScanIter::new(MergedIter::new(iters));
+        match writer.write(info.level_top, number, OutputIter::new(&mut iter, &config)) {
+            Ok(meta) => outputs.push(meta),
+            Err(e) => {
+                log::warn!("major compact fail {:?}", e);
+                remove_outputs(&config, backend, &outputs);
+                info.cancel();
+                return Err(e);
+            }
+        }
+    }
+    if let Some(e) = statuses.iter().find_map(ScanStatus::error) {
+        log::warn!("major compact read input fail {:?}", e);
+        remove_outputs(&config, backend, &outputs);
+        info.cancel();
+        return Err(e);
+    }
+    info!(
+        "major compaction output {:?}",
+        outputs.iter().map(|meta| meta.number).collect::<Vec<_>>()
+    );
 
-    let meta = match writer.write(info.level_top, info.number, iter) {
-        Ok(v) => v,
+    // remove first, output files may take the key range of removed files
+    let mut edits: Vec<_> = info
+        .files()
+        .map(|fs| VersionEdit::SSTRemove(fs.meta().number))
+        .collect();
+    if info.new_run && !outputs.is_empty() {
+        edits.push(VersionEdit::NewRun(info.level_top as u64));
+    }
+    edits.extend(outputs.into_iter().map(VersionEdit::SSTAppended));
+
+    let version = match f(edits) {
+        Ok(version) => version,
         Err(e) => {
-            log::warn!("major compact fail {:?}", e);
-            return;
+            // the record may be persisted, keep the output files
+            log::warn!("major compaction commit fail {:?}", e);
+            info.cancel();
+            return Err(e);
         }
     };
+    for fs in info.files() {
+        fs.set_deprecated();
+    }
+    Ok(Some(version))
+}
 
-    additional.push(meta);
-
-    f(additional, removal)
+/// output files are not committed, remove them
+fn remove_outputs(config: &Config, backend: &Backend, outputs: &[FileMetaData]) {
+    for meta in outputs {
+        if let Err(e) = backend.fs.remove(&fname::sst_name(config, meta.number)) {
+            log::warn!("remove sst {} fail {:?}", meta.number, e);
+        }
+    }
 }
 
 impl MajorCompactionTaskPool {
-    pub fn new<F: Fn(CompactSSTFiles, Vec<u64>) + Sync + Send + 'static>(
+    /// `allocate` returns a new sst number for compaction output,
+    /// `snapshots` returns sequences of live snapshots in ascending order,
+    /// `f` commits the version edits of removed and output files, then returns the new version
+    pub fn new<A, S, F>(
         config: &Config,
         backend: &Backend,
+        allocate: A,
+        snapshots: S,
         f: F,
-    ) -> Arc<Self> {
+    ) -> Arc<Self>
+    where
+        A: Fn() -> u64 + Sync + Send + 'static,
+        S: Fn() -> Vec<u64> + Sync + Send + 'static,
+        F: Fn(Vec<VersionEdit>) -> Result<VersionRef> + Sync + Send + 'static,
+    {
         Arc::new(Self {
             pool: threadpool::Builder::new()
                 .num_threads(config.major_compaction_threads as usize)
@@ -115,31 +516,39 @@ impl MajorCompactionTaskPool {
                 .build(),
             config: Arc::new(config.clone()),
             f: Arc::new(f),
-            factor: AtomicU32::new(10),
-            backend: unsafe { std::mem::transmute(backend) },
+            allocate: Arc::new(allocate),
+            snapshots: Arc::new(snapshots),
+            backend: unsafe { std::mem::transmute::<&Backend, &'static Backend>(backend) },
             stop: AtomicBool::new(false).into(),
         })
     }
 
-    fn compact_async(&self, info: CompactInfo) {
-        let f = self.f.clone();
-        let config = self.config.clone();
-        let stop_flag = self.stop.clone();
-        let backend = self.backend;
-        self.pool
-            .execute(|| major_compaction(info, config, f, backend, stop_flag))
+    fn compact_async(self: &Arc<Self>, info: CompactInfo) {
+        let this = self.clone();
+        self.pool.execute(move || {
+            let version = major_compaction(
+                info,
+                this.config.clone(),
+                this.allocate.clone(),
+                this.snapshots.clone(),
+                this.f.clone(),
+                this.backend,
+                this.stop.clone(),
+            );
+            // output files may trigger compaction of next level
+            if let Ok(Some(version)) = version {
+                this.notify(&version);
+            }
+        })
     }
 
-    pub fn notify(&self, sv: &SuperVersion) {
-        let fac = self.factor.load(Ordering::Relaxed);
-        let config = &self.config;
-        if rand::thread_rng().next_u32() % fac == 0 {
-            if let Some(info) = pick_compaction_info(config, &sv.sst_version) {
-                self.compact_async(info);
-                self.factor.store(2, Ordering::Relaxed);
-            } else {
-                self.factor.store(3, Ordering::Relaxed);
-            }
+    /// pick files from version and compact them in background
+    pub fn notify(self: &Arc<Self>, version: &Version) {
+        if self.stop.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Some(info) = pick_compaction_info(&self.config, version) {
+            self.compact_async(info);
         }
     }
 }
@@ -157,78 +566,726 @@ impl CompactSerializer for MajorCompactionTaskPool {
     }
 }
 
-fn pick_compaction_info(_config: &Config, version: &Version) -> Option<CompactInfo> {
-    // check level 0
-    let mut info = CompactInfo::default();
-    let mut cancel = false;
+fn level_files(version: &Version, level: u32) -> impl Iterator<Item = &Arc<FileStatistics>> {
+    version
+        .level_n(level)
+        .iter()
+        .flat_map(|run| run.files().iter())
+}
+
+/// max files of level n (n >= 1) before it should be compacted into level n + 1
+fn level_max_files(config: &Config, level: u32) -> u64 {
+    let mut max_files = config.lx_compaction_files.max(1) as u64;
+    for _ in 1..level {
+        max_files = max_files.saturating_mul(config.level_data_radio.max(1) as u64);
+    }
+    max_files
+}
+
+fn key_range(version: &Version, files: &[Arc<FileStatistics>]) -> (Bytes, Bytes) {
+    let comparator = version.comparator();
+    let min = files
+        .iter()
+        .map(|fs| &fs.meta().min)
+        .min_by(|a, b| comparator.compare(a, b))
+        .unwrap()
+        .clone();
+    let max = files
+        .iter()
+        .map(|fs| &fs.meta().max)
+        .max_by(|a, b| comparator.compare(a, b))
+        .unwrap()
+        .clone();
+    (min, max)
+}
+
+/// no data older than compaction files overlaps with them,
+/// `older_runs` are runs of top level which are older than compaction files
+fn is_bottommost(version: &Version, info: &CompactInfo, older_runs: &[Run]) -> bool {
+    let files: Vec<_> = info.files().cloned().collect();
+    let (min, max) = key_range(version, &files);
+    let comparator = version.comparator();
+    let overlapped = |fs: &Arc<FileStatistics>| {
+        comparator.overlapped(&fs.meta().min, &fs.meta().max, &min, &max)
+    };
+
+    if older_runs
+        .iter()
+        .flat_map(|run| run.files())
+        .any(overlapped)
+    {
+        return false;
+    }
+    !((info.level_top + 1)..MAX_LEVEL).any(|level| level_files(version, level).any(overlapped))
+}
+
+/// pick files of top level which overlapped with bottom files
+fn pick_top_overlapped(version: &Version, info: &mut CompactInfo) -> bool {
+    let (min, max) = key_range(version, &info.compact_bottom);
+    let comparator = version.comparator();
+    for fs in level_files(version, info.level_top) {
+        if !comparator.overlapped(&fs.meta().min, &fs.meta().max, &min, &max) {
+            continue;
+        }
+        // file is compacting by other task
+        if !fs.set_picked() {
+            return false;
+        }
+        info.compact_top.push(fs.clone());
+    }
+    true
+}
+
+fn pick_level0(config: &Config, version: &Version) -> Option<CompactInfo> {
+    let files: Vec<_> = level_files(version, 0).collect();
+    if files.len() < config.l0_compaction_files.max(1) as usize {
+        return None;
+    }
+    // files of level 0 are overlapped, only one compaction is allowed
+    if files.iter().any(|fs| !fs.is_using_relaxed()) {
+        return None;
+    }
+
+    let mut info = CompactInfo {
+        level_bottom: 0,
+        level_top: 1,
+        ..Default::default()
+    };
+    for fs in files {
+        if !fs.set_picked() {
+            info.cancel();
+            return None;
+        }
+        info.compact_bottom.push(fs.clone());
+    }
+    if !pick_top_overlapped(version, &mut info) {
+        info.cancel();
+        return None;
+    }
+    info.bottommost = is_bottommost(version, &info, &[]);
+    Some(info)
+}
+
+fn pick_level_n(config: &Config, version: &Version, level: u32) -> Option<CompactInfo> {
+    let total = level_files(version, level).count() as u64;
+    if total <= level_max_files(config, level) {
+        return None;
+    }
+
+    // the oldest file is compacted first
+    let mut candidates: Vec<_> = level_files(version, level)
+        .filter(|fs| fs.is_using_relaxed())
+        .collect();
+    candidates.sort_by_key(|fs| fs.meta().number);
+
+    for fs in candidates {
+        if !fs.set_picked() {
+            continue;
+        }
+        let mut info = CompactInfo {
+            level_bottom: level,
+            level_top: level + 1,
+            compact_bottom: vec![fs.clone()],
+            ..Default::default()
+        };
+        if pick_top_overlapped(version, &mut info) {
+            info.bottommost = is_bottommost(version, &info, &[]);
+            return Some(info);
+        }
+        info.cancel();
+    }
+    None
+}
+
+fn run_size(run: &Run) -> u64 {
+    run.files().iter().map(|fs| fs.meta().keys).sum()
+}
+
+/// pick consecutive runs of similar size, runs are ordered from old to new.
+/// starting from a newer run, an older run joins the candidates while its size
+/// is not `size_tried_radio` percent larger than the total size of candidates
+fn pick_size_tiered(config: &Config, version: &Version, level: u32) -> Option<CompactInfo> {
+    let runs = version.level_n(level);
+    let min_runs = config.size_tiered_min_runs.max(2) as usize;
+    if runs.len() < min_runs {
+        return None;
+    }
+    let radio = config.size_tried_radio as u64;
+
+    for newest in (0..runs.len()).rev() {
+        let mut total = 0u64;
+        let mut oldest = newest + 1;
+        for idx in (0..=newest).rev() {
+            let run = &runs[idx];
+            let size = run_size(run);
+            if idx != newest && size.saturating_mul(100) > total.saturating_mul(100 + radio) {
+                break;
+            }
+            // run is compacting by other task
+            if run.files().iter().any(|fs| !fs.is_using_relaxed()) {
+                break;
+            }
+            total += size;
+            oldest = idx;
+        }
+        if newest + 1 - oldest < min_runs {
+            continue;
+        }
+
+        let mut info = CompactInfo {
+            level_bottom: level,
+            level_top: level,
+            new_run: true,
+            ..Default::default()
+        };
+        for fs in runs[oldest..=newest].iter().flat_map(|run| run.files()) {
+            if !fs.set_picked() {
+                info.cancel();
+                return None;
+            }
+            info.compact_bottom.push(fs.clone());
+        }
+        info.bottommost = is_bottommost(version, &info, &runs[..oldest]);
+        return Some(info);
+    }
+    None
+}
+
+fn pick_compaction_info(config: &Config, version: &Version) -> Option<CompactInfo> {
+    if config.compaction_strategy == CompactionStrategy::SizeTiered {
+        return (0..MAX_LEVEL).find_map(|level| pick_size_tiered(config, version, level));
+    }
+    if let Some(info) = pick_level0(config, version) {
+        return Some(info);
+    }
+    // the last level can't be compacted
+    for level in 1..(MAX_LEVEL - 1) {
+        if let Some(info) = pick_level_n(config, version, level) {
+            return Some(info);
+        }
+    }
     None
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{kv::manifest::VersionSet, merge::UInt64AddOperator};
+
+    use super::*;
+
+    fn add_file(vs: &mut VersionSet, number: u64, min: &str, max: &str, level: u32, new_run: bool) {
+        if new_run {
+            vs.add(&VersionEdit::NewRun(level as u64));
+        }
+        let meta = FileMetaData::new(
+            number,
+            Bytes::copy_from_slice(min.as_bytes()),
+            Bytes::copy_from_slice(max.as_bytes()),
+            number * 10,
+            number * 10 + 9,
+            10,
+            level,
+        );
+        vs.add(&VersionEdit::SSTAppended(meta));
+    }
+
+    fn numbers(files: &[Arc<FileStatistics>]) -> Vec<u64> {
+        let mut v: Vec<_> = files.iter().map(|fs| fs.meta().number).collect();
+        v.sort();
+        v
+    }
+
+    #[test]
+    pub fn pick_level0_files() {
+        let config = Config::default();
+        let mut vs = VersionSet::default();
+        add_file(&mut vs, 1, "100", "200", 1, true);
+        add_file(&mut vs, 2, "300", "400", 1, false);
+        add_file(&mut vs, 3, "500", "600", 1, false);
+        for number in 10..13 {
+            add_file(&mut vs, number, "150", "350", 0, true);
+        }
+        assert!(pick_compaction_info(&config, &vs.current()).is_none());
+
+        add_file(&mut vs, 13, "120", "160", 0, true);
+        let version = vs.current();
+        let info = pick_compaction_info(&config, &version).unwrap();
+        assert_eq!(info.level_bottom, 0);
+        assert_eq!(info.level_top, 1);
+        assert_eq!(numbers(&info.compact_bottom), vec![10, 11, 12, 13]);
+        assert_eq!(numbers(&info.compact_top), vec![1, 2]);
+
+        // files are picked by running compaction
+        assert!(pick_compaction_info(&config, &version).is_none());
 
-    // if version.level_size(0) >= 4 {
-    //     let mut num = 0;
-    //     for fs in version.level_n(0) {
-    //         if fs.is_using_relaxed() {
-    //             num += 1;
-    //         }
-    //     }
-
-    //     if num > 4 {
-    //         for fs in version.level_n(0) {
-    //             if fs.set_picked() {
-    //                 info.compact_bottom.push(fs.meta().number);
-    //             }
-    //         }
-    //     }
-    //     // level 1
-    //     for fs in version.level_n(1) {
-    //         if fs.set_picked() {
-    //             info.compact_top.push(fs.meta().number);
-    //             break;
-    //         }
-    //     }
-    //     info.level_top = 1;
-
-    //     if info.compact_bottom.len() < 4 {
-    //         cancel = true;
-    //     }
-    // } else {
-    //     for level in 1..MAX_LEVEL {
-    //         let mut num = 0;
-    //         for fs in version.level_n(level) {
-    //             if fs.is_using_relaxed() {
-    //                 num += 1;
-    //             }
-    //         }
-
-    //         if num > 1 {
-    //             for fs in version.level_n(0) {
-    //                 if fs.set_picked() {
-    //                     info.compact_bottom.push(fs.meta().number);
-    //                 }
-    //             }
-    //         }
-    //     }
-    // }
-    // if info.compact_bottom.is_empty() {
-    //     cancel = true;
-    // }
-
-    // if cancel {
-    //     for seq in info.compact_bottom {
-    //         for fs in version.level_n(info.level_bottom) {
-    //             if fs.meta().number == seq {
-    //                 fs.set_using();
-    //             }
-    //         }
-    //     }
-    //     for seq in info.compact_top {
-    //         for fs in version.level_n(info.level_top) {
-    //             if fs.meta().number == seq {
-    //                 fs.set_using();
-    //             }
-    //         }
-    //     }
-    //     None
-    // } else {
-    //     Some(info)
-    // }
+        info.cancel();
+        assert!(pick_compaction_info(&config, &version).is_some());
+    }
+
+    #[test]
+    pub fn pick_level_n_files() {
+        let config = Config::default();
+        let mut vs = VersionSet::default();
+        add_file(&mut vs, 1, "100", "200", 2, true);
+        add_file(&mut vs, 2, "300", "400", 2, false);
+
+        add_file(&mut vs, 6, "100", "120", 1, true);
+        add_file(&mut vs, 4, "130", "320", 1, false);
+        add_file(&mut vs, 5, "500", "600", 1, false);
+        assert!(pick_compaction_info(&config, &vs.current()).is_none());
+
+        add_file(&mut vs, 7, "700", "800", 1, false);
+        let version = vs.current();
+        let info = pick_compaction_info(&config, &version).unwrap();
+        assert_eq!(info.level_bottom, 1);
+        assert_eq!(info.level_top, 2);
+        assert_eq!(numbers(&info.compact_bottom), vec![4]);
+        assert_eq!(numbers(&info.compact_top), vec![1, 2]);
+
+        // level 2 files are picked, the next candidate doesn't overlap with them
+        let info2 = pick_compaction_info(&config, &version).unwrap();
+        assert_eq!(numbers(&info2.compact_bottom), vec![5]);
+        assert!(info2.compact_top.is_empty());
+    }
+
+    #[test]
+    pub fn pick_size_tiered_runs() {
+        let config = Config {
+            compaction_strategy: CompactionStrategy::SizeTiered,
+            ..Default::default()
+        };
+        let mut vs = VersionSet::default();
+        // a large old run and 3 small runs
+        add_file(&mut vs, 0, "100", "900", 0, true);
+        add_file(&mut vs, 1, "100", "900", 0, false);
+        add_file(&mut vs, 2, "100", "900", 0, false);
+        add_file(&mut vs, 3, "100", "900", 0, false);
+        add_file(&mut vs, 4, "100", "900", 0, false);
+        add_file(&mut vs, 5, "100", "200", 0, true);
+        add_file(&mut vs, 6, "100", "200", 0, true);
+        add_file(&mut vs, 7, "100", "200", 0, true);
+        assert!(pick_compaction_info(&config, &vs.current()).is_none());
+
+        add_file(&mut vs, 8, "300", "400", 0, true);
+        let version = vs.current();
+        // the min runs are not taken from the level 0 trigger of leveled compaction
+        let strict = Config {
+            size_tiered_min_runs: 5,
+            l0_compaction_files: 2,
+            ..config.clone()
+        };
+        assert!(pick_compaction_info(&strict, &version).is_none());
+        let info = pick_compaction_info(&config, &version).unwrap();
+        assert_eq!(info.level_bottom, 0);
+        assert_eq!(info.level_top, 0);
+        assert!(info.new_run);
+        assert_eq!(numbers(&info.compact_bottom), vec![5, 6, 7, 8]);
+        assert!(info.compact_top.is_empty());
+        assert!(pick_compaction_info(&config, &version).is_none());
+
+        // merged run takes the place of the old runs
+        for number in 5..9 {
+            vs.add(&VersionEdit::SSTRemove(number)).unwrap();
+        }
+        add_file(&mut vs, 9, "300", "400", 0, true);
+        vs.add(&VersionEdit::NewRun(0));
+        let meta = FileMetaData::new(10, "100".into(), "400".into(), 50, 89, 40, 0);
+        vs.add(&VersionEdit::SSTAppended(meta));
+        let version = vs.current();
+        let runs: Vec<_> = version
+            .level_n(0)
+            .iter()
+            .map(|run| run.files()[0].meta().number)
+            .collect();
+        assert_eq!(runs, vec![0, 10, 9]);
+    }
+
+    #[test]
+    pub fn split_output_files() {
+        let config = Config {
+            target_file_keys: 2,
+            ..Default::default()
+        };
+        let entries = vec![("a", 3), ("b", 5), ("b", 4), ("b", 1), ("c", 2), ("d", 6)];
+        let mut iter = entries
+            .into_iter()
+            .map(|(key, seq)| {
+                (
+                    InternalKey::new(key, seq, KeyType::Set),
+                    Value::from(Bytes::from_static(b"value")),
+                )
+            })
+            .peekable();
+
+        let mut files = Vec::new();
+        while iter.peek().is_some() {
+            let file: Vec<_> = OutputIter::new(&mut iter, &config)
+                .map(|(key, _)| (key.user_key(), key.seq()))
+                .collect();
+            files.push(file);
+        }
+        // versions of key b stay in the same file
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].len(), 4);
+        assert_eq!(files[0][3], (Bytes::from_static(b"b"), 1));
+        assert_eq!(files[1][0], (Bytes::from_static(b"c"), 2));
+        assert_eq!(files[1].len(), 2);
+    }
+
+    fn filter_versions(
+        entries: &[(&str, u64, KeyType)],
+        snapshots: Vec<u64>,
+        bottommost: bool,
+    ) -> Vec<String> {
+        let iter = entries.iter().map(|(key, seq, ty)| {
+            (
+                InternalKey::new(key, *seq, *ty),
+                Value::from(Bytes::from_static(b"value")),
+            )
+        });
+        VersionFilter::new(iter, snapshots, bottommost, None, ComparatorRef::default())
+            .map(|(key, _)| format!("{}@{}", key.user_key_slice().escape_ascii(), key.seq()))
+            .collect()
+    }
+
+    #[test]
+    pub fn drop_invisible_versions() {
+        let entries = [
+            ("a", 9, KeyType::Set),
+            ("a", 7, KeyType::Set),
+            ("a", 5, KeyType::Set),
+            ("a", 2, KeyType::Set),
+            ("b", 8, KeyType::Del),
+            ("b", 3, KeyType::Set),
+            ("c", 4, KeyType::Del),
+            ("c", 1, KeyType::Set),
+        ];
+        // only the newest version is visible without snapshots
+        assert_eq!(
+            filter_versions(&entries, vec![], false),
+            vec!["a@9", "b@8", "c@4"]
+        );
+        assert_eq!(filter_versions(&entries, vec![], true), vec!["a@9"]);
+
+        // snapshot 6 sees a@5, b@3 and c@4, snapshot 3 sees a@2, b@3 and c@1
+        assert_eq!(
+            filter_versions(&entries, vec![3, 6], false),
+            vec!["a@9", "a@5", "a@2", "b@8", "b@3", "c@4", "c@1"]
+        );
+        // tombstone c@4 is hidden from snapshot 3, it can't be dropped
+        assert_eq!(
+            filter_versions(&entries, vec![3, 6], true),
+            vec!["a@9", "a@5", "a@2", "b@8", "b@3", "c@4", "c@1"]
+        );
+    }
+
+    #[test]
+    pub fn merge_operands() {
+        let entries = [
+            ("a", 9, KeyType::Merge, 1),
+            ("a", 7, KeyType::Merge, 2),
+            ("a", 5, KeyType::Set, 10),
+            ("a", 2, KeyType::Merge, 100),
+            ("b", 8, KeyType::Merge, 1),
+            ("b", 6, KeyType::Merge, 2),
+            ("b", 4, KeyType::Merge, 3),
+            ("c", 3, KeyType::Merge, 5),
+            ("c", 1, KeyType::Del, 0),
+        ];
+        let filter = |snapshots: Vec<u64>, bottommost: bool| -> Vec<String> {
+            let iter = entries.iter().map(|(key, seq, ty, value)| {
+                (
+                    InternalKey::new(key, *seq, *ty),
+                    Value::from(Bytes::copy_from_slice(&u64::to_le_bytes(*value))),
+                )
+            });
+            let operator = Some(MergeOperatorRef::new(UInt64AddOperator));
+            VersionFilter::new(
+                iter,
+                snapshots,
+                bottommost,
+                operator,
+                ComparatorRef::default(),
+            )
+            .map(|(key, value)| {
+                format!(
+                    "{}@{}:{:?}={}",
+                    key.user_key_slice().escape_ascii(),
+                    key.seq(),
+                    key.key_type(),
+                    u64::from_le_bytes(value.data().try_into().unwrap())
+                )
+            })
+            .collect()
+        };
+
+        // operands are merged with the base, or with each other if there is no base
+        assert_eq!(
+            filter(vec![], false),
+            vec!["a@9:Set=13", "b@8:Merge=6", "c@3:Set=5"]
+        );
+        assert_eq!(
+            filter(vec![], true),
+            vec!["a@9:Set=13", "b@8:Set=6", "c@3:Set=5"]
+        );
+
+        // operands are not merged across snapshots
+        assert_eq!(
+            filter(vec![6], false),
+            vec![
+                "a@9:Merge=3",
+                "a@5:Set=10",
+                "b@8:Merge=1",
+                "b@6:Merge=5",
+                "c@3:Set=5"
+            ]
+        );
+        assert_eq!(
+            filter(vec![6], true),
+            vec![
+                "a@9:Merge=3",
+                "a@5:Set=10",
+                "b@8:Merge=1",
+                "b@6:Set=5",
+                "c@3:Set=5"
+            ]
+        );
+    }
+
+    #[test]
+    pub fn range_tombstones() {
+        let entries = [
+            ("a", 9, KeyType::Set),
+            ("b", 10, KeyType::RangeDel),
+            ("b", 7, KeyType::Set),
+            ("c", 12, KeyType::Set),
+            ("c", 3, KeyType::Set),
+            ("d", 5, KeyType::Set),
+            ("e", 2, KeyType::Set),
+        ];
+        let iter = || {
+            entries.iter().map(|(key, seq, ty)| {
+                // the tombstone deletes [b, e)
+                let value = if *ty == KeyType::RangeDel {
+                    "e"
+                } else {
+                    "value"
+                };
+                (
+                    InternalKey::new(key, *seq, *ty),
+                    Value::from(Bytes::from_static(value.as_bytes())),
+                )
+            })
+        };
+        let filter = |snapshots: Vec<u64>, bottommost: bool| -> Vec<String> {
+            VersionFilter::new(
+                iter(),
+                snapshots,
+                bottommost,
+                None,
+                ComparatorRef::default(),
+            )
+            .map(|(key, _)| format!("{}@{}", key.user_key_slice().escape_ascii(), key.seq()))
+            .collect()
+        };
+
+        assert_eq!(filter(vec![], false), vec!["a@9", "b@10", "c@12", "e@2"]);
+        assert_eq!(filter(vec![], true), vec!["a@9", "c@12", "e@2"]);
+        // snapshot 6 sees c@3 and d@5 under the tombstone
+        assert_eq!(
+            filter(vec![6], true),
+            vec!["a@9", "b@10", "c@12", "c@3", "d@5", "e@2"]
+        );
+
+        // keys until the end of the tombstone, which is the max key of the file, stay in its file
+        let config = Config {
+            target_file_keys: 1,
+            ..Default::default()
+        };
+        let mut iter = iter().peekable();
+        let mut files = Vec::new();
+        while iter.peek().is_some() {
+            let file: Vec<_> = OutputIter::new(&mut iter, &config)
+                .map(|(key, _)| format!("{}@{}", key.user_key_slice().escape_ascii(), key.seq()))
+                .collect();
+            files.push(file);
+        }
+        assert_eq!(
+            files,
+            vec![
+                vec!["a@9"],
+                vec!["b@10", "b@7", "c@12", "c@3", "d@5", "e@2"]
+            ]
+        );
+    }
+
+    #[test]
+    pub fn pick_bottommost_files() {
+        let config = Config::default();
+        let mut vs = VersionSet::default();
+        add_file(&mut vs, 1, "100", "200", 1, true);
+        add_file(&mut vs, 2, "700", "800", 2, true);
+        for number in 10..14 {
+            add_file(&mut vs, number, "150", "350", 0, true);
+        }
+        let info = pick_compaction_info(&config, &vs.current()).unwrap();
+        assert!(info.bottommost);
+        info.cancel();
+
+        add_file(&mut vs, 3, "300", "400", 3, true);
+        let info = pick_compaction_info(&config, &vs.current()).unwrap();
+        assert!(!info.bottommost);
+    }
+
+    #[test]
+    pub fn remove_picked_files() {
+        let mut vs = VersionSet::default();
+        add_file(&mut vs, 3, "100", "200", 1, true);
+        add_file(&mut vs, 1, "300", "400", 1, false);
+        add_file(&mut vs, 2, "500", "600", 1, false);
+
+        vs.add(&VersionEdit::SSTRemove(1)).unwrap();
+        vs.add(&VersionEdit::SSTRemove(2)).unwrap();
+        let version = vs.current();
+        let run = &version.level_n(1)[0];
+        assert_eq!(run.files().len(), 1);
+        assert!(run.binary_find_file(b"550").is_none());
+        assert_eq!(run.binary_find_file(b"150").unwrap().meta().number, 3);
+    }
+
+    #[test]
+    pub fn comparator_equal_keys() {
+        use crate::test::CaseInsensitiveComparator;
+
+        let comparator = ComparatorRef::new(CaseInsensitiveComparator);
+        let entries = [
+            ("A", 9, KeyType::Merge, 1),
+            ("a", 7, KeyType::Set, 10),
+            ("a", 5, KeyType::Set, 100),
+            ("B", 6, KeyType::Set, 2),
+            ("b", 3, KeyType::Set, 3),
+            ("c", 2, KeyType::Set, 4),
+        ];
+        let iter = entries.iter().map(|(key, seq, ty, value)| {
+            (
+                InternalKey::new(key, *seq, *ty),
+                Value::from(Bytes::copy_from_slice(&u64::to_le_bytes(*value))),
+            )
+        });
+        let operator = Some(MergeOperatorRef::new(UInt64AddOperator));
+        let filtered: Vec<_> =
+            VersionFilter::new(iter, vec![], false, operator, comparator.clone())
+                .map(|(key, value)| {
+                    format!(
+                        "{}@{}:{:?}={}",
+                        key.user_key_slice().escape_ascii(),
+                        key.seq(),
+                        key.key_type(),
+                        u64::from_le_bytes(value.data().try_into().unwrap())
+                    )
+                })
+                .collect();
+        assert_eq!(filtered, vec!["A@9:Set=11", "B@6:Set=2", "c@2:Set=4"]);
+
+        // versions of a key in different cases stay in the same file
+        let config = Config {
+            target_file_keys: 1,
+            comparator,
+            ..Default::default()
+        };
+        let mut iter = entries
+            .iter()
+            .map(|(key, seq, ty, _)| {
+                (
+                    InternalKey::new(key, *seq, *ty),
+                    Value::from(Bytes::from_static(b"value")),
+                )
+            })
+            .peekable();
+        let mut files = Vec::new();
+        while iter.peek().is_some() {
+            let file: Vec<_> = OutputIter::new(&mut iter, &config)
+                .map(|(key, _)| key.seq())
+                .collect();
+            files.push(file);
+        }
+        assert_eq!(files, vec![vec![9, 7, 5], vec![6, 3], vec![2]]);
+    }
+
+    #[test]
+    pub fn corrupted_input() {
+        use crate::backend::fs::memory::MemoryBasedPersistBackend;
+        use crate::err::StorageError;
+        use crate::kv::sst::compression::Compression;
+        use crate::kv::sst::format::Format;
+        use std::sync::atomic::AtomicU64;
+
+        let backend: &'static Backend =
+            Box::leak(Box::new(Backend::new(MemoryBasedPersistBackend::new())));
+        // offset of the first value
+        for (format, offset) in [(Format::RawSST, 11), (Format::BlockSST, 12)] {
+            let config = Arc::new(Config {
+                path: "/db".into(),
+                sst_format: format,
+                compression_per_level: vec![Compression::None],
+                ..Default::default()
+            });
+            let mut info = CompactInfo {
+                level_bottom: 0,
+                level_top: 1,
+                ..Default::default()
+            };
+            for number in [1, 2] {
+                let name = fname::sst_name(&config, number);
+                let mut writer = sst::format::FormatWriter::new(&config, backend, name, 0);
+                let iter = ["a", "b", "c"].into_iter().map(|k| {
+                    (
+                        InternalKey::new(k, number, KeyType::Set),
+                        Bytes::from(k).into(),
+                    )
+                });
+                let fs = Arc::new(FileStatistics::new(writer.write(0, number, iter).unwrap()));
+                assert!(fs.set_picked());
+                info.compact_bottom.push(fs);
+            }
+            let name = fname::sst_name(&config, 2);
+            let file = backend.fs.open(&name, false).unwrap();
+            let mut data = vec![0; file.size() as usize];
+            file.read_exact_at(0, &mut data).unwrap();
+            data[offset] ^= 0x1;
+            backend
+                .fs
+                .create(&name, None)
+                .unwrap()
+                .write_all(&data)
+                .unwrap();
+
+            let files = info.compact_bottom.clone();
+            let next = AtomicU64::new(3);
+            let result = major_compaction(
+                info,
+                config.clone(),
+                Arc::new(move || next.fetch_add(1, Ordering::SeqCst)),
+                Arc::new(Vec::new),
+                Arc::new(|_| panic!("corrupted compaction is committed")),
+                backend,
+                Arc::new(AtomicBool::new(false)),
+            );
+            assert_eq!(result.unwrap_err(), StorageError::SSTDataCorrupt(2));
+            // inputs are released and outputs are removed
+            assert!(files.iter().all(|fs| fs.is_using_relaxed()));
+            let mut names = backend.fs.list(&fname::sst_dir(&config)).unwrap();
+            names.sort();
+            assert_eq!(
+                names,
+                vec![fname::sst_name(&config, 1), fname::sst_name(&config, 2)]
+            );
+            for number in [1, 2] {
+                backend
+                    .fs
+                    .remove(&fname::sst_name(&config, number))
+                    .unwrap();
+            }
+        }
+    }
 }
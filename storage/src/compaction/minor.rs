@@ -42,11 +42,15 @@ fn minor_compaction(
     let meta = {
         let beg = Instant::now();
         let number = table.number();
-        let iter = table.iter();
-        let mut sst =
-            sst::raw_sst::RawSSTWriter::new(backend, fname::sst_name(&config, table.number()));
+        let iter = table.entries();
+        let mut sst = sst::format::FormatWriter::new(
+            &config,
+            backend,
+            fname::sst_name(&config, table.number()),
+            0,
+        );
 
-        let meta = match sst.write(0, number, iter.map(|v| (v.0.clone(), v.1.clone().into()))) {
+        let meta = match sst.write(0, number, iter) {
             Ok(v) => v,
             Err(e) => {
                 log::warn!("minor compaction fail {:?}", e);
@@ -74,7 +78,7 @@ impl MinorCompactionTaskPool {
                 .build(),
             config: Arc::new(config.clone()),
             f: Arc::new(f),
-            backend: unsafe { std::mem::transmute(backend) },
+            backend: unsafe { std::mem::transmute::<&Backend, &'static Backend>(backend) },
             stop: AtomicBool::new(false).into(),
         })
     }
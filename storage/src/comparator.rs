@@ -0,0 +1,93 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::iterator::KvIteratorItem;
+
+/// order of user keys
+pub trait Comparator: Send + Sync {
+    /// persisted in the manifest, a db can not be opened with a comparator of another name
+    fn name(&self) -> &str;
+
+    /// keys which compare equal are the same user key, even if their bytes differ.
+    /// bloom filters hash key bytes, disable them if equal keys may differ in bytes
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "nanokv.BytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+#[derive(Clone)]
+pub struct ComparatorRef(Arc<dyn Comparator>);
+
+impl ComparatorRef {
+    pub fn new<C: Comparator + 'static>(comparator: C) -> Self {
+        Self(Arc::new(comparator))
+    }
+
+    /// user key ascending, then sequence descending
+    pub fn compare_item<T: KvIteratorItem>(&self, a: &T, b: &T) -> Ordering {
+        match self.compare(a.user_key_slice(), b.user_key_slice()) {
+            Ordering::Equal => b.seq().cmp(&a.seq()),
+            v => v,
+        }
+    }
+
+    /// user keys are the same key, they may differ in bytes
+    pub fn equal(&self, a: &[u8], b: &[u8]) -> bool {
+        self.compare(a, b) == Ordering::Equal
+    }
+
+    pub fn min<'a>(&self, a: &'a [u8], b: &'a [u8]) -> &'a [u8] {
+        if self.compare(a, b) == Ordering::Greater {
+            b
+        } else {
+            a
+        }
+    }
+
+    pub fn max<'a>(&self, a: &'a [u8], b: &'a [u8]) -> &'a [u8] {
+        if self.compare(a, b) == Ordering::Less {
+            b
+        } else {
+            a
+        }
+    }
+
+    /// key range [min_a, max_a] overlaps with [min_b, max_b]
+    pub fn overlapped(&self, min_a: &[u8], max_a: &[u8], min_b: &[u8], max_b: &[u8]) -> bool {
+        self.compare(max_a, min_b) != Ordering::Less
+            && self.compare(min_a, max_b) != Ordering::Greater
+    }
+}
+
+impl Default for ComparatorRef {
+    fn default() -> Self {
+        Self::new(BytewiseComparator)
+    }
+}
+
+impl Deref for ComparatorRef {
+    type Target = dyn Comparator;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl Debug for ComparatorRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -4,7 +4,37 @@ use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
 
+use crate::comparator::ComparatorRef;
+use crate::kv::sst::compression::Compression;
+use crate::kv::sst::format::Format;
+use crate::merge::MergeOperatorRef;
+use crate::prefix::PrefixExtractorRef;
+
+/// how sst files are merged by major compaction
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompactionStrategy {
+    /// compact files of level n into level n + 1, each level n >= 1 holds a single run
+    #[default]
+    Leveled,
+    /// merge runs of similar size within a level into one run
+    SizeTiered,
+}
+
+/// when the wal is synced to disk, a write with `WriteOption::with_fsync` is always synced
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalSyncPolicy {
+    /// left to the os
+    #[default]
+    Never,
+    EveryWrite,
+    /// synced by a background thread every n milliseconds
+    IntervalMs(u64),
+    /// synced when n bytes are written since last sync
+    Bytes(u64),
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
 pub struct Config {
     pub path: PathBuf,
     pub no_wal: bool,
@@ -14,8 +44,43 @@ pub struct Config {
     pub l0_compaction_files: u32,
     pub lx_compaction_files: u32,
     pub leveled_compaction_level: u32,
+    /// size-tiered: a run is merged with newer runs when it is at most
+    /// `size_tried_radio` percent larger than their total size
     pub size_tried_radio: u32,
+    /// size-tiered: min number of similar sized runs of a level merged at once
+    pub size_tiered_min_runs: u32,
     pub level_data_radio: u32,
+    pub compaction_strategy: CompactionStrategy,
+    /// compaction output rolls over to a new sst file when it reaches the size in bytes, 0 is unlimited
+    pub target_file_size: u64,
+    /// compaction output rolls over to a new sst file when it reaches the keys, 0 is unlimited
+    pub target_file_keys: u64,
+    pub wal_sync: WalSyncPolicy,
+    /// bits per key of sst bloom filter, 0 disables the filter
+    pub bloom_bits_per_key: u32,
+    pub sst_format: Format,
+    /// data block size of block sst in bytes
+    pub block_size: u64,
+    /// block compression of level n is the nth entry, or the last one if there are fewer entries
+    pub compression_per_level: Vec<Compression>,
+    /// max sst files kept opened by the table cache
+    pub table_cache_size: usize,
+    /// capacity in bytes of the block cache shared by block sst readers, 0 disables the cache
+    pub block_cache_size: u64,
+    /// index and filter blocks are kept in memory by opened readers,
+    /// otherwise they are read through the block cache
+    pub pin_index_and_filter: bool,
+    /// time a pessimistic transaction waits for a key lock
+    pub lock_timeout_ms: u64,
+    /// user key order, it must not change once the db is created
+    #[serde(skip)]
+    pub comparator: ComparatorRef,
+    /// prefixes of keys are added to sst bloom filters, prefix scans skip files without the prefix
+    #[serde(skip)]
+    pub prefix_extractor: Option<PrefixExtractorRef>,
+    /// combines operands written by `Storage::merge`, it is required to read them
+    #[serde(skip)]
+    pub merge_operator: Option<MergeOperatorRef>,
 }
 
 impl Default for Config {
@@ -30,11 +95,37 @@ impl Default for Config {
             lx_compaction_files: 3,
             leveled_compaction_level: 2,
             size_tried_radio: 10,
+            size_tiered_min_runs: 4,
             level_data_radio: 10,
+            compaction_strategy: CompactionStrategy::Leveled,
+            target_file_size: 8 * 1024 * 1024,
+            target_file_keys: 0,
+            wal_sync: WalSyncPolicy::Never,
+            bloom_bits_per_key: 10,
+            sst_format: Format::BlockSST,
+            block_size: 4096,
+            compression_per_level: vec![Compression::None, Compression::Lz4],
+            table_cache_size: 200,
+            block_cache_size: 8 * 1024 * 1024,
+            pin_index_and_filter: true,
+            lock_timeout_ms: 1000,
+            comparator: ComparatorRef::default(),
+            prefix_extractor: None,
+            merge_operator: None,
         }
     }
 }
 
+impl Config {
+    pub fn compression(&self, level: u32) -> Compression {
+        self.compression_per_level
+            .get(level as usize)
+            .or(self.compression_per_level.last())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
 pub type ConfigRef = &'static Config;
 
 pub fn load_config() -> Config {
@@ -42,12 +133,11 @@ pub fn load_config() -> Config {
 }
 
 pub fn current_config() -> Config {
-    let cfg = Config {
+    Config {
         path: "nanokv_data/".into(),
         no_wal: false,
         ..Default::default()
-    };
-    cfg
+    }
 }
 
 pub fn load_config_from(file: &str) -> Config {
@@ -61,10 +151,10 @@ pub fn load_config_from(file: &str) -> Config {
 
 pub fn test_config() -> Config {
     let path = std::env::temp_dir();
-    let cfg = Config {
+
+    Config {
         path,
         no_wal: true,
         ..Default::default()
-    };
-    cfg
+    }
 }
@@ -0,0 +1,153 @@
+use std::{
+    cell::RefCell,
+    ops::Bound::{self, Included, Unbounded},
+    rc::Rc,
+    sync::Arc,
+};
+
+use bytes::Bytes;
+
+use crate::{
+    err::Result,
+    iterator::{MergedIter, ScanIter, ScanStatus},
+    key::{InternalKey, Value},
+    range_del::RangeTombstones,
+    snapshot::Snapshot,
+    Storage,
+};
+
+type SharedMerge<'a> = Rc<RefCell<MergedIter<'a, (InternalKey, Value)>>>;
+
+/// versions from the merged iterator shared with the cursor
+struct SharedIter<'a>(SharedMerge<'a>);
+
+impl<'a> Iterator for SharedIter<'a> {
+    type Item = (InternalKey, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.borrow_mut().next()
+    }
+}
+
+/// bidirectional cursor over a snapshot of storage.
+/// the merged iterator of the memtable, imemtables and sst files is kept for the life of the
+/// cursor, seeks and direction switches re-seek its children around the key
+pub struct Cursor<'a> {
+    storage: &'a Storage,
+    snapshot: Snapshot,
+    merged: SharedMerge<'a>,
+    tombstones: Arc<RangeTombstones>,
+    status: ScanStatus,
+    iter: Option<ScanIter<'a, (Bytes, Value)>>,
+    current: Option<(Bytes, Value)>,
+    reverse: bool,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(
+        storage: &'a Storage,
+        merged: MergedIter<'a, (InternalKey, Value)>,
+        tombstones: Arc<RangeTombstones>,
+        snapshot: Snapshot,
+        status: ScanStatus,
+    ) -> Self {
+        Self {
+            storage,
+            snapshot,
+            merged: Rc::new(RefCell::new(merged)),
+            tombstones,
+            status,
+            iter: None,
+            current: None,
+            reverse: false,
+        }
+    }
+
+    fn scan(&mut self, beg: Bound<Bytes>, end: Bound<Bytes>, reverse: bool) {
+        self.merged.borrow_mut().seek((beg, end), reverse);
+        self.fold(reverse);
+    }
+
+    /// fold versions of the re-seeked merged iterator, versions read ahead before are dropped
+    fn fold(&mut self, reverse: bool) {
+        let mut iter = self.storage.fold_versions(
+            ScanIter::new(SharedIter(self.merged.clone())),
+            self.tombstones.clone(),
+            self.snapshot.clone(),
+            &self.status,
+        );
+        self.current = iter.next();
+        self.iter = Some(iter);
+        self.reverse = reverse;
+    }
+
+    /// move from the current key in the other direction
+    fn switch_direction(&mut self, key: Bytes) {
+        self.merged.borrow_mut().switch_direction(key);
+        self.fold(!self.reverse);
+    }
+
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// the first error since the cursor is created, keys after a corrupted entry are skipped.
+    /// an invalid cursor reached the end only if it's ok
+    pub fn status(&self) -> Result<()> {
+        self.status.get()
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.scan(Unbounded, Unbounded, false);
+    }
+
+    pub fn seek_to_last(&mut self) {
+        self.scan(Unbounded, Unbounded, true);
+    }
+
+    /// position at the first key >= key
+    pub fn seek<K: Into<Bytes>>(&mut self, key: K) {
+        self.scan(Included(key.into()), Unbounded, false);
+    }
+
+    /// position at the last key <= key
+    pub fn seek_for_prev<K: Into<Bytes>>(&mut self, key: K) {
+        self.scan(Unbounded, Included(key.into()), true);
+    }
+
+    /// move to the next key, the cursor is invalid after the last key
+    pub fn next(&mut self) {
+        let key = match &self.current {
+            Some((key, _)) => key.clone(),
+            None => return,
+        };
+        if self.reverse {
+            self.switch_direction(key);
+        } else {
+            self.current = self.iter.as_mut().and_then(|iter| iter.next());
+        }
+    }
+
+    /// move to the previous key, the cursor is invalid before the first key
+    pub fn prev(&mut self) {
+        let key = match &self.current {
+            Some((key, _)) => key.clone(),
+            None => return,
+        };
+        if self.reverse {
+            self.current = self.iter.as_mut().and_then(|iter| iter.next());
+        } else {
+            self.switch_direction(key);
+        }
+    }
+
+    /// panics if the cursor is not valid
+    pub fn key(&self) -> &Bytes {
+        &self.current.as_ref().expect("cursor is not valid").0
+    }
+
+    /// panics if the cursor is not valid
+    pub fn value(&self) -> &Value {
+        &self.current.as_ref().expect("cursor is not valid").1
+    }
+}
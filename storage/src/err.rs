@@ -23,28 +23,75 @@ pub enum StorageError {
     ValueTooLarge,
     #[error("data corrupt")]
     DataCorrupt,
+    #[error("sst {0} data corrupt")]
+    SSTDataCorrupt(u64),
+    #[error("comparator mismatch, db is created with {0}")]
+    ComparatorMismatch(String),
+    #[error("transaction conflict")]
+    Conflict,
+    #[error("lock wait timeout")]
+    LockTimeout,
+    #[error("deadlock")]
+    Deadlock,
+    #[error("merge operator not set")]
+    MergeOperatorNotSet,
+    #[error("write aborted, the leader of its group panicked")]
+    WriteAborted,
     #[error("io fail {0}")]
     Io(#[from] io::Error),
 }
 
 impl StorageError {
+    pub fn is_data_corrupt(&self) -> bool {
+        matches!(self, Self::DataCorrupt | Self::SSTDataCorrupt(_))
+    }
+
+    /// attach sst file number to data corrupt error
+    pub fn with_sst(self, number: u64) -> Self {
+        match self {
+            Self::DataCorrupt => Self::SSTDataCorrupt(number),
+            Self::Io(e) if e.kind() == io::ErrorKind::InvalidData => Self::SSTDataCorrupt(number),
+            e => e,
+        }
+    }
+
     pub fn is_io_not_found(&self) -> bool {
         if let Self::Io(i) = self {
             if io::ErrorKind::NotFound == i.kind() {
                 return true;
             }
         }
-        return false;
+        false
     }
 }
 
 impl PartialEq for StorageError {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Self::Io(l0), Self::Io(r0)) => return l0.kind() == r0.kind(),
+            (Self::Io(l0), Self::Io(r0)) => l0.kind() == r0.kind(),
+            (Self::SSTDataCorrupt(l0), Self::SSTDataCorrupt(r0)) => l0 == r0,
             _ => core::mem::discriminant(self) == core::mem::discriminant(other),
         }
     }
 }
 
+impl Clone for StorageError {
+    fn clone(&self) -> Self {
+        match self {
+            Self::KeyNotExist => Self::KeyNotExist,
+            Self::Unknown => Self::Unknown,
+            Self::ValueTooLarge => Self::ValueTooLarge,
+            Self::DataCorrupt => Self::DataCorrupt,
+            Self::SSTDataCorrupt(number) => Self::SSTDataCorrupt(*number),
+            Self::ComparatorMismatch(name) => Self::ComparatorMismatch(name.clone()),
+            Self::Conflict => Self::Conflict,
+            Self::LockTimeout => Self::LockTimeout,
+            Self::Deadlock => Self::Deadlock,
+            Self::MergeOperatorNotSet => Self::MergeOperatorNotSet,
+            Self::WriteAborted => Self::WriteAborted,
+            Self::Io(e) => Self::Io(io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, StorageError>;
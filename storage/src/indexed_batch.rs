@@ -0,0 +1,256 @@
+use std::{
+    cmp::Ordering,
+    ops::{Bound, RangeBounds},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    comparator::ComparatorRef,
+    err::{Result, StorageError},
+    iterator::ScanIter,
+    key::{InternalKey, KeyType, Value, WriteBatch, WriteBatchBuilder},
+    kv::superversion::SuperVersion,
+    GetOption, Storage, WriteOption,
+};
+
+// entries of the batch are newer than all versions in storage
+const BATCH_SEQ: u64 = 0xFFFF_FFFF_FFFF;
+
+/// write batch with an index of its keys, reads overlay the batch on top of the storage.
+/// it is committed by `Storage::set_batch` with the batch built
+pub struct IndexedWriteBatch {
+    batch: WriteBatchBuilder,
+    comparator: ComparatorRef,
+    // latest write of keys in comparator order, None is deletion
+    index: Vec<(Bytes, Option<Bytes>)>,
+}
+
+impl Default for IndexedWriteBatch {
+    fn default() -> Self {
+        Self::new(WriteOption::default(), ComparatorRef::default())
+    }
+}
+
+impl IndexedWriteBatch {
+    /// `comparator` must be the comparator of the storage it reads from
+    pub fn new(option: WriteOption, comparator: ComparatorRef) -> Self {
+        Self {
+            batch: WriteBatchBuilder::new(option),
+            comparator,
+            index: Vec::new(),
+        }
+    }
+
+    pub fn set<K: Into<Bytes>, V: Into<Bytes>>(&mut self, key: K, value: V) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        self.batch.set(&key, &value)?;
+        self.insert(key, Some(value));
+        Ok(())
+    }
+
+    pub fn del<K: Into<Bytes>>(&mut self, key: K) -> Result<()> {
+        let key = key.into();
+        self.batch.del(&key)?;
+        self.insert(key, None);
+        Ok(())
+    }
+
+    fn insert(&mut self, key: Bytes, value: Option<Bytes>) {
+        match self
+            .index
+            .binary_search_by(|(k, _)| self.comparator.compare(k, &key))
+        {
+            Ok(idx) => self.index[idx].1 = value,
+            Err(idx) => self.index.insert(idx, (key, value)),
+        }
+    }
+
+    /// number of keys written, a key written more than once is counted once
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// latest write of the key in the batch, `Some(None)` if it is deleted
+    pub fn get_from_batch(&self, key: &[u8]) -> Option<Option<&Bytes>> {
+        self.index
+            .binary_search_by(|(k, _)| self.comparator.compare(k, key))
+            .ok()
+            .map(|idx| self.index[idx].1.as_ref())
+    }
+
+    /// value of the key in the batch, or in the storage if the batch has no write of it
+    pub fn get<K: Into<Bytes>>(&self, storage: &Storage, opt: &GetOption, key: K) -> Result<Value> {
+        let key = key.into();
+        match self.get_from_batch(&key) {
+            Some(Some(value)) => Ok(value.clone().into()),
+            Some(None) => Err(StorageError::KeyNotExist),
+            None => match opt.snapshot() {
+                Some(snapshot) => {
+                    storage.get_ex(opt, key, &storage.super_version(), snapshot.clone())
+                }
+                None => storage.get(opt, key),
+            },
+        }
+    }
+
+    /// scan the batch merged with the storage
+    pub fn scan<'a, R: RangeBounds<Bytes> + Clone>(
+        &'a self,
+        storage: &'a Storage,
+        opt: &GetOption,
+        range: R,
+        super_version: &'a SuperVersion,
+    ) -> ScanIter<'a, (Bytes, Value)> {
+        self.scan_inner(storage, opt, range, super_version, false)
+    }
+
+    /// scan in reverse order of keys
+    pub fn scan_rev<'a, R: RangeBounds<Bytes> + Clone>(
+        &'a self,
+        storage: &'a Storage,
+        opt: &GetOption,
+        range: R,
+        super_version: &'a SuperVersion,
+    ) -> ScanIter<'a, (Bytes, Value)> {
+        self.scan_inner(storage, opt, range, super_version, true)
+    }
+
+    fn scan_inner<'a, R: RangeBounds<Bytes> + Clone>(
+        &'a self,
+        storage: &'a Storage,
+        opt: &GetOption,
+        range: R,
+        super_version: &'a SuperVersion,
+        reverse: bool,
+    ) -> ScanIter<'a, (Bytes, Value)> {
+        let snapshot = match opt.snapshot() {
+            Some(snapshot) => snapshot.clone(),
+            None => storage.read_snapshot(),
+        };
+        let opt = opt.clone().set_snapshot(snapshot.clone());
+        let entries = self.range(&range).iter().map(|(key, value)| match value {
+            Some(value) => (
+                InternalKey::new(key, BATCH_SEQ, KeyType::Set),
+                Value::from(value.clone()),
+            ),
+            None => (
+                InternalKey::new(key, BATCH_SEQ, KeyType::Del),
+                Value::from(Bytes::new()),
+            ),
+        });
+        let overlay = if reverse {
+            ScanIter::new(entries.rev())
+        } else {
+            ScanIter::new(entries)
+        };
+        storage.scan_overlay(&opt, range, super_version, snapshot, reverse, overlay)
+    }
+
+    fn range<R: RangeBounds<Bytes>>(&self, range: &R) -> &[(Bytes, Option<Bytes>)] {
+        let cmp = |key: &Bytes, bound: &Bytes| self.comparator.compare(key, bound);
+        let beg = match range.start_bound() {
+            Bound::Included(b) => self
+                .index
+                .partition_point(|(k, _)| cmp(k, b) == Ordering::Less),
+            Bound::Excluded(b) => self
+                .index
+                .partition_point(|(k, _)| cmp(k, b) != Ordering::Greater),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(b) => self
+                .index
+                .partition_point(|(k, _)| cmp(k, b) != Ordering::Greater),
+            Bound::Excluded(b) => self
+                .index
+                .partition_point(|(k, _)| cmp(k, b) == Ordering::Less),
+            Bound::Unbounded => self.index.len(),
+        };
+        &self.index[beg..end.max(beg)]
+    }
+
+    pub fn build(self) -> WriteBatch {
+        self.batch.build()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backend::{fs::local::LocalFileBasedPersistBackend, Backend};
+
+    use super::*;
+
+    #[test]
+    pub fn indexed_batch() {
+        let mut config = crate::config::test_config();
+        config.path = config.path.join("nanokv_indexed_batch");
+        let _ = std::fs::remove_dir_all(&config.path);
+        let storage = Storage::new(config.clone(), Backend::new(LocalFileBasedPersistBackend));
+        let wopt = WriteOption::default();
+        for key in ["a", "b", "c", "d"] {
+            storage.set(&wopt, key, key).unwrap();
+        }
+        storage.flush_memtable();
+        storage.flush_wait_imemtables();
+        storage.set(&wopt, "e", "e").unwrap();
+
+        let mut batch = storage.indexed_batch();
+        batch.set("b", "b1").unwrap();
+        batch.set("b", "b2").unwrap();
+        batch.del("c").unwrap();
+        batch.set("bb", "bb").unwrap();
+        batch.del("e").unwrap();
+        batch.set("f", "f").unwrap();
+        assert_eq!(batch.len(), 5);
+
+        let opt = GetOption::default();
+        assert_eq!(batch.get(&storage, &opt, "a").unwrap().data(), b"a");
+        assert_eq!(batch.get(&storage, &opt, "b").unwrap().data(), b"b2");
+        assert_eq!(
+            batch.get(&storage, &opt, "c").unwrap_err(),
+            StorageError::KeyNotExist
+        );
+        assert_eq!(batch.get_from_batch(b"e"), Some(None));
+        assert_eq!(batch.get_from_batch(b"a"), None);
+
+        let super_version = storage.super_version();
+        let kv = |iter: ScanIter<'_, (Bytes, Value)>| -> Vec<(Bytes, Bytes)> {
+            iter.map(|(k, v)| (k, v.internal())).collect()
+        };
+        let expect: Vec<(Bytes, Bytes)> = [
+            ("a", "a"),
+            ("b", "b2"),
+            ("bb", "bb"),
+            ("d", "d"),
+            ("f", "f"),
+        ]
+        .iter()
+        .map(|(k, v)| (Bytes::from(*k), Bytes::from(*v)))
+        .collect();
+        assert_eq!(kv(batch.scan(&storage, &opt, .., &super_version)), expect);
+        let mut rev = expect.clone();
+        rev.reverse();
+        assert_eq!(kv(batch.scan_rev(&storage, &opt, .., &super_version)), rev);
+        let range = Bytes::from("b")..Bytes::from("e");
+        assert_eq!(
+            kv(batch.scan(&storage, &opt, range, &super_version)),
+            expect[1..4]
+        );
+        drop(super_version);
+
+        // storage is unchanged until the batch is committed
+        assert_eq!(storage.get(&opt, "c").unwrap().data(), b"c");
+        storage.set_batch(&wopt, batch.build()).unwrap();
+        let super_version = storage.super_version();
+        assert_eq!(kv(storage.scan(&opt, .., &super_version)), expect);
+        drop(super_version);
+
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&config.path);
+    }
+}
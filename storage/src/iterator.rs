@@ -1,7 +1,18 @@
-use std::{collections::BinaryHeap, marker::PhantomData};
+use std::{
+    collections::BinaryHeap,
+    iter::Peekable,
+    marker::PhantomData,
+    ops::Bound::{self, Excluded, Unbounded},
+    sync::{Arc, Mutex},
+};
 
 use bytes::Bytes;
 
+use crate::{
+    comparator::ComparatorRef,
+    err::{Result, StorageError},
+};
+
 pub trait KvIterator: Iterator {
     fn prefetch(&mut self, n: usize);
 }
@@ -11,11 +22,15 @@ pub trait KvIteratorItem: Clone {
     fn user_key(&self) -> Bytes;
     fn seq(&self) -> u64;
     fn deleted(&self) -> bool;
+    /// merge operand, older versions are needed until the first one which is not an operand
+    fn is_merge(&self) -> bool;
 }
 
 struct MergedItem<T> {
     t: T,
     idx: usize,
+    comparator: ComparatorRef,
+    reverse: bool,
 }
 
 impl<T> Ord for MergedItem<T>
@@ -23,10 +38,14 @@ where
     T: KvIteratorItem,
 {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        match self.t.user_key_slice().cmp(other.t.user_key_slice()) {
+        // max heap pops the smallest user key (largest if reverse) and the newest version first
+        match self
+            .comparator
+            .compare(self.t.user_key_slice(), other.t.user_key_slice())
+        {
             std::cmp::Ordering::Equal => self.t.seq().cmp(&other.t.seq()),
-            std::cmp::Ordering::Less => std::cmp::Ordering::Greater,
-            std::cmp::Ordering::Greater => std::cmp::Ordering::Less,
+            v if self.reverse => v,
+            v => v.reverse(),
         }
     }
 }
@@ -38,7 +57,9 @@ where
     T: KvIteratorItem,
 {
     fn eq(&self, other: &Self) -> bool {
-        self.t.user_key_slice() == other.t.user_key_slice() && self.t.seq() == other.t.seq()
+        self.comparator
+            .equal(self.t.user_key_slice(), other.t.user_key_slice())
+            && self.t.seq() == other.t.seq()
     }
 }
 
@@ -51,23 +72,92 @@ where
     }
 }
 
+/// iterators of the children of a merge positioned in the range, in reverse order if it's set
+pub type SeekFn<'a, T> =
+    Box<dyn Fn((Bound<Bytes>, Bound<Bytes>), bool) -> Vec<ScanIter<'a, T>> + 'a>;
+
 pub struct MergedIter<'a, T> {
     iters: Vec<ScanIter<'a, T>>,
+    seek: Option<SeekFn<'a, T>>,
     heap: BinaryHeap<MergedItem<T>>,
     last_key: Option<Bytes>,
+    last_merge: bool,
     init: bool,
+    all_versions: bool,
+    reverse: bool,
+    comparator: ComparatorRef,
 }
 
 impl<'a, T> MergedIter<'a, T>
 where
     T: KvIteratorItem,
 {
-    pub fn new(iters: Vec<ScanIter<'a, T>>) -> Self {
+    pub fn new(iters: Vec<ScanIter<'a, T>>, comparator: ComparatorRef) -> Self {
         Self {
             iters,
+            seek: None,
             heap: BinaryHeap::new(),
             last_key: None,
+            last_merge: false,
             init: false,
+            all_versions: false,
+            reverse: false,
+            comparator,
+        }
+    }
+
+    /// merge iterators in reverse order, an iterator yields at most one version of a user key
+    pub fn new_reverse(iters: Vec<ScanIter<'a, T>>, comparator: ComparatorRef) -> Self {
+        Self {
+            reverse: true,
+            ..Self::new(iters, comparator)
+        }
+    }
+
+    /// merge without dropping older versions of the same user key
+    pub fn new_all_versions(iters: Vec<ScanIter<'a, T>>, comparator: ComparatorRef) -> Self {
+        Self {
+            all_versions: true,
+            ..Self::new(iters, comparator)
+        }
+    }
+
+    /// merge in both directions, it's empty until `seek` positions the children
+    pub fn new_seekable(seek: SeekFn<'a, T>, comparator: ComparatorRef) -> Self {
+        Self {
+            seek: Some(seek),
+            ..Self::new(Vec::new(), comparator)
+        }
+    }
+
+    /// position the children in the range and merge them in the direction.
+    /// panics if the iterator is not created by `new_seekable`
+    pub fn seek(&mut self, range: (Bound<Bytes>, Bound<Bytes>), reverse: bool) {
+        let seek = self.seek.as_ref().expect("merged iterator is not seekable");
+        self.iters = seek(range, reverse);
+        self.heap.clear();
+        self.last_key = None;
+        self.last_merge = false;
+        self.init = false;
+        self.reverse = reverse;
+    }
+
+    /// turn around at `key`, the children are positioned at the keys after it if the merge
+    /// was in reverse order, or before it if the merge was forward
+    pub fn switch_direction(&mut self, key: Bytes) {
+        if self.reverse {
+            self.seek((Excluded(key), Unbounded), false);
+        } else {
+            self.seek((Unbounded, Excluded(key)), true);
+        }
+    }
+
+    fn item(&self, t: T, idx: usize) -> MergedItem<T> {
+        MergedItem {
+            t,
+            idx,
+            comparator: self.comparator.clone(),
+            reverse: self.reverse,
         }
     }
 }
@@ -87,9 +177,10 @@ where
 
     fn next(&mut self) -> Option<Self::Item> {
         if !self.init {
-            for (idx, iter) in self.iters.iter_mut().enumerate() {
-                if let Some(val) = iter.next() {
-                    self.heap.push(MergedItem { t: val, idx });
+            for idx in 0..self.iters.len() {
+                if let Some(val) = self.iters[idx].next() {
+                    let item = self.item(val, idx);
+                    self.heap.push(item);
                 }
             }
 
@@ -99,24 +190,53 @@ where
             let item = self.heap.pop()?;
 
             if let Some(new_val) = self.iters[item.idx].next() {
-                self.heap.push(MergedItem {
-                    t: new_val,
-                    idx: item.idx,
-                });
+                let new_item = self.item(new_val, item.idx);
+                self.heap.push(new_item);
+            }
+            if self.all_versions {
+                break Some(item.t);
             }
             if let Some(last_key) = &self.last_key {
-                if last_key == item.t.user_key_slice() {
+                if !self.last_merge && self.comparator.equal(last_key, item.t.user_key_slice()) {
                     continue;
                 }
             }
             self.last_key = Some(item.t.user_key());
+            self.last_merge = item.t.is_merge();
             break Some(item.t);
         }
     }
 }
 
+/// the first error of a scan, shared by iterators of the scan.
+/// an iterator stops at an error and records it here
+#[derive(Debug, Clone, Default)]
+pub struct ScanStatus(Arc<Mutex<Option<StorageError>>>);
+
+impl ScanStatus {
+    /// later errors are dropped
+    pub fn set(&self, e: StorageError) {
+        let mut status = self.0.lock().unwrap();
+        if status.is_none() {
+            *status = Some(e);
+        }
+    }
+
+    pub fn get(&self) -> Result<()> {
+        match &*self.0.lock().unwrap() {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn error(&self) -> Option<StorageError> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
 pub struct ScanIter<'a, T> {
     inner: Box<dyn Iterator<Item = T> + 'a>,
+    status: Option<ScanStatus>,
     _pd: PhantomData<&'a ()>,
 }
 
@@ -124,9 +244,21 @@ impl<'a, T> ScanIter<'a, T> {
     pub fn new<I: Iterator<Item = T> + 'a>(inner: I) -> Self {
         Self {
             inner: Box::new(inner),
-            _pd: PhantomData::default(),
+            status: None,
+            _pd: PhantomData,
         }
     }
+
+    pub(crate) fn with_status(mut self, status: ScanStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// the first error of the scan, entries after the error are not returned.
+    /// check it after the scan ends, an ended scan is complete only if it's ok
+    pub fn status(&self) -> Result<()> {
+        self.status.as_ref().map_or(Ok(()), |status| status.get())
+    }
 }
 
 impl<'a, T> KvIterator for ScanIter<'a, T> {
@@ -148,6 +280,7 @@ where
 {
     iter: I,
     last: Option<I::Item>,
+    comparator: ComparatorRef,
 }
 
 impl<I> EqualFilter<I>
@@ -155,8 +288,12 @@ where
     I: Iterator,
     I::Item: KvIteratorItem,
 {
-    pub(crate) fn new(iter: I) -> Self {
-        Self { iter, last: None }
+    pub(crate) fn new(iter: I, comparator: ComparatorRef) -> Self {
+        Self {
+            iter,
+            last: None,
+            comparator,
+        }
     }
 }
 
@@ -172,7 +309,12 @@ where
         loop {
             let a = self.iter.next()?;
             if let Some(last) = &self.last {
-                if last.user_key_slice() == a.user_key_slice() {
+                // versions under a merge operand are kept until its base
+                if self
+                    .comparator
+                    .equal(last.user_key_slice(), a.user_key_slice())
+                    && !last.is_merge()
+                {
                     // item filtered
                     continue;
                 }
@@ -187,6 +329,59 @@ where
     }
 }
 
+/// keep the newest version of each user key from an iterator in reverse order,
+/// where versions of a user key are from old to new
+pub struct RevEqualFilter<I>
+where
+    I: Iterator,
+{
+    iter: Peekable<I>,
+    // versions under a merge operand from old to new
+    pending: Vec<I::Item>,
+    comparator: ComparatorRef,
+}
+
+impl<I> RevEqualFilter<I>
+where
+    I: Iterator,
+    I::Item: KvIteratorItem,
+{
+    pub(crate) fn new(iter: I, comparator: ComparatorRef) -> Self {
+        Self {
+            iter: iter.peekable(),
+            pending: Vec::new(),
+            comparator,
+        }
+    }
+}
+
+impl<I> Iterator for RevEqualFilter<I>
+where
+    I: Iterator,
+    I::Item: KvIteratorItem,
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(item) = self.pending.pop() {
+            return Some(item);
+        }
+        let mut cur = self.iter.next()?;
+        while let Some(next) = self.iter.next_if(|next| {
+            self.comparator
+                .equal(next.user_key_slice(), cur.user_key_slice())
+        }) {
+            if next.is_merge() {
+                self.pending.push(cur);
+            } else {
+                self.pending.clear();
+            }
+            cur = next;
+        }
+        Some(cur)
+    }
+}
+
 pub struct LevelIter<'a, T> {
     iters: Vec<ScanIter<'a, T>>,
     pos: usize,
@@ -216,3 +411,112 @@ impl<'a, T> Iterator for LevelIter<'a, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::ops::{Bound::Included, RangeBounds};
+
+    use crate::key::{InternalKey, KeyType};
+    use crate::test::CaseInsensitiveComparator;
+
+    use super::*;
+
+    fn iter(keys: &[(&str, u64)]) -> ScanIter<'static, InternalKey> {
+        let keys: Vec<_> = keys
+            .iter()
+            .map(|(key, seq)| InternalKey::new(key, *seq, KeyType::Set))
+            .collect();
+        ScanIter::new(keys.into_iter())
+    }
+
+    #[test]
+    pub fn merged_iter_comparator_equal() {
+        let comparator = ComparatorRef::new(CaseInsensitiveComparator);
+        let iters = vec![iter(&[("a", 3), ("C", 1)]), iter(&[("A", 5), ("b", 2)])];
+        let keys: Vec<_> = MergedIter::new(iters, comparator.clone())
+            .map(|k| (k.user_key(), k.seq()))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                (Bytes::from("A"), 5),
+                (Bytes::from("b"), 2),
+                (Bytes::from("C"), 1)
+            ]
+        );
+
+        let iters = vec![iter(&[("C", 1), ("a", 3)]), iter(&[("b", 2), ("A", 5)])];
+        let keys: Vec<_> = MergedIter::new_reverse(iters, comparator)
+            .map(|k| (k.user_key(), k.seq()))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                (Bytes::from("C"), 1),
+                (Bytes::from("b"), 2),
+                (Bytes::from("A"), 5)
+            ]
+        );
+    }
+
+    #[test]
+    pub fn equal_filter_comparator_equal() {
+        let comparator = ComparatorRef::new(CaseInsensitiveComparator);
+        let keys: Vec<_> = EqualFilter::new(
+            iter(&[("A", 5), ("a", 3), ("b", 2), ("B", 1)]),
+            comparator.clone(),
+        )
+        .map(|k| (k.user_key(), k.seq()))
+        .collect();
+        assert_eq!(keys, [(Bytes::from("A"), 5), (Bytes::from("b"), 2)]);
+
+        // versions of a key are from old to new in reverse order
+        let keys: Vec<_> =
+            RevEqualFilter::new(iter(&[("B", 1), ("b", 2), ("a", 3), ("A", 5)]), comparator)
+                .map(|k| (k.user_key(), k.seq()))
+                .collect();
+        assert_eq!(keys, [(Bytes::from("b"), 2), (Bytes::from("A"), 5)]);
+    }
+
+    #[test]
+    pub fn merged_iter_switch_direction() {
+        let children = [
+            vec![("a", 1), ("c", 3), ("e", 5)],
+            vec![("b", 2), ("c", 6), ("d", 4)],
+        ];
+        let seek = move |range: (Bound<Bytes>, Bound<Bytes>), reverse| {
+            children
+                .iter()
+                .map(|keys| {
+                    let mut keys: Vec<_> = keys
+                        .iter()
+                        .filter(|(key, _)| range.contains(&Bytes::from(*key)))
+                        .map(|(key, seq)| InternalKey::new(*key, *seq, KeyType::Set))
+                        .collect();
+                    if reverse {
+                        keys.reverse();
+                    }
+                    ScanIter::new(keys.into_iter())
+                })
+                .collect()
+        };
+        let mut iter = MergedIter::new_seekable(Box::new(seek), ComparatorRef::default());
+        let next = |iter: &mut MergedIter<_>| iter.next().map(|k: InternalKey| k.user_key());
+        assert_eq!(next(&mut iter), None);
+
+        iter.seek((Included(Bytes::from("b")), Unbounded), false);
+        assert_eq!(next(&mut iter), Some(Bytes::from("b")));
+        assert_eq!(next(&mut iter), Some(Bytes::from("c")));
+        iter.switch_direction(Bytes::from("c"));
+        assert_eq!(next(&mut iter), Some(Bytes::from("b")));
+        assert_eq!(next(&mut iter), Some(Bytes::from("a")));
+        assert_eq!(next(&mut iter), None);
+
+        iter.seek((Unbounded, Included(Bytes::from("d"))), true);
+        assert_eq!(next(&mut iter), Some(Bytes::from("d")));
+        assert_eq!(next(&mut iter), Some(Bytes::from("c")));
+        iter.switch_direction(Bytes::from("c"));
+        let keys: Vec<_> = iter.map(|k| (k.user_key(), k.seq())).collect();
+        assert_eq!(keys, [(Bytes::from("d"), 4), (Bytes::from("e"), 5)]);
+    }
+}
@@ -10,17 +10,20 @@ use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use bytes::{buf::Writer, Buf, BufMut, Bytes, BytesMut};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-#[derive(IntoPrimitive, TryFromPrimitive, Eq, PartialEq, Debug)]
+#[derive(IntoPrimitive, TryFromPrimitive, Eq, PartialEq, Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum KeyType {
     Set = 0,
     Del = 1,
+    Merge = 2,
+    /// deletion of user keys from the key until the end key in value
+    RangeDel = 3,
 }
 
 // user_key
 // seq
 // type
-#[derive(Debug, PartialEq, Eq, Ord, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct InternalKey {
     bytes: Bytes,
 }
@@ -31,14 +34,18 @@ impl From<Bytes> for InternalKey {
     }
 }
 
+impl Ord for InternalKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        match self.user_key_slice().cmp(other.user_key_slice()) {
+            std::cmp::Ordering::Equal => other.seq().cmp(&self.seq()),
+            v => v,
+        }
+    }
+}
+
 impl PartialOrd for InternalKey {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.user_key()
-            .partial_cmp(&other.user_key())
-            .and_then(|v| match v {
-                std::cmp::Ordering::Equal => other.seq().partial_cmp(&self.seq()),
-                v => Some(v),
-            })
+        Some(self.cmp(other))
     }
 }
 
@@ -50,7 +57,7 @@ impl InternalKey {
         let prefix: u8 = ty.into();
         let value = ((prefix as u64) << 56) | (seq & 0xFFFF_FFFF_FFFF);
 
-        let _ = bytes.write(&user_key);
+        let _ = bytes.write(user_key);
         let _ = bytes.write_u64::<LE>(value);
         Self {
             bytes: bytes.into_inner().freeze(),
@@ -74,6 +81,10 @@ impl InternalKey {
     pub fn len(&self) -> usize {
         self.bytes.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
 }
 
 impl KvIteratorItem for InternalKey {
@@ -88,6 +99,9 @@ impl KvIteratorItem for InternalKey {
     fn deleted(&self) -> bool {
         self.key_type() == KeyType::Del
     }
+    fn is_merge(&self) -> bool {
+        self.key_type() == KeyType::Merge
+    }
 
     fn user_key(&self) -> Bytes {
         self.bytes.slice(..(self.bytes.len() - 8))
@@ -107,6 +121,10 @@ impl KvIteratorItem for (InternalKey, Value) {
         self.0.deleted()
     }
 
+    fn is_merge(&self) -> bool {
+        self.0.is_merge()
+    }
+
     fn user_key(&self) -> Bytes {
         self.0.user_key()
     }
@@ -127,7 +145,7 @@ pub struct WriteBatch {
 }
 
 impl WriteBatch {
-    pub fn iter(&self) -> BatchIter {
+    pub fn iter(&self) -> BatchIter<'_> {
         BatchIter {
             inner: self,
             offset: 16,
@@ -146,6 +164,11 @@ impl WriteBatch {
         self.seq
     }
 
+    /// sequence of the first entry, it's written into the wal with the batch
+    pub fn set_seq(&mut self, seq: u64) {
+        self.seq = seq;
+    }
+
     pub fn count(&self) -> usize {
         self.total as usize
     }
@@ -208,10 +231,28 @@ impl WriteBatchBuilder {
         self.add_internal(internal_key, Bytes::default())
     }
 
+    /// merge operand of the key, it is combined with older versions by the merge operator
+    pub fn merge<A: AsRef<[u8]>, B: AsRef<[u8]>>(&mut self, key: A, operand: B) -> Result<()> {
+        let internal_key = InternalKey::new(key, 0, KeyType::Merge);
+        self.add_internal(internal_key, operand)
+    }
+
+    /// delete keys in [start, end)
+    pub fn delete_range<A: AsRef<[u8]>, B: AsRef<[u8]>>(&mut self, start: A, end: B) -> Result<()> {
+        let internal_key = InternalKey::new(start, 0, KeyType::RangeDel);
+        self.add_internal(internal_key, end)
+    }
+
     pub fn set_seq(&mut self, seq: u64) {
         self.seq = seq;
     }
 
+    /// append all entries of `batch`, they take the sequences after entries added before
+    pub fn append_batch(&mut self, batch: &WriteBatch) {
+        let _ = self.bytes.write_all(&batch.bytes[16..]);
+        self.total += batch.total;
+    }
+
     pub fn build(self) -> WriteBatch {
         let mut b = self.bytes.into_inner();
 
@@ -289,7 +330,10 @@ impl LogEntrySerializer for BatchLogSerializer {
     where
         W: SegmentWrite,
     {
-        w.write_all(&entry.bytes).map(|_| ())
+        // header seq of bytes is not updated by `WriteBatch::set_seq`
+        w.write_u64::<LE>(entry.total as u64)?;
+        w.write_u64::<LE>(entry.seq)?;
+        w.write_all(&entry.bytes[16..])
     }
 
     fn read<R>(&self, r: &mut R) -> io::Result<Self::Entry>
@@ -339,13 +383,45 @@ mod test {
         let mut buf = BytesMut::default().writer();
         let mut w = DummySegmentWrite::new(&mut buf);
 
-        BatchLogSerializer::default().write(&batch, &mut w).unwrap();
+        BatchLogSerializer.write(&batch, &mut w).unwrap();
         let buf = buf.into_inner().freeze();
         let mut r = DummySegmentRead::new(buf.reader());
-        let batch2 = BatchLogSerializer::default().read(&mut r).unwrap();
+        let batch2 = BatchLogSerializer.read(&mut r).unwrap();
 
         assert_eq!(batch.count(), batch2.count());
         assert_eq!(batch.data(), batch2.data());
+
+        let mut batch = batch2;
+        batch.set_seq(100);
+        let mut buf = BytesMut::default().writer();
+        let mut w = DummySegmentWrite::new(&mut buf);
+        BatchLogSerializer.write(&batch, &mut w).unwrap();
+        let buf = buf.into_inner().freeze();
+        let mut r = DummySegmentRead::new(buf.reader());
+        let batch3 = BatchLogSerializer.read(&mut r).unwrap();
+        assert_eq!(batch3.seq(), 100);
+        assert_eq!(batch3.count(), 3);
+    }
+
+    #[test]
+    pub fn append_batch() {
+        let mut batch_builder = WriteBatchBuilder::default();
+        batch_builder.set("123", "abc").unwrap();
+        let batch1 = batch_builder.build();
+        let mut batch_builder = WriteBatchBuilder::default();
+        batch_builder.del("456").unwrap();
+        batch_builder.set("1", "a").unwrap();
+        let batch2 = batch_builder.build();
+
+        let mut batch_builder = WriteBatchBuilder::default();
+        batch_builder.append_batch(&batch1);
+        batch_builder.append_batch(&batch2);
+        let batch = batch_builder.build();
+        assert_eq!(batch.count(), 3);
+        assert_eq!(batch.data().len(), 75);
+
+        let keys: Vec<_> = batch.iter().map(|(k, _)| k.user_key()).collect();
+        assert_eq!(keys, vec!["123", "456", "1"]);
     }
 
     #[test]
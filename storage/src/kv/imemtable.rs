@@ -7,6 +7,7 @@ use crate::{
     err::{Result, StorageError},
     iterator::{MergedIter, ScanIter},
     key::{InternalKey, Value},
+    range_del::RangeTombstone,
 };
 
 #[derive(Debug, Clone, Default)]
@@ -30,6 +31,13 @@ impl Imemtables {
         Self { imemtables }
     }
 
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.imemtables
+            .iter()
+            .flat_map(|table| table.range_tombstones())
+            .collect()
+    }
+
     pub fn remove(&self, number: u64) -> Self {
         let mut imemtables = self.imemtables.clone();
         if let Some(idx) = imemtables
@@ -76,7 +84,30 @@ impl Imemtables {
             iters.push(table.scan(opt, range.clone(), lifetime));
         }
 
-        ScanIter::new(MergedIter::new(iters))
+        let comparator = self
+            .imemtables
+            .first()
+            .map(|table| table.comparator().clone())
+            .unwrap_or_default();
+        ScanIter::new(MergedIter::new(iters, comparator))
+    }
+
+    pub fn scan_rev<'a, R: RangeBounds<Bytes> + Clone>(
+        &self,
+        opt: &GetOption,
+        range: R,
+        lifetime: &Lifetime<'a>,
+    ) -> ScanIter<'a, (InternalKey, Value)> {
+        let mut iters = Vec::new();
+        for table in self.imemtables.iter().rev() {
+            iters.push(table.scan_rev(opt, range.clone(), lifetime));
+        }
+        let comparator = self
+            .imemtables
+            .first()
+            .map(|table| table.comparator().clone())
+            .unwrap_or_default();
+        ScanIter::new(MergedIter::new_reverse(iters, comparator))
     }
 }
 
@@ -146,7 +177,7 @@ mod test {
         assert_eq!(
             table
                 .scan(&opt, .., &lifetime)
-                .filter(|(k, v)| !k.deleted())
+                .filter(|(k, _v)| !k.deleted())
                 .count(),
             199
         );
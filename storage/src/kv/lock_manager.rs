@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::err::{Result, StorageError};
+
+#[derive(Default)]
+struct LockState {
+    // user key -> transaction holding the lock
+    locks: HashMap<Bytes, u64>,
+    // waiting transaction -> transaction holding the lock it waits for
+    waits: HashMap<u64, u64>,
+}
+
+impl LockState {
+    /// `txn` waiting for `owner` closes a cycle of waits
+    fn deadlock(&self, txn: u64, owner: u64) -> bool {
+        let mut cur = owner;
+        // a transaction waits for one lock at a time, the chain is not longer than waiters
+        for _ in 0..=self.waits.len() {
+            if cur == txn {
+                return true;
+            }
+            match self.waits.get(&cur) {
+                Some(next) => cur = *next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+/// exclusive locks of user keys held by transactions until they end
+#[derive(Default)]
+pub struct LockManager {
+    state: Mutex<LockState>,
+    cond: Condvar,
+    next_txn: AtomicU64,
+}
+
+impl LockManager {
+    pub fn new_txn_id(&self) -> u64 {
+        self.next_txn.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// lock the key for the transaction, a lock held by the transaction is locked again.
+    /// fails with `StorageError::Deadlock` if waiting would close a cycle, or
+    /// `StorageError::LockTimeout` if the lock is not released in time
+    pub fn lock(&self, txn: u64, key: &Bytes, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let owner = match state.locks.get(key) {
+                None => {
+                    state.locks.insert(key.clone(), txn);
+                    state.waits.remove(&txn);
+                    return Ok(());
+                }
+                Some(owner) if *owner == txn => return Ok(()),
+                Some(owner) => *owner,
+            };
+            if state.deadlock(txn, owner) {
+                state.waits.remove(&txn);
+                return Err(StorageError::Deadlock);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                state.waits.remove(&txn);
+                return Err(StorageError::LockTimeout);
+            }
+            state.waits.insert(txn, owner);
+            state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// release locks of the transaction and wake up waiters
+    pub fn unlock<'a, I: IntoIterator<Item = &'a Bytes>>(&self, txn: u64, keys: I) {
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            if state.locks.get(key) == Some(&txn) {
+                state.locks.remove(key);
+            }
+        }
+        state.waits.remove(&txn);
+        drop(state);
+        self.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{mpsc, Arc};
+
+    use super::*;
+
+    #[test]
+    pub fn lock_wait_and_deadlock() {
+        let manager = Arc::new(LockManager::default());
+        let timeout = Duration::from_secs(5);
+        let (a, b) = (Bytes::from("a"), Bytes::from("b"));
+        let (t1, t2) = (manager.new_txn_id(), manager.new_txn_id());
+
+        manager.lock(t1, &a, timeout).unwrap();
+        manager.lock(t1, &a, timeout).unwrap();
+        manager.lock(t2, &b, timeout).unwrap();
+        assert_eq!(
+            manager.lock(t2, &a, Duration::from_millis(10)),
+            Err(StorageError::LockTimeout)
+        );
+
+        // t2 waits for t1, then t1 waiting for t2 is a deadlock
+        let (tx, rx) = mpsc::channel();
+        let handle = {
+            let manager = manager.clone();
+            let a = a.clone();
+            std::thread::spawn(move || {
+                tx.send(()).unwrap();
+                manager.lock(t2, &a, timeout)
+            })
+        };
+        rx.recv().unwrap();
+        while !manager.state.lock().unwrap().waits.contains_key(&t2) {
+            std::thread::yield_now();
+        }
+        assert_eq!(manager.lock(t1, &b, timeout), Err(StorageError::Deadlock));
+
+        // waiter gets the lock once it's released
+        manager.unlock(t1, [&a]);
+        handle.join().unwrap().unwrap();
+        assert_eq!(manager.state.lock().unwrap().locks.get(&a), Some(&t2));
+        manager.unlock(t2, [&a, &b]);
+        assert!(manager.state.lock().unwrap().locks.is_empty());
+    }
+}
@@ -1,9 +1,8 @@
 use std::{
-    borrow::Borrow,
     collections::{BTreeMap, HashMap, LinkedList},
     fmt::Debug,
     io::{self, Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
         Arc, Mutex,
@@ -16,11 +15,9 @@ use bytes::Bytes;
 use superslice::Ext;
 
 use crate::{
-    backend::{
-        fs::{ExtReader, ReadablePersist},
-        Backend,
-    },
-    err::Result,
+    backend::{fs::ExtReader, Backend},
+    comparator::ComparatorRef,
+    err::{Result, StorageError},
     log::{self, replayer::SegmentRead, wal::SegmentWrite, LogEntrySerializer, LogWriter},
     snapshot::Snapshot,
     util::fname::{self, manifest_name},
@@ -32,14 +29,22 @@ pub struct Run {
     files: Vec<Arc<FileStatistics>>,
     min: Bytes,
     max: Bytes,
+    comparator: ComparatorRef,
+}
+
+impl Default for Run {
+    fn default() -> Self {
+        Self::new(ComparatorRef::default())
+    }
 }
 
 impl Run {
-    pub fn new() -> Self {
+    pub fn new(comparator: ComparatorRef) -> Self {
         Self {
             files: Vec::with_capacity(6),
             min: Bytes::new(),
             max: Bytes::new(),
+            comparator,
         }
     }
 
@@ -48,17 +53,17 @@ impl Run {
     }
 
     pub fn push(&mut self, sst: Arc<FileStatistics>) {
-        if self.min.is_empty() {
+        // empty key is a valid min key
+        if self.files.is_empty() {
             self.min = sst.meta.min.clone();
-        } else {
-            self.min = self.min.clone().min(sst.meta.min.clone());
-        }
-        if self.max.is_empty() {
             self.max = sst.meta.max.clone();
         } else {
-            self.max = self.max.clone().max(sst.meta.max.clone());
+            self.min = Bytes::copy_from_slice(self.comparator.min(&self.min, &sst.meta.min));
+            self.max = Bytes::copy_from_slice(self.comparator.max(&self.max, &sst.meta.max));
         }
-        let idx = self.files.lower_bound_by(|f| f.meta.min.cmp(&sst.meta.max));
+        let idx = self
+            .files
+            .lower_bound_by(|f| self.comparator.compare(&f.meta.min, &sst.meta.max));
         if idx >= self.files.len() {
             self.files.push(sst);
         } else {
@@ -66,19 +71,51 @@ impl Run {
         }
     }
 
+    /// remove file by sst number, files are sorted by key so the number is searched linearly
+    pub fn remove(&mut self, number: u64) -> bool {
+        let idx = match self.files.iter().position(|f| f.meta.number == number) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        self.files.remove(idx);
+
+        let comparator = &self.comparator;
+        self.min = self
+            .files
+            .iter()
+            .map(|f| f.meta.min.clone())
+            .min_by(|a, b| comparator.compare(a, b))
+            .unwrap_or_default();
+        self.max = self
+            .files
+            .iter()
+            .map(|f| f.meta.max.clone())
+            .max_by(|a, b| comparator.compare(a, b))
+            .unwrap_or_default();
+        true
+    }
+
+    /// max sequence of the run, an empty run is treated as the newest one
+    pub fn max_ver(&self) -> u64 {
+        self.files
+            .iter()
+            .map(|f| f.meta.max_ver)
+            .max()
+            .unwrap_or(u64::MAX)
+    }
+
     pub fn binary_find_file(&self, key: &[u8]) -> Option<&FileStatistics> {
-        if &self.min > key || &self.max < key {
+        if self.files.is_empty()
+            || self.comparator.compare(&self.min, key) == std::cmp::Ordering::Greater
+            || self.comparator.compare(&self.max, key) == std::cmp::Ordering::Less
+        {
             return None;
         }
         let idx = self
             .files
-            .lower_bound_by(|val| (&val.meta.max[..]).cmp(key));
+            .lower_bound_by(|val| self.comparator.compare(&val.meta.max, key));
         if idx >= self.files.len() {
-            ::log::info!(
-                "key {} in file {:?}",
-                String::from_utf8(key.into()).unwrap(),
-                self
-            );
+            ::log::info!("key {} in file {:?}", key.escape_ascii(), self);
             return None;
         }
         Some(&self.files[idx])
@@ -101,12 +138,19 @@ pub struct Version {
     id: u64,
     sst_files: Vec<Runs>,
     seq_map: HashMap<u64, Arc<FileStatistics>>,
+    comparator: ComparatorRef,
 }
 
 pub type VersionRef = Arc<Version>;
 
 impl Default for Version {
     fn default() -> Self {
+        Self::new(ComparatorRef::default())
+    }
+}
+
+impl Version {
+    pub fn new(comparator: ComparatorRef) -> Self {
         let mut sst_files = Vec::new();
         sst_files.resize((MAX_LEVEL + 1) as usize, Runs::new());
 
@@ -114,11 +158,14 @@ impl Default for Version {
             id: 0,
             sst_files,
             seq_map: HashMap::new(),
+            comparator,
         }
     }
-}
 
-impl Version {
+    pub fn comparator(&self) -> &ComparatorRef {
+        &self.comparator
+    }
+
     pub fn level_n(&self, n: u32) -> &[Run] {
         &self.sst_files[n as usize].runs[..]
     }
@@ -127,6 +174,14 @@ impl Version {
             f(run)
         }
     }
+
+    /// runs of a level are ordered from old to new,
+    /// a merged run takes the place of the runs it replaced
+    fn sort_runs(&mut self) {
+        for level in &mut self.sst_files {
+            level.runs.sort_by_key(|run| run.max_ver());
+        }
+    }
 }
 
 impl Debug for Version {
@@ -139,45 +194,29 @@ impl Debug for Version {
 }
 
 #[derive(Debug, Default)]
-pub struct VersionLogSerializer;
-
-impl LogEntrySerializer for VersionLogSerializer {
-    type Entry = Version;
-
-    fn write<W>(&self, entry: &Self::Entry, w: &mut W) -> io::Result<()>
-    where
-        W: SegmentWrite,
-    {
-        let total_iter = entry
-            .sst_files
-            .iter()
-            .flat_map(|r| r.runs.iter())
-            .flat_map(|v| v.files.iter());
-        let total_files = total_iter.clone().count();
-        w.write_u32::<LE>(total_files as u32)?;
+pub struct VersionLogSerializer {
+    comparator: ComparatorRef,
+}
 
-        for file in total_iter {
-            let s = FileMetaDataLogSerializer::default();
-            s.write(&file.meta, w)?;
-        }
-        Ok(())
+impl VersionLogSerializer {
+    pub fn new(comparator: ComparatorRef) -> Self {
+        Self { comparator }
     }
 
-    fn read<R>(&self, r: &mut R) -> io::Result<Self::Entry>
+    /// snapshots of old manifests have no run boundaries,
+    /// runs are rebuilt from files which don't overlap
+    fn read_by_overlap<R>(&self, r: &mut R) -> io::Result<Version>
     where
         R: SegmentRead,
     {
         let total_files = r.read_u32::<LE>().unwrap();
 
-        let mut entry = Self::Entry::default();
-        entry
-            .sst_files
-            .resize((MAX_LEVEL + 1) as usize, Runs::new());
+        let mut entry = Version::new(self.comparator.clone());
         let mut last_max: Option<Bytes> = None;
         let mut last_level = MAX_LEVEL + 1;
 
         for _ in 0..total_files {
-            let s = FileMetaDataLogSerializer::default();
+            let s = FileMetaDataLogSerializer;
             let meta = s.read(r)?;
             if meta.level >= MAX_LEVEL {
                 panic!("level not match {}", meta.level);
@@ -189,12 +228,15 @@ impl LogEntrySerializer for VersionLogSerializer {
             let level = meta.level as usize;
             let mut new_run = true;
             if let Some(last_max) = &last_max {
-                if meta.min < last_max {
+                // files in the same run are sorted and never overlapped
+                if self.comparator.compare(&meta.min, last_max) == std::cmp::Ordering::Greater {
                     new_run = false;
                 }
             }
             if new_run {
-                entry.sst_files[level].runs.push(Run::new());
+                entry.sst_files[level]
+                    .runs
+                    .push(Run::new(self.comparator.clone()));
             }
             last_max = Some(meta.max.clone());
             let seq = meta.number;
@@ -207,6 +249,61 @@ impl LogEntrySerializer for VersionLogSerializer {
     }
 }
 
+// run count | (level | file count | file meta * file count) * run count
+impl LogEntrySerializer for VersionLogSerializer {
+    type Entry = Version;
+
+    fn write<W>(&self, entry: &Self::Entry, w: &mut W) -> io::Result<()>
+    where
+        W: SegmentWrite,
+    {
+        let runs = entry
+            .sst_files
+            .iter()
+            .enumerate()
+            .flat_map(|(level, r)| r.runs.iter().map(move |run| (level, run)));
+        w.write_u32::<LE>(runs.clone().count() as u32)?;
+
+        for (level, run) in runs {
+            w.write_u32::<LE>(level as u32)?;
+            w.write_u32::<LE>(run.files.len() as u32)?;
+            for file in &run.files {
+                let s = FileMetaDataLogSerializer;
+                s.write(&file.meta, w)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read<R>(&self, r: &mut R) -> io::Result<Self::Entry>
+    where
+        R: SegmentRead,
+    {
+        let total_runs = r.read_u32::<LE>()?;
+        let mut entry = Version::new(self.comparator.clone());
+
+        for _ in 0..total_runs {
+            let level = r.read_u32::<LE>()?;
+            if level >= MAX_LEVEL {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid level {}", level),
+                ));
+            }
+            let mut run = Run::new(self.comparator.clone());
+            for _ in 0..r.read_u32::<LE>()? {
+                let s = FileMetaDataLogSerializer;
+                let meta = s.read(r)?;
+                let sst = Arc::new(FileStatistics::new(meta));
+                entry.seq_map.insert(sst.meta.number, sst.clone());
+                run.push(sst);
+            }
+            entry.sst_files[level as usize].runs.push(run);
+        }
+        Ok(entry)
+    }
+}
+
 #[derive(Debug)]
 pub enum VersionEdit {
     SSTAppended(FileMetaData),
@@ -216,6 +313,12 @@ pub enum VersionEdit {
     SSTSequenceChanged(u64),
     ManifestSequenceChanged(u64),
     Snapshot(VersionRef),
+    /// wal of memtables before this number are flushed
+    LogNumberChanged(u64),
+    /// name of the comparator the db is created with
+    ComparatorName(String),
+    /// edits committed as one record, a torn batch is dropped on restore
+    Batch(Vec<VersionEdit>),
 }
 
 #[derive(Debug, Clone)]
@@ -267,7 +370,10 @@ const FILE_STATE_DEPRECATED: u8 = 2;
 pub struct FileStatistics {
     meta: FileMetaData,
     state: AtomicU8,
-    bloom_fail_count: AtomicU32,
+    /// lookups skipped because the bloom filter rejects the key
+    bloom_useful_count: AtomicU32,
+    /// lookups passed the bloom filter but no visible version of the key is found
+    bloom_false_positive_count: AtomicU32,
 }
 
 impl FileStatistics {
@@ -275,13 +381,31 @@ impl FileStatistics {
         Self {
             meta,
             state: AtomicU8::new(FILE_STATE_USING),
-            bloom_fail_count: AtomicU32::new(0),
+            bloom_useful_count: AtomicU32::new(0),
+            bloom_false_positive_count: AtomicU32::new(0),
         }
     }
     pub fn meta(&self) -> &FileMetaData {
         &self.meta
     }
 
+    pub fn bloom_useful_count(&self) -> u32 {
+        self.bloom_useful_count.load(Ordering::Relaxed)
+    }
+
+    pub fn bloom_false_positive_count(&self) -> u32 {
+        self.bloom_false_positive_count.load(Ordering::Relaxed)
+    }
+
+    pub fn add_bloom_useful(&self) {
+        self.bloom_useful_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bloom_false_positive(&self) {
+        self.bloom_false_positive_count
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn set_using(&self) {
         self.state.store(FILE_STATE_USING, Ordering::Relaxed);
     }
@@ -350,14 +474,12 @@ impl LogEntrySerializer for FileMetaDataLogSerializer {
         let level = r.read_u32::<LE>()?;
 
         let min_key_len = r.read_u32::<LE>()?;
-        let mut vec = Vec::new();
-        vec.resize(min_key_len as usize, 0);
+        let mut vec = vec![0; min_key_len as usize];
         r.read_exact(&mut vec)?;
         let min_key = vec.into();
 
         let max_key_len = r.read_u32::<LE>()?;
-        let mut vec = Vec::new();
-        vec.resize(max_key_len as usize, 0);
+        let mut vec = vec![0; max_key_len as usize];
         r.read_exact(&mut vec)?;
         let max_key = vec.into();
 
@@ -376,7 +498,15 @@ impl LogEntrySerializer for FileMetaDataLogSerializer {
 }
 
 #[derive(Debug, Default)]
-pub struct ManifestLogSerializer;
+pub struct ManifestLogSerializer {
+    comparator: ComparatorRef,
+}
+
+impl ManifestLogSerializer {
+    pub fn new(comparator: ComparatorRef) -> Self {
+        Self { comparator }
+    }
+}
 
 impl LogEntrySerializer for ManifestLogSerializer {
     type Entry = VersionEdit;
@@ -388,7 +518,7 @@ impl LogEntrySerializer for ManifestLogSerializer {
         match &entry {
             VersionEdit::SSTAppended(meta) => {
                 w.write_u8(1)?;
-                let s = FileMetaDataLogSerializer::default();
+                let s = FileMetaDataLogSerializer;
                 s.write(meta, w)
             }
             VersionEdit::SSTRemove(seq) => {
@@ -412,8 +542,8 @@ impl LogEntrySerializer for ManifestLogSerializer {
                 Ok(())
             }
             VersionEdit::Snapshot(ver) => {
-                w.write_u8(6)?;
-                let s = VersionLogSerializer::default();
+                w.write_u8(11)?;
+                let s = VersionLogSerializer::new(self.comparator.clone());
                 s.write(ver, w)
             }
             VersionEdit::NewRun(level) => {
@@ -421,6 +551,25 @@ impl LogEntrySerializer for ManifestLogSerializer {
                 w.write_u64::<LE>(*level)?;
                 Ok(())
             }
+            VersionEdit::LogNumberChanged(number) => {
+                w.write_u8(8)?;
+                w.write_u64::<LE>(*number)?;
+                Ok(())
+            }
+            VersionEdit::ComparatorName(name) => {
+                w.write_u8(9)?;
+                w.write_u32::<LE>(name.len() as u32)?;
+                w.write_all(name.as_bytes())?;
+                Ok(())
+            }
+            VersionEdit::Batch(edits) => {
+                w.write_u8(10)?;
+                w.write_u32::<LE>(edits.len() as u32)?;
+                for edit in edits {
+                    self.write(edit, w)?;
+                }
+                Ok(())
+            }
         }
     }
 
@@ -431,7 +580,7 @@ impl LogEntrySerializer for ManifestLogSerializer {
         let ty = r.read_u8()?;
         match ty {
             1 => {
-                let s = FileMetaDataLogSerializer::default();
+                let s = FileMetaDataLogSerializer;
                 let meta = s.read(r)?;
                 Ok(VersionEdit::SSTAppended(meta))
             }
@@ -440,13 +589,34 @@ impl LogEntrySerializer for ManifestLogSerializer {
             4 => Ok(VersionEdit::SSTSequenceChanged(r.read_u64::<LE>()?)),
             5 => Ok(VersionEdit::ManifestSequenceChanged(r.read_u64::<LE>()?)),
             6 => {
-                let s = VersionLogSerializer::default();
-                Ok(VersionEdit::Snapshot(Arc::new(s.read(r)?)))
+                let s = VersionLogSerializer::new(self.comparator.clone());
+                Ok(VersionEdit::Snapshot(Arc::new(s.read_by_overlap(r)?)))
             }
             7 => {
                 let level = r.read_u64::<LE>()?;
                 Ok(VersionEdit::NewRun(level))
             }
+            8 => Ok(VersionEdit::LogNumberChanged(r.read_u64::<LE>()?)),
+            9 => {
+                let len = r.read_u32::<LE>()?;
+                let mut name = vec![0; len as usize];
+                r.read_exact(&mut name)?;
+                let name = String::from_utf8(name)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(VersionEdit::ComparatorName(name))
+            }
+            10 => {
+                let len = r.read_u32::<LE>()?;
+                let mut edits = Vec::with_capacity(len as usize);
+                for _ in 0..len {
+                    edits.push(self.read(r)?);
+                }
+                Ok(VersionEdit::Batch(edits))
+            }
+            11 => {
+                let s = VersionLogSerializer::new(self.comparator.clone());
+                Ok(VersionEdit::Snapshot(Arc::new(s.read(r)?)))
+            }
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "invalid manifest type",
@@ -457,22 +627,34 @@ impl LogEntrySerializer for ManifestLogSerializer {
 
 #[derive(Debug, Default)]
 pub struct VersionSet {
+    /// versions which may be referenced by readers
     current: LinkedList<VersionRef>,
     version: Version,
+    /// sst files removed from version but may be referenced by alive versions
+    obsolete_files: Vec<u64>,
 
     last_seq: u64,
     last_sst_num: u64,
     last_manifest_num: u64,
+    log_number: u64,
     snapshot_versions: BTreeMap<u64, usize>,
 }
 
 impl VersionSet {
+    pub fn new(comparator: ComparatorRef) -> Self {
+        Self {
+            version: Version::new(comparator),
+            ..Default::default()
+        }
+    }
+
     pub fn add(&mut self, edit: &VersionEdit) -> Option<()> {
         match edit {
             VersionEdit::SSTAppended(meta) => {
                 let level = meta.level as usize;
                 if self.version.sst_files[level].runs.is_empty() {
-                    self.version.sst_files[level].runs.push(Run::new());
+                    let run = Run::new(self.version.comparator.clone());
+                    self.version.sst_files[level].runs.push(run);
                 }
                 let cur = self.version.sst_files[level].runs.last_mut().unwrap();
                 let fs = Arc::new(FileStatistics::new(meta.clone()));
@@ -488,9 +670,8 @@ impl VersionSet {
                 let mut ok = false;
                 let mut drop_idx = None;
                 for (idx, run) in self.version.sst_files[level].runs.iter_mut().enumerate() {
-                    if let Ok(index) = run.files.binary_search_by(|f| f.meta.number.cmp(seq)) {
+                    if run.remove(*seq) {
                         ok = true;
-                        run.files.remove(index);
                         self.version.seq_map.remove(seq);
                         if run.files.is_empty() {
                             drop_idx = Some(idx);
@@ -502,6 +683,7 @@ impl VersionSet {
                     error!("sst remove level {} seq {} fail", level, seq);
                     return None;
                 }
+                self.obsolete_files.push(*seq);
                 if let Some(idx) = drop_idx {
                     self.version.sst_files[level].runs.remove(idx);
                 }
@@ -509,9 +691,8 @@ impl VersionSet {
                 Some(())
             }
             VersionEdit::NewRun(level) => {
-                self.version.sst_files[*level as usize]
-                    .runs
-                    .push(Run::new());
+                let run = Run::new(self.version.comparator.clone());
+                self.version.sst_files[*level as usize].runs.push(run);
                 Some(())
             }
             VersionEdit::VersionChanged(ver) => {
@@ -526,14 +707,29 @@ impl VersionSet {
                 self.last_manifest_num = *seq;
                 Some(())
             }
+            VersionEdit::LogNumberChanged(number) => {
+                self.log_number = *number;
+                Some(())
+            }
+            // checked when the manifest is restored
+            VersionEdit::ComparatorName(_) => Some(()),
             VersionEdit::Snapshot(ver) => {
-                self.current.push_front(ver.clone());
+                let id = self.version.id;
+                self.version = ver.as_ref().clone();
+                self.version.id = id;
+                Some(())
+            }
+            VersionEdit::Batch(edits) => {
+                for edit in edits {
+                    self.add(edit)?;
+                }
                 Some(())
             }
         }
     }
 
     pub fn current(&mut self) -> VersionRef {
+        self.version.sort_runs();
         let ver = Arc::new(self.version.clone());
         self.version.id += 1;
 
@@ -541,12 +737,29 @@ impl VersionSet {
         ver
     }
 
-    pub fn detach_version(&self, ver: VersionRef) {
-        self.current.iter().find(|v| v.id == ver.id);
+    pub fn detach_version(&mut self, ver: VersionRef) {
+        let current = std::mem::take(&mut self.current);
+        self.current = current.into_iter().filter(|v| v.id != ver.id).collect();
     }
 
-    pub fn current_snapshot_version(&mut self) -> u64 {
-        let ver = self.last_seq;
+    /// obsolete sst files which are not referenced by any alive version,
+    /// a version is dead when only the version set holds it
+    pub fn take_unreferenced_files(&mut self) -> Vec<u64> {
+        let current = std::mem::take(&mut self.current);
+        self.current = current
+            .into_iter()
+            .filter(|v| Arc::strong_count(v) > 1)
+            .collect();
+
+        let (unreferenced, referenced) = self.obsolete_files.iter().partition(|number| {
+            !self.version.seq_map.contains_key(number)
+                && self.current.iter().all(|v| !v.seq_map.contains_key(number))
+        });
+        self.obsolete_files = referenced;
+        unreferenced
+    }
+
+    pub fn acquire_snapshot_version(&mut self, ver: u64) -> u64 {
         self.snapshot_versions
             .entry(ver)
             .and_modify(|val| *val += 1)
@@ -579,15 +792,18 @@ pub struct Manifest<'a> {
     version_set: Mutex<VersionSet>,
     current_path: PathBuf,
     current_tmp_path: PathBuf,
+    // locked after version_set if both are held
     wal: Mutex<LogWriter<'a, ManifestLogSerializer>>,
     seq: AtomicU64,
+    // the last sequence inserted into the memtable, read without the version set lock
+    last_seq: AtomicU64,
     backend: &'a Backend,
 }
 
 impl<'a> Manifest<'a> {
     pub fn new(config: &'a Config, backend: &'a Backend) -> Self {
         // replay version log
-        let version_set = Mutex::new(VersionSet::default());
+        let version_set = Mutex::new(VersionSet::new(config.comparator.clone()));
         let current_path = fname::manifest_current(config);
         let current_tmp_path = fname::manifest_current_tmp(config);
 
@@ -598,9 +814,10 @@ impl<'a> Manifest<'a> {
             seq
         );
 
-        let wal = LogWriter::new(backend.clone(), ManifestLogSerializer);
-        let path = manifest_name(config, seq + 1);
-        wal.rotate(path).unwrap();
+        let wal = LogWriter::new(
+            backend,
+            ManifestLogSerializer::new(config.comparator.clone()),
+        );
 
         let mut this = Self {
             config,
@@ -609,25 +826,37 @@ impl<'a> Manifest<'a> {
             current_tmp_path,
             wal: Mutex::new(wal),
             seq: AtomicU64::new(seq + 1),
+            last_seq: AtomicU64::new(0),
             backend,
         };
 
         this.restore_from_wal(seq).unwrap();
 
-        this.save_current_log();
+        // the new log starts with a snapshot of restored version, then old logs can be removed
+        {
+            let mut ver = this.version_set.lock().unwrap();
+            let wal = this.wal.lock().unwrap();
+            ver.last_manifest_num = ver.last_manifest_num.max(seq + 2);
+            // last seq is saved on rotation only, files flushed later may have newer data
+            let max_ver = ver.version.seq_map.values().map(|fs| fs.meta.max_ver + 1);
+            ver.last_seq = max_ver.fold(ver.last_seq, u64::max);
+            this.last_seq.store(ver.last_seq, Ordering::Release);
+            wal.rotate(manifest_name(config, seq + 1)).unwrap();
+            Self::write_snapshot(&wal, &mut ver).unwrap();
+        }
 
-        this.version_set.lock().unwrap().last_manifest_num = seq + 2;
+        this.save_current_log();
         this.remove_unused_wal(seq + 1);
+        this.remove_orphan_files();
 
-        // TODO: reload global version from wal
         this
     }
 
-    pub fn load_current_log_sequence(backend: &Backend, path: &PathBuf) -> Option<u64> {
+    pub fn load_current_log_sequence(backend: &Backend, path: &Path) -> Option<u64> {
         let mut buf = String::new();
-        if let Err(e) = backend.fs.open(path, false).and_then(|mut f| {
+        if let Err(e) = backend.fs.open(path, false).map(|f| {
             let len = f.size();
-            Ok(ExtReader::new(f.as_ref(), 0, len).read_to_string(&mut buf))
+            ExtReader::new(f.as_ref(), 0, len).read_to_string(&mut buf)
         }) {
             warn!("{:?} read {}", path.as_os_str(), e);
         }
@@ -655,10 +884,10 @@ impl<'a> Manifest<'a> {
 
     fn restore_from_wal(&mut self, seq: u64) -> Result<()> {
         let path = fname::manifest_name(self.config, seq);
-        let s = ManifestLogSerializer::default();
-        let replayer = log::LogReplayer::new(self.backend.clone(), s);
+        let s = ManifestLogSerializer::new(self.config.comparator.clone());
+        let replayer = log::LogReplayer::new(self.backend, s);
 
-        let mut state = VersionSet::default();
+        let mut state = VersionSet::new(self.config.comparator.clone());
 
         let iter = match replayer.iter(path) {
             Ok(e) => e,
@@ -672,7 +901,13 @@ impl<'a> Manifest<'a> {
         };
 
         for edit in iter {
-            state.add(&edit?);
+            let edit = edit?;
+            if let VersionEdit::ComparatorName(name) = &edit {
+                if name != self.config.comparator.name() {
+                    return Err(StorageError::ComparatorMismatch(name.clone()));
+                }
+            }
+            state.add(&edit);
         }
 
         info!("load manifest {} {:?}", seq, state);
@@ -680,23 +915,85 @@ impl<'a> Manifest<'a> {
         Ok(())
     }
 
-    fn remove_unused_wal(&self, _except_seq: u64) {}
+    /// remove manifest logs before `except_seq`, older logs are removed by previous rotation
+    fn remove_unused_wal(&self, except_seq: u64) {
+        let mut seq = except_seq;
+        while seq > 0 {
+            seq -= 1;
+            let path = manifest_name(self.config, seq);
+            if self.backend.fs.open(&path, false).is_err() {
+                break;
+            }
+            if let Err(e) = self.backend.fs.remove(&path) {
+                warn!("remove manifest log {:?} fail {:?}", path.as_os_str(), e);
+                break;
+            }
+        }
+    }
+
+    /// remove sst files left by a crash, they are allocated but never committed
+    fn remove_orphan_files(&self) {
+        let dir = fname::sst_dir(self.config);
+        let files = match self.backend.fs.list(&dir) {
+            Ok(files) => files,
+            Err(e) => {
+                if !e.is_io_not_found() {
+                    warn!("list {:?} fail {:?}", dir.as_os_str(), e);
+                }
+                return;
+            }
+        };
+        let vs = self.version_set.lock().unwrap();
+        for path in files {
+            if path.extension().is_none_or(|ext| ext != "sst") {
+                continue;
+            }
+            let Some(number) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            // numbers from the last one are not allocated yet
+            if number >= vs.last_sst_num || vs.version.seq_map.contains_key(&number) {
+                continue;
+            }
+            match self.backend.fs.remove(&path) {
+                Ok(_) => info!("remove orphan sst {}", number),
+                Err(e) => warn!("remove orphan sst {} fail {:?}", number, e),
+            }
+        }
+    }
+
+    /// remove sst files which are not referenced by any version, returns the removed numbers
+    pub fn remove_obsolete_files(&self) -> Vec<u64> {
+        let files = self.version_set.lock().unwrap().take_unreferenced_files();
+        for number in &files {
+            let path = fname::sst_name(self.config, *number);
+            match self.backend.fs.remove(&path) {
+                Ok(_) => info!("remove obsolete sst {}", number),
+                Err(e) => warn!("remove obsolete sst {} fail {:?}", number, e),
+            }
+        }
+        files
+    }
 }
 
 impl<'a> Manifest<'a> {
     fn commit(&self, edit: &VersionEdit) -> Result<()> {
-        self.wal.lock().unwrap().append(edit)?;
-        self.wal.lock().unwrap().sync()?;
-        Ok(())
+        let wal = self.wal.lock().unwrap();
+        wal.append(edit)?;
+        wal.sync()
     }
 
     fn rotate(&self) -> Result<()> {
         let old_seq = {
+            let mut ver = self.version_set.lock().unwrap();
             let wal = self.wal.lock().unwrap();
             let old_seq = self.seq.load(Ordering::Acquire);
-            let seq = self.allocate_manifest_number();
-
-            let mut ver = self.version_set.lock().unwrap();
+            let seq = ver.last_manifest_num;
+            ver.last_manifest_num += 1;
 
             wal.append(&VersionEdit::VersionChanged(ver.last_seq))?;
             wal.append(&VersionEdit::ManifestSequenceChanged(ver.last_manifest_num))?;
@@ -707,12 +1004,7 @@ impl<'a> Manifest<'a> {
 
             wal.rotate(path)?;
             // new wal file
-            wal.append(&VersionEdit::Snapshot(ver.current()))?;
-            wal.append(&VersionEdit::VersionChanged(ver.last_seq))?;
-            wal.append(&VersionEdit::ManifestSequenceChanged(ver.last_manifest_num))?;
-            wal.append(&VersionEdit::SSTSequenceChanged(ver.last_sst_num))?;
-
-            wal.sync()?;
+            Self::write_snapshot(&wal, &mut ver)?;
             self.seq.store(seq, Ordering::Release);
             old_seq
         };
@@ -727,20 +1019,67 @@ impl<'a> Manifest<'a> {
         Ok(())
     }
 
+    fn write_snapshot(
+        wal: &LogWriter<'a, ManifestLogSerializer>,
+        ver: &mut VersionSet,
+    ) -> Result<()> {
+        let name = ver.version.comparator.name().to_owned();
+        wal.append(&VersionEdit::ComparatorName(name))?;
+        wal.append(&VersionEdit::Snapshot(ver.current()))?;
+        wal.append(&VersionEdit::VersionChanged(ver.last_seq))?;
+        wal.append(&VersionEdit::ManifestSequenceChanged(ver.last_manifest_num))?;
+        wal.append(&VersionEdit::SSTSequenceChanged(ver.last_sst_num))?;
+        wal.append(&VersionEdit::LogNumberChanged(ver.log_number))?;
+        wal.sync()
+    }
+
     pub fn flush(&self) -> Result<()> {
         self.rotate()
     }
 
+    /// sequences start after the last allocated one,
+    /// reads see them after they are inserted into the memtable and published by `publish_seq`
     pub fn allocate_seq(&self, num: u64) -> u64 {
         let mut ver = self.version_set.lock().unwrap();
-        let return_ver = ver.last_seq;
+        let return_ver = ver.last_seq + 1;
         ver.last_seq += num;
         return_ver
     }
 
+    /// versions up to `seq` are in the memtable, reads without snapshot see them
+    pub fn publish_seq(&self, seq: u64) {
+        self.last_seq.fetch_max(seq, Ordering::AcqRel);
+    }
+
+    /// the last published sequence
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::Acquire)
+    }
+
+    /// make sure sequences allocated later are greater than `seq`, restored versions up to
+    /// `seq` are published
     pub fn set_latest_seq(&self, seq: u64) {
         let mut ver = self.version_set.lock().unwrap();
-        ver.last_seq = seq;
+        ver.last_seq = ver.last_seq.max(seq);
+        self.publish_seq(seq);
+    }
+
+    /// wal of memtables before the number are flushed, they are not replayed on restore
+    pub fn log_number(&self) -> u64 {
+        let ver = self.version_set.lock().unwrap();
+        ver.log_number
+    }
+
+    /// the log number only grows, imemtables flushed out of order can't move it back
+    pub fn set_log_number(&self, number: u64) -> Result<()> {
+        let mut ver = self.version_set.lock().unwrap();
+        if number <= ver.log_number {
+            return Ok(());
+        }
+        let edit = VersionEdit::LogNumberChanged(number);
+        self.commit(&edit)?;
+        ver.add(&edit);
+        Ok(())
     }
 
     pub fn allocate_sst_number(&self) -> u64 {
@@ -758,38 +1097,37 @@ impl<'a> Manifest<'a> {
         ver.last_sst_num
     }
 
-    fn allocate_manifest_number(&self) -> u64 {
-        let mut ver = self.version_set.lock().unwrap();
-        let return_num = ver.last_manifest_num;
-        ver.last_manifest_num += 1;
-        return_num
-    }
-
-    pub fn add_sst_with<F: FnOnce(Arc<Version>)>(&self, meta: FileMetaData, new_run: bool, f: F) {
+    pub fn add_sst_with<F: FnOnce(Arc<Version>)>(
+        &self,
+        meta: FileMetaData,
+        new_run: bool,
+        f: F,
+    ) -> Result<VersionRef> {
+        let num = meta.number;
+        let mut edits = Vec::with_capacity(2);
         if new_run {
-            let edit = VersionEdit::NewRun(meta.level as u64);
-            self.commit(&edit).unwrap();
-            let mut vs = self.version_set.lock().unwrap();
-            vs.add(&edit);
+            edits.push(VersionEdit::NewRun(meta.level as u64));
         }
-        let num = meta.number;
-        let edit = VersionEdit::SSTAppended(meta);
-        self.commit(&edit).unwrap();
-        let mut vs = self.version_set.lock().unwrap();
-        vs.add(&edit);
-        info!("add sst {} {:?}", num, vs.current());
-
-        f(vs.current());
+        edits.push(VersionEdit::SSTAppended(meta));
+        let current = self.modify_with(edits, f)?;
+        info!("add sst {} {:?}", num, current);
+        Ok(current)
     }
 
-    pub fn modify_with<F: FnOnce(Arc<Version>)>(&self, versions: Vec<VersionEdit>, f: F) {
+    /// edits are committed as one record, the version set is changed after the record is synced
+    pub fn modify_with<F: FnOnce(Arc<Version>)>(
+        &self,
+        edits: Vec<VersionEdit>,
+        f: F,
+    ) -> Result<VersionRef> {
+        // hold the lock, other edits can't be inserted between the commit and apply
         let mut vs = self.version_set.lock().unwrap();
-        for v in versions {
-            self.wal.lock().unwrap().append(&v).unwrap();
-            vs.add(&v);
-        }
-        self.wal.lock().unwrap().sync().unwrap();
-        f(vs.current());
+        let edit = VersionEdit::Batch(edits);
+        self.commit(&edit)?;
+        vs.add(&edit);
+        let current = vs.current();
+        f(current.clone());
+        Ok(current)
     }
 
     pub fn current(&self) -> VersionRef {
@@ -797,47 +1135,291 @@ impl<'a> Manifest<'a> {
         ver.current()
     }
 
-    pub fn new_snapshot(&'a self) -> SnapshotGuard<'a> {
-        let mut ver = self.version_set.lock().unwrap();
-        SnapshotGuard {
-            snapshot: Snapshot::new(ver.current_snapshot_version()),
-            manifest: self,
-        }
-    }
-
+    /// snapshot of the last published sequence, it must be released by `release_snapshot`
     pub fn snapshot(&self) -> Snapshot {
         let mut ver = self.version_set.lock().unwrap();
-        Snapshot::new(ver.current_snapshot_version())
+        Snapshot::new(ver.acquire_snapshot_version(self.last_seq()))
     }
 
-    fn release_snapshot(&self, snapshot_version: u64) {
+    pub fn release_snapshot(&self, snapshot_version: u64) {
         let mut ver = self.version_set.lock().unwrap();
         ver.release_snapshot_version(snapshot_version);
     }
 
+    /// sequences of live snapshots in ascending order
+    pub fn snapshot_versions(&self) -> Vec<u64> {
+        let ver = self.version_set.lock().unwrap();
+        ver.snapshot_versions.keys().copied().collect()
+    }
+
     pub fn oldest_snapshot_version(&self) -> u64 {
         let ver = self.version_set.lock().unwrap();
         ver.snapshot_versions
-            .iter()
-            .map(|(k, _v)| *k)
+            .keys()
+            .copied()
             .next()
             .unwrap_or(u64::MAX)
     }
 }
 
-pub struct SnapshotGuard<'a> {
-    snapshot: Snapshot,
-    manifest: &'a Manifest<'a>,
-}
+#[cfg(test)]
+mod test {
+    use crate::backend::fs::memory::MemoryBasedPersistBackend;
+
+    use super::*;
 
-impl<'a> SnapshotGuard<'a> {
-    pub fn get(&self) -> Snapshot {
-        self.snapshot.clone()
+    fn meta(number: u64, min: &'static str, max: &'static str, level: u32) -> FileMetaData {
+        FileMetaData::new(number, min.into(), max.into(), 0, 0, 1, level)
     }
-}
 
-impl<'a> Drop for SnapshotGuard<'a> {
-    fn drop(&mut self) {
-        self.manifest.release_snapshot(self.snapshot.sequence())
+    #[test]
+    pub fn unreferenced_files() {
+        let mut vs = VersionSet::default();
+        vs.add(&VersionEdit::SSTAppended(meta(1, "a", "b", 1)));
+        vs.add(&VersionEdit::SSTAppended(meta(2, "c", "d", 1)));
+        let reader = vs.current();
+
+        vs.add(&VersionEdit::SSTRemove(1)).unwrap();
+        let _current = vs.current();
+        // file 1 is still referenced by reader
+        assert!(vs.take_unreferenced_files().is_empty());
+
+        drop(reader);
+        assert_eq!(vs.take_unreferenced_files(), vec![1]);
+        assert!(vs.take_unreferenced_files().is_empty());
+    }
+
+    #[test]
+    pub fn restore_manifest() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let config = Config {
+            path: "/nanokv".into(),
+            ..Default::default()
+        };
+        {
+            let manifest = Manifest::new(&config, &backend);
+            manifest
+                .add_sst_with(meta(1, "a", "b", 0), true, |_| {})
+                .unwrap();
+            manifest
+                .add_sst_with(meta(2, "c", "d", 1), true, |_| {})
+                .unwrap();
+        }
+        // reopen twice, version is restored from the snapshot of previous log
+        for _ in 0..2 {
+            let manifest = Manifest::new(&config, &backend);
+            let version = manifest.current();
+            assert_eq!(version.level_n(0)[0].files()[0].meta().number, 1);
+            assert_eq!(version.level_n(1)[0].files()[0].meta().number, 2);
+        }
+    }
+
+    #[test]
+    pub fn rotate_with_concurrent_edits() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let config = Config {
+            path: "/nanokv".into(),
+            ..Default::default()
+        };
+        let manifest = Manifest::new(&config, &backend);
+        // edits take the version set then the wal, as rotation does
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..100 {
+                    manifest.flush().unwrap();
+                }
+            });
+            s.spawn(|| {
+                for number in 1..=100 {
+                    manifest
+                        .add_sst_with(meta(number, "a", "b", 0), true, |_| {})
+                        .unwrap();
+                    manifest.set_log_number(number).unwrap();
+                    manifest.allocate_sst_number();
+                }
+            });
+        });
+        drop(manifest);
+        let manifest = Manifest::new(&config, &backend);
+        assert_eq!(manifest.current().level_n(0).len(), 100);
+        assert_eq!(manifest.log_number(), 100);
+    }
+
+    #[test]
+    pub fn restore_runs() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let config = Config {
+            path: "/nanokv".into(),
+            ..Default::default()
+        };
+        let runs = |version: &Version, level| -> Vec<Vec<u64>> {
+            version
+                .level_n(level)
+                .iter()
+                .map(|run| run.files().iter().map(|fs| fs.meta().number).collect())
+                .collect()
+        };
+        {
+            let manifest = Manifest::new(&config, &backend);
+            // runs of level 0 don't overlap, they are still distinct runs
+            manifest
+                .add_sst_with(meta(1, "a", "b", 0), true, |_| {})
+                .unwrap();
+            manifest
+                .add_sst_with(meta(2, "c", "d", 0), true, |_| {})
+                .unwrap();
+            manifest
+                .add_sst_with(meta(3, "a", "b", 1), true, |_| {})
+                .unwrap();
+            manifest
+                .add_sst_with(meta(4, "c", "d", 1), false, |_| {})
+                .unwrap();
+        }
+        // reopen twice, runs are restored from the snapshot of previous log
+        for _ in 0..2 {
+            let manifest = Manifest::new(&config, &backend);
+            let version = manifest.current();
+            assert_eq!(runs(&version, 0), vec![vec![1], vec![2]]);
+            assert_eq!(runs(&version, 1), vec![vec![3, 4]]);
+        }
+    }
+
+    #[test]
+    pub fn torn_commit() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let config = Config {
+            path: "/nanokv".into(),
+            ..Default::default()
+        };
+        let seq = {
+            let manifest = Manifest::new(&config, &backend);
+            manifest
+                .add_sst_with(meta(1, "a", "b", 0), true, |_| {})
+                .unwrap();
+            manifest
+                .modify_with(
+                    vec![
+                        VersionEdit::SSTRemove(1),
+                        VersionEdit::SSTAppended(meta(2, "a", "b", 1)),
+                    ],
+                    |_| {},
+                )
+                .unwrap();
+            // the batch spans several log segments
+            let key: &'static str = "k".repeat(100).leak();
+            let mut edits = vec![VersionEdit::SSTRemove(2)];
+            edits.extend((3..2000).map(|n| VersionEdit::SSTAppended(meta(n, key, key, 1))));
+            manifest.modify_with(edits, |_| {}).unwrap();
+            manifest.current_log_sequence().unwrap()
+        };
+        // crash in the middle of the last commit
+        let path = manifest_name(&config, seq);
+        let bytes = backend
+            .fs
+            .open(&path, false)
+            .unwrap()
+            .addr()
+            .unwrap()
+            .to_vec();
+        let mut f = backend.fs.create(&path, None).unwrap();
+        f.write_all(&bytes[..bytes.len() / 2]).unwrap();
+        drop(f);
+
+        let manifest = Manifest::new(&config, &backend);
+        let version = manifest.current();
+        assert!(version.level_n(0).is_empty());
+        let files = version.level_n(1)[0].files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].meta().number, 2);
+    }
+
+    #[test]
+    pub fn remove_orphan_files() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let config = Config {
+            path: "/nanokv".into(),
+            ..Default::default()
+        };
+        let touch = |number| {
+            let path = fname::sst_name(&config, number);
+            backend
+                .fs
+                .create(&path, None)
+                .unwrap()
+                .write_all(b"sst")
+                .unwrap();
+        };
+        {
+            let manifest = Manifest::new(&config, &backend);
+            let committed = manifest.allocate_sst_number();
+            let orphan = manifest.allocate_sst_number();
+            touch(committed);
+            touch(orphan);
+            manifest
+                .add_sst_with(meta(committed, "a", "b", 0), true, |_| {})
+                .unwrap();
+            // not allocated by this manifest
+            touch(manifest.last_sst_number() + 1);
+        }
+        let manifest = Manifest::new(&config, &backend);
+        let last = manifest.last_sst_number();
+        let exists = |number| {
+            backend
+                .fs
+                .open(&fname::sst_name(&config, number), false)
+                .is_ok()
+        };
+        assert!(exists(last - 2));
+        assert!(!exists(last - 1));
+        assert!(exists(last + 1));
+    }
+
+    #[test]
+    pub fn publish_seq() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let config = Config {
+            path: "/nanokv".into(),
+            ..Default::default()
+        };
+        let manifest = Manifest::new(&config, &backend);
+        let last = manifest.last_seq();
+        // allocated sequences are not visible before they are published
+        let seq = manifest.allocate_seq(3);
+        assert_eq!(seq, last + 1);
+        assert_eq!(manifest.last_seq(), last);
+        let snapshot = manifest.snapshot();
+        assert_eq!(snapshot.sequence(), last);
+        manifest.release_snapshot(snapshot.sequence());
+
+        manifest.publish_seq(seq + 2);
+        assert_eq!(manifest.last_seq(), seq + 2);
+        // sequences are published in order, an older one doesn't go back
+        manifest.publish_seq(seq);
+        assert_eq!(manifest.last_seq(), seq + 2);
+        assert_eq!(manifest.allocate_seq(1), seq + 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "ComparatorMismatch")]
+    pub fn comparator_mismatch() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let mut config = Config {
+            path: "/nanokv".into(),
+            comparator: ComparatorRef::new(crate::test::ReverseComparator),
+            ..Default::default()
+        };
+        {
+            let manifest = Manifest::new(&config, &backend);
+            manifest
+                .add_sst_with(meta(1, "b", "a", 0), true, |_| {})
+                .unwrap();
+        }
+        // same comparator
+        {
+            let manifest = Manifest::new(&config, &backend);
+            assert_eq!(manifest.current().level_n(0)[0].files().len(), 1);
+        }
+        config.comparator = ComparatorRef::default();
+        Manifest::new(&config, &backend);
     }
 }
@@ -3,23 +3,26 @@ use std::marker::PhantomData;
 use std::ops::{Bound, RangeBounds};
 
 use std::sync::atomic::AtomicU64;
+use std::sync::RwLock;
 
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use super::superversion::Lifetime;
 use super::GetOption;
+use crate::comparator::ComparatorRef;
 use crate::err::{Result, StorageError};
-use crate::iterator::{EqualFilter, KvIteratorItem, ScanIter};
+use crate::iterator::{EqualFilter, KvIteratorItem, MergedIter, RevEqualFilter, ScanIter};
 use crate::key::{InternalKey, KeyType, Value, WriteBatch, WriteBatchBuilder};
+use crate::range_del::{covering_seq, deleted_entry, RangeTombstone};
 use crate::WriteOption;
 
-#[derive(Debug, Eq, Ord, Clone)]
+#[derive(Debug, Eq, Clone)]
 struct LookupKeyValue {
     bytes: Bytes,
 }
 
-impl<'a> KvIteratorItem for &'a LookupKeyValue {
+impl KvIteratorItem for &LookupKeyValue {
     fn user_key_slice(&self) -> &[u8] {
         unsafe { std::mem::transmute(self.internal_key().user_key_slice()) }
     }
@@ -35,8 +38,13 @@ impl<'a> KvIteratorItem for &'a LookupKeyValue {
     fn deleted(&self) -> bool {
         self.internal_key().key_type() == KeyType::Del
     }
+
+    fn is_merge(&self) -> bool {
+        self.internal_key().key_type() == KeyType::Merge
+    }
 }
 
+#[allow(unused)]
 impl LookupKeyValue {
     pub fn new(key: InternalKey, value: &[u8]) -> Self {
         let mut bytes = BytesMut::default().writer();
@@ -99,9 +107,15 @@ impl PartialEq for LookupKeyValue {
     }
 }
 
+impl Ord for LookupKeyValue {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.internal_key().cmp(&other.internal_key())
+    }
+}
+
 impl PartialOrd for LookupKeyValue {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.internal_key().partial_cmp(&other.internal_key())
+        Some(self.cmp(other))
     }
 }
 
@@ -109,27 +123,44 @@ impl PartialOrd for LookupKeyValue {
 pub struct Memtable {
     list: skiplist::OrderedSkipList<LookupKeyValue>,
 
+    // range tombstones are kept out of the list of point entries
+    range_dels: RwLock<Vec<RangeTombstone>>,
+
     total_bytes: AtomicU64,
     min_seq: AtomicU64,
     max_seq: AtomicU64,
     number: u64,
+    comparator: ComparatorRef,
 }
 
 impl Memtable {
-    pub fn new(number: u64) -> Self {
+    pub fn new(number: u64, comparator: ComparatorRef) -> Self {
+        let list_comparator = comparator.clone();
+        // safety: the comparator is a total order
+        let list = unsafe {
+            skiplist::OrderedSkipList::with_comp(move |a: &LookupKeyValue, b: &LookupKeyValue| {
+                list_comparator.compare_item(&a.internal_key(), &b.internal_key())
+            })
+        };
         Self {
-            list: skiplist::OrderedSkipList::new(),
+            list,
+            range_dels: RwLock::new(Vec::new()),
             max_seq: AtomicU64::new(0),
             total_bytes: AtomicU64::new(0),
             min_seq: AtomicU64::new(0),
             number,
+            comparator,
         }
     }
 
+    pub fn comparator(&self) -> &ComparatorRef {
+        &self.comparator
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (InternalKey, Bytes, PhantomData<&'_ ()>)> {
         self.list.iter().map(|v| {
             let res = (v.internal_key(), v.value());
-            (res.0, res.1, PhantomData::default())
+            (res.0, res.1, PhantomData)
         })
     }
 
@@ -155,7 +186,29 @@ impl Memtable {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.list.is_empty()
+        self.list.is_empty() && self.range_dels.read().unwrap().is_empty()
+    }
+
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_dels.read().unwrap().clone()
+    }
+
+    /// point entries and range tombstones in order of internal keys
+    pub fn entries(&self) -> impl Iterator<Item = (InternalKey, Value)> + '_ {
+        let mut tombstones: Vec<_> = self
+            .range_tombstones()
+            .iter()
+            .map(RangeTombstone::to_entry)
+            .collect();
+        tombstones.sort_by(|a, b| self.comparator.compare_item(&a.0, &b.0));
+        let points = self
+            .list
+            .iter()
+            .map(|v| (v.internal_key(), v.value().into()));
+        MergedIter::new_all_versions(
+            vec![ScanIter::new(points), ScanIter::new(tombstones.into_iter())],
+            self.comparator.clone(),
+        )
     }
 
     pub fn first_key(&self) -> InternalKey {
@@ -178,16 +231,23 @@ impl Memtable {
             Included(&LookupKeyValue::new_lookup(&key, u64::MAX)),
             Included(&LookupKeyValue::new_lookup(&key, 0)),
         );
-        if let Some(snapshot) = opt.snapshot() {
-            // snapshot get
-            if let Some(entry) = iter.find(|entry| entry.seq() <= snapshot.sequence()) {
-                return Ok((entry.internal_key(), entry.value().into()));
+        let ver = opt.snapshot().map(|v| v.sequence()).unwrap_or(u64::MAX);
+        let entry = iter.find(|entry| entry.seq() <= ver);
+
+        // a newer range tombstone deletes the key
+        let deleted = covering_seq(
+            &self.range_dels.read().unwrap(),
+            &self.comparator,
+            &key,
+            ver,
+        );
+        match entry {
+            Some(entry) if entry.seq() > deleted => {
+                Ok((entry.internal_key(), entry.value().into()))
             }
-        } else if let Some(entry) = iter.next() {
-            return Ok((entry.internal_key(), entry.value().into()));
+            _ if deleted > 0 => Ok(deleted_entry(&key, deleted)),
+            _ => Err(StorageError::KeyNotExist),
         }
-
-        Err(StorageError::KeyNotExist)
     }
 
     pub fn scan<'a, R: RangeBounds<Bytes> + Clone>(
@@ -196,6 +256,49 @@ impl Memtable {
         range: R,
         _lifetime: &Lifetime<'a>, // lifetime parameter
     ) -> ScanIter<'a, (InternalKey, Value)> {
+        let iter = self.range(range);
+        if let Some(snapshot) = opt.snapshot() {
+            let snapshot_seq = snapshot.sequence();
+            let iter = iter.filter(move |entry| entry.seq() <= snapshot_seq);
+            ScanIter::new(
+                EqualFilter::new(iter, self.comparator.clone())
+                    .map(|v| (v.internal_key(), v.value().into())),
+            )
+        } else {
+            ScanIter::new(
+                EqualFilter::new(iter, self.comparator.clone())
+                    .map(|v| (v.internal_key(), v.value().into())),
+            )
+        }
+    }
+
+    /// scan in reverse order of user keys
+    pub fn scan_rev<'a, R: RangeBounds<Bytes> + Clone>(
+        &self,
+        opt: &GetOption,
+        range: R,
+        _lifetime: &Lifetime<'a>,
+    ) -> ScanIter<'a, (InternalKey, Value)> {
+        let iter = self.range(range).rev();
+        if let Some(snapshot) = opt.snapshot() {
+            let snapshot_seq = snapshot.sequence();
+            let iter = iter.filter(move |entry| entry.seq() <= snapshot_seq);
+            ScanIter::new(
+                RevEqualFilter::new(iter, self.comparator.clone())
+                    .map(|v| (v.internal_key(), v.value().into())),
+            )
+        } else {
+            ScanIter::new(
+                RevEqualFilter::new(iter, self.comparator.clone())
+                    .map(|v| (v.internal_key(), v.value().into())),
+            )
+        }
+    }
+
+    fn range<R: RangeBounds<Bytes>>(
+        &self,
+        range: R,
+    ) -> skiplist::ordered_skiplist::Iter<'static, LookupKeyValue> {
         use std::ops::Bound::*;
         let beg = match range.start_bound() {
             Included(val) => Included(LookupKeyValue::new_lookup(val, u64::MAX)),
@@ -211,17 +314,11 @@ impl Memtable {
         let beg = map_bound(&beg);
         let end = map_bound(&end);
 
-        let iter = unsafe {
-            core::mem::transmute::<_, skiplist::ordered_skiplist::Iter<'static, LookupKeyValue>>(
-                self.list.range(beg, end),
-            )
-        };
-        if let Some(snapshot) = opt.snapshot() {
-            let snapshot_seq = snapshot.sequence();
-            let iter = iter.filter(move |entry| entry.seq() <= snapshot_seq);
-            ScanIter::new(EqualFilter::new(iter).map(|v| (v.internal_key(), v.value().into())))
-        } else {
-            ScanIter::new(EqualFilter::new(iter).map(|v| (v.internal_key(), v.value().into())))
+        unsafe {
+            core::mem::transmute::<
+                skiplist::ordered_skiplist::Iter<'_, LookupKeyValue>,
+                skiplist::ordered_skiplist::Iter<'static, LookupKeyValue>,
+            >(self.list.range(beg, end))
         }
     }
 }
@@ -246,13 +343,23 @@ impl Memtable {
     }
 
     pub fn set_batch(&self, b: WriteBatch, mut seq: u64) -> Result<()> {
-        if self.list.is_empty() {
+        if self.is_empty() {
             self.min_seq
                 .store(seq, std::sync::atomic::Ordering::Release);
         }
 
         let l = &self.list as *const skiplist::OrderedSkipList<LookupKeyValue>;
         for (key, value) in b.iter() {
+            if key.key_type() == KeyType::RangeDel {
+                self.total_bytes.fetch_add(
+                    (key.len() + value.len()) as u64,
+                    std::sync::atomic::Ordering::AcqRel,
+                );
+                let tombstone = RangeTombstone::new(key.user_key(), value, seq);
+                self.range_dels.write().unwrap().push(tombstone);
+                seq += 1;
+                continue;
+            }
             let kv = LookupKeyValue::new_with_seq(key, seq, &value);
             self.total_bytes
                 .fetch_add(kv.len() as u64, std::sync::atomic::Ordering::AcqRel);
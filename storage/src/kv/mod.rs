@@ -3,12 +3,14 @@ use std::sync::Arc;
 use crate::GetOption;
 
 pub mod imemtable;
+pub mod lock_manager;
 pub mod manifest;
 pub mod memtable;
 pub mod sst;
 pub use imemtable::Imemtables;
 pub use memtable::Memtable;
 pub mod superversion;
+pub mod write_queue;
 
 pub struct ColumnFamilyTables {
     pub memtable: Arc<Memtable>,
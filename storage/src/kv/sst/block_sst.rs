@@ -0,0 +1,1370 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::io::{self, BufWriter, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use bytes::{Bytes, BytesMut};
+use integer_encoding::{VarInt, VarIntReader, VarIntWriter};
+use log::debug;
+
+use crate::backend::fs::{ReadablePersist, WriteablePersist};
+use crate::backend::Backend;
+use crate::cache::BlockCache;
+use crate::comparator::{Comparator, ComparatorRef};
+use crate::err::*;
+use crate::iterator::{EqualFilter, KvIteratorItem, RevEqualFilter, ScanIter, ScanStatus};
+use crate::key::{InternalKey, KeyType, Value};
+use crate::kv::superversion::Lifetime;
+use crate::prefix::PrefixExtractorRef;
+use crate::range_del::{self, RangeTombstone};
+use crate::util::bloom;
+use crate::util::crc::{crc_mask, crc_unmask};
+use crate::KvIterator;
+
+use super::compression::{self, Compression};
+use super::format::{self, Footer, ReaderOptions};
+use super::{FileMetaData, SSTReader, SSTWriter};
+
+pub(crate) const BLOCKSST_MAGIC: u32 = 0xA18C0002;
+const BLOCKSST_VERSION: u32 = 1;
+// keys between two restart points share prefix with the previous key
+const RESTART_INTERVAL: usize = 16;
+
+// data block
+//
+// entry: shared key len | unshared key len | value len | unshared key | value
+// restarts: u32 offset of entries which share nothing
+// num restarts: u32
+struct BlockBuilder {
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    counter: usize,
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            restarts: vec![0],
+            counter: 0,
+            last_key: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8]) {
+        let shared = if self.counter < RESTART_INTERVAL {
+            key.iter()
+                .zip(self.last_key.iter())
+                .take_while(|(a, b)| a == b)
+                .count()
+        } else {
+            self.restarts.push(self.buf.len() as u32);
+            self.counter = 0;
+            0
+        };
+        let mut tmp = [0u8; 10];
+        for n in [shared, key.len() - shared, value.len()] {
+            let len = n.encode_var(&mut tmp);
+            self.buf.extend_from_slice(&tmp[..len]);
+        }
+        self.buf.extend_from_slice(&key[shared..]);
+        self.buf.extend_from_slice(value);
+
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.counter += 1;
+    }
+
+    fn estimated_size(&self) -> usize {
+        self.buf.len() + self.restarts.len() * 4 + 4
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// returns the block and resets the builder
+    fn finish(&mut self) -> Vec<u8> {
+        for restart in &self.restarts {
+            let _ = self.buf.write_u32::<LE>(*restart);
+        }
+        let _ = self.buf.write_u32::<LE>(self.restarts.len() as u32);
+        let block = std::mem::take(&mut self.buf);
+        *self = Self::new();
+        block
+    }
+}
+
+#[derive(Clone)]
+struct Block {
+    data: Bytes,
+    restarts_offset: usize,
+    num_restarts: usize,
+}
+
+impl Block {
+    fn new(data: Bytes) -> Result<Self> {
+        if data.len() < 4 {
+            return Err(StorageError::DataCorrupt);
+        }
+        let num_restarts = (&data[data.len() - 4..]).read_u32::<LE>()? as usize;
+        let restarts_size = (num_restarts + 1) * 4;
+        if num_restarts == 0 || data.len() < restarts_size {
+            return Err(StorageError::DataCorrupt);
+        }
+        Ok(Self {
+            restarts_offset: data.len() - restarts_size,
+            num_restarts,
+            data,
+        })
+    }
+
+    fn restart(&self, index: usize) -> usize {
+        let offset = self.restarts_offset + index * 4;
+        (&self.data[offset..offset + 4]).read_u32::<LE>().unwrap() as usize
+    }
+
+    fn iter(&self) -> BlockIter {
+        BlockIter {
+            block: self.clone(),
+            offset: 0,
+            key: Vec::new(),
+        }
+    }
+}
+
+struct BlockIter {
+    block: Block,
+    offset: usize,
+    key: Vec<u8>,
+}
+
+impl BlockIter {
+    fn decode_next(&mut self) -> Result<Option<(InternalKey, Value)>> {
+        if self.offset >= self.block.restarts_offset {
+            return Ok(None);
+        }
+        let mut r = &self.block.data[self.offset..self.block.restarts_offset];
+        let rest = r.len();
+        let shared: usize = r.read_varint()?;
+        let unshared: usize = r.read_varint()?;
+        let value_len: usize = r.read_varint()?;
+        if shared > self.key.len() || r.len() < unshared + value_len {
+            return Err(StorageError::DataCorrupt);
+        }
+        self.key.truncate(shared);
+        self.key.extend_from_slice(&r[..unshared]);
+        if self.key.len() < 8 {
+            return Err(StorageError::DataCorrupt);
+        }
+
+        let value_offset = self.offset + (rest - r.len()) + unshared;
+        let value = self
+            .block
+            .data
+            .slice(value_offset..value_offset + value_len);
+        self.offset = value_offset + value_len;
+
+        let key: InternalKey = Bytes::copy_from_slice(&self.key).into();
+        Ok(Some((key, value.into())))
+    }
+
+    /// key of the entry at restart point, which shares nothing
+    fn restart_user_key(&self, index: usize) -> Result<&[u8]> {
+        let offset = self.block.restart(index);
+        let mut r = &self.block.data[offset..self.block.restarts_offset];
+        let _shared: usize = r.read_varint()?;
+        let unshared: usize = r.read_varint()?;
+        let _value_len: usize = r.read_varint()?;
+        if unshared < 8 || r.len() < unshared {
+            return Err(StorageError::DataCorrupt);
+        }
+        Ok(&r[..unshared - 8])
+    }
+
+    /// position at the first entry whose user key >= key
+    fn seek(&mut self, key: &[u8], comparator: &dyn Comparator) -> Result<()> {
+        // find the last restart point whose user key < key
+        let mut left = 0;
+        let mut right = self.block.num_restarts - 1;
+        while left < right {
+            let mid = (left + right).div_ceil(2);
+            if comparator.compare(self.restart_user_key(mid)?, key) == Ordering::Less {
+                left = mid;
+            } else {
+                right = mid - 1;
+            }
+        }
+        self.offset = self.block.restart(left);
+        self.key.clear();
+
+        loop {
+            let offset = self.offset;
+            let last_key = self.key.clone();
+            match self.decode_next()? {
+                Some((k, _)) if comparator.compare(k.user_key_slice(), key) == Ordering::Less => {
+                    continue
+                }
+                Some(_) => {
+                    // step back
+                    self.offset = offset;
+                    self.key = last_key;
+                    return Ok(());
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+impl Iterator for BlockIter {
+    type Item = Result<(InternalKey, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decode_next().transpose()
+    }
+}
+
+// trailer of blocks: compression type u8 | crc u32
+const BLOCK_TRAILER_SIZE: usize = 5;
+
+/// returns the written size
+fn write_block<W: Write>(mut w: W, data: &[u8], compression: Compression) -> io::Result<u64> {
+    let compressed = compression::compress(compression, data);
+    let (data, ty) = match &compressed {
+        Some(compressed) => (&compressed[..], compression),
+        None => (data, Compression::None),
+    };
+    let mut crc_builder = crc32fast::Hasher::new();
+    crc_builder.update(data);
+    crc_builder.update(&[ty.into()]);
+
+    w.write_all(data)?;
+    w.write_u8(ty.into())?;
+    w.write_u32::<LE>(crc_mask(crc_builder.finalize()))?;
+    Ok((data.len() + BLOCK_TRAILER_SIZE) as u64)
+}
+
+// block handle of index block entry
+#[derive(Debug, Clone, Copy)]
+struct BlockHandle {
+    offset: u64,
+    size: u64,
+    // size of block contents before compression
+    raw_size: u64,
+}
+
+fn encode_handle(offset: u64, size: u64, raw_size: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let _ = buf.write_varint(offset);
+    let _ = buf.write_varint(size);
+    let _ = buf.write_varint(raw_size);
+    buf
+}
+
+fn decode_handle(mut data: &[u8]) -> Result<BlockHandle> {
+    let offset = data.read_varint()?;
+    let size = data.read_varint()?;
+    let raw_size = data.read_varint()?;
+    Ok(BlockHandle {
+        offset,
+        size,
+        raw_size,
+    })
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct BlockSSTMetaInfo {
+    pub number: u64,
+    pub level: u32,
+    pub total_keys: u64,
+    pub index_offset: u64,
+    pub index_size: u64,
+    pub filter_offset: u64,
+    pub filter_size: u64,
+    pub prefix_extractor: String,
+    pub range_del_offset: u64,
+    pub range_del_size: u64,
+
+    pub version: u32,
+    pub meta_size: u32,
+    pub magic: u32,
+}
+
+impl BlockSSTMetaInfo {
+    pub fn write<W: Write>(&mut self, w: W) -> io::Result<()> {
+        let mut meta = Vec::new();
+        meta.write_varint(self.number)?;
+        meta.write_varint(self.level)?;
+        meta.write_varint(self.total_keys)?;
+        meta.write_varint(self.index_offset)?;
+        meta.write_varint(self.index_size)?;
+        meta.write_varint(self.filter_offset)?;
+        meta.write_varint(self.filter_size)?;
+        format::write_name(&mut meta, &self.prefix_extractor)?;
+        meta.write_varint(self.range_del_offset)?;
+        meta.write_varint(self.range_del_size)?;
+
+        self.meta_size = Footer::write(w, &meta, self.version, self.magic, true)?;
+        Ok(())
+    }
+
+    pub fn read(r: &dyn ReadablePersist, size: u64) -> io::Result<Self> {
+        let footer = Footer::read(r, size, |_| true)?;
+        if footer.version != BLOCKSST_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown block sst version",
+            ));
+        }
+        let mut rr = &footer.meta[..];
+
+        Ok(Self {
+            number: rr.read_varint()?,
+            level: rr.read_varint()?,
+            total_keys: rr.read_varint()?,
+            index_offset: rr.read_varint()?,
+            index_size: rr.read_varint()?,
+            filter_offset: rr.read_varint()?,
+            filter_size: rr.read_varint()?,
+            prefix_extractor: format::read_name(&mut rr)?,
+            range_del_offset: rr.read_varint()?,
+            range_del_size: rr.read_varint()?,
+            version: footer.version,
+            meta_size: footer.size,
+            magic: footer.magic,
+        })
+    }
+}
+
+struct BlockSSTReaderInner {
+    file: Box<dyn ReadablePersist>,
+    meta: BlockSSTMetaInfo,
+    comparator: ComparatorRef,
+    block_cache: Option<Arc<BlockCache>>,
+    // pinned index and filter blocks, read through the block cache if not set
+    index: Option<Block>,
+    filter: Option<Bytes>,
+    // prefixes of keys are in the filter
+    prefix_filter: bool,
+    range_tombstones: Vec<RangeTombstone>,
+}
+
+impl BlockSSTReaderInner {
+    fn read_bytes(&self, offset: u64, size: u64) -> Result<Bytes> {
+        let mut buf = BytesMut::zeroed(size as usize);
+        let f = self.file.borrow() as &dyn ReadablePersist;
+        f.read_exact_at(offset, &mut buf)?;
+        Ok(buf.freeze())
+    }
+
+    /// read block and strip the trailer, the contents must have `raw_size` bytes
+    fn read_contents(&self, offset: u64, size: u64, raw_size: u64, verify: bool) -> Result<Bytes> {
+        let mut data = self.read_bytes(offset, size)?;
+        if data.len() < BLOCK_TRAILER_SIZE {
+            return Err(StorageError::DataCorrupt);
+        }
+        let crc_offset = data.len() - 4;
+        if verify {
+            let crc = crc_unmask((&data[crc_offset..]).read_u32::<LE>()?);
+            if crc != crc32fast::hash(&data[..crc_offset]) {
+                return Err(StorageError::DataCorrupt);
+            }
+        }
+        let ty = data[crc_offset - 1];
+        let compression = Compression::try_from(ty).map_err(|_| StorageError::DataCorrupt)?;
+        data.truncate(crc_offset - 1);
+        compression::decompress(compression, data, raw_size)
+    }
+
+    /// index and filter blocks are not compressed
+    fn read_meta_block(&self, offset: u64, size: u64) -> Result<Bytes> {
+        let raw_size = size
+            .checked_sub(BLOCK_TRAILER_SIZE as u64)
+            .ok_or(StorageError::DataCorrupt)?;
+        self.read_contents(offset, size, raw_size, true)
+    }
+
+    fn read_cached<F: FnOnce() -> Result<Bytes>>(&self, offset: u64, read: F) -> Result<Bytes> {
+        let cache = match &self.block_cache {
+            Some(cache) => cache,
+            None => return read(),
+        };
+        if let Some(data) = cache.get(self.meta.number, offset) {
+            return Ok(data);
+        }
+        let data = read()?;
+        cache.insert(self.meta.number, offset, data.clone());
+        Ok(data)
+    }
+
+    fn read_block(&self, handle: BlockHandle, verify: bool) -> Result<Block> {
+        let BlockHandle {
+            offset,
+            size,
+            raw_size,
+        } = handle;
+        Block::new(self.read_cached(offset, || {
+            self.read_contents(offset, size, raw_size, verify)
+        })?)
+    }
+
+    /// entries of index block are last internal key of data blocks and their handles
+    fn index_block(&self) -> Result<Block> {
+        if let Some(index) = &self.index {
+            return Ok(index.clone());
+        }
+        let (offset, size) = (self.meta.index_offset, self.meta.index_size);
+        Block::new(self.read_cached(offset, || self.read_meta_block(offset, size))?)
+    }
+
+    fn filter(&self) -> Result<Bytes> {
+        if let Some(filter) = &self.filter {
+            return Ok(filter.clone());
+        }
+        let (offset, size) = (self.meta.filter_offset, self.meta.filter_size);
+        self.read_cached(offset, || self.read_meta_block(offset, size))
+    }
+
+    /// get the key with a positioned index iterator, `block` holds the last read data block
+    /// with its offset, keys in the same data block read it once
+    fn get_in_blocks(
+        &self,
+        index: &mut BlockIter,
+        block: &mut Option<(u64, Block)>,
+        key: &[u8],
+        ver: u64,
+        verify: bool,
+    ) -> Result<(InternalKey, Value)> {
+        let comparator = &*self.comparator;
+        // first data block whose last key >= key
+        index.seek(key, comparator)?;
+        loop {
+            let (_, handle) = match index.next() {
+                Some(entry) => entry?,
+                None => return Err(StorageError::KeyNotExist),
+            };
+            let handle = decode_handle(handle.data())?;
+            if block.as_ref().map(|(cur, _)| *cur) != Some(handle.offset) {
+                *block = Some((handle.offset, self.read_block(handle, verify)?));
+            }
+            let mut iter = block.as_ref().unwrap().1.iter();
+            iter.seek(key, comparator)?;
+            for entry in iter {
+                let (internal_key, value) = entry?;
+                if !self.comparator.equal(internal_key.user_key_slice(), key) {
+                    return Err(StorageError::KeyNotExist);
+                }
+                if internal_key.seq() <= ver {
+                    return Ok((internal_key, value));
+                }
+            }
+            // versions of the key continue in the next block
+        }
+    }
+
+    fn read_range_tombstones(&mut self) -> Result<()> {
+        let (offset, size) = (self.meta.range_del_offset, self.meta.range_del_size);
+        if size > 0 {
+            self.range_tombstones = range_del::read_section(&self.read_bytes(offset, size)?)?;
+        }
+        Ok(())
+    }
+
+    fn pin_index_and_filter(&mut self) -> Result<()> {
+        let meta = &self.meta;
+        let index = Block::new(self.read_meta_block(meta.index_offset, meta.index_size)?)?;
+        let filter = self.read_meta_block(meta.filter_offset, meta.filter_size)?;
+        self.index = Some(index);
+        self.filter = Some(filter);
+        Ok(())
+    }
+}
+
+pub struct BlockSSTReader {
+    inner: Arc<BlockSSTReaderInner>,
+}
+
+impl BlockSSTReader {
+    pub fn new(name: &Path, backend: &Backend, opt: &ReaderOptions) -> Result<Self> {
+        let file = backend.fs.open(name, opt.enable_mmap)?;
+        Self::with_file(file, opt)
+    }
+
+    pub fn with_file(file: Box<dyn ReadablePersist>, opt: &ReaderOptions) -> Result<Self> {
+        let meta = BlockSSTMetaInfo::read(file.borrow() as &dyn ReadablePersist, file.size())?;
+        if meta.magic != BLOCKSST_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "magic invalid").into());
+        }
+        log::info!("read meta {:?}", meta);
+
+        let prefix_filter = opt.prefix_filter(&meta.prefix_extractor);
+        let mut inner = BlockSSTReaderInner {
+            file,
+            meta,
+            comparator: opt.comparator.clone(),
+            block_cache: opt.block_cache.clone(),
+            index: None,
+            filter: None,
+            prefix_filter,
+            range_tombstones: Vec::new(),
+        };
+        let number = inner.meta.number;
+        let res = if opt.pin_index_and_filter {
+            inner.pin_index_and_filter()
+        } else {
+            // check the blocks and warm up the cache
+            inner.index_block().and(inner.filter()).map(|_| ())
+        }
+        .and_then(|_| inner.read_range_tombstones());
+        res.map_err(|e| e.with_sst(number))?;
+
+        Ok(Self {
+            inner: inner.into(),
+        })
+    }
+
+    pub fn meta(&self) -> BlockSSTMetaInfo {
+        self.inner.meta.clone()
+    }
+}
+
+// holds the reader, which may be evicted from table cache while iterating
+struct BlockSSTIter {
+    reader: Arc<BlockSSTReaderInner>,
+    // iterator of index block, loaded on first read
+    index: Option<BlockIter>,
+    iter: Option<BlockIter>,
+    verify: bool,
+    done: bool,
+    // errors of `next` are recorded here
+    status: ScanStatus,
+}
+
+impl BlockSSTIter {
+    fn new(reader: Arc<BlockSSTReaderInner>, verify: bool) -> Self {
+        Self {
+            reader,
+            index: None,
+            iter: None,
+            verify,
+            done: false,
+            status: ScanStatus::default(),
+        }
+    }
+
+    /// position at the first entry whose user key >= key
+    fn seek(reader: Arc<BlockSSTReaderInner>, key: &[u8], verify: bool) -> Result<Self> {
+        let number = reader.meta.number;
+        let mut iter = Self::new(reader, verify);
+        iter.seek_inner(key).map_err(|e| e.with_sst(number))?;
+        Ok(iter)
+    }
+
+    fn seek_inner(&mut self, key: &[u8]) -> Result<()> {
+        let reader = self.reader.clone();
+        let comparator = &*reader.comparator;
+        // first data block whose last key >= key
+        self.index_iter()?.seek(key, comparator)?;
+        if let Some(block) = self.next_block()? {
+            let mut iter = block.iter();
+            iter.seek(key, comparator)?;
+            self.iter = Some(iter);
+        }
+        Ok(())
+    }
+
+    fn index_iter(&mut self) -> Result<&mut BlockIter> {
+        if self.index.is_none() {
+            self.index = Some(self.reader.index_block()?.iter());
+        }
+        Ok(self.index.as_mut().unwrap())
+    }
+
+    fn next_block(&mut self) -> Result<Option<Block>> {
+        let (_, handle) = match self.index_iter()?.next() {
+            Some(entry) => entry?,
+            None => return Ok(None),
+        };
+        let handle = decode_handle(handle.data())?;
+        self.reader.read_block(handle, self.verify).map(Some)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(InternalKey, Value)>> {
+        self.next_entry_inner()
+            .map_err(|e| e.with_sst(self.reader.meta.number))
+    }
+
+    fn next_entry_inner(&mut self) -> Result<Option<(InternalKey, Value)>> {
+        if self.done {
+            return Ok(None);
+        }
+        loop {
+            if let Some(iter) = &mut self.iter {
+                if let Some(entry) = iter.next() {
+                    return entry.map(Some);
+                }
+            }
+            match self.next_block()? {
+                Some(block) => self.iter = Some(block.iter()),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Iterator for BlockSSTIter {
+    type Item = (InternalKey, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_entry() {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("error {:?} in iterator", e);
+                self.status.set(e);
+                self.done = true;
+                None
+            }
+        }
+    }
+}
+
+impl KvIterator for BlockSSTIter {
+    fn prefetch(&mut self, _n: usize) {}
+}
+
+// reads data blocks backward, entries of a block are decoded at once
+struct BlockSSTRevIter {
+    reader: Arc<BlockSSTReaderInner>,
+    // handles of data blocks not read yet
+    blocks: Vec<BlockHandle>,
+    entries: Vec<(InternalKey, Value)>,
+    verify: bool,
+    // errors of `next` are recorded here
+    status: ScanStatus,
+}
+
+impl BlockSSTRevIter {
+    fn new(reader: Arc<BlockSSTReaderInner>, verify: bool) -> Self {
+        Self {
+            reader,
+            blocks: Vec::new(),
+            entries: Vec::new(),
+            verify,
+            status: ScanStatus::default(),
+        }
+    }
+
+    /// position at the last entry whose user key <= key, or the last entry if key is none
+    fn seek_for_prev(
+        reader: Arc<BlockSSTReaderInner>,
+        key: Option<&[u8]>,
+        verify: bool,
+    ) -> Result<Self> {
+        let number = reader.meta.number;
+        let mut iter = Self::new(reader, verify);
+        iter.seek_inner(key).map_err(|e| e.with_sst(number))?;
+        Ok(iter)
+    }
+
+    fn seek_inner(&mut self, key: Option<&[u8]>) -> Result<()> {
+        for entry in self.reader.index_block()?.iter() {
+            let (last_key, handle) = entry?;
+            self.blocks.push(decode_handle(handle.data())?);
+            // versions of the key may continue in the next block
+            if let Some(key) = key {
+                if self
+                    .reader
+                    .comparator
+                    .compare(last_key.user_key_slice(), key)
+                    == Ordering::Greater
+                {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(InternalKey, Value)>> {
+        loop {
+            if let Some(entry) = self.entries.pop() {
+                return Ok(Some(entry));
+            }
+            let handle = match self.blocks.pop() {
+                Some(handle) => handle,
+                None => return Ok(None),
+            };
+            let block = self.reader.read_block(handle, self.verify)?;
+            self.entries = block.iter().collect::<Result<Vec<_>>>()?;
+        }
+    }
+}
+
+impl Iterator for BlockSSTRevIter {
+    type Item = (InternalKey, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_entry() {
+            Ok(v) => v,
+            Err(e) => {
+                let e = e.with_sst(self.reader.meta.number);
+                log::error!("error {:?} in iterator", e);
+                self.status.set(e);
+                self.blocks.clear();
+                self.entries.clear();
+                None
+            }
+        }
+    }
+}
+
+impl SSTReader for BlockSSTReader {
+    fn get<'a>(
+        &self,
+        opt: &crate::GetOption,
+        key: Bytes,
+        _lifetime: &Lifetime<'a>,
+    ) -> Result<(InternalKey, Value)> {
+        let ver = opt.snapshot().map(|v| v.sequence()).unwrap_or(u64::MAX);
+        let mut iter = BlockSSTIter::seek(self.inner.clone(), &key, opt.verify_checksum())?;
+        while let Some((internal_key, value)) = iter.next_entry()? {
+            if !self
+                .inner
+                .comparator
+                .equal(internal_key.user_key_slice(), &key)
+            {
+                break;
+            }
+            if internal_key.seq() <= ver {
+                return Ok((internal_key, value));
+            }
+        }
+        Err(StorageError::KeyNotExist)
+    }
+
+    fn multi_get<'a>(
+        &self,
+        opt: &crate::GetOption,
+        keys: &[Bytes],
+        _lifetime: &Lifetime<'a>,
+    ) -> Vec<Result<(InternalKey, Value)>> {
+        let number = self.inner.meta.number;
+        let ver = opt.snapshot().map(|v| v.sequence()).unwrap_or(u64::MAX);
+        let mut index = match self.inner.index_block() {
+            Ok(index) => index.iter(),
+            Err(e) => {
+                let e = e.with_sst(number);
+                return keys.iter().map(|_| Err(e.clone())).collect();
+            }
+        };
+        let mut block = None;
+        keys.iter()
+            .map(|key| {
+                self.inner
+                    .get_in_blocks(&mut index, &mut block, key, ver, opt.verify_checksum())
+                    .map_err(|e| e.with_sst(number))
+            })
+            .collect()
+    }
+
+    fn scan<'a>(
+        &self,
+        opt: &crate::GetOption,
+        beg: Bound<Bytes>,
+        end: Bound<Bytes>,
+        _lifetime: &Lifetime<'a>,
+        status: &ScanStatus,
+    ) -> ScanIter<'a, (InternalKey, Value)> {
+        let reader = self.inner.clone();
+        let verify = opt.verify_checksum();
+        let beg_comparator = reader.comparator.clone();
+        let end_comparator = reader.comparator.clone();
+        let mut iter = match &beg {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                BlockSSTIter::seek(reader.clone(), key, verify).unwrap_or_else(|e| {
+                    log::error!("error {:?} in seek", e);
+                    status.set(e);
+                    let mut iter = BlockSSTIter::new(reader, verify);
+                    iter.done = true;
+                    iter
+                })
+            }
+            Bound::Unbounded => BlockSSTIter::new(reader, verify),
+        };
+        iter.status = status.clone();
+        let iter = iter
+            .skip_while(move |(k, _)| match &beg {
+                Bound::Excluded(key) => beg_comparator.equal(k.user_key_slice(), key),
+                _ => false,
+            })
+            .take_while(move |(k, _)| match &end {
+                Bound::Included(key) => {
+                    end_comparator.compare(k.user_key_slice(), key) != Ordering::Greater
+                }
+                Bound::Excluded(key) => {
+                    end_comparator.compare(k.user_key_slice(), key) == Ordering::Less
+                }
+                Bound::Unbounded => true,
+            });
+
+        if let Some(snapshot) = opt.snapshot() {
+            let snapshot_ver = snapshot.sequence();
+            let iter = iter.filter(move |entry| entry.seq() <= snapshot_ver);
+            ScanIter::new(EqualFilter::new(iter, self.inner.comparator.clone()))
+        } else {
+            ScanIter::new(EqualFilter::new(iter, self.inner.comparator.clone()))
+        }
+    }
+
+    fn scan_rev<'a>(
+        &self,
+        opt: &crate::GetOption,
+        beg: Bound<Bytes>,
+        end: Bound<Bytes>,
+        _lifetime: &Lifetime<'a>,
+        status: &ScanStatus,
+    ) -> ScanIter<'a, (InternalKey, Value)> {
+        let reader = self.inner.clone();
+        let verify = opt.verify_checksum();
+        let end_comparator = reader.comparator.clone();
+        let beg_comparator = reader.comparator.clone();
+        let end_key = match &end {
+            Bound::Included(key) | Bound::Excluded(key) => Some(&key[..]),
+            Bound::Unbounded => None,
+        };
+        let mut iter = BlockSSTRevIter::seek_for_prev(reader.clone(), end_key, verify)
+            .unwrap_or_else(|e| {
+                log::error!("error {:?} in seek", e);
+                status.set(e);
+                BlockSSTRevIter::new(reader, verify)
+            });
+        iter.status = status.clone();
+        let iter = iter
+            .skip_while(move |(k, _)| match &end {
+                Bound::Included(key) => {
+                    end_comparator.compare(k.user_key_slice(), key) == Ordering::Greater
+                }
+                Bound::Excluded(key) => {
+                    end_comparator.compare(k.user_key_slice(), key) != Ordering::Less
+                }
+                Bound::Unbounded => false,
+            })
+            .take_while(move |(k, _)| match &beg {
+                Bound::Included(key) => {
+                    beg_comparator.compare(k.user_key_slice(), key) != Ordering::Less
+                }
+                Bound::Excluded(key) => {
+                    beg_comparator.compare(k.user_key_slice(), key) == Ordering::Greater
+                }
+                Bound::Unbounded => true,
+            });
+
+        if let Some(snapshot) = opt.snapshot() {
+            let snapshot_ver = snapshot.sequence();
+            let iter = iter.filter(move |entry| entry.seq() <= snapshot_ver);
+            ScanIter::new(RevEqualFilter::new(iter, self.inner.comparator.clone()))
+        } else {
+            ScanIter::new(RevEqualFilter::new(iter, self.inner.comparator.clone()))
+        }
+    }
+
+    fn has_filter(&self) -> bool {
+        self.inner.filter().is_ok_and(|filter| !filter.is_empty())
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        match self.inner.filter() {
+            // files without filter contain all keys
+            Ok(filter) => filter.is_empty() || bloom::may_contain(&filter, key),
+            Err(e) => {
+                log::warn!("read filter of sst {} fail {:?}", self.inner.meta.number, e);
+                true
+            }
+        }
+    }
+
+    fn may_contain_prefix(&self, prefix: &[u8]) -> bool {
+        !self.inner.prefix_filter || self.may_contain(prefix)
+    }
+
+    fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.inner.range_tombstones
+    }
+
+    fn format_meta(&self) -> format::FormatMeta {
+        format::FormatMeta::Block(self.meta())
+    }
+
+    fn raw_scan<'a>(
+        &self,
+        _lifetime: &Lifetime<'a>,
+        status: &ScanStatus,
+    ) -> ScanIter<'a, (InternalKey, Value)> {
+        let mut iter = BlockSSTIter::new(self.inner.clone(), true);
+        iter.status = status.clone();
+        ScanIter::new(iter).with_status(status.clone())
+    }
+}
+
+pub struct BlockSSTWriter {
+    file: Box<dyn WriteablePersist>,
+    name: PathBuf,
+    success: bool,
+    block_size: u64,
+    bloom_bits_per_key: u32,
+    compression: Compression,
+    prefix_extractor: Option<PrefixExtractorRef>,
+    comparator: ComparatorRef,
+}
+
+impl BlockSSTWriter {
+    /// data blocks are cut at `block_size` bytes before compression,
+    /// bloom filter is not written if `bloom_bits_per_key` is 0
+    pub fn new(
+        backend: &Backend,
+        name: PathBuf,
+        block_size: u64,
+        bloom_bits_per_key: u32,
+        compression: Compression,
+    ) -> Self {
+        let file = backend.fs.create(&name, None).unwrap();
+        Self {
+            file,
+            name,
+            success: false,
+            block_size,
+            bloom_bits_per_key,
+            compression,
+            prefix_extractor: None,
+            comparator: ComparatorRef::default(),
+        }
+    }
+
+    /// prefixes of keys are added to the bloom filter
+    pub fn with_prefix_extractor(mut self, prefix_extractor: Option<PrefixExtractorRef>) -> Self {
+        self.prefix_extractor = prefix_extractor;
+        self
+    }
+
+    /// the max key of the file covers ends of range tombstones by the comparator
+    pub fn with_comparator(mut self, comparator: ComparatorRef) -> Self {
+        self.comparator = comparator;
+        self
+    }
+}
+
+impl Drop for BlockSSTWriter {
+    fn drop(&mut self) {
+        if !self.success {
+            let _ = self.file.delete();
+        }
+    }
+}
+
+impl SSTWriter for BlockSSTWriter {
+    fn write<I>(&mut self, level: u32, number: u64, iter: I) -> Result<FileMetaData>
+    where
+        I: Iterator<Item = (InternalKey, Value)>,
+    {
+        self.success = false;
+        let mut w = BufWriter::new(&mut self.file);
+
+        let mut min_key = None;
+        let mut min_ver = u64::MAX;
+        let mut max_ver = u64::MIN;
+        let mut keys = 0;
+        let mut range_tombstones = Vec::new();
+        let mut bloom = (self.bloom_bits_per_key > 0)
+            .then(|| bloom::BloomFilterBuilder::new(self.bloom_bits_per_key));
+
+        let mut block = BlockBuilder::new();
+        let mut index = BlockBuilder::new();
+        let mut last_key = None;
+        let mut cur = 0;
+
+        let compression = self.compression;
+        let mut flush_block =
+            |block: &mut BlockBuilder, last_key: &InternalKey, w: &mut BufWriter<_>| {
+                let data = block.finish();
+                let size = write_block(w, &data, compression)?;
+                index.add(
+                    last_key.data(),
+                    &encode_handle(cur, size, data.len() as u64),
+                );
+                cur += size;
+                io::Result::Ok(())
+            };
+
+        for (internal_key, value) in iter {
+            if min_key.is_none() {
+                min_key = Some(internal_key.user_key());
+            }
+            min_ver = min_ver.min(internal_key.seq());
+            max_ver = max_ver.max(internal_key.seq());
+            if internal_key.key_type() == KeyType::RangeDel {
+                range_tombstones.push(RangeTombstone::from_entry(&internal_key, &value));
+                continue;
+            }
+            if let Some(bloom) = &mut bloom {
+                bloom.add(internal_key.user_key_slice());
+                if let Some(prefix) = self
+                    .prefix_extractor
+                    .as_ref()
+                    .and_then(|extractor| extractor.prefix(internal_key.user_key_slice()))
+                {
+                    bloom.add_prefix(prefix);
+                }
+            }
+            keys += 1;
+
+            block.add(internal_key.data(), value.data());
+            if block.estimated_size() as u64 >= self.block_size {
+                flush_block(&mut block, &internal_key, &mut w)?;
+            }
+            last_key = Some(internal_key);
+        }
+        if let Some(last_key) = &last_key {
+            if !block.is_empty() {
+                flush_block(&mut block, last_key, &mut w)?;
+            }
+        }
+        let max_key = range_del::max_key(
+            &self.comparator,
+            &range_tombstones,
+            last_key.map(|key| key.user_key()),
+        );
+
+        let index_offset = cur;
+        let index_size = write_block(&mut w, &index.finish(), Compression::None)?;
+
+        let filter_offset = index_offset + index_size;
+        let filter = bloom.map(|bloom| bloom.build()).unwrap_or_default();
+        let filter_size = write_block(&mut w, &filter, Compression::None)?;
+
+        let range_del_offset = filter_offset + filter_size;
+        let mut range_del_size = 0;
+        if !range_tombstones.is_empty() {
+            range_del_size = range_del::write_section(&mut w, &range_tombstones)?;
+        }
+
+        let mut meta_info = BlockSSTMetaInfo {
+            number,
+            level,
+            total_keys: keys,
+            index_offset,
+            index_size,
+            filter_offset,
+            filter_size,
+            prefix_extractor: self
+                .prefix_extractor
+                .as_ref()
+                .map(|extractor| extractor.name().to_owned())
+                .unwrap_or_default(),
+            range_del_offset,
+            range_del_size,
+            version: BLOCKSST_VERSION,
+            meta_size: 0,
+            magic: BLOCKSST_MAGIC,
+        };
+
+        meta_info.write(&mut w)?;
+        w.flush()?;
+
+        debug!("write block sst {:?} meta info {:?}", self.name, meta_info);
+        self.success = true;
+
+        Ok(FileMetaData::new(
+            number,
+            min_key.unwrap_or_default(),
+            max_key,
+            min_ver,
+            max_ver,
+            keys,
+            level,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backend::fs::memory::MemoryBasedPersistBackend;
+    use crate::GetOption;
+
+    use super::*;
+
+    fn write_sst(
+        backend: &Backend,
+        name: &Path,
+        block_size: u64,
+        compression: Compression,
+    ) -> Vec<String> {
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{:04}", i * 2)).collect();
+        let mut writer =
+            BlockSSTWriter::new(backend, name.to_path_buf(), block_size, 10, compression);
+        // every key has two versions
+        let iter = keys.iter().enumerate().flat_map(|(i, k)| {
+            let seq = i as u64 * 2 + 1;
+            [
+                (
+                    InternalKey::new(k, seq + 1, KeyType::Set),
+                    Bytes::from(format!("{}-new", k)).into(),
+                ),
+                (
+                    InternalKey::new(k, seq, KeyType::Set),
+                    Bytes::from(format!("{}-old", k)).into(),
+                ),
+            ]
+        });
+        let meta = writer.write(1, 1, iter).unwrap();
+        assert_eq!(meta.keys, 2000);
+        keys
+    }
+
+    #[test]
+    pub fn block_sst_read_write() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let name = PathBuf::from("/1.sst");
+        let keys = write_sst(&backend, &name, 256, Compression::None);
+
+        let reader = BlockSSTReader::new(&name, &backend, &ReaderOptions::default()).unwrap();
+        assert!(reader.inner.index_block().unwrap().iter().count() > 1);
+        let lifetime = Lifetime::default();
+
+        for (i, k) in keys.iter().enumerate() {
+            let (key, value) = reader
+                .get(&GetOption::default(), k.clone().into(), &lifetime)
+                .unwrap();
+            assert_eq!(key.user_key_slice(), k.as_bytes());
+            assert_eq!(value.data(), format!("{}-new", k).as_bytes());
+
+            // older version is visible to snapshot
+            let opt = GetOption::with_snapshot(i as u64 * 2 + 1);
+            let (_, value) = reader.get(&opt, k.clone().into(), &lifetime).unwrap();
+            assert_eq!(value.data(), format!("{}-old", k).as_bytes());
+
+            let missing = format!("key{:04}", i * 2 + 1);
+            assert_eq!(
+                reader
+                    .get(&GetOption::default(), missing.into(), &lifetime)
+                    .unwrap_err(),
+                StorageError::KeyNotExist
+            );
+        }
+
+        assert_eq!(
+            reader.raw_scan(&lifetime, &ScanStatus::default()).count(),
+            2000
+        );
+        let all: Vec<_> = reader
+            .scan(
+                &GetOption::default(),
+                Bound::Unbounded,
+                Bound::Unbounded,
+                &lifetime,
+                &ScanStatus::default(),
+            )
+            .map(|(k, _)| k.user_key())
+            .collect();
+        assert_eq!(all, keys);
+
+        let range: Vec<_> = reader
+            .scan(
+                &GetOption::default(),
+                Bound::Excluded("key0010".into()),
+                Bound::Included("key0020".into()),
+                &lifetime,
+                &ScanStatus::default(),
+            )
+            .map(|(k, _)| k.user_key())
+            .collect();
+        assert_eq!(
+            range,
+            ["key0012", "key0014", "key0016", "key0018", "key0020"]
+        );
+
+        let range: Vec<_> = reader
+            .scan(
+                &GetOption::default(),
+                Bound::Included("key0011".into()),
+                Bound::Excluded("key0016".into()),
+                &lifetime,
+                &ScanStatus::default(),
+            )
+            .map(|(k, _)| k.user_key())
+            .collect();
+        assert_eq!(range, ["key0012", "key0014"]);
+    }
+
+    #[test]
+    pub fn block_multi_get() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let lifetime = Lifetime::default();
+        // versions of a key are in different blocks if blocks are small
+        for (number, block_size) in [(1, 32), (2, 256)] {
+            let name = PathBuf::from(format!("/{}.sst", number));
+            let keys = write_sst(&backend, &name, block_size, Compression::None);
+            let reader = BlockSSTReader::new(&name, &backend, &ReaderOptions::default()).unwrap();
+            let blocks = reader.inner.index_block().unwrap().iter().count() as u64;
+
+            // existing and missing keys
+            let lookup: Vec<Bytes> = (0..2000)
+                .map(|i| Bytes::from(format!("key{:04}", i)))
+                .collect();
+            for opt in [GetOption::default(), GetOption::with_snapshot(1000)] {
+                let values = reader.multi_get(&opt, &lookup, &lifetime);
+                for (key, value) in lookup.iter().zip(values) {
+                    match reader.get(&opt, key.clone(), &lifetime) {
+                        Ok((_, expected)) => assert_eq!(value.unwrap().1.data(), expected.data()),
+                        Err(e) => assert_eq!(value.unwrap_err(), e),
+                    }
+                }
+            }
+
+            // every data block is read at most once by a batch
+            let cache = Arc::new(BlockCache::new(16 * 1024 * 1024));
+            let opt = ReaderOptions::default().with_block_cache(cache.clone());
+            let reader = BlockSSTReader::new(&name, &backend, &opt).unwrap();
+            let values = reader.multi_get(&GetOption::default(), &lookup, &lifetime);
+            assert_eq!(values.iter().filter(|v| v.is_ok()).count(), keys.len());
+            assert!(cache.misses() > 0 && cache.misses() <= blocks);
+            assert_eq!(cache.hits(), 0);
+        }
+    }
+
+    #[test]
+    pub fn block_cache_read() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let name = PathBuf::from("/1.sst");
+        let keys = write_sst(&backend, &name, 256, Compression::Lz4);
+        let cache = Arc::new(BlockCache::new(1024 * 1024));
+        let lifetime = Lifetime::default();
+
+        for pin in [true, false] {
+            let opt = ReaderOptions {
+                pin_index_and_filter: pin,
+                ..Default::default()
+            }
+            .with_block_cache(cache.clone());
+            let reader = BlockSSTReader::new(&name, &backend, &opt).unwrap();
+            assert_eq!(reader.inner.index.is_some(), pin);
+
+            for _ in 0..2 {
+                for k in &keys {
+                    let (_, value) = reader
+                        .get(&GetOption::default(), k.clone().into(), &lifetime)
+                        .unwrap();
+                    assert_eq!(value.data(), format!("{}-new", k).as_bytes());
+                }
+                assert_eq!(
+                    reader.raw_scan(&lifetime, &ScanStatus::default()).count(),
+                    2000
+                );
+            }
+        }
+        // data blocks are read from disk once
+        let blocks = BlockSSTReader::new(&name, &backend, &ReaderOptions::default())
+            .unwrap()
+            .inner
+            .index_block()
+            .unwrap()
+            .iter()
+            .count() as u64;
+        // index and filter block of the unpinned reader
+        assert_eq!(cache.misses(), blocks + 2);
+        assert!(cache.hits() > 0);
+        assert!(cache.usage() > 0);
+    }
+
+    #[test]
+    pub fn compressed_blocks() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let name = PathBuf::from("/0.sst");
+        write_sst(&backend, &name, 4096, Compression::None);
+        let raw_size = backend.fs.open(&name, false).unwrap().size();
+        let lifetime = Lifetime::default();
+
+        for (i, compression) in [Compression::Lz4, Compression::Snappy, Compression::Zstd]
+            .into_iter()
+            .enumerate()
+        {
+            let name = PathBuf::from(format!("/{}.sst", i + 1));
+            let keys = write_sst(&backend, &name, 4096, compression);
+            assert!(backend.fs.open(&name, false).unwrap().size() < raw_size);
+
+            let reader = BlockSSTReader::new(&name, &backend, &ReaderOptions::default()).unwrap();
+            for k in &keys {
+                let (_, value) = reader
+                    .get(&GetOption::default(), k.clone().into(), &lifetime)
+                    .unwrap();
+                assert_eq!(value.data(), format!("{}-new", k).as_bytes());
+            }
+            assert_eq!(
+                reader.raw_scan(&lifetime, &ScanStatus::default()).count(),
+                2000
+            );
+
+            // handles record the size before compression
+            for entry in reader.inner.index_block().unwrap().iter() {
+                let handle = decode_handle(entry.unwrap().1.data()).unwrap();
+                assert!(handle.raw_size >= handle.size);
+            }
+        }
+    }
+
+    #[test]
+    pub fn comparator_equal_keys() {
+        use crate::test::CaseInsensitiveComparator;
+
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let name = PathBuf::from("/1.sst");
+        let comparator = ComparatorRef::new(CaseInsensitiveComparator);
+        // small blocks split versions of a key, the bloom filter hashes bytes
+        let mut writer = BlockSSTWriter::new(&backend, name.clone(), 32, 0, Compression::None)
+            .with_comparator(comparator.clone());
+        let entries = [("A", 5), ("a", 3), ("B", 4), ("b", 2), ("c", 1)];
+        let iter = entries.iter().map(|(key, seq)| {
+            (
+                InternalKey::new(key, *seq, KeyType::Set),
+                Bytes::from(format!("{}{}", key, seq)).into(),
+            )
+        });
+        writer.write(1, 1, iter).unwrap();
+        drop(writer);
+
+        let opt = ReaderOptions {
+            comparator,
+            ..Default::default()
+        };
+        let reader = BlockSSTReader::new(&name, &backend, &opt).unwrap();
+        let lifetime = Lifetime::default();
+        let snapshot = GetOption::with_snapshot(3);
+        let (_, value) = reader.get(&snapshot, "A".into(), &lifetime).unwrap();
+        assert_eq!(value.data(), b"a3");
+        let values: Vec<_> = reader
+            .multi_get(&snapshot, &["A".into(), "b".into()], &lifetime)
+            .into_iter()
+            .map(|v| v.unwrap().1.data().to_vec())
+            .collect();
+        assert_eq!(values, [b"a3".to_vec(), b"b2".to_vec()]);
+
+        let scan = |beg: Bound<Bytes>| -> Vec<Bytes> {
+            reader
+                .scan(
+                    &GetOption::default(),
+                    beg,
+                    Bound::Unbounded,
+                    &lifetime,
+                    &ScanStatus::default(),
+                )
+                .map(|(k, _)| k.user_key())
+                .collect()
+        };
+        assert_eq!(scan(Bound::Unbounded), ["A", "B", "c"]);
+        assert_eq!(scan(Bound::Excluded("a".into())), ["B", "c"]);
+    }
+}
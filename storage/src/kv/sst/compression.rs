@@ -0,0 +1,115 @@
+use bytes::Bytes;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::err::*;
+
+/// codec of sst data blocks, recorded in each block
+#[derive(
+    IntoPrimitive,
+    TryFromPrimitive,
+    Deserialize,
+    Serialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+)]
+#[repr(u8)]
+pub enum Compression {
+    #[default]
+    None = 0,
+    Lz4 = 1,
+    Snappy = 2,
+    Zstd = 3,
+}
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// returns None if the data is not worth compressing
+pub fn compress(compression: Compression, data: &[u8]) -> Option<Vec<u8>> {
+    let compressed = match compression {
+        Compression::None => return None,
+        Compression::Lz4 => lz4_flex::compress_prepend_size(data),
+        Compression::Snappy => snap::raw::Encoder::new().compress_vec(data).ok()?,
+        Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok()?,
+    };
+    // keep raw data if less than 12.5% is saved
+    if compressed.len() < data.len() - data.len() / 8 {
+        Some(compressed)
+    } else {
+        None
+    }
+}
+
+/// `raw_size` is the size before compression recorded by the writer, the size in the
+/// compressed data is untrusted and must match it before any buffer is allocated
+pub fn decompress(compression: Compression, data: Bytes, raw_size: u64) -> Result<Bytes> {
+    let size = match compression {
+        Compression::None => Some(data.len() as u64),
+        Compression::Lz4 => data
+            .get(..4)
+            .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as u64),
+        Compression::Snappy => snap::raw::decompress_len(&data).ok().map(|len| len as u64),
+        Compression::Zstd => zstd::zstd_safe::get_frame_content_size(&data)
+            .ok()
+            .flatten(),
+    }
+    .ok_or(StorageError::DataCorrupt)?;
+    if size != raw_size {
+        return Err(StorageError::DataCorrupt);
+    }
+
+    let decompressed = match compression {
+        Compression::None => return Ok(data),
+        Compression::Lz4 => {
+            lz4_flex::decompress_size_prepended(&data).map_err(|_| StorageError::DataCorrupt)?
+        }
+        Compression::Snappy => snap::raw::Decoder::new()
+            .decompress_vec(&data)
+            .map_err(|_| StorageError::DataCorrupt)?,
+        Compression::Zstd => {
+            zstd::bulk::decompress(&data, size as usize).map_err(|_| StorageError::DataCorrupt)?
+        }
+    };
+    if decompressed.len() as u64 != size {
+        return Err(StorageError::DataCorrupt);
+    }
+    Ok(decompressed.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn compress_block() {
+        let data: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+        for compression in [Compression::Lz4, Compression::Snappy, Compression::Zstd] {
+            let compressed = compress(compression, &data).unwrap();
+            assert!(compressed.len() < data.len());
+            let raw = decompress(compression, compressed.clone().into(), 4096).unwrap();
+            assert_eq!(&raw[..], &data[..]);
+            // the size in compressed data doesn't match the recorded one
+            let err = decompress(compression, compressed.into(), 4095).unwrap_err();
+            assert_eq!(err, StorageError::DataCorrupt);
+        }
+        assert!(compress(Compression::None, &data).is_none());
+        let raw = decompress(Compression::None, data.clone().into(), 4096).unwrap();
+        assert_eq!(&raw[..], &data[..]);
+        assert!(decompress(Compression::None, data.clone().into(), 4095).is_err());
+
+        // a zstd frame claims a huge content size
+        let mut frame = zstd::bulk::compress(&data, ZSTD_LEVEL).unwrap();
+        let huge = zstd::bulk::compress(&vec![0; 1 << 20], ZSTD_LEVEL).unwrap();
+        assert!(decompress(Compression::Zstd, huge.into(), 4096).is_err());
+        frame.truncate(frame.len() / 2);
+        assert!(decompress(Compression::Zstd, frame.into(), 4096).is_err());
+
+        // random data is kept raw
+        let data: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+        assert!(compress(Compression::Zstd, &data).is_none());
+    }
+}
@@ -1,4 +1,424 @@
+use std::borrow::Borrow;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use byteorder::{WriteBytesExt, LE};
+use integer_encoding::{VarIntReader, VarIntWriter};
+use positioned_io::ReadBytesAtExt;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::backend::fs::ReadablePersist;
+use crate::backend::Backend;
+use crate::cache::BlockCache;
+use crate::comparator::ComparatorRef;
+use crate::err::*;
+use crate::key::{InternalKey, Value};
+use crate::prefix::PrefixExtractorRef;
+use crate::util::crc::{crc_mask, crc_unmask};
+use crate::Config;
+
+use super::block_sst::{BlockSSTMetaInfo, BlockSSTReader, BlockSSTWriter, BLOCKSST_MAGIC};
+use super::raw_sst::{RawSSTMetaInfo, RawSSTReader, RawSSTWriter, RAWSST_MAGIC};
+use super::{FileMetaData, SSTReader, SSTWriter};
+
+/// table format of new sst files, files of any format can be read
 #[repr(C)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum Format {
+    /// a flat offset per key
     RawSST = 1,
+    /// prefix compressed data blocks with an index block
+    #[default]
+    BlockSST = 2,
+}
+
+/// footer meta of an opened file
+#[derive(Debug, Clone)]
+pub enum FormatMeta {
+    Raw(RawSSTMetaInfo),
+    Block(BlockSSTMetaInfo),
+}
+
+pub enum FormatWriter {
+    Raw(RawSSTWriter),
+    Block(BlockSSTWriter),
+}
+
+impl FormatWriter {
+    pub fn new(config: &Config, backend: &Backend, name: PathBuf, level: u32) -> Self {
+        match config.sst_format {
+            Format::RawSST => Self::Raw(
+                RawSSTWriter::new(backend, name, config.bloom_bits_per_key)
+                    .with_prefix_extractor(config.prefix_extractor.clone())
+                    .with_comparator(config.comparator.clone()),
+            ),
+            Format::BlockSST => Self::Block(
+                BlockSSTWriter::new(
+                    backend,
+                    name,
+                    config.block_size,
+                    config.bloom_bits_per_key,
+                    config.compression(level),
+                )
+                .with_prefix_extractor(config.prefix_extractor.clone())
+                .with_comparator(config.comparator.clone()),
+            ),
+        }
+    }
+}
+
+impl SSTWriter for FormatWriter {
+    fn write<I>(&mut self, level: u32, number: u64, iter: I) -> Result<FileMetaData>
+    where
+        I: Iterator<Item = (InternalKey, Value)>,
+    {
+        match self {
+            Self::Raw(w) => w.write(level, number, iter),
+            Self::Block(w) => w.write(level, number, iter),
+        }
+    }
+}
+
+/// name of the prefix extractor in sst meta, empty if there is no extractor
+pub(crate) fn write_name<W: Write>(mut w: W, name: &str) -> io::Result<()> {
+    w.write_varint(name.len() as u64)?;
+    w.write_all(name.as_bytes())
+}
+
+pub(crate) fn read_name<R: io::Read>(mut r: R) -> io::Result<String> {
+    let len: u64 = r.read_varint()?;
+    let mut name = vec![0; len as usize];
+    r.read_exact(&mut name)?;
+    String::from_utf8(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// footer of all formats
+///
+/// meta | crc of meta, if checksum | version u32 | footer size u32 | magic u32
+pub(crate) struct Footer {
+    pub meta: Vec<u8>,
+    pub version: u32,
+    pub size: u32,
+    pub magic: u32,
+}
+
+impl Footer {
+    /// returns the footer size
+    pub fn write<W: Write>(
+        mut w: W,
+        meta: &[u8],
+        version: u32,
+        magic: u32,
+        checksum: bool,
+    ) -> io::Result<u32> {
+        w.write_all(meta)?;
+        let mut size = meta.len() as u32 + 12;
+        if checksum {
+            w.write_u32::<LE>(crc_mask(crc32fast::hash(meta)))?;
+            size += 4;
+        }
+        w.write_u32::<LE>(version)?;
+        w.write_u32::<LE>(size)?;
+        w.write_u32::<LE>(magic)?;
+        Ok(size)
+    }
+
+    /// `checksum` tells whether the footer of the version has checksum
+    pub fn read(r: &dyn ReadablePersist, size: u64, checksum: fn(u32) -> bool) -> io::Result<Self> {
+        // read tail first
+        if size < 12 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid header"));
+        }
+        let offset = size - 12;
+        let version = r.read_u32_at::<LE>(offset)?;
+        let footer_size = r.read_u32_at::<LE>(offset + 4)?;
+        let magic = r.read_u32_at::<LE>(offset + 8)?;
+
+        let tail_size = if checksum(version) { 16 } else { 12 };
+        if size < footer_size as u64 || footer_size < tail_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid header meta size",
+            ));
+        }
+
+        let mut meta = vec![0; (footer_size - tail_size) as usize];
+        r.read_exact_at(size - footer_size as u64, &mut meta)?;
+        if checksum(version) {
+            let crc = crc_unmask(r.read_u32_at::<LE>(size - 16)?);
+            if crc != crc32fast::hash(&meta) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "crc not match"));
+            }
+        }
+
+        Ok(Self {
+            meta,
+            version,
+            size: footer_size,
+            magic,
+        })
+    }
+}
+
+/// options of opened sst readers
+#[derive(Clone)]
+pub struct ReaderOptions {
+    pub enable_mmap: bool,
+    pub comparator: ComparatorRef,
+    /// blocks are read through the cache if set, raw sst has no blocks to cache
+    pub block_cache: Option<Arc<BlockCache>>,
+    pub pin_index_and_filter: bool,
+    /// prefix filters of files written by the same extractor are used
+    pub prefix_extractor: Option<PrefixExtractorRef>,
+}
+
+impl Default for ReaderOptions {
+    fn default() -> Self {
+        Self {
+            enable_mmap: false,
+            comparator: ComparatorRef::default(),
+            block_cache: None,
+            pin_index_and_filter: true,
+            prefix_extractor: None,
+        }
+    }
+}
+
+impl ReaderOptions {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            enable_mmap: config.enable_mmap,
+            comparator: config.comparator.clone(),
+            block_cache: None,
+            pin_index_and_filter: config.pin_index_and_filter,
+            prefix_extractor: config.prefix_extractor.clone(),
+        }
+    }
+
+    /// the filter of a file written with the prefix extractor contains prefixes of its keys
+    pub fn prefix_filter(&self, prefix_extractor: &str) -> bool {
+        match &self.prefix_extractor {
+            Some(extractor) => !prefix_extractor.is_empty() && extractor.name() == prefix_extractor,
+            None => false,
+        }
+    }
+
+    pub fn with_block_cache(mut self, block_cache: Arc<BlockCache>) -> Self {
+        self.block_cache = Some(block_cache);
+        self
+    }
+}
+
+/// open sst file, the format is picked by the magic of footer
+pub fn open_reader(
+    name: &Path,
+    backend: &Backend,
+    opt: &ReaderOptions,
+) -> Result<Arc<dyn SSTReader + Send + Sync>> {
+    let file = backend.fs.open(name, opt.enable_mmap)?;
+    let size = file.size();
+    if size < 4 {
+        return Err(StorageError::DataCorrupt);
+    }
+    let magic = (file.borrow() as &dyn ReadablePersist).read_u32_at::<LE>(size - 4)?;
+
+    Ok(match magic {
+        RAWSST_MAGIC => Arc::new(RawSSTReader::with_file(file, opt)?),
+        BLOCKSST_MAGIC => Arc::new(BlockSSTReader::with_file(file, opt)?),
+        _ => return Err(StorageError::DataCorrupt),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use crate::backend::fs::memory::MemoryBasedPersistBackend;
+    use crate::iterator::{KvIteratorItem, ScanStatus};
+    use crate::key::KeyType;
+    use crate::kv::sst::compression::Compression;
+    use crate::kv::superversion::Lifetime;
+    use crate::GetOption;
+
+    use super::*;
+
+    #[test]
+    pub fn open_reader_by_magic() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        for (number, format) in [(1, Format::RawSST), (2, Format::BlockSST)] {
+            let config = Config {
+                sst_format: format,
+                ..Default::default()
+            };
+            let name = PathBuf::from(format!("/{}.sst", number));
+            let mut writer = FormatWriter::new(&config, &backend, name.clone(), 0);
+            let iter = ["a", "b", "c"].into_iter().map(|k| {
+                (
+                    InternalKey::new(k, number, KeyType::Set),
+                    Bytes::from(k).into(),
+                )
+            });
+            writer.write(0, number, iter).unwrap();
+            drop(writer);
+
+            let reader = open_reader(&name, &backend, &ReaderOptions::default()).unwrap();
+            let lifetime = Lifetime::default();
+            let (key, value) = reader
+                .get(&GetOption::default(), "b".into(), &lifetime)
+                .unwrap();
+            assert_eq!(key.seq(), number);
+            assert_eq!(value.data(), b"b");
+            assert_eq!(
+                reader.raw_scan(&lifetime, &ScanStatus::default()).count(),
+                3
+            );
+        }
+    }
+
+    #[test]
+    pub fn prefix_filter() {
+        use crate::prefix::{DelimiterPrefix, FixedPrefix};
+
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let extractor = PrefixExtractorRef::new(DelimiterPrefix::new(b'/'));
+        for (number, format) in [(1, Format::RawSST), (2, Format::BlockSST)] {
+            let config = Config {
+                sst_format: format,
+                prefix_extractor: Some(extractor.clone()),
+                ..Default::default()
+            };
+            let name = PathBuf::from(format!("/{}.sst", number));
+            let mut writer = FormatWriter::new(&config, &backend, name.clone(), 0);
+            let iter = (0..1000).map(|i| {
+                let key = format!("tenant{}/key{:04}", i % 100 * 2, i);
+                (
+                    InternalKey::new(key.clone(), number, KeyType::Set),
+                    Bytes::from(key).into(),
+                )
+            });
+            let mut keys: Vec<_> = iter.collect();
+            keys.sort_by_key(|(key, _)| key.user_key());
+            writer.write(0, number, keys.into_iter()).unwrap();
+            drop(writer);
+
+            let reader = open_reader(&name, &backend, &ReaderOptions::new(&config)).unwrap();
+            for i in 0..100 {
+                assert!(reader.may_contain_prefix(format!("tenant{}/", i * 2).as_bytes()));
+            }
+            let absent = (0..100)
+                .filter(|i| !reader.may_contain_prefix(format!("tenant{}/", i * 2 + 1).as_bytes()))
+                .count();
+            assert!(absent > 90, "{}", absent);
+
+            // filters of another extractor are not used
+            for opt in [
+                ReaderOptions::default(),
+                ReaderOptions {
+                    prefix_extractor: Some(PrefixExtractorRef::new(FixedPrefix::new(7))),
+                    ..Default::default()
+                },
+            ] {
+                let reader = open_reader(&name, &backend, &opt).unwrap();
+                assert!(reader.may_contain_prefix(b"tenant1/"));
+            }
+        }
+    }
+
+    #[test]
+    pub fn range_tombstone_section() {
+        use crate::range_del::RangeTombstone;
+
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let lifetime = Lifetime::default();
+        for (number, format) in [(1, Format::RawSST), (2, Format::BlockSST)] {
+            let config = Config {
+                sst_format: format,
+                ..Default::default()
+            };
+            let name = PathBuf::from(format!("/{}.sst", number));
+            let mut writer = FormatWriter::new(&config, &backend, name.clone(), 0);
+            let tombstone = RangeTombstone::new("b", "z", 10);
+            let iter = [
+                (
+                    InternalKey::new("a", 1, KeyType::Set),
+                    Bytes::from("a").into(),
+                ),
+                tombstone.to_entry(),
+                (
+                    InternalKey::new("c", 2, KeyType::Set),
+                    Bytes::from("c").into(),
+                ),
+            ];
+            let meta = writer.write(0, number, iter.into_iter()).unwrap();
+            drop(writer);
+            // the tombstone is not a key, the max key is its end
+            assert_eq!(meta.keys, 2);
+            assert_eq!(meta.min, Bytes::from("a"));
+            assert_eq!(meta.max, Bytes::from("z"));
+            assert_eq!((meta.min_ver, meta.max_ver), (1, 10));
+
+            let reader = open_reader(&name, &backend, &ReaderOptions::default()).unwrap();
+            assert_eq!(reader.range_tombstones(), &[tombstone]);
+            assert_eq!(
+                reader.raw_scan(&lifetime, &ScanStatus::default()).count(),
+                2
+            );
+        }
+    }
+
+    fn flip_byte(backend: &Backend, name: &Path, offset: u64) {
+        let file = backend.fs.open(name, false).unwrap();
+        let mut data = vec![0; file.size() as usize];
+        file.read_exact_at(0, &mut data).unwrap();
+        data[offset as usize] ^= 0x1;
+        let mut file = backend.fs.create(name, None).unwrap();
+        file.write_all(&data).unwrap();
+    }
+
+    #[test]
+    pub fn detect_corruption() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let lifetime = Lifetime::default();
+        // offset of the first value
+        for (number, format, offset) in [(1, Format::RawSST, 11), (2, Format::BlockSST, 12)] {
+            let config = Config {
+                sst_format: format,
+                compression_per_level: vec![Compression::None],
+                ..Default::default()
+            };
+            let name = PathBuf::from(format!("/{}.sst", number));
+            let mut writer = FormatWriter::new(&config, &backend, name.clone(), 0);
+            let iter = ["a", "b", "c"].into_iter().map(|k| {
+                (
+                    InternalKey::new(k, number, KeyType::Set),
+                    Bytes::from(k).into(),
+                )
+            });
+            writer.write(0, number, iter).unwrap();
+            drop(writer);
+
+            flip_byte(&backend, &name, offset);
+            let reader = open_reader(&name, &backend, &ReaderOptions::default()).unwrap();
+            assert_eq!(
+                reader
+                    .get(&GetOption::default(), "a".into(), &lifetime)
+                    .unwrap_err(),
+                StorageError::SSTDataCorrupt(number)
+            );
+            // compaction input stops at corrupted data and records the error
+            let status = ScanStatus::default();
+            assert!(reader.raw_scan(&lifetime, &status).count() < 3);
+            assert_eq!(status.get(), Err(StorageError::SSTDataCorrupt(number)));
+
+            let opt = GetOption::default().set_skip_checksum(true);
+            let (_, value) = reader.get(&opt, "a".into(), &lifetime).unwrap();
+            assert_ne!(value.data(), b"a");
+
+            // corrupted footer
+            let size = backend.fs.open(&name, false).unwrap().size();
+            flip_byte(&backend, &name, size - 17);
+            assert!(open_reader(&name, &backend, &ReaderOptions::default()).is_err());
+        }
+    }
 }
@@ -1,10 +1,13 @@
+use std::cmp::Ordering;
 use std::ops::{Bound, RangeBounds};
 
 use bytes::Bytes;
 
 use crate::backend::Backend;
+use crate::comparator::ComparatorRef;
 use crate::err::*;
-use crate::iterator::LevelIter;
+use crate::iterator::{KvIteratorItem, LevelIter, ScanStatus};
+use crate::range_del::{covering_seq, deleted_entry, RangeTombstone};
 use crate::{
     cache::Cache,
     iterator::{MergedIter, ScanIter},
@@ -14,10 +17,12 @@ use crate::{
 };
 
 use super::{
-    manifest::{FileMetaData, VersionRef, MAX_LEVEL},
+    manifest::{FileMetaData, FileStatistics, VersionRef, MAX_LEVEL},
     superversion::Lifetime,
 };
 
+pub mod block_sst;
+pub mod compression;
 pub mod format;
 pub mod raw_sst;
 
@@ -34,14 +39,57 @@ pub trait SSTReader {
         key: Bytes,
         lifetime: &Lifetime<'a>,
     ) -> Result<(InternalKey, Value)>;
+    /// get keys sorted by the comparator, results are in the order of keys
+    fn multi_get<'a>(
+        &self,
+        opt: &crate::GetOption,
+        keys: &[Bytes],
+        lifetime: &Lifetime<'a>,
+    ) -> Vec<Result<(InternalKey, Value)>> {
+        keys.iter()
+            .map(|key| self.get(opt, key.clone(), lifetime))
+            .collect()
+    }
+    /// the scan stops at the first error, which is recorded in `status`
     fn scan<'a>(
         &self,
         opt: &crate::GetOption,
         beg: Bound<bytes::Bytes>,
         end: Bound<bytes::Bytes>,
         lifetime: &Lifetime<'a>,
+        status: &ScanStatus,
+    ) -> ScanIter<'a, (InternalKey, Value)>;
+    /// scan in reverse order of user keys
+    fn scan_rev<'a>(
+        &self,
+        opt: &crate::GetOption,
+        beg: Bound<bytes::Bytes>,
+        end: Bound<bytes::Bytes>,
+        lifetime: &Lifetime<'a>,
+        status: &ScanStatus,
+    ) -> ScanIter<'a, (InternalKey, Value)>;
+    /// true if `may_contain` consults a bloom filter
+    fn has_filter(&self) -> bool {
+        false
+    }
+    /// false if the user key is absolutely not in the table
+    fn may_contain(&self, _key: &[u8]) -> bool {
+        true
+    }
+    /// false if no user key of the prefix extractor's prefix is in the table
+    fn may_contain_prefix(&self, _prefix: &[u8]) -> bool {
+        true
+    }
+    /// range tombstones of the file, they are not returned by scans
+    fn range_tombstones(&self) -> &[RangeTombstone];
+    /// meta in the footer of the file
+    fn format_meta(&self) -> format::FormatMeta;
+    /// scan all versions of all keys, the first error is recorded in `status`
+    fn raw_scan<'a>(
+        &self,
+        lifetime: &Lifetime<'a>,
+        status: &ScanStatus,
     ) -> ScanIter<'a, (InternalKey, Value)>;
-    fn raw_scan<'a>(&self, lifetime: &Lifetime<'a>) -> ScanIter<'a, (InternalKey, Value)>;
 }
 
 pub struct SnapshotTable<'a> {
@@ -70,6 +118,7 @@ impl<'a> SnapshotTable<'a> {
         backend: &Backend,
         lifetime: &Lifetime<'b>,
     ) -> Result<(InternalKey, Value)> {
+        let ver = opt.snapshot().map(|v| v.sequence()).unwrap_or(u64::MAX);
         for level in 0..MAX_LEVEL {
             let runs = self.version.level_n(level);
             for run in runs.iter().rev() {
@@ -78,17 +127,29 @@ impl<'a> SnapshotTable<'a> {
                     if fs.meta().min_ver > self.snapshot.sequence() {
                         continue;
                     }
-                    let sst_reader = self.cache.get_opened_sst(config, fs.meta().number, backend);
+                    let sst_reader =
+                        self.cache
+                            .get_opened_sst(config, fs.meta().number, backend)?;
+                    // a newer range tombstone of the file deletes the key
+                    let deleted =
+                        covering_seq(sst_reader.range_tombstones(), &config.comparator, &key, ver);
+                    // the file is read anyway if a tombstone covers the key
+                    let filtered = deleted == 0 && sst_reader.has_filter();
+                    if filtered && !sst_reader.may_contain(&key) {
+                        fs.add_bloom_useful();
+                        continue;
+                    }
 
                     match sst_reader.get(opt, key.clone(), lifetime) {
-                        Ok(val) => return Ok(val),
-                        Err(e) => {
-                            if StorageError::KeyNotExist == e {
-                                // search next run
-                                continue;
-                            } else {
-                                return Err(e);
+                        Ok(val) if val.0.seq() > deleted => return Ok(val),
+                        Err(e) if e != StorageError::KeyNotExist => return Err(e),
+                        _ if deleted > 0 => return Ok(deleted_entry(&key, deleted)),
+                        _ => {
+                            if filtered {
+                                fs.add_bloom_false_positive();
                             }
+                            // search next run
+                            continue;
                         }
                     }
                 }
@@ -99,6 +160,102 @@ impl<'a> SnapshotTable<'a> {
         Err(StorageError::KeyNotExist)
     }
 
+    /// get keys sorted by the comparator, keys in the same file are read by one lookup of the file
+    pub fn multi_get<'b>(
+        &self,
+        opt: &crate::GetOption,
+        config: &Config,
+        keys: &[Bytes],
+        backend: &Backend,
+        lifetime: &Lifetime<'b>,
+    ) -> Vec<Result<(InternalKey, Value)>> {
+        let ver = opt.snapshot().map(|v| v.sequence()).unwrap_or(u64::MAX);
+        let mut results: Vec<Option<Result<(InternalKey, Value)>>> =
+            keys.iter().map(|_| None).collect();
+        for level in 0..MAX_LEVEL {
+            let runs = self.version.level_n(level);
+            for run in runs.iter().rev() {
+                // pending keys grouped by file, keys of a file are adjacent
+                let mut groups: Vec<(&FileStatistics, Vec<usize>)> = Vec::new();
+                for (idx, key) in keys.iter().enumerate() {
+                    if results[idx].is_some() {
+                        continue;
+                    }
+                    let fs = match run.binary_find_file(key) {
+                        Some(fs) => fs,
+                        None => continue,
+                    };
+                    match groups.last_mut() {
+                        Some((last, idxs)) if last.meta().number == fs.meta().number => {
+                            idxs.push(idx)
+                        }
+                        _ => groups.push((fs, vec![idx])),
+                    }
+                }
+
+                for (fs, idxs) in groups {
+                    if fs.meta().min_ver > self.snapshot.sequence() {
+                        continue;
+                    }
+                    let sst_reader =
+                        match self.cache.get_opened_sst(config, fs.meta().number, backend) {
+                            Ok(r) => r,
+                            Err(e) => {
+                                for idx in idxs {
+                                    results[idx] = Some(Err(e.clone()));
+                                }
+                                continue;
+                            }
+                        };
+                    let has_filter = sst_reader.has_filter();
+                    // sequences of range tombstones deleting the keys
+                    let idxs: Vec<(usize, u64)> = idxs
+                        .into_iter()
+                        .map(|idx| {
+                            let tombstones = sst_reader.range_tombstones();
+                            (
+                                idx,
+                                covering_seq(tombstones, &config.comparator, &keys[idx], ver),
+                            )
+                        })
+                        .filter(|(idx, deleted)| {
+                            let contain =
+                                *deleted > 0 || !has_filter || sst_reader.may_contain(&keys[*idx]);
+                            if !contain {
+                                fs.add_bloom_useful();
+                            }
+                            contain
+                        })
+                        .collect();
+                    let file_keys: Vec<Bytes> =
+                        idxs.iter().map(|(idx, _)| keys[*idx].clone()).collect();
+
+                    for ((idx, deleted), res) in idxs
+                        .into_iter()
+                        .zip(sst_reader.multi_get(opt, &file_keys, lifetime))
+                    {
+                        match res {
+                            Ok(val) if val.0.seq() > deleted => results[idx] = Some(Ok(val)),
+                            Err(e) if e != StorageError::KeyNotExist => results[idx] = Some(Err(e)),
+                            _ if deleted > 0 => {
+                                results[idx] = Some(Ok(deleted_entry(&keys[idx], deleted)))
+                            }
+                            // search next run
+                            _ if deleted == 0 && has_filter => fs.add_bloom_false_positive(),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|res| res.unwrap_or(Err(StorageError::KeyNotExist)))
+            .collect()
+    }
+
+    /// errors of files are recorded in `status`
     pub fn scan<'b, R: RangeBounds<bytes::Bytes> + Clone>(
         &self,
         opt: &crate::GetOption,
@@ -106,6 +263,92 @@ impl<'a> SnapshotTable<'a> {
         range: R,
         backend: &Backend,
         lifetime: &Lifetime<'b>,
+        status: &ScanStatus,
+    ) -> ScanIter<'b, (InternalKey, Value)> {
+        self.scan_inner(opt, config, range, backend, lifetime, false, None, status)
+    }
+
+    /// files are skipped if their filters do not contain the prefix,
+    /// `prefix` must be a whole prefix of the prefix extractor
+    #[allow(clippy::too_many_arguments)]
+    pub fn scan_prefix<'b, R: RangeBounds<bytes::Bytes> + Clone>(
+        &self,
+        opt: &crate::GetOption,
+        config: &Config,
+        range: R,
+        prefix: &[u8],
+        backend: &Backend,
+        lifetime: &Lifetime<'b>,
+        status: &ScanStatus,
+    ) -> ScanIter<'b, (InternalKey, Value)> {
+        self.scan_inner(
+            opt,
+            config,
+            range,
+            backend,
+            lifetime,
+            false,
+            Some(prefix),
+            status,
+        )
+    }
+
+    /// scan in reverse order of user keys
+    pub fn scan_rev<'b, R: RangeBounds<bytes::Bytes> + Clone>(
+        &self,
+        opt: &crate::GetOption,
+        config: &Config,
+        range: R,
+        backend: &Backend,
+        lifetime: &Lifetime<'b>,
+        status: &ScanStatus,
+    ) -> ScanIter<'b, (InternalKey, Value)> {
+        self.scan_inner(opt, config, range, backend, lifetime, true, None, status)
+    }
+
+    /// range tombstones of files overlapping with the range
+    pub fn range_tombstones<R: RangeBounds<bytes::Bytes>>(
+        &self,
+        config: &Config,
+        range: R,
+        backend: &Backend,
+        status: &ScanStatus,
+    ) -> Vec<RangeTombstone> {
+        let comparator = &config.comparator;
+        let mut tombstones = Vec::new();
+        for level in 0..MAX_LEVEL {
+            for run in self.version.level_n(level).iter() {
+                for fs in run.files() {
+                    let meta = fs.meta();
+                    if meta.min_ver > self.snapshot.sequence()
+                        || !overlaps(comparator, &range, &meta.min, &meta.max)
+                    {
+                        continue;
+                    }
+                    match self.cache.get_opened_sst(config, meta.number, backend) {
+                        Ok(r) => tombstones.extend_from_slice(r.range_tombstones()),
+                        Err(e) => {
+                            log::error!("scan open sst {} fail {:?}", meta.number, e);
+                            status.set(e.with_sst(meta.number));
+                        }
+                    }
+                }
+            }
+        }
+        tombstones
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn scan_inner<'b, R: RangeBounds<bytes::Bytes> + Clone>(
+        &self,
+        opt: &crate::GetOption,
+        config: &Config,
+        range: R,
+        backend: &Backend,
+        lifetime: &Lifetime<'b>,
+        reverse: bool,
+        prefix: Option<&[u8]>,
+        status: &ScanStatus,
     ) -> ScanIter<'b, (InternalKey, Value)> {
         let mut iters = Vec::new();
         for level in 0..MAX_LEVEL {
@@ -113,26 +356,71 @@ impl<'a> SnapshotTable<'a> {
             for run in runs.iter().rev() {
                 // find key
                 let mut file_iters = Vec::new();
-                for fs in run.files() {
+                let files: Box<dyn Iterator<Item = _>> = if reverse {
+                    Box::new(run.files().iter().rev())
+                } else {
+                    Box::new(run.files().iter())
+                };
+                for fs in files {
                     if fs.meta().min_ver > self.snapshot.sequence() {
                         continue;
                     }
-                    let sst_reader = self.cache.get_opened_sst(config, fs.meta().number, backend);
-
-                    file_iters.push(sst_reader.scan(
-                        opt,
-                        range.start_bound().cloned(),
-                        range.end_bound().cloned(),
-                        lifetime,
-                    ));
+                    let sst_reader =
+                        match self.cache.get_opened_sst(config, fs.meta().number, backend) {
+                            Ok(r) => r,
+                            Err(e) => {
+                                log::error!("scan open sst {} fail {:?}", fs.meta().number, e);
+                                status.set(e.with_sst(fs.meta().number));
+                                continue;
+                            }
+                        };
+                    if let Some(prefix) = prefix {
+                        if !sst_reader.may_contain_prefix(prefix) {
+                            fs.add_bloom_useful();
+                            continue;
+                        }
+                    }
+
+                    let beg = range.start_bound().cloned();
+                    let end = range.end_bound().cloned();
+                    file_iters.push(if reverse {
+                        sst_reader.scan_rev(opt, beg, end, lifetime, status)
+                    } else {
+                        sst_reader.scan(opt, beg, end, lifetime, status)
+                    });
                 }
 
-                if file_iters.len() > 0 {
+                if !file_iters.is_empty() {
                     iters.push(ScanIter::new(LevelIter::new(file_iters)));
                 }
             }
         }
 
-        ScanIter::new(MergedIter::new(iters))
+        let comparator = config.comparator.clone();
+        if reverse {
+            ScanIter::new(MergedIter::new_reverse(iters, comparator))
+        } else {
+            ScanIter::new(MergedIter::new(iters, comparator))
+        }
     }
 }
+
+/// keys in [min, max] overlap with the range
+fn overlaps<R: RangeBounds<bytes::Bytes>>(
+    comparator: &ComparatorRef,
+    range: &R,
+    min: &[u8],
+    max: &[u8],
+) -> bool {
+    let after_start = match range.start_bound() {
+        Bound::Included(start) => comparator.compare(max, start) != Ordering::Less,
+        Bound::Excluded(start) => comparator.compare(max, start) == Ordering::Greater,
+        Bound::Unbounded => true,
+    };
+    let before_end = match range.end_bound() {
+        Bound::Included(end) => comparator.compare(min, end) != Ordering::Greater,
+        Bound::Excluded(end) => comparator.compare(min, end) == Ordering::Less,
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
@@ -1,74 +1,85 @@
-use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt};
-use bytes::{Buf, Bytes, BytesMut};
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use bytes::{Bytes, BytesMut};
 use integer_encoding::{VarIntReader, VarIntWriter};
 use log::debug;
 use positioned_io::ReadBytesAtExt;
 
 use crate::backend::fs::{ExtReader, ReadablePersist, WriteablePersist};
 use crate::backend::Backend;
-use crate::iterator::{EqualFilter, KvIteratorItem, ScanIter};
-use crate::key::{InternalKey, Value};
+use crate::comparator::ComparatorRef;
+use crate::err::*;
+use crate::iterator::{EqualFilter, KvIteratorItem, RevEqualFilter, ScanIter, ScanStatus};
+use crate::key::{InternalKey, KeyType, Value};
 use crate::kv::superversion::Lifetime;
-use crate::{err::*, ConfigRef};
-use crate::{Config, KvIterator};
+use crate::prefix::PrefixExtractorRef;
+use crate::range_del::{self, RangeTombstone};
+use crate::util::bloom;
+use crate::util::crc::crc_mask;
+use crate::KvIterator;
 use byteorder::LE;
 use std::borrow::Borrow;
-use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use super::format::{self, Footer, ReaderOptions};
 use super::{FileMetaData, SSTReader, SSTWriter};
 
-const RAWSST_MAGIC: u32 = 0xA18C0001;
-
-struct RawSSTIter<'a> {
-    reader: &'a RawSSTReaderInner,
+pub(crate) const RAWSST_MAGIC: u32 = 0xA18C0001;
+// version 1: bloom filter is written after key offsets
+// version 2: entries and footer end with crc
+// version 3: prefix extractor name, the filter contains prefixes of keys
+// version 4: range tombstone section
+const RAWSST_VERSION: u32 = 4;
+
+// holds the reader, which may be evicted from table cache while iterating
+// entries in [beg, end) are not read yet
+struct RawSSTIter {
+    reader: Arc<RawSSTReaderInner>,
     beg: u64,
     end: u64,
-    idx: u64,
+    verify: bool,
+    status: ScanStatus,
+}
+
+impl RawSSTIter {
+    fn read(&mut self, idx: u64) -> Option<(InternalKey, Value)> {
+        match self.reader.index(idx, self.verify) {
+            Ok(v) => Some(v.into()),
+            Err(e) => {
+                let e = e.with_sst(self.reader.seq);
+                log::error!("error {:?} in iterator", e);
+                self.status.set(e);
+                self.end = self.beg;
+                None
+            }
+        }
+    }
 }
 
-impl<'a> Iterator for RawSSTIter<'a> {
+impl Iterator for RawSSTIter {
     type Item = (InternalKey, Value);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.idx >= self.end {
+        if self.beg >= self.end {
             return None;
         }
-        let idx = self.idx;
-        self.idx += 1;
-        let res = match self.reader.index(idx) {
-            Ok(v) => v.into(),
-            Err(e) => {
-                log::error!("error {:?} in iterator", e);
-                return None;
-            }
-        };
-
-        Some(res)
+        self.beg += 1;
+        self.read(self.beg - 1)
     }
 }
 
-impl<'a> DoubleEndedIterator for RawSSTIter<'a> {
+impl DoubleEndedIterator for RawSSTIter {
     fn next_back(&mut self) -> Option<Self::Item> {
-        if self.idx <= self.beg {
+        if self.beg >= self.end {
             return None;
         }
-        let idx = self.idx;
-        self.idx -= 1;
-        let res = match self.reader.index(idx) {
-            Ok(v) => v.into(),
-            Err(e) => {
-                log::error!("error {:?} in iterator", e);
-                return None;
-            }
-        };
-
-        Some(res)
+        self.end -= 1;
+        self.read(self.end)
     }
 }
 
-impl<'a> KvIterator for RawSSTIter<'a> {
+impl KvIterator for RawSSTIter {
     fn prefetch(&mut self, _n: usize) {}
 }
 
@@ -80,6 +91,11 @@ struct RawSSTReaderInner {
     size: u64,
     meta: RawSSTMetaInfo,
     seq: u64,
+    filter: Bytes,
+    // prefixes of keys are in the filter
+    prefix_filter: bool,
+    comparator: ComparatorRef,
+    range_tombstones: Vec<RangeTombstone>,
 }
 
 pub struct RawSSTReader {
@@ -87,8 +103,12 @@ pub struct RawSSTReader {
 }
 
 impl RawSSTReader {
-    pub fn new(name: &Path, backend: &Backend, enable_mmap: bool) -> Result<Self> {
-        let file = backend.fs.open(name, enable_mmap)?;
+    pub fn new(name: &Path, backend: &Backend, opt: &ReaderOptions) -> Result<Self> {
+        let file = backend.fs.open(name, opt.enable_mmap)?;
+        Self::with_file(file, opt)
+    }
+
+    pub fn with_file(file: Box<dyn ReadablePersist>, opt: &ReaderOptions) -> Result<Self> {
         let meta = RawSSTMetaInfo::read(file.borrow() as &dyn ReadablePersist, file.size())?;
         let size = file.size();
 
@@ -97,12 +117,28 @@ impl RawSSTReader {
         }
         log::info!("read meta {:?}", meta);
 
+        let mut filter = BytesMut::zeroed(meta.filter_size as usize);
+        file.read_exact_at(meta.filter_offset, &mut filter)?;
+        let prefix_filter = opt.prefix_filter(&meta.prefix_extractor);
+
+        let mut range_tombstones = Vec::new();
+        if meta.range_del_size > 0 {
+            let mut section = vec![0; meta.range_del_size as usize];
+            file.read_exact_at(meta.range_del_offset, &mut section)?;
+            range_tombstones = range_del::read_section(&section)
+                .map_err(|e| StorageError::from(e).with_sst(meta.number))?;
+        }
+
         Ok(Self {
             inner: RawSSTReaderInner {
                 seq: meta.number,
                 file,
                 meta,
                 size,
+                filter: filter.freeze(),
+                prefix_filter,
+                comparator: opt.comparator.clone(),
+                range_tombstones,
             }
             .into(),
         })
@@ -113,16 +149,14 @@ impl RawSSTReader {
     }
 
     pub fn get_index(&self, index: u64) -> Result<(InternalKey, Value)> {
-        let entry = self.inner.index(index)?;
+        let entry = self.inner.index(index, true)?;
         Ok(entry.into())
     }
 }
 
 impl RawSSTReaderInner {
     fn lower_bound(&self, key: &Bytes) -> Result<u64> {
-        let key = unsafe {
-            std::str::from_utf8_unchecked(std::slice::from_raw_parts(key.as_ptr(), key.len()))
-        };
+        let key = &key[..];
         use std::cmp::Ordering::*;
 
         let mut size = self.meta.total_keys;
@@ -135,18 +169,18 @@ impl RawSSTReaderInner {
         while size > 1 {
             let half = size / 2;
             let mid = base + half;
-            let cmp = unsafe { self.index_key_unchecked(mid, &mut tmp)?.cmp(key) };
+            let cmp = self.comparator.compare(self.index_key(mid, &mut tmp)?, key);
             base = if cmp == Less { mid } else { base };
             size -= half;
         }
-        let cmp = unsafe { self.index_key_unchecked(base, &mut tmp)?.cmp(key) };
+        let cmp = self
+            .comparator
+            .compare(self.index_key(base, &mut tmp)?, key);
         Ok(base + (cmp == Less) as u64)
     }
 
     fn upper_bound(&self, key: &Bytes) -> Result<u64> {
-        let key = unsafe {
-            std::str::from_utf8_unchecked(std::slice::from_raw_parts(key.as_ptr(), key.len()))
-        };
+        let key = &key[..];
         use std::cmp::Ordering::*;
 
         let mut size = self.meta.total_keys;
@@ -159,24 +193,31 @@ impl RawSSTReaderInner {
         while size > 1 {
             let half = size / 2;
             let mid = base + half;
-            let cmp = unsafe { self.index_key_unchecked(mid, &mut tmp)?.cmp(key) };
+            let cmp = self.comparator.compare(self.index_key(mid, &mut tmp)?, key);
             base = if cmp == Greater { base } else { mid };
             size -= half;
         }
-        let cmp = unsafe { self.index_key_unchecked(base, &mut tmp)?.cmp(key) };
+        let cmp = self
+            .comparator
+            .compare(self.index_key(base, &mut tmp)?, key);
         Ok(base + (cmp != Greater) as u64)
     }
 
-    fn index(&self, index: u64) -> Result<RawSSTEntry> {
+    fn index(&self, index: u64, verify: bool) -> Result<RawSSTEntry> {
         if index >= self.meta.total_keys {
-            return Err(StorageError::DataCorrupt);
+            return Err(StorageError::SSTDataCorrupt(self.seq));
         }
         let f = self.file.borrow() as &dyn ReadablePersist;
         let offset = f.read_u64_at::<LE>(self.meta.index_offset + index * 8)?;
-        RawSSTEntry::read(ExtReader::new(f, offset, self.size))
+        RawSSTEntry::read(
+            ExtReader::new(f, offset, self.size),
+            self.meta.version >= 2,
+            verify,
+        )
+        .map_err(|e| e.with_sst(self.seq))
     }
 
-    unsafe fn index_key_unchecked<'a>(&self, index: u64, tmp: &'a mut Vec<u8>) -> Result<&'a str> {
+    fn index_key<'a>(&self, index: u64, tmp: &'a mut Vec<u8>) -> Result<&'a [u8]> {
         let f = self.file.borrow() as &dyn ReadablePersist;
         let offset = f.read_u64_at::<LE>(self.meta.index_offset + index * 8)?;
         RawSSTEntry::read_user_key(ExtReader::new(f, offset, self.size), tmp)
@@ -193,11 +234,15 @@ impl SSTReader for RawSSTReader {
         let ver = opt.snapshot().map(|v| v.sequence()).unwrap_or(u64::MAX);
         let mut index = self.inner.lower_bound(&key)?;
         while index < self.inner.meta.total_keys {
-            let (internal_key, value) = match self.inner.index(index) {
+            let (internal_key, value) = match self.inner.index(index, opt.verify_checksum()) {
                 Ok(e) => e.into(),
                 Err(e) => return Err(e),
             };
-            if internal_key.user_key() == key {
+            if self
+                .inner
+                .comparator
+                .equal(internal_key.user_key_slice(), &key)
+            {
                 if internal_key.seq() <= ver {
                     return Ok((internal_key, value));
                 }
@@ -216,53 +261,118 @@ impl SSTReader for RawSSTReader {
         beg: std::ops::Bound<bytes::Bytes>,
         end: std::ops::Bound<bytes::Bytes>,
         _mark: &Lifetime<'a>,
+        status: &ScanStatus,
     ) -> ScanIter<'a, (InternalKey, Value)> {
-        let beg = match beg {
-            std::ops::Bound::Included(val) => self.inner.lower_bound(&val).unwrap(),
-            std::ops::Bound::Excluded(val) => self.inner.upper_bound(&val).unwrap(),
-            std::ops::Bound::Unbounded => 0,
-        };
-        let end = match end {
-            std::ops::Bound::Included(val) => self.inner.upper_bound(&val).unwrap(),
-            std::ops::Bound::Excluded(val) => self.inner.lower_bound(&val).unwrap(),
-            std::ops::Bound::Unbounded => self.inner.meta.total_keys,
-        };
-
-        let reader = unsafe {
-            core::mem::transmute::<_, &'static RawSSTReaderInner>(self.inner.clone().as_ref())
-        };
-
-        let iter = RawSSTIter {
-            reader,
-            beg,
-            end,
-            idx: beg,
-        };
+        let iter = self.range_iter(opt, beg, end, status);
+        if let Some(snapshot) = opt.snapshot() {
+            let snapshot_ver = snapshot.sequence();
+            let iter = iter.filter(move |entry| entry.seq() <= snapshot_ver);
+            ScanIter::new(EqualFilter::new(iter, self.inner.comparator.clone()))
+        } else {
+            ScanIter::new(EqualFilter::new(iter, self.inner.comparator.clone()))
+        }
+    }
 
+    fn scan_rev<'a>(
+        &self,
+        opt: &crate::GetOption,
+        beg: std::ops::Bound<bytes::Bytes>,
+        end: std::ops::Bound<bytes::Bytes>,
+        _mark: &Lifetime<'a>,
+        status: &ScanStatus,
+    ) -> ScanIter<'a, (InternalKey, Value)> {
+        let iter = self.range_iter(opt, beg, end, status).rev();
         if let Some(snapshot) = opt.snapshot() {
             let snapshot_ver = snapshot.sequence();
             let iter = iter.filter(move |entry| entry.seq() <= snapshot_ver);
-            ScanIter::new(EqualFilter::new(iter))
+            ScanIter::new(RevEqualFilter::new(iter, self.inner.comparator.clone()))
         } else {
-            ScanIter::new(EqualFilter::new(iter))
+            ScanIter::new(RevEqualFilter::new(iter, self.inner.comparator.clone()))
         }
     }
 
-    fn raw_scan<'a>(&self, _lifetime: &Lifetime<'a>) -> ScanIter<'a, (InternalKey, Value)> {
-        let beg = 0;
-        let end = self.inner.meta.total_keys;
-        let reader = unsafe {
-            core::mem::transmute::<_, &'static RawSSTReaderInner>(self.inner.clone().as_ref())
-        };
+    fn has_filter(&self) -> bool {
+        !self.inner.filter.is_empty()
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        // files without filter contain all keys
+        self.inner.filter.is_empty() || bloom::may_contain(&self.inner.filter, key)
+    }
+
+    fn may_contain_prefix(&self, prefix: &[u8]) -> bool {
+        !self.inner.prefix_filter
+            || self.inner.filter.is_empty()
+            || bloom::may_contain(&self.inner.filter, prefix)
+    }
 
+    fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.inner.range_tombstones
+    }
+
+    fn format_meta(&self) -> format::FormatMeta {
+        format::FormatMeta::Raw(self.meta())
+    }
+
+    fn raw_scan<'a>(
+        &self,
+        _lifetime: &Lifetime<'a>,
+        status: &ScanStatus,
+    ) -> ScanIter<'a, (InternalKey, Value)> {
         let iter = RawSSTIter {
-            reader,
-            beg,
-            end,
-            idx: beg,
+            reader: self.inner.clone(),
+            beg: 0,
+            end: self.inner.meta.total_keys,
+            verify: true,
+            status: status.clone(),
+        };
+
+        ScanIter::new(iter).with_status(status.clone())
+    }
+}
+
+impl RawSSTReader {
+    /// index range of entries in the bounds
+    fn bounds(
+        &self,
+        beg: std::ops::Bound<bytes::Bytes>,
+        end: std::ops::Bound<bytes::Bytes>,
+    ) -> Result<(u64, u64)> {
+        let beg = match beg {
+            std::ops::Bound::Included(val) => self.inner.lower_bound(&val)?,
+            std::ops::Bound::Excluded(val) => self.inner.upper_bound(&val)?,
+            std::ops::Bound::Unbounded => 0,
         };
+        let end = match end {
+            std::ops::Bound::Included(val) => self.inner.upper_bound(&val)?,
+            std::ops::Bound::Excluded(val) => self.inner.lower_bound(&val)?,
+            std::ops::Bound::Unbounded => self.inner.meta.total_keys,
+        };
+        Ok((beg, end))
+    }
 
-        ScanIter::new(EqualFilter::new(iter))
+    fn range_iter(
+        &self,
+        opt: &crate::GetOption,
+        beg: std::ops::Bound<bytes::Bytes>,
+        end: std::ops::Bound<bytes::Bytes>,
+        status: &ScanStatus,
+    ) -> RawSSTIter {
+        // nothing is read if the bounds can't be found
+        let (beg, end) = self.bounds(beg, end).unwrap_or_else(|e| {
+            let e = e.with_sst(self.inner.seq);
+            log::error!("error {:?} in seek", e);
+            status.set(e);
+            (0, 0)
+        });
+
+        RawSSTIter {
+            reader: self.inner.clone(),
+            beg,
+            end,
+            verify: opt.verify_checksum(),
+            status: status.clone(),
+        }
     }
 }
 
@@ -275,25 +385,39 @@ struct RawSSTEntry {
     value: Bytes,
 }
 
-impl Into<(InternalKey, Value)> for RawSSTEntry {
-    fn into(self) -> (InternalKey, Value) {
-        (self.key.into(), self.value.into())
+impl From<RawSSTEntry> for (InternalKey, Value) {
+    fn from(val: RawSSTEntry) -> Self {
+        (val.key, val.value.into())
     }
 }
 
 impl RawSSTEntry {
+    fn encode_header(key: &InternalKey, value: &[u8]) -> Vec<u8> {
+        let mut header = Vec::with_capacity(8);
+        let _ = header.write_varint(key.data().len());
+        let _ = header.write_varint(value.len());
+        header
+    }
+
+    fn checksum(header: &[u8], key: &[u8], value: &[u8]) -> u32 {
+        let mut crc_builder = crc32fast::Hasher::new();
+        crc_builder.update(header);
+        crc_builder.update(key);
+        crc_builder.update(value);
+        crc_mask(crc_builder.finalize())
+    }
+
     fn write<W: Write>(key: &InternalKey, value: &Value, mut w: W) -> io::Result<u64> {
-        let mut len = 0usize;
-        len += w.write_varint(key.data().len())?;
-        len += w.write_varint(value.data().len())?;
+        let header = Self::encode_header(key, value.data());
+        w.write_all(&header)?;
         w.write_all(key.data())?;
-        len += key.data().len();
         w.write_all(value.data())?;
-        len += value.data().len();
-        Ok(len as u64)
+        w.write_u32::<LE>(Self::checksum(&header, key.data(), value.data()))?;
+        Ok((header.len() + key.data().len() + value.data().len() + 4) as u64)
     }
 
-    fn read<R: Read>(mut r: R) -> Result<Self> {
+    /// `checksum` tells whether the entry ends with crc
+    fn read<R: Read>(mut r: R, checksum: bool, verify: bool) -> Result<Self> {
         // read key length
         let key_len = r.read_varint::<usize>()?;
         let value_len = r.read_varint::<usize>()?;
@@ -301,26 +425,36 @@ impl RawSSTEntry {
         let mut key = BytesMut::new();
         key.resize(key_len, 0);
         r.read_exact(&mut key)?;
-        let key = key.freeze();
+        let key: InternalKey = key.freeze().into();
 
         let mut value = BytesMut::new();
         value.resize(value_len, 0);
         r.read_exact(&mut value)?;
         let value = value.freeze();
 
-        Ok(Self {
-            key: key.into(),
-            value,
-        })
+        if checksum {
+            let crc = r.read_u32::<LE>()?;
+            if verify {
+                let header = Self::encode_header(&key, &value);
+                if crc != Self::checksum(&header, key.data(), &value) {
+                    return Err(StorageError::DataCorrupt);
+                }
+            }
+        }
+
+        Ok(Self { key, value })
     }
 
-    fn read_user_key<R: Read>(mut r: R, tmp: &mut Vec<u8>) -> Result<&str> {
-        let key_len = r.read_varint::<usize>()? - 8;
+    fn read_user_key<R: Read>(mut r: R, tmp: &mut Vec<u8>) -> Result<&[u8]> {
+        let key_len = r
+            .read_varint::<usize>()?
+            .checked_sub(8)
+            .ok_or(StorageError::DataCorrupt)?;
         let _ = r.read_varint::<usize>()?;
         tmp.resize(key_len, 0);
         r.read_exact(tmp)?;
 
-        Ok(unsafe { std::str::from_utf8_unchecked(&tmp[..(key_len as usize)]) })
+        Ok(&tmp[..key_len])
     }
 }
 
@@ -330,6 +464,11 @@ pub struct RawSSTMetaInfo {
     pub level: u32,
     pub total_keys: u64,
     pub index_offset: u64,
+    pub filter_offset: u64,
+    pub filter_size: u64,
+    pub prefix_extractor: String,
+    pub range_del_offset: u64,
+    pub range_del_size: u64,
 
     pub version: u32,
     pub meta_size: u32,
@@ -337,55 +476,66 @@ pub struct RawSSTMetaInfo {
 }
 
 impl RawSSTMetaInfo {
-    pub fn write<W: Write>(&mut self, mut w: W) -> io::Result<()> {
-        let mut bytes = 0;
-        bytes += w.write_varint(self.number)?;
-        bytes += w.write_varint(self.level)?;
-        bytes += w.write_varint(self.total_keys)?;
-        bytes += w.write_varint(self.index_offset)?;
-
-        let meta_size = bytes as u32 + 12;
-        self.meta_size = meta_size;
-
-        w.write_u32::<LE>(self.version)?;
-        w.write_u32::<LE>(meta_size)?;
-        w.write_u32::<LE>(self.magic)?;
+    pub fn write<W: Write>(&mut self, w: W) -> io::Result<()> {
+        let mut meta = Vec::new();
+        meta.write_varint(self.number)?;
+        meta.write_varint(self.level)?;
+        meta.write_varint(self.total_keys)?;
+        meta.write_varint(self.index_offset)?;
+        if self.version >= 1 {
+            meta.write_varint(self.filter_offset)?;
+            meta.write_varint(self.filter_size)?;
+        }
+        if self.version >= 3 {
+            format::write_name(&mut meta, &self.prefix_extractor)?;
+        }
+        if self.version >= 4 {
+            meta.write_varint(self.range_del_offset)?;
+            meta.write_varint(self.range_del_size)?;
+        }
 
+        self.meta_size = Footer::write(w, &meta, self.version, self.magic, self.version >= 2)?;
         Ok(())
     }
 
     pub fn read(r: &dyn ReadablePersist, size: u64) -> io::Result<Self> {
-        // read tail first
-        if size < 12 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid header"));
-        }
-        let offset = size - 12;
-        let version = r.read_u32_at::<LE>(offset)?;
-        let meta_size = r.read_u32_at::<LE>(offset + 4)?;
-        let magic = r.read_u32_at::<LE>(offset + 8)?;
-
-        if size < meta_size as u64 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "invalid header meta size",
-            ));
-        }
+        let footer = Footer::read(r, size, |version| version >= 2)?;
+        let version = footer.version;
 
-        let offset = size - meta_size as u64;
-        let mut rr = ExtReader::new(r, offset, size);
+        let mut rr = &footer.meta[..];
         let seq: u64 = rr.read_varint()?;
         let level: u32 = rr.read_varint()?;
         let total_keys: u64 = rr.read_varint()?;
         let index_offset: u64 = rr.read_varint()?;
+        let (filter_offset, filter_size) = if version >= 1 {
+            (rr.read_varint()?, rr.read_varint()?)
+        } else {
+            (0, 0)
+        };
+        let prefix_extractor = if version >= 3 {
+            format::read_name(&mut rr)?
+        } else {
+            String::new()
+        };
+        let (range_del_offset, range_del_size) = if version >= 4 {
+            (rr.read_varint()?, rr.read_varint()?)
+        } else {
+            (0, 0)
+        };
 
         Ok(Self {
             number: seq,
             total_keys,
             index_offset,
+            filter_offset,
+            filter_size,
+            prefix_extractor,
+            range_del_offset,
+            range_del_size,
             level,
             version,
-            meta_size,
-            magic,
+            meta_size: footer.size,
+            magic: footer.magic,
         })
     }
 }
@@ -394,17 +544,36 @@ pub struct RawSSTWriter {
     file: Box<dyn WriteablePersist>,
     name: PathBuf,
     success: bool,
+    bloom_bits_per_key: u32,
+    prefix_extractor: Option<PrefixExtractorRef>,
+    comparator: ComparatorRef,
 }
 
 impl RawSSTWriter {
-    pub fn new(backend: &Backend, name: PathBuf) -> Self {
+    /// bloom filter is not written if `bloom_bits_per_key` is 0
+    pub fn new(backend: &Backend, name: PathBuf, bloom_bits_per_key: u32) -> Self {
         let file = backend.fs.create(&name, None).unwrap();
         Self {
             file,
             name,
             success: false,
+            bloom_bits_per_key,
+            prefix_extractor: None,
+            comparator: ComparatorRef::default(),
         }
     }
+
+    /// prefixes of keys are added to the bloom filter
+    pub fn with_prefix_extractor(mut self, prefix_extractor: Option<PrefixExtractorRef>) -> Self {
+        self.prefix_extractor = prefix_extractor;
+        self
+    }
+
+    /// the max key of the file covers ends of range tombstones by the comparator
+    pub fn with_comparator(mut self, comparator: ComparatorRef) -> Self {
+        self.comparator = comparator;
+        self
+    }
 }
 
 impl Drop for RawSSTWriter {
@@ -423,48 +592,89 @@ impl SSTWriter for RawSSTWriter {
         self.success = false;
         let mut w = BufWriter::new(&mut self.file);
 
-        let mut min_key = Bytes::new();
-        let mut max_key = Bytes::new();
+        let mut min_key = None;
         let mut last_entry = None;
         let mut keys_offset = Vec::new();
+        let mut range_tombstones = Vec::new();
 
         let mut min_ver = u64::MAX;
         let mut max_ver = u64::MIN;
         keys_offset.push(0);
+        let mut bloom = (self.bloom_bits_per_key > 0)
+            .then(|| bloom::BloomFilterBuilder::new(self.bloom_bits_per_key));
 
         let mut cur = 0;
         for (internal_key, value) in iter {
             // write key value entry
-            if min_key.is_empty() {
-                min_key = internal_key.user_key();
+            if min_key.is_none() {
+                min_key = Some(internal_key.user_key());
             }
             min_ver = min_ver.min(internal_key.seq());
             max_ver = max_ver.max(internal_key.seq());
+            if internal_key.key_type() == KeyType::RangeDel {
+                range_tombstones.push(RangeTombstone::from_entry(&internal_key, &value));
+                continue;
+            }
+            if let Some(bloom) = &mut bloom {
+                bloom.add(internal_key.user_key_slice());
+                if let Some(prefix) = self
+                    .prefix_extractor
+                    .as_ref()
+                    .and_then(|extractor| extractor.prefix(internal_key.user_key_slice()))
+                {
+                    bloom.add_prefix(prefix);
+                }
+            }
 
             cur += RawSSTEntry::write(&internal_key, &value, &mut w)?;
             last_entry = Some(internal_key);
 
             keys_offset.push(cur);
         }
-        if let Some(entry) = last_entry {
-            max_key = entry.user_key();
-        }
+        let max_key = range_del::max_key(
+            &self.comparator,
+            &range_tombstones,
+            last_entry.map(|entry| entry.user_key()),
+        );
         let key_offset_begin = cur;
         // write key offset
 
         let keys = keys_offset.len() as u64 - 1;
 
         for key_offset in keys_offset {
-            let k = key_offset as u64;
+            let k = key_offset;
             w.write_u64::<LE>(k)?;
         }
 
+        let filter_offset = key_offset_begin + (keys + 1) * 8;
+        let mut filter_size = 0;
+        if let Some(bloom) = bloom {
+            let filter = bloom.build();
+            w.write_all(&filter)?;
+            filter_size = filter.len() as u64;
+        }
+
+        let range_del_offset = filter_offset + filter_size;
+        let mut range_del_size = 0;
+        if !range_tombstones.is_empty() {
+            range_del_size = range_del::write_section(&mut w, &range_tombstones)?;
+        }
+
         let mut meta_info = RawSSTMetaInfo {
             number,
             total_keys: keys,
             index_offset: key_offset_begin,
+            filter_offset,
+            filter_size,
+            prefix_extractor: self
+                .prefix_extractor
+                .as_ref()
+                .map(|extractor| extractor.name().to_owned())
+                .unwrap_or_default(),
+            range_del_offset,
+            range_del_size,
             level,
-            version: 0,
+            version: RAWSST_VERSION,
             meta_size: 0,
             magic: RAWSST_MAGIC,
         };
@@ -472,11 +682,51 @@ impl SSTWriter for RawSSTWriter {
         meta_info.write(&mut w)?;
         w.flush().unwrap();
 
-        debug!("write raw sst meta info {:?}", meta_info);
+        debug!("write raw sst {:?} meta info {:?}", self.name, meta_info);
         self.success = true;
 
         Ok(FileMetaData::new(
-            number, min_key, max_key, min_ver, max_ver, keys, level,
+            number,
+            min_key.unwrap_or_default(),
+            max_key,
+            min_ver,
+            max_ver,
+            keys,
+            level,
         ))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::backend::fs::memory::MemoryBasedPersistBackend;
+
+    use super::*;
+
+    #[test]
+    pub fn bloom_filter_in_sst() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let name = PathBuf::from("/1.sst");
+        let keys: Vec<String> = (0..100).map(|i| format!("key{:03}", i * 2)).collect();
+
+        let mut writer = RawSSTWriter::new(&backend, name.clone(), 10);
+        let iter = keys.iter().enumerate().map(|(i, k)| {
+            (
+                InternalKey::new(k, i as u64 + 1, KeyType::Set),
+                Bytes::from("v").into(),
+            )
+        });
+        writer.write(1, 1, iter).unwrap();
+        drop(writer);
+
+        let reader = RawSSTReader::new(&name, &backend, &ReaderOptions::default()).unwrap();
+        assert!(reader.meta().filter_size > 0);
+        for k in &keys {
+            assert!(reader.may_contain(k.as_bytes()));
+        }
+        let hits = (0..100)
+            .filter(|i| !reader.may_contain(format!("key{:03}", i * 2 + 1).as_bytes()))
+            .count();
+        assert!(hits > 90);
+    }
+}
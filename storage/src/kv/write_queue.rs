@@ -0,0 +1,321 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use crate::{
+    err::{Result, StorageError},
+    key::{WriteBatch, WriteBatchBuilder},
+    WriteOption,
+};
+
+/// max bytes of batches merged into one write group
+const MAX_GROUP_BYTES: usize = 1024 * 1024;
+
+type Check = Box<dyn FnOnce() -> Result<()> + Send>;
+
+struct Writer {
+    batch: Mutex<Option<WriteBatch>>,
+    // run by the leader before the batch is written, the batch is dropped if it fails
+    check: Mutex<Option<Check>>,
+    sync: bool,
+    result: Mutex<Option<Result<u64>>>,
+}
+
+impl Writer {
+    fn done(&self) -> bool {
+        self.result.lock().unwrap().is_some()
+    }
+}
+
+/// hands off leadership when the leader is done with its group, writers of the group
+/// without a result are failed, so followers don't wait forever if the leader panics
+struct GroupGuard<'a> {
+    queue: &'a WriteQueue,
+    group: Vec<Arc<Writer>>,
+}
+
+impl Drop for GroupGuard<'_> {
+    fn drop(&mut self) {
+        let mut writers = self.queue.writers.lock().unwrap_or_else(|e| e.into_inner());
+        for w in &self.group {
+            let mut result = w.result.lock().unwrap_or_else(|e| e.into_inner());
+            if result.is_none() {
+                *result = Some(Err(StorageError::WriteAborted));
+            }
+        }
+        for _ in &self.group {
+            writers.pop_front();
+        }
+        // wake up followers and the next leader
+        self.queue.cond.notify_all();
+    }
+}
+
+/// leader/follower write queue. the writer in front of the queue is the leader,
+/// it merges batches of followers into one write and wakes them up when it's done
+#[derive(Default)]
+pub struct WriteQueue {
+    writers: Mutex<VecDeque<Arc<Writer>>>,
+    cond: Condvar,
+}
+
+impl WriteQueue {
+    /// `f` writes the merged batch with sync flag and returns the sequence of its first entry,
+    /// only one `f` is running at a time. returns the sequence of the first entry of `batch`
+    pub fn write<F>(&self, opt: &WriteOption, batch: WriteBatch, f: F) -> Result<u64>
+    where
+        F: FnOnce(WriteBatch, bool) -> Result<u64>,
+    {
+        self.write_inner(opt, batch, None, f)
+    }
+
+    /// `check` runs after all writes queued before are done and no other write is running,
+    /// the batch is written only if it passes, otherwise its error is returned
+    pub fn write_checked<C, F>(
+        &self,
+        opt: &WriteOption,
+        batch: WriteBatch,
+        check: C,
+        f: F,
+    ) -> Result<u64>
+    where
+        C: FnOnce() -> Result<()> + Send + 'static,
+        F: FnOnce(WriteBatch, bool) -> Result<u64>,
+    {
+        self.write_inner(opt, batch, Some(Box::new(check)), f)
+    }
+
+    fn write_inner<F>(
+        &self,
+        opt: &WriteOption,
+        batch: WriteBatch,
+        check: Option<Check>,
+        f: F,
+    ) -> Result<u64>
+    where
+        F: FnOnce(WriteBatch, bool) -> Result<u64>,
+    {
+        let writer = Arc::new(Writer {
+            batch: Mutex::new(Some(batch)),
+            check: Mutex::new(check),
+            sync: opt.fsync(),
+            result: Mutex::new(None),
+        });
+
+        let group = {
+            let mut writers = self.writers.lock().unwrap();
+            writers.push_back(writer.clone());
+            while !writer.done() && !Arc::ptr_eq(writers.front().unwrap(), &writer) {
+                writers = self.cond.wait(writers).unwrap();
+            }
+            if writer.done() {
+                let result = writer.result.lock().unwrap().take().unwrap();
+                return result;
+            }
+
+            // become the leader, take followers in queue
+            let mut size = 0;
+            let mut group = Vec::new();
+            for w in writers.iter() {
+                let len = w.batch.lock().unwrap().as_ref().unwrap().data().len();
+                if !group.is_empty() && size + len > MAX_GROUP_BYTES {
+                    break;
+                }
+                // a check must see writes before it, checked writers lead their groups
+                if !group.is_empty() && w.check.lock().unwrap().is_some() {
+                    break;
+                }
+                size += len;
+                group.push(w.clone());
+            }
+            GroupGuard { queue: self, group }
+        };
+
+        // the leader is not written if it fails the check
+        let mut failed = Vec::new();
+        let mut written = Vec::with_capacity(group.group.len());
+        for w in &group.group {
+            let check = w.check.lock().unwrap().take();
+            match check.map(|check| check()).unwrap_or(Ok(())) {
+                Ok(()) => written.push(w.clone()),
+                Err(e) => failed.push((w.clone(), e)),
+            }
+        }
+
+        let mut sync = false;
+        let mut counts = Vec::with_capacity(written.len());
+        let batch = if written.len() == 1 {
+            let batch = written[0].batch.lock().unwrap().take().unwrap();
+            sync = written[0].sync;
+            counts.push(batch.count() as u64);
+            Some(batch)
+        } else if !written.is_empty() {
+            let mut builder = WriteBatchBuilder::default();
+            for w in &written {
+                let batch = w.batch.lock().unwrap().take().unwrap();
+                sync |= w.sync;
+                counts.push(batch.count() as u64);
+                builder.append_batch(&batch);
+            }
+            Some(builder.build())
+        } else {
+            None
+        };
+
+        let result = batch.map(|batch| f(batch, sync));
+
+        if let Some(result) = result {
+            let mut seq = result.as_ref().copied().unwrap_or_default();
+            for (w, count) in written.iter().zip(counts) {
+                *w.result.lock().unwrap() =
+                    Some(result.as_ref().map(|_| seq).map_err(|e| e.clone()));
+                seq += count;
+            }
+        }
+        for (w, e) in failed {
+            *w.result.lock().unwrap() = Some(Err(e));
+        }
+        drop(group);
+
+        let result = writer.result.lock().unwrap().take().unwrap();
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    fn batch(key: &str) -> WriteBatch {
+        let mut builder = WriteBatchBuilder::default();
+        builder.set(key, "value").unwrap();
+        builder.build()
+    }
+
+    /// queues followers while the first leader is blocked in `f`, they are written in one group.
+    /// returns results of the first leader and followers
+    fn blocked_leader<F>(
+        queue: &Arc<WriteQueue>,
+        followers: usize,
+        f: F,
+    ) -> Vec<std::thread::Result<Result<u64>>>
+    where
+        F: Fn() -> Result<u64> + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let (entered_tx, entered_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let leader = {
+            let queue = queue.clone();
+            let f = f.clone();
+            std::thread::spawn(move || {
+                queue.write(&WriteOption::default(), batch("leader"), |_, _| {
+                    entered_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                    f()
+                })
+            })
+        };
+        entered_rx.recv().unwrap();
+
+        let handles: Vec<_> = (0..followers)
+            .map(|i| {
+                let queue = queue.clone();
+                let f = f.clone();
+                std::thread::spawn(move || {
+                    queue.write(
+                        &WriteOption::default(),
+                        batch(&format!("key{}", i)),
+                        |_, _| f(),
+                    )
+                })
+            })
+            .collect();
+        // all followers are queued behind the leader
+        while queue.writers.lock().unwrap().len() < followers + 1 {
+            std::thread::yield_now();
+        }
+        release_tx.send(()).unwrap();
+
+        std::iter::once(leader)
+            .chain(handles)
+            .map(|h| h.join())
+            .collect()
+    }
+
+    #[test]
+    pub fn group_write() {
+        let queue = Arc::new(WriteQueue::default());
+        let calls = Arc::new(AtomicU64::new(0));
+
+        let calls2 = calls.clone();
+        let seqs = blocked_leader(&queue, 8, move || {
+            Ok(calls2.fetch_add(1, Ordering::Relaxed) * 100)
+        });
+        // the leader writes its own batch, then followers are merged into one group
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        let mut seqs: Vec<_> = seqs.into_iter().map(|seq| seq.unwrap().unwrap()).collect();
+        seqs.sort();
+        assert_eq!(seqs, vec![0, 100, 101, 102, 103, 104, 105, 106, 107]);
+        assert!(queue.writers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn leader_panic() {
+        let queue = Arc::new(WriteQueue::default());
+        let calls = AtomicU64::new(0);
+        let results = blocked_leader(&queue, 4, move || {
+            if calls.fetch_add(1, Ordering::Relaxed) == 1 {
+                panic!("leader of followers panics");
+            }
+            Ok(0)
+        });
+        assert_eq!(*results[0].as_ref().unwrap(), Ok(0));
+        // one follower leads the group and panics, the others are failed instead of hanging
+        let followers = &results[1..];
+        assert_eq!(followers.iter().filter(|r| r.is_err()).count(), 1);
+        assert!(followers
+            .iter()
+            .filter_map(|r| r.as_ref().ok())
+            .all(|r| *r == Err(StorageError::WriteAborted)));
+        assert!(queue.writers.lock().unwrap().is_empty());
+
+        // leadership is handed off, later writes still work
+        let seq = queue
+            .write(&WriteOption::default(), batch("key"), |_, _| Ok(7))
+            .unwrap();
+        assert_eq!(seq, 7);
+    }
+
+    #[test]
+    pub fn checked_write() {
+        let queue = WriteQueue::default();
+        let mut builder = WriteBatchBuilder::default();
+        builder.set("key", "value").unwrap();
+
+        let err = queue
+            .write_checked(
+                &WriteOption::default(),
+                builder.build(),
+                || Err(crate::err::StorageError::Conflict),
+                |_, _| panic!("failed batch is written"),
+            )
+            .unwrap_err();
+        assert_eq!(err, crate::err::StorageError::Conflict);
+
+        let mut builder = WriteBatchBuilder::default();
+        builder.set("key", "value").unwrap();
+        let seq = queue
+            .write_checked(
+                &WriteOption::default(),
+                builder.build(),
+                || Ok(()),
+                |_, _| Ok(7),
+            )
+            .unwrap();
+        assert_eq!(seq, 7);
+    }
+}
@@ -1,36 +1,54 @@
 pub mod backend;
 mod cache;
 pub mod compaction;
+pub mod comparator;
 pub mod config;
+pub mod cursor;
 pub mod err;
+pub mod indexed_batch;
 pub mod iterator;
 pub mod key;
 pub mod kv;
 pub mod log;
+pub mod merge;
 pub mod option;
+pub mod prefix;
+pub mod range_del;
 pub mod snapshot;
 pub mod storage;
+pub mod transaction;
 pub mod util;
 
+pub use crate::storage::Statistics;
 pub use crate::storage::Storage;
+pub use comparator::BytewiseComparator;
+pub use comparator::Comparator;
+pub use config::CompactionStrategy;
 pub use config::Config;
 pub use config::ConfigRef;
+pub use config::WalSyncPolicy;
+pub use cursor::Cursor;
+pub use indexed_batch::IndexedWriteBatch;
 
 pub use iterator::KvIterator;
+pub use merge::MergeOperator;
 pub use option::GetOption;
 pub use option::WriteOption;
+pub use prefix::PrefixExtractor;
+pub use transaction::{PessimisticTransaction, Transaction};
 
 mod test {
     use rand::seq::SliceRandom;
 
     use crate::{
+        comparator::Comparator,
         key::{InternalKey, KeyType},
         kv::Memtable,
         WriteOption,
     };
 
     pub fn load_test_data() -> Vec<String> {
-        let mut input: Vec<String> = (100..300).into_iter().map(|k| k.to_string()).collect();
+        let mut input: Vec<String> = (100..300).map(|k| k.to_string()).collect();
         input.shuffle(&mut rand::thread_rng());
 
         input
@@ -41,7 +59,7 @@ mod test {
         let input = load_test_data();
         let mut ver = 0;
 
-        let table = Memtable::new(0);
+        let table = Memtable::new(0, Default::default());
         let opt = WriteOption::default();
         let mut sorted_input = Vec::new();
 
@@ -84,4 +102,32 @@ mod test {
         let (sorted_input, table, ver) = crate::test::init_table();
         (sorted_input, table, ver)
     }
+
+    /// byte order in reverse
+    #[allow(unused)]
+    pub struct ReverseComparator;
+
+    impl Comparator for ReverseComparator {
+        fn name(&self) -> &str {
+            "test.ReverseComparator"
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            b.cmp(a)
+        }
+    }
+
+    /// ascii case insensitive, keys differing only in case are the same key
+    #[allow(unused)]
+    pub struct CaseInsensitiveComparator;
+
+    impl Comparator for CaseInsensitiveComparator {
+        fn name(&self) -> &str {
+            "test.CaseInsensitiveComparator"
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+        }
+    }
 }
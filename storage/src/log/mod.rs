@@ -50,8 +50,7 @@ impl LogEntrySerializer for String {
         R: SegmentRead,
     {
         let len = r.read_u32::<LE>()?;
-        let mut vec = Vec::new();
-        vec.resize(len as usize, 0);
+        let mut vec = vec![0; len as usize];
         r.read_exact(&mut vec)?;
         unsafe { Ok(String::from_utf8_unchecked(vec)) }
     }
@@ -61,7 +60,10 @@ impl LogEntrySerializer for String {
 mod test {
     use rand::{distributions::Alphanumeric, prelude::Distribution};
 
-    use crate::backend::{fs::memory::MemoryBasedPersistBackend, Backend};
+    use crate::{
+        backend::{fs::memory::MemoryBasedPersistBackend, Backend},
+        WalSyncPolicy,
+    };
 
     struct TestSerializer;
     impl LogEntrySerializer for TestSerializer {
@@ -98,7 +100,7 @@ mod test {
                 }
                 buf.extend_from_slice(&tmp[..n]);
             }
-            if buf.len() == 0 {
+            if buf.is_empty() {
                 return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "empty"));
             }
             Ok(String::from_utf8(buf).unwrap())
@@ -146,4 +148,26 @@ mod test {
             assert_eq!(test_strings[idx], var.unwrap());
         }
     }
+
+    #[test]
+    fn wal_sync_policy() {
+        let backend = Backend::new(MemoryBasedPersistBackend::new());
+        let wal = wal::LogWriter::with_sync_policy(
+            &backend,
+            TestSerializer,
+            WalSyncPolicy::Bytes(SEGMENT_SIZE as u64 * 2),
+        );
+        wal.rotate("test").unwrap();
+
+        wal.append(&"a".to_owned()).unwrap();
+        assert_eq!(wal.unsynced_bytes(), SEGMENT_SIZE as u64);
+        wal.append(&"b".to_owned()).unwrap();
+        assert_eq!(wal.unsynced_bytes(), 0);
+
+        let wal =
+            wal::LogWriter::with_sync_policy(&backend, TestSerializer, WalSyncPolicy::EveryWrite);
+        wal.rotate("test2").unwrap();
+        wal.append(&"a".to_owned()).unwrap();
+        assert_eq!(wal.unsynced_bytes(), 0);
+    }
 }
@@ -1,5 +1,5 @@
 use std::{
-    borrow::{Borrow, BorrowMut},
+    borrow::Borrow,
     io::{self, Read},
     marker::PhantomData,
     path::PathBuf,
@@ -108,7 +108,7 @@ where
     R: Read,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut buf_offset = 0 as usize;
+        let mut buf_offset = 0_usize;
         loop {
             if buf_offset == buf.len() {
                 break;
@@ -195,15 +195,14 @@ where
         }
     }
 
-    pub fn iter<P>(&self, path: P) -> Result<LogReplayerIter<S, E>>
+    pub fn iter<P>(&self, path: P) -> Result<LogReplayerIter<'_, S, E>>
     where
         P: Into<PathBuf>,
     {
         let path = path.into();
         let file = self.backend.fs.open(&path, false)?;
         let size = file.size();
-        let mut cache = vec![];
-        cache.resize(SEGMENT_SIZE, 0);
+        let cache = vec![0; SEGMENT_SIZE];
         Ok(LogReplayerIter::new(
             file,
             |file| {
@@ -212,7 +211,7 @@ where
             },
             cache,
             &self.serializer,
-            PhantomData::default(),
+            PhantomData,
         ))
     }
 }
@@ -9,8 +9,9 @@ use bytes::BufMut;
 
 use crate::{
     backend::{fs::WriteablePersist, Backend},
-    err::Result,
+    err::{Result, StorageError},
     util::crc::crc_mask,
+    WalSyncPolicy,
 };
 
 use super::{LogEntrySerializer, LogSegmentFlags, SEGMENT_SIZE};
@@ -78,9 +79,9 @@ where
 
         // flush
         let crc_buffer = Self::crc_value(flags, length, &self.cache[8..]);
-        self.cache.writer().write(&crc_buffer)?;
+        self.cache.writer().write_all(&crc_buffer)?;
 
-        self.w.write_all(&self.cache)?;
+        self.w.write_all(self.cache)?;
 
         self.segment_index += 1;
         self.segment_written = 0;
@@ -116,7 +117,7 @@ where
 
             self.cache[(8 + self.segment_written as usize)..]
                 .writer()
-                .write(&chunk)?;
+                .write(chunk)?;
 
             self.segment_written += write_size;
             beg = end;
@@ -145,12 +146,14 @@ where
 struct LogInner {
     current: Option<(Box<dyn WriteablePersist>, Vec<u8>)>,
     write_bytes: u64,
+    unsynced_bytes: u64,
 }
 
 pub struct LogWriter<'a, S> {
     inner: Mutex<LogInner>,
     backend: &'a Backend,
     serializer: S,
+    sync_policy: WalSyncPolicy,
 }
 
 impl<'a, E, S> LogWriter<'a, S>
@@ -158,16 +161,24 @@ where
     S: LogEntrySerializer<Entry = E>,
 {
     pub fn new(backend: &'a Backend, serializer: S) -> Self {
-        let mut write_buffer = vec![];
-        write_buffer.resize(SEGMENT_SIZE, 0);
+        Self::with_sync_policy(backend, serializer, WalSyncPolicy::Never)
+    }
 
+    /// `append` syncs the log by `sync_policy`, `WalSyncPolicy::IntervalMs` is left to the caller
+    pub fn with_sync_policy(
+        backend: &'a Backend,
+        serializer: S,
+        sync_policy: WalSyncPolicy,
+    ) -> Self {
         Self {
             inner: Mutex::new(LogInner {
                 current: None,
                 write_bytes: 0,
+                unsynced_bytes: 0,
             }),
             serializer,
             backend,
+            sync_policy,
         }
     }
 }
@@ -182,7 +193,7 @@ where
             let (underlying, buf) = match &mut inner.current {
                 Some(f) => f,
                 None => {
-                    return Err(io::Error::new(io::ErrorKind::Other, "empty underlying"));
+                    return Err(io::Error::other("empty underlying"));
                 }
             };
             let mut w = SegmentWriter::new(underlying, SEGMENT_SIZE as u64, buf);
@@ -191,17 +202,48 @@ where
             w.flush_all()?
         };
         inner.write_bytes += bytes;
+        inner.unsynced_bytes += bytes;
+
+        let need_sync = match self.sync_policy {
+            WalSyncPolicy::EveryWrite => true,
+            WalSyncPolicy::Bytes(n) => inner.unsynced_bytes >= n,
+            WalSyncPolicy::Never | WalSyncPolicy::IntervalMs(_) => false,
+        };
+        if need_sync {
+            Self::sync_inner(&mut inner).map_err(|e| match e {
+                StorageError::Io(e) => e,
+                e => io::Error::other(e.to_string()),
+            })?;
+        }
         Ok(())
     }
 
-    pub fn sync(&self) -> Result<()> {
-        let mut inner = self.inner.lock().unwrap();
+    fn sync_inner(inner: &mut LogInner) -> Result<()> {
         if let Some((cur, _)) = inner.current.as_mut() {
             cur.sync()?;
         }
+        inner.unsynced_bytes = 0;
         Ok(())
     }
 
+    pub fn sync(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        Self::sync_inner(&mut inner)
+    }
+
+    pub fn unsynced_bytes(&self) -> u64 {
+        self.inner.lock().unwrap().unsynced_bytes
+    }
+
+    /// sync only if something is written since last sync
+    pub fn sync_dirty(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.unsynced_bytes == 0 {
+            return Ok(());
+        }
+        Self::sync_inner(&mut inner)
+    }
+
     pub fn rotate<P>(&self, path: P) -> Result<()>
     where
         P: Into<PathBuf>,
@@ -209,15 +251,14 @@ where
         let mut inner = self.inner.lock().unwrap();
         if let Some((mut cur, _)) = inner.current.take() {
             cur.sync()?;
-        } else {
         }
         let path = path.into();
 
-        let mut write_buffer = vec![];
-        write_buffer.resize(SEGMENT_SIZE, 0);
+        let write_buffer = vec![0; SEGMENT_SIZE];
         let file = self.backend.fs.create(&path, Some(DEFAULT_ALLOC_SIZE))?;
         inner.current = Some((file, write_buffer));
         inner.write_bytes = 0;
+        inner.unsynced_bytes = 0;
 
         Ok(())
     }
@@ -0,0 +1,296 @@
+use std::fmt::Debug;
+use std::iter::Peekable;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::{
+    comparator::ComparatorRef,
+    err::StorageError,
+    iterator::{KvIteratorItem, ScanStatus},
+    key::{InternalKey, KeyType, Value},
+};
+
+/// combines merge operands written by `Storage::merge` into values
+pub trait MergeOperator: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// value of the key from its base value and operands from old to new,
+    /// base is None if the key doesn't exist or is deleted
+    fn full_merge(&self, key: &[u8], base: Option<&[u8]>, operands: &[Bytes]) -> Bytes;
+
+    /// combine two adjacent operands into one, None if they can't be combined
+    fn partial_merge(&self, _key: &[u8], _older: &[u8], _newer: &[u8]) -> Option<Bytes> {
+        None
+    }
+}
+
+/// adds little endian u64 operands, an absent or malformed value is 0
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UInt64AddOperator;
+
+impl UInt64AddOperator {
+    fn decode(value: &[u8]) -> u64 {
+        value.try_into().map(u64::from_le_bytes).unwrap_or(0)
+    }
+}
+
+impl MergeOperator for UInt64AddOperator {
+    fn name(&self) -> &str {
+        "nanokv.UInt64AddOperator"
+    }
+
+    fn full_merge(&self, _key: &[u8], base: Option<&[u8]>, operands: &[Bytes]) -> Bytes {
+        let sum = operands
+            .iter()
+            .fold(base.map(Self::decode).unwrap_or(0), |sum, operand| {
+                sum.wrapping_add(Self::decode(operand))
+            });
+        Bytes::copy_from_slice(&sum.to_le_bytes())
+    }
+
+    fn partial_merge(&self, _key: &[u8], older: &[u8], newer: &[u8]) -> Option<Bytes> {
+        let sum = Self::decode(older).wrapping_add(Self::decode(newer));
+        Some(Bytes::copy_from_slice(&sum.to_le_bytes()))
+    }
+}
+
+#[derive(Clone)]
+pub struct MergeOperatorRef(Arc<dyn MergeOperator>);
+
+impl MergeOperatorRef {
+    pub fn new<M: MergeOperator + 'static>(operator: M) -> Self {
+        Self(Arc::new(operator))
+    }
+
+    /// combine adjacent operands from new to old, operands which can't be combined are kept
+    pub(crate) fn partial_merge_all(
+        &self,
+        key: &[u8],
+        operands: Vec<(InternalKey, Value)>,
+    ) -> Vec<(InternalKey, Value)> {
+        let mut output: Vec<(InternalKey, Value)> = Vec::with_capacity(operands.len());
+        for (older_key, older) in operands {
+            if let Some((_, newer)) = output.last_mut() {
+                // the combined operand keeps the newer sequence
+                if let Some(value) = self.partial_merge(key, older.data(), newer.data()) {
+                    *newer = Value::from(value);
+                    continue;
+                }
+            }
+            output.push((older_key, older));
+        }
+        output
+    }
+}
+
+impl Deref for MergeOperatorRef {
+    type Target = dyn MergeOperator;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl Debug for MergeOperatorRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// fold merge operands of a key onto its base value. versions of a key are from new to old
+/// and end with the first version which is not a merge operand.
+/// without operator the fold stops at the first operand and records `MergeOperatorNotSet`,
+/// as `get` of the key fails
+pub struct MergeFold<I>
+where
+    I: Iterator,
+{
+    iter: Peekable<I>,
+    operator: Option<MergeOperatorRef>,
+    comparator: ComparatorRef,
+    status: ScanStatus,
+    stopped: bool,
+}
+
+impl<I> MergeFold<I>
+where
+    I: Iterator<Item = (InternalKey, Value)>,
+{
+    pub(crate) fn new(
+        iter: I,
+        operator: Option<MergeOperatorRef>,
+        comparator: ComparatorRef,
+        status: ScanStatus,
+    ) -> Self {
+        Self {
+            iter: iter.peekable(),
+            operator,
+            comparator,
+            status,
+            stopped: false,
+        }
+    }
+}
+
+impl<I> Iterator for MergeFold<I>
+where
+    I: Iterator<Item = (InternalKey, Value)>,
+{
+    type Item = (InternalKey, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stopped {
+            return None;
+        }
+        let (key, value) = self.iter.next()?;
+        if key.key_type() != KeyType::Merge {
+            return Some((key, value));
+        }
+        let operator = match &self.operator {
+            Some(operator) => operator,
+            None => {
+                log::error!("merge operator of key {:?} not set", key.user_key());
+                self.status.set(StorageError::MergeOperatorNotSet);
+                self.stopped = true;
+                return None;
+            }
+        };
+        let comparator = &self.comparator;
+        let same_key = |(older, _): &(InternalKey, Value)| {
+            comparator.equal(older.user_key_slice(), key.user_key_slice())
+        };
+        let mut operands = vec![value.internal()];
+        let mut base = None;
+        while let Some((older, value)) = self.iter.next_if(same_key) {
+            match older.key_type() {
+                KeyType::Merge => operands.push(value.internal()),
+                KeyType::Set => {
+                    base = Some(value);
+                    break;
+                }
+                KeyType::Del | KeyType::RangeDel => break,
+            }
+        }
+        // versions under the base are hidden by it
+        while self.iter.next_if(same_key).is_some() {}
+
+        operands.reverse();
+        let value = operator.full_merge(
+            key.user_key_slice(),
+            base.as_ref().map(|v| v.data()),
+            &operands,
+        );
+        Some((
+            InternalKey::new(key.user_key_slice(), key.seq(), KeyType::Set),
+            Value::from(value),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(key: &str, seq: u64, ty: KeyType, value: u64) -> (InternalKey, Value) {
+        (
+            InternalKey::new(key, seq, ty),
+            Value::from(Bytes::copy_from_slice(&value.to_le_bytes())),
+        )
+    }
+
+    fn number(value: &Value) -> u64 {
+        u64::from_le_bytes(value.data().try_into().unwrap())
+    }
+
+    #[test]
+    pub fn merge_fold() {
+        let operator = MergeOperatorRef::new(UInt64AddOperator);
+        let entries = vec![
+            entry("a", 5, KeyType::Merge, 1),
+            entry("a", 4, KeyType::Merge, 2),
+            entry("a", 3, KeyType::Set, 10),
+            entry("b", 6, KeyType::Merge, 3),
+            entry("b", 2, KeyType::Del, 0),
+            entry("c", 1, KeyType::Set, 7),
+            entry("d", 7, KeyType::Merge, 4),
+        ];
+        let fold = |entries: Vec<(InternalKey, Value)>| -> Vec<(Bytes, u64, KeyType, u64)> {
+            MergeFold::new(
+                entries.into_iter(),
+                Some(operator.clone()),
+                ComparatorRef::default(),
+                ScanStatus::default(),
+            )
+            .map(|(key, value)| (key.user_key(), key.seq(), key.key_type(), number(&value)))
+            .collect()
+        };
+        let folded = fold(entries.clone());
+        assert_eq!(
+            folded,
+            vec![
+                (Bytes::from("a"), 5, KeyType::Set, 13),
+                (Bytes::from("b"), 6, KeyType::Set, 3),
+                (Bytes::from("c"), 1, KeyType::Set, 7),
+                (Bytes::from("d"), 7, KeyType::Set, 4),
+            ]
+        );
+
+        // the fold stops at the first version which is not an operand
+        let folded = fold(vec![
+            entry("a", 5, KeyType::Merge, 1),
+            entry("a", 4, KeyType::Set, 10),
+            entry("a", 3, KeyType::Set, 100),
+            entry("a", 2, KeyType::Merge, 1000),
+            entry("b", 3, KeyType::Merge, 1),
+            entry("b", 2, KeyType::Del, 0),
+            entry("b", 1, KeyType::Set, 100),
+        ]);
+        assert_eq!(
+            folded,
+            vec![
+                (Bytes::from("a"), 5, KeyType::Set, 11),
+                (Bytes::from("b"), 3, KeyType::Set, 1),
+            ]
+        );
+
+        // versions of a key differ in bytes by the comparator
+        let folded: Vec<_> = MergeFold::new(
+            vec![
+                entry("A", 5, KeyType::Merge, 1),
+                entry("a", 4, KeyType::Set, 10),
+                entry("b", 3, KeyType::Set, 2),
+            ]
+            .into_iter(),
+            Some(operator.clone()),
+            ComparatorRef::new(crate::test::CaseInsensitiveComparator),
+            ScanStatus::default(),
+        )
+        .map(|(key, value)| (key.user_key(), number(&value)))
+        .collect();
+        assert_eq!(folded, vec![(Bytes::from("A"), 11), (Bytes::from("b"), 2)]);
+
+        // without operator, the fold stops at the first operand with an error
+        let status = ScanStatus::default();
+        let keys: Vec<_> = MergeFold::new(
+            entries.into_iter().skip(2),
+            None,
+            ComparatorRef::default(),
+            status.clone(),
+        )
+        .map(|(key, _)| key.user_key())
+        .collect();
+        assert_eq!(keys, vec![Bytes::from("a")]);
+        assert_eq!(status.get(), Err(StorageError::MergeOperatorNotSet));
+
+        let operands = vec![
+            entry("a", 5, KeyType::Merge, 1),
+            entry("a", 4, KeyType::Merge, 2),
+        ];
+        let combined = operator.partial_merge_all(b"a", operands);
+        assert_eq!(combined.len(), 1);
+        assert_eq!(combined[0].0.seq(), 5);
+        assert_eq!(number(&combined[0].1), 3);
+    }
+}
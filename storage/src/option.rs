@@ -1,10 +1,11 @@
 use crate::snapshot::Snapshot;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct GetOption {
     must_fetch_value: bool,
     snapshot: Option<Snapshot>,
     fetch_delete: bool,
+    skip_checksum: bool,
     debug: bool,
 }
 
@@ -27,6 +28,16 @@ impl GetOption {
         self.fetch_delete = fetch;
         self
     }
+    /// sst data is read without checksum verification
+    pub fn set_skip_checksum(mut self, skip: bool) -> Self {
+        self.skip_checksum = skip;
+        self
+    }
+
+    pub fn verify_checksum(&self) -> bool {
+        !self.skip_checksum
+    }
+
     pub fn debug(&self) -> bool {
         self.debug
     }
@@ -38,6 +49,7 @@ impl GetOption {
             must_fetch_value: false,
             snapshot: Some(snapshot.into()),
             fetch_delete: false,
+            skip_checksum: false,
             debug: false,
         }
     }
@@ -71,6 +83,19 @@ impl WriteOption {
             ..Default::default()
         }
     }
+
+    /// the write is synced to disk before it returns
+    pub fn with_fsync() -> Self {
+        Self {
+            fsync: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
 }
 
 #[derive(Debug, Default)]
@@ -0,0 +1,129 @@
+use std::fmt::Debug;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// prefix of user keys, prefixes are added to the bloom filters of sst files
+pub trait PrefixExtractor: Send + Sync {
+    /// persisted in sst files, prefix filters of another extractor are not used
+    fn name(&self) -> &str;
+
+    /// None if the key has no prefix
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]>;
+}
+
+/// first n bytes, shorter keys have no prefix
+pub struct FixedPrefix {
+    len: usize,
+    name: String,
+}
+
+impl FixedPrefix {
+    pub fn new(len: usize) -> Self {
+        Self {
+            len,
+            name: format!("nanokv.FixedPrefix.{}", len),
+        }
+    }
+}
+
+impl PrefixExtractor for FixedPrefix {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        key.get(..self.len)
+    }
+}
+
+/// bytes until the first delimiter, the delimiter included,
+/// e.g. `tenant/` of `tenant/key` with delimiter `/`
+pub struct DelimiterPrefix {
+    delimiter: u8,
+    name: String,
+}
+
+impl DelimiterPrefix {
+    pub fn new(delimiter: u8) -> Self {
+        Self {
+            delimiter,
+            name: format!("nanokv.DelimiterPrefix.{}", delimiter),
+        }
+    }
+}
+
+impl PrefixExtractor for DelimiterPrefix {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        let pos = key.iter().position(|b| *b == self.delimiter)?;
+        Some(&key[..=pos])
+    }
+}
+
+#[derive(Clone)]
+pub struct PrefixExtractorRef(Arc<dyn PrefixExtractor>);
+
+impl PrefixExtractorRef {
+    pub fn new<P: PrefixExtractor + 'static>(extractor: P) -> Self {
+        Self(Arc::new(extractor))
+    }
+
+    /// the prefix is a whole prefix of the extractor, then prefix filters can be used for it
+    pub fn is_prefix(&self, prefix: &[u8]) -> bool {
+        self.prefix(prefix) == Some(prefix)
+    }
+}
+
+impl Deref for PrefixExtractorRef {
+    type Target = dyn PrefixExtractor;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl Debug for PrefixExtractorRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// smallest key greater than all keys with the prefix in bytewise order, None if there is no such key
+pub fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last != 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn extract_prefix() {
+        let fixed = PrefixExtractorRef::new(FixedPrefix::new(3));
+        assert_eq!(fixed.prefix(b"abcd"), Some(&b"abc"[..]));
+        assert_eq!(fixed.prefix(b"ab"), None);
+        assert!(fixed.is_prefix(b"abc"));
+        assert!(!fixed.is_prefix(b"abcd"));
+
+        let delimiter = PrefixExtractorRef::new(DelimiterPrefix::new(b'/'));
+        assert_eq!(delimiter.prefix(b"t1/a/b"), Some(&b"t1/"[..]));
+        assert_eq!(delimiter.prefix(b"t1"), None);
+        assert!(delimiter.is_prefix(b"t1/"));
+        assert!(!delimiter.is_prefix(b"t1"));
+
+        assert_eq!(prefix_successor(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_successor(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(b"\xff"), None);
+        assert_eq!(prefix_successor(b""), None);
+    }
+}
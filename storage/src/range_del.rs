@@ -0,0 +1,200 @@
+use std::cmp::Ordering;
+use std::io::{self, Read, Write};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use bytes::Bytes;
+use integer_encoding::{VarIntReader, VarIntWriter};
+
+use crate::{
+    comparator::ComparatorRef,
+    iterator::KvIteratorItem,
+    key::{InternalKey, KeyType, Value},
+    util::crc::{crc_mask, crc_unmask},
+};
+
+/// deletion of user keys in [start, end) written before `seq`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Bytes,
+    pub end: Bytes,
+    pub seq: u64,
+}
+
+impl RangeTombstone {
+    pub fn new<S: Into<Bytes>, E: Into<Bytes>>(start: S, end: E, seq: u64) -> Self {
+        Self {
+            start: start.into(),
+            end: end.into(),
+            seq,
+        }
+    }
+
+    pub fn covers(&self, comparator: &ComparatorRef, key: &[u8]) -> bool {
+        comparator.compare(&self.start, key) != Ordering::Greater
+            && comparator.compare(key, &self.end) == Ordering::Less
+    }
+
+    /// the tombstone overlaps with keys in [min, max]
+    pub fn overlaps(&self, comparator: &ComparatorRef, min: &[u8], max: &[u8]) -> bool {
+        comparator.compare(&self.start, max) != Ordering::Greater
+            && comparator.compare(min, &self.end) == Ordering::Less
+    }
+
+    /// tombstones are entries of `KeyType::RangeDel` keyed by start with the end as value
+    /// in write batches and sst writer input
+    pub fn from_entry(key: &InternalKey, value: &Value) -> Self {
+        Self::new(key.user_key(), value.internal(), key.seq())
+    }
+
+    pub fn to_entry(&self) -> (InternalKey, Value) {
+        (
+            InternalKey::new(&self.start, self.seq, KeyType::RangeDel),
+            Value::from(self.end.clone()),
+        )
+    }
+}
+
+/// the entry returned by gets of keys deleted by a tombstone of `seq`
+pub(crate) fn deleted_entry(key: &[u8], seq: u64) -> (InternalKey, Value) {
+    (
+        InternalKey::new(key, seq, KeyType::Del),
+        Value::from(Bytes::new()),
+    )
+}
+
+/// max key of an sst file, which covers ends of its tombstones
+pub(crate) fn max_key(
+    comparator: &ComparatorRef,
+    tombstones: &[RangeTombstone],
+    max: Option<Bytes>,
+) -> Bytes {
+    tombstones
+        .iter()
+        .map(|t| &t.end)
+        .chain(max.as_ref())
+        .max_by(|a, b| comparator.compare(a, b))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// newest sequence not greater than `ver` of tombstones covering the key, 0 if there is none
+pub fn covering_seq(
+    tombstones: &[RangeTombstone],
+    comparator: &ComparatorRef,
+    key: &[u8],
+    ver: u64,
+) -> u64 {
+    tombstones
+        .iter()
+        .filter(|t| t.seq <= ver && t.covers(comparator, key))
+        .map(|t| t.seq)
+        .max()
+        .unwrap_or(0)
+}
+
+/// visible tombstones of a read, sorted by start
+pub struct RangeTombstones {
+    list: Vec<RangeTombstone>,
+    comparator: ComparatorRef,
+}
+
+impl RangeTombstones {
+    pub fn new(mut list: Vec<RangeTombstone>, comparator: ComparatorRef) -> Self {
+        list.sort_by(|a, b| comparator.compare(&a.start, &b.start));
+        Self { list, comparator }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// the version of the key is deleted by a newer tombstone
+    pub fn covered(&self, key: &[u8], seq: u64) -> bool {
+        let end = self
+            .list
+            .partition_point(|t| self.comparator.compare(&t.start, key) != Ordering::Greater);
+        self.list[..end]
+            .iter()
+            .any(|t| t.seq > seq && self.comparator.compare(key, &t.end) == Ordering::Less)
+    }
+}
+
+// range tombstone section of sst files
+//
+// count | (seq | start len | start | end len | end) * count | crc u32
+pub(crate) fn write_section<W: Write>(mut w: W, tombstones: &[RangeTombstone]) -> io::Result<u64> {
+    let mut data = Vec::new();
+    data.write_varint(tombstones.len() as u64)?;
+    for t in tombstones {
+        data.write_varint(t.seq)?;
+        data.write_varint(t.start.len() as u64)?;
+        data.write_all(&t.start)?;
+        data.write_varint(t.end.len() as u64)?;
+        data.write_all(&t.end)?;
+    }
+    w.write_all(&data)?;
+    w.write_u32::<LE>(crc_mask(crc32fast::hash(&data)))?;
+    Ok(data.len() as u64 + 4)
+}
+
+pub(crate) fn read_section(data: &[u8]) -> io::Result<Vec<RangeTombstone>> {
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "range tombstones corrupt");
+    if data.len() < 4 {
+        return Err(corrupt());
+    }
+    let (mut data, mut crc) = data.split_at(data.len() - 4);
+    if crc_unmask(crc.read_u32::<LE>()?) != crc32fast::hash(data) {
+        return Err(corrupt());
+    }
+    let read_bytes = |r: &mut &[u8]| -> io::Result<Bytes> {
+        let len: u64 = r.read_varint()?;
+        let mut buf = vec![0; len as usize];
+        r.read_exact(&mut buf)?;
+        Ok(buf.into())
+    };
+    let count: u64 = data.read_varint()?;
+    let mut tombstones = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let seq = data.read_varint()?;
+        let start = read_bytes(&mut data)?;
+        let end = read_bytes(&mut data)?;
+        tombstones.push(RangeTombstone { start, end, seq });
+    }
+    Ok(tombstones)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn range_tombstones() {
+        let comparator = ComparatorRef::default();
+        let list = vec![
+            RangeTombstone::new("d", "f", 10),
+            RangeTombstone::new("a", "c", 5),
+            RangeTombstone::new("b", "e", 20),
+        ];
+        assert!(list[1].covers(&comparator, b"a"));
+        assert!(!list[1].covers(&comparator, b"c"));
+        assert!(list[0].overlaps(&comparator, b"a", b"d"));
+        assert!(!list[0].overlaps(&comparator, b"f", b"g"));
+        assert_eq!(covering_seq(&list, &comparator, b"d", u64::MAX), 20);
+        assert_eq!(covering_seq(&list, &comparator, b"d", 19), 10);
+        assert_eq!(covering_seq(&list, &comparator, b"f", u64::MAX), 0);
+
+        let tombstones = RangeTombstones::new(list.clone(), comparator);
+        assert!(tombstones.covered(b"a", 4));
+        assert!(!tombstones.covered(b"a", 5));
+        assert!(tombstones.covered(b"e", 9));
+        assert!(!tombstones.covered(b"e", 10));
+        assert!(!tombstones.covered(b"f", 0));
+
+        let mut data = Vec::new();
+        let size = write_section(&mut data, &list).unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(read_section(&data).unwrap(), list);
+        data[1] ^= 1;
+        assert!(read_section(&data).is_err());
+    }
+}
@@ -1,12 +1,50 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// reads with a snapshot see keys written before it,
+/// snapshots taken by `Storage::snapshot` are released when the last clone is dropped
 #[derive(Debug, Default, Clone)]
 pub struct Snapshot {
     seq: u64,
+    _guard: Option<Arc<SnapshotGuard>>,
+}
+
+/// releases the sequence on drop, then compaction may drop versions it sees
+struct SnapshotGuard {
+    seq: u64,
+    release: Box<dyn Fn(u64) + Send + Sync>,
+}
+
+impl Drop for SnapshotGuard {
+    fn drop(&mut self) {
+        (self.release)(self.seq)
+    }
+}
+
+impl Debug for SnapshotGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SnapshotGuard")
+            .field("seq", &self.seq)
+            .finish()
+    }
 }
 
 impl Snapshot {
     pub fn new(seq: u64) -> Self {
-        Self { seq }
+        Self { seq, _guard: None }
     }
+
+    /// `release` is called once when all clones are dropped
+    pub(crate) fn with_release<F: Fn(u64) + Send + Sync + 'static>(seq: u64, release: F) -> Self {
+        Self {
+            seq,
+            _guard: Some(Arc::new(SnapshotGuard {
+                seq,
+                release: Box::new(release),
+            })),
+        }
+    }
+
     pub fn sequence(&self) -> u64 {
         self.seq
     }
@@ -14,6 +52,6 @@ impl Snapshot {
 
 impl From<u64> for Snapshot {
     fn from(seq: u64) -> Self {
-        Self { seq }
+        Self::new(seq)
     }
 }
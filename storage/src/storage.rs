@@ -1,9 +1,11 @@
 use std::{
-    ops::RangeBounds,
+    ops::{Bound, RangeBounds},
     sync::{
         atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
         Arc, Mutex,
     },
+    thread::JoinHandle,
     time::Duration,
 };
 
@@ -16,21 +18,30 @@ use crate::{
     backend::Backend,
     cache::Cache,
     compaction::{major::MajorCompactionTaskPool, minor::MinorCompactionTaskPool},
+    comparator::{BytewiseComparator, Comparator},
+    cursor::Cursor,
     err::{Result, StorageError},
-    iterator::{KvIteratorItem, MergedIter, ScanIter},
-    key::{BatchLogSerializer, KeyType, Value, WriteBatch, WriteBatchBuilder},
-    kv::{
-        manifest::VersionEdit, sst::SnapshotTable, superversion::SuperVersion, ColumnFamilyTables,
-        Imemtables,
-    },
+    indexed_batch::IndexedWriteBatch,
+    iterator::{KvIteratorItem, MergedIter, ScanIter, ScanStatus},
+    key::{BatchLogSerializer, InternalKey, KeyType, Value, WriteBatch, WriteBatchBuilder},
+    kv::{sst::SnapshotTable, superversion::SuperVersion, ColumnFamilyTables, Imemtables},
     log::LogReplayer,
+    merge::MergeFold,
+    prefix::prefix_successor,
+    range_del::RangeTombstones,
     snapshot::Snapshot,
+    transaction::{PessimisticTransaction, Transaction},
     util::fname::{manifest_name, sst_name, wal_name},
-    Config, GetOption, WriteOption,
+    Config, GetOption, WalSyncPolicy, WriteOption,
 };
 use crate::{
     compaction::CompactSerializer,
-    kv::{manifest::Manifest, Memtable},
+    kv::{
+        lock_manager::LockManager,
+        manifest::{FileMetaData, Manifest, VersionRef},
+        write_queue::WriteQueue,
+        Memtable,
+    },
     log::LogWriter,
 };
 
@@ -53,6 +64,8 @@ struct StorageInner {
     step_version: AtomicU64,
     super_version: ArcSwap<SuperVersion>,
     cache: Cache,
+    write_queue: WriteQueue,
+    lock_manager: LockManager,
 }
 
 impl StorageInner {
@@ -83,18 +96,112 @@ impl StorageInner {
     pub fn modify_super_version_opt<F: FnOnce(&SuperVersion) -> Option<SuperVersion>>(&self, f: F) {
         let _lock = self.lock.lock().unwrap();
         let sv = self.super_version.load();
-        let val = self.step_version.fetch_add(1, Ordering::SeqCst);
         let sv = match f(sv.as_ref()) {
             Some(v) => v,
             None => {
                 return;
             }
         };
+        // the step only moves when a new super version is stored
+        let val = self.step_version.fetch_add(1, Ordering::SeqCst);
         assert!(val + 1 == sv.step_version);
 
         self.tables.store(sv.cf_tables.clone());
         self.super_version.store(Arc::new(sv));
     }
+
+    /// sequence of the newest version of the key, deletion included
+    pub fn latest_seq(&self, key: &Bytes) -> Result<Option<u64>> {
+        let super_version = self.super_version();
+        let lifetime = super_version.lifetime();
+        let opt = GetOption::default();
+        let seq = |res: Result<(InternalKey, Value)>| match res {
+            Ok((internal_key, _)) => Ok(Some(internal_key.seq())),
+            Err(StorageError::KeyNotExist) => Ok(None),
+            Err(e) => Err(e),
+        };
+
+        let tables = &super_version.cf_tables;
+        if let Some(seq) = seq(tables.memtable.get(&opt, key.clone(), &lifetime))? {
+            return Ok(Some(seq));
+        }
+        if let Some(seq) = seq(tables.imemtables.get(&opt, key.clone(), &lifetime))? {
+            return Ok(Some(seq));
+        }
+        seq(SnapshotTable::new(
+            Snapshot::new(u64::MAX),
+            super_version.sst_version.clone(),
+            &self.cache,
+        )
+        .get(
+            &opt,
+            self.info.borrow_config(),
+            key.clone(),
+            self.info.borrow_backend(),
+            &lifetime,
+        ))
+    }
+
+    /// remove sst files which are compacted and no longer referenced by readers
+    pub fn remove_obsolete_files(&self) {
+        let files = self.info.with_manifest(|m| m.remove_obsolete_files());
+        for number in files {
+            self.cache.remove_opened_sst(number);
+        }
+    }
+
+    /// commit the sst of a flushed imemtable, then its wal is not replayed on restart.
+    /// imemtables may be flushed in any order
+    fn memtable_flushed(&self, meta: FileMetaData) -> Result<VersionRef> {
+        let number = meta.number;
+        let mut log_number = 0;
+        let version = self.info.with_manifest(|m| {
+            m.add_sst_with(meta, true, |current| {
+                self.modify_super_version(|sv| {
+                    let cf_tables = ColumnFamilyTables {
+                        memtable: sv.cf_tables.memtable.clone(),
+                        imemtables: sv.cf_tables.imemtables.remove(number),
+                    };
+                    // the oldest memtable which is not flushed, its wal is the first to replay
+                    log_number = cf_tables
+                        .imemtables
+                        .iter()
+                        .map(|table| table.number())
+                        .fold(cf_tables.memtable.number(), u64::min);
+                    SuperVersion {
+                        cf_tables: Arc::new(cf_tables),
+                        sst_version: current,
+                        step_version: sv.step_version + 1,
+                    }
+                });
+            })
+        })?;
+        if let Err(e) = self.info.with_manifest(|m| m.set_log_number(log_number)) {
+            error!("set log number {} fail {:?}", log_number, e);
+        }
+        // memtable is persisted, its wal is useless
+        let wal_path = wal_name(self.info.borrow_config(), number);
+        if let Err(e) = self.info.borrow_backend().fs.remove(&wal_path) {
+            if !e.is_io_not_found() {
+                error!("remove wal {:?} fail {:?}", wal_path.as_os_str(), e);
+            }
+        }
+        self.remove_obsolete_files();
+        Ok(version)
+    }
+}
+
+/// counters of storage caches
+#[derive(Debug, Clone, Default)]
+pub struct Statistics {
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+    /// bytes of cached blocks
+    pub block_cache_usage: u64,
+    pub block_cache_capacity: u64,
+    /// opened sst files
+    pub table_cache_usage: usize,
+    pub table_cache_capacity: usize,
 }
 
 pub struct Storage {
@@ -102,10 +209,12 @@ pub struct Storage {
 
     minor_pool: Arc<MinorCompactionTaskPool>,
     major_pool: Arc<MajorCompactionTaskPool>,
+    // dropping the sender stops the thread
+    wal_sync_thread: Option<(mpsc::Sender<()>, JoinHandle<()>)>,
 }
 
 impl Storage {
-    pub fn clear(config: Config) {}
+    pub fn clear(_config: Config) {}
 
     pub fn new(config: Config, backend: Backend) -> Self {
         backend
@@ -114,15 +223,15 @@ impl Storage {
             .unwrap();
         backend
             .fs
-            .make_sure_dir(&sst_name(&config, 0).parent().unwrap().to_path_buf())
+            .make_sure_dir(sst_name(&config, 0).parent().unwrap())
             .unwrap();
         backend
             .fs
-            .make_sure_dir(&manifest_name(&config, 0).parent().unwrap().to_path_buf())
+            .make_sure_dir(manifest_name(&config, 0).parent().unwrap())
             .unwrap();
         backend
             .fs
-            .make_sure_dir(&wal_name(&config, 0).parent().unwrap().to_path_buf())
+            .make_sure_dir(wal_name(&config, 0).parent().unwrap())
             .unwrap();
         // prepare dir
         // fname::make_sure(conf);
@@ -133,28 +242,32 @@ impl Storage {
         let info = StorageInfoInner::new(
             config.clone(),
             backend,
-            |c, b| Manifest::new(&c, &b),
+            |c, b| Manifest::new(c, b),
             |c, b| {
                 if c.no_wal {
                     None
                 } else {
-                    Some(LogWriter::new(&b, BatchLogSerializer))
+                    Some(LogWriter::with_sync_policy(
+                        b,
+                        BatchLogSerializer,
+                        c.wal_sync,
+                    ))
                 }
             },
         );
-        let (number, need_restore, sst_version) = info.with_manifest(|manifest| {
-            let mut restore_number = manifest.last_sst_number();
-            if restore_number > 0 {
-                restore_number -= 1;
-                (restore_number, true, manifest.current())
-            } else {
-                let number = manifest.allocate_sst_number();
-                (number, false, manifest.current())
+        // memtables before `number` may be not flushed, their wal are replayed
+        let (number, log_number, sst_version) = info.with_manifest(|manifest| {
+            let number = manifest.allocate_sst_number();
+            (number, manifest.log_number(), manifest.current())
+        });
+        info.with_wal(|wal| {
+            if let Some(wal) = wal {
+                wal.rotate(wal_name(&config, number)).unwrap();
             }
         });
 
         let tables = ArcSwap::new(Arc::new(ColumnFamilyTables {
-            memtable: Arc::new(Memtable::new(number)),
+            memtable: Arc::new(Memtable::new(number, config.comparator.clone())),
             imemtables: Imemtables::default(),
         }));
 
@@ -169,40 +282,27 @@ impl Storage {
             info,
             lock: Mutex::new(()),
             step_version: 0.into(),
-            cache: Cache::new(),
+            cache: Cache::new(&config),
+            write_queue: WriteQueue::default(),
+            lock_manager: LockManager::default(),
         });
         // init compaction thread pool
 
-        let inner2 = inner.clone();
-        let backend = unsafe { std::mem::transmute(inner.info.borrow_backend()) };
-        let minor_pool = MinorCompactionTaskPool::new(&config, backend, move |meta| {
-            inner2.info.with_manifest(|m| {
-                let number = meta.number;
-                m.add_sst_with(meta, true, |current| {
-                    inner2.modify_super_version(move |sv| SuperVersion {
-                        cf_tables: Arc::new(ColumnFamilyTables {
-                            memtable: sv.cf_tables.memtable.clone(),
-                            imemtables: sv.cf_tables.imemtables.remove(number),
-                        }),
-                        sst_version: current,
-                        step_version: sv.step_version + 1,
-                    });
-                })
-            });
-        });
+        let backend = unsafe {
+            std::mem::transmute::<&Backend, &'static Backend>(inner.info.borrow_backend())
+        };
 
         let inner2 = inner.clone();
-        let major_pool =
-            MajorCompactionTaskPool::new(&config, backend, move |additional, removal| {
-                let mut vec = Vec::new();
-                for meta in additional {
-                    vec.push(VersionEdit::SSTAppended(meta));
-                }
-                for seq in removal {
-                    vec.push(VersionEdit::SSTRemove(seq));
-                }
-                inner2.info.with_manifest(|m| {
-                    m.modify_with(vec, |current| {
+        let inner3 = inner.clone();
+        let inner4 = inner.clone();
+        let major_pool = MajorCompactionTaskPool::new(
+            &config,
+            backend,
+            move || inner3.info.with_manifest(|m| m.allocate_sst_number()),
+            move || inner4.info.with_manifest(|m| m.snapshot_versions()),
+            move |edits| {
+                let version = inner2.info.with_manifest(|m| {
+                    m.modify_with(edits, |current| {
                         inner2.modify_super_version(move |sv| SuperVersion {
                             cf_tables: sv.cf_tables.clone(),
                             sst_version: current,
@@ -210,20 +310,66 @@ impl Storage {
                         });
                     })
                 });
-            });
+                inner2.remove_obsolete_files();
+                version
+            },
+        );
+
+        let inner2 = inner.clone();
+        let major_pool2 = major_pool.clone();
+        let minor_pool = MinorCompactionTaskPool::new(&config, backend, move |meta| {
+            let number = meta.number;
+            match inner2.memtable_flushed(meta) {
+                // level 0 grows, try to compact it
+                Ok(version) => major_pool2.notify(&version),
+                // the memtable is kept, its wal is replayed on restart
+                Err(e) => error!("add sst {} fail {:?}", number, e),
+            }
+        });
+
+        let wal_sync_thread = match config.wal_sync {
+            WalSyncPolicy::IntervalMs(ms) if !config.no_wal => {
+                Some(Self::start_wal_sync_thread(inner.clone(), ms))
+            }
+            _ => None,
+        };
 
         let mut this = Self {
             inner,
             minor_pool,
             major_pool,
+            wal_sync_thread,
         };
-        if need_restore {
-            this.restore();
-        }
+        this.restore(log_number, number);
         this
     }
 }
 
+impl Storage {
+    fn start_wal_sync_thread(
+        inner: Arc<StorageInner>,
+        interval_ms: u64,
+    ) -> (mpsc::Sender<()>, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel::<()>();
+        let interval = Duration::from_millis(interval_ms.max(1));
+        let handle = std::thread::Builder::new()
+            .name("wal sync".into())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                    let res = inner.info.with_wal(|wal| match wal {
+                        Some(wal) => wal.sync_dirty(),
+                        None => Ok(()),
+                    });
+                    if let Err(e) = res {
+                        error!("wal sync fail {:?}", e);
+                    }
+                }
+            })
+            .unwrap();
+        (tx, handle)
+    }
+}
+
 impl Storage {
     pub fn get_ex<K: Into<Bytes>>(
         &self,
@@ -232,23 +378,88 @@ impl Storage {
         super_version: &SuperVersion,
         snapshot: Snapshot,
     ) -> Result<Value> {
-        let lifetime = super_version.lifetime();
+        match self.get_entry(opt, key, super_version, snapshot) {
+            Ok((internal_key, _)) if internal_key.key_type() == KeyType::Del => {
+                Err(StorageError::KeyNotExist)
+            }
+            Ok((_, value)) => Ok(value),
+            Err(e) => Err(e),
+        }
+    }
 
+    /// newest visible version of the key, deletion included. merge operands are combined
+    /// with older versions into a value
+    pub(crate) fn get_entry<K: Into<Bytes>>(
+        &self,
+        opt: &GetOption,
+        key: K,
+        super_version: &SuperVersion,
+        snapshot: Snapshot,
+    ) -> Result<(InternalKey, Value)> {
         let key = key.into();
+        let (internal_key, value) = self.get_version(opt, key.clone(), super_version, snapshot)?;
+        if internal_key.key_type() != KeyType::Merge {
+            return Ok((internal_key, value));
+        }
+        let operator = self
+            .inner
+            .info
+            .borrow_config()
+            .merge_operator
+            .clone()
+            .ok_or(StorageError::MergeOperatorNotSet)?;
+
+        // read older versions until the base value
+        let mut operands = vec![value.internal()];
+        let mut base = None;
+        let mut seq = internal_key.seq();
+        while seq > 0 {
+            let older = Snapshot::new(seq - 1);
+            let opt = opt.clone().set_snapshot(older.clone());
+            match self.get_version(&opt, key.clone(), super_version, older) {
+                Ok((older_key, value)) => match older_key.key_type() {
+                    KeyType::Merge => {
+                        operands.push(value.internal());
+                        seq = older_key.seq();
+                    }
+                    KeyType::Set => {
+                        base = Some(value);
+                        break;
+                    }
+                    KeyType::Del | KeyType::RangeDel => break,
+                },
+                Err(StorageError::KeyNotExist) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        operands.reverse();
+        let value = operator.full_merge(&key, base.as_ref().map(|v| v.data()), &operands);
+        Ok((
+            InternalKey::new(&key, internal_key.seq(), KeyType::Set),
+            Value::from(value),
+        ))
+    }
+
+    fn get_version(
+        &self,
+        opt: &GetOption,
+        key: Bytes,
+        super_version: &SuperVersion,
+        snapshot: Snapshot,
+    ) -> Result<(InternalKey, Value)> {
+        let lifetime = super_version.lifetime();
+
         // query from memtable
         match super_version
             .cf_tables
             .memtable
             .get(opt, key.clone(), &lifetime)
         {
-            Ok((internal_key, value)) => {
+            Ok(entry) => {
                 if opt.debug() {
                     info!("find key {:?} in memtable", key);
                 }
-                if internal_key.key_type() == KeyType::Del {
-                    return Err(StorageError::KeyNotExist);
-                }
-                return Ok(value);
+                return Ok(entry);
             }
             Err(e) => {
                 if StorageError::KeyNotExist == e {
@@ -264,14 +475,11 @@ impl Storage {
             .imemtables
             .get(opt, key.clone(), &lifetime)
         {
-            Ok((internal_key, value)) => {
+            Ok(entry) => {
                 if opt.debug() {
                     info!("find key {:?} in imemtables", key);
                 }
-                if internal_key.key_type() == KeyType::Del {
-                    return Err(StorageError::KeyNotExist);
-                }
-                return Ok(value);
+                return Ok(entry);
             }
             Err(e) => {
                 if StorageError::KeyNotExist == e {
@@ -281,7 +489,7 @@ impl Storage {
             }
         }
 
-        match SnapshotTable::new(
+        SnapshotTable::new(
             snapshot,
             super_version.sst_version.clone(),
             &self.inner.cache,
@@ -289,35 +497,112 @@ impl Storage {
         .get(
             opt,
             self.inner.info.borrow_config(),
-            key.clone(),
+            key,
             self.inner.info.borrow_backend(),
             &lifetime,
-        ) {
-            Ok((internal_key, value)) => {
-                if internal_key.key_type() == KeyType::Del {
-                    return Err(StorageError::KeyNotExist);
-                }
-                return Ok(value);
-            }
-            Err(e) => return Err(e),
-        }
+        )
     }
 
     pub fn get<K: Into<Bytes>>(&self, opt: &GetOption, key: K) -> Result<Value> {
-        let inner = self.inner.as_ref();
         let super_version = self.super_version();
-        let snapshot = inner.info.with_manifest(|m| m.snapshot());
+        let snapshot = self.read_snapshot();
         self.get_ex(opt, key, &super_version, snapshot)
     }
 
+    /// values in the order of keys, all keys are read from one super version and snapshot
+    pub fn multi_get<K: Into<Bytes> + Clone>(
+        &self,
+        opt: &GetOption,
+        keys: &[K],
+    ) -> Vec<Result<Value>> {
+        let super_version = self.super_version();
+        let snapshot = self.read_snapshot();
+        self.multi_get_ex(opt, keys, &super_version, snapshot)
+    }
+
+    /// keys are read with the snapshot if the option has no snapshot
+    pub fn multi_get_ex<K: Into<Bytes> + Clone>(
+        &self,
+        opt: &GetOption,
+        keys: &[K],
+        super_version: &SuperVersion,
+        snapshot: Snapshot,
+    ) -> Vec<Result<Value>> {
+        let lifetime = super_version.lifetime();
+        let inner = self.inner.as_ref();
+        let config = inner.info.borrow_config();
+        let opt = match opt.snapshot() {
+            Some(_) => opt.clone(),
+            None => opt.clone().set_snapshot(snapshot.clone()),
+        };
+
+        let keys: Vec<Bytes> = keys.iter().map(|key| key.clone().into()).collect();
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|a, b| config.comparator.compare(&keys[*a], &keys[*b]));
+
+        let mut results: Vec<Option<Result<(InternalKey, Value)>>> =
+            keys.iter().map(|_| None).collect();
+        let found = |res: Result<(InternalKey, Value)>| match res {
+            Err(StorageError::KeyNotExist) => None,
+            res => Some(res),
+        };
+
+        let tables = &super_version.cf_tables;
+        for idx in &order {
+            let key = &keys[*idx];
+            results[*idx] = found(tables.memtable.get(&opt, key.clone(), &lifetime))
+                .or_else(|| found(tables.imemtables.get(&opt, key.clone(), &lifetime)));
+        }
+
+        // pending keys in order of the comparator
+        let pending: Vec<usize> = order
+            .into_iter()
+            .filter(|idx| results[*idx].is_none())
+            .collect();
+        if !pending.is_empty() {
+            let pending_keys: Vec<Bytes> = pending.iter().map(|idx| keys[*idx].clone()).collect();
+            let values = SnapshotTable::new(
+                snapshot.clone(),
+                super_version.sst_version.clone(),
+                &inner.cache,
+            )
+            .multi_get(
+                &opt,
+                config,
+                &pending_keys,
+                inner.info.borrow_backend(),
+                &lifetime,
+            );
+            for (idx, res) in pending.into_iter().zip(values) {
+                results[idx] = found(res);
+            }
+        }
+
+        results
+            .into_iter()
+            .zip(keys)
+            .map(|(res, key)| match res {
+                Some(Ok((internal_key, _))) if internal_key.key_type() == KeyType::Del => {
+                    Err(StorageError::KeyNotExist)
+                }
+                // operands are combined with older versions
+                Some(Ok((internal_key, _))) if internal_key.key_type() == KeyType::Merge => {
+                    self.get_ex(&opt, key, super_version, snapshot.clone())
+                }
+                Some(Ok((_, value))) => Ok(value),
+                Some(Err(e)) => Err(e),
+                None => Err(StorageError::KeyNotExist),
+            })
+            .collect()
+    }
+
     pub fn scan<'a, R: RangeBounds<Bytes> + Clone>(
         &'a self,
         opt: &GetOption,
         range: R,
         super_version: &'a SuperVersion,
     ) -> ScanIter<'a, (Bytes, Value)> {
-        let inner = self.inner.as_ref();
-        let snapshot = inner.info.with_manifest(|m| m.snapshot());
+        let snapshot = self.read_snapshot();
         self.scan_ex(opt, range, super_version, snapshot)
     }
 
@@ -328,31 +613,334 @@ impl Storage {
         super_version: &'a SuperVersion,
         snapshot: Snapshot,
     ) -> ScanIter<'a, (Bytes, Value)> {
+        self.scan_inner(
+            opt,
+            range,
+            super_version,
+            snapshot,
+            false,
+            None,
+            &ScanStatus::default(),
+        )
+    }
+
+    /// scan keys starting with the prefix, sst files are skipped by their prefix filters
+    /// if the prefix is a whole prefix of `Config::prefix_extractor`
+    pub fn scan_prefix<'a, K: Into<Bytes>>(
+        &'a self,
+        opt: &GetOption,
+        prefix: K,
+        super_version: &'a SuperVersion,
+    ) -> ScanIter<'a, (Bytes, Value)> {
+        let snapshot = self.read_snapshot();
+        self.scan_prefix_ex(opt, prefix, super_version, snapshot)
+    }
+
+    pub fn scan_prefix_ex<'a, K: Into<Bytes>>(
+        &self,
+        opt: &GetOption,
+        prefix: K,
+        super_version: &'a SuperVersion,
+        snapshot: Snapshot,
+    ) -> ScanIter<'a, (Bytes, Value)> {
+        let prefix = prefix.into();
+        let config = self.inner.info.borrow_config();
+        // keys of a prefix are adjacent in bytewise order only
+        let range = if config.comparator.name() == BytewiseComparator.name() {
+            let end = match prefix_successor(&prefix) {
+                Some(end) => Bound::Excluded(Bytes::from(end)),
+                None => Bound::Unbounded,
+            };
+            (Bound::Included(prefix.clone()), end)
+        } else {
+            (Bound::Unbounded, Bound::Unbounded)
+        };
+        let filter = config
+            .prefix_extractor
+            .as_ref()
+            .filter(|extractor| extractor.is_prefix(&prefix))
+            .map(|_| &prefix[..]);
+
+        let status = ScanStatus::default();
+        let iter = self.scan_inner(opt, range, super_version, snapshot, false, filter, &status);
+        ScanIter::new(iter.filter(move |(key, _)| key.starts_with(&prefix))).with_status(status)
+    }
+
+    /// scan in reverse order of keys
+    pub fn scan_rev<'a, R: RangeBounds<Bytes> + Clone>(
+        &'a self,
+        opt: &GetOption,
+        range: R,
+        super_version: &'a SuperVersion,
+    ) -> ScanIter<'a, (Bytes, Value)> {
+        let snapshot = self.read_snapshot();
+        self.scan_rev_ex(opt, range, super_version, snapshot)
+    }
+
+    pub fn scan_rev_ex<'a, R: RangeBounds<Bytes> + Clone>(
+        &self,
+        opt: &GetOption,
+        range: R,
+        super_version: &'a SuperVersion,
+        snapshot: Snapshot,
+    ) -> ScanIter<'a, (Bytes, Value)> {
+        self.scan_inner(
+            opt,
+            range,
+            super_version,
+            snapshot,
+            true,
+            None,
+            &ScanStatus::default(),
+        )
+    }
+
+    /// errors of the scan are recorded in `status`, the returned iterator reports it
+    #[allow(clippy::too_many_arguments)]
+    fn scan_inner<'a, R: RangeBounds<Bytes> + Clone>(
+        &self,
+        opt: &GetOption,
+        range: R,
+        super_version: &'a SuperVersion,
+        snapshot: Snapshot,
+        reverse: bool,
+        prefix: Option<&[u8]>,
+        status: &ScanStatus,
+    ) -> ScanIter<'a, (Bytes, Value)> {
+        let tombstones =
+            self.range_tombstones(opt, range.clone(), super_version, &snapshot, status);
+        let iters = self.table_iters(
+            opt,
+            range,
+            super_version,
+            &snapshot,
+            reverse,
+            prefix,
+            status,
+        );
+        self.merge_iters(iters, tombstones, snapshot, reverse, status)
+    }
+
+    /// scan with entries of `overlay` on top of the storage, they are newer than all versions
+    pub(crate) fn scan_overlay<'a, R: RangeBounds<Bytes> + Clone>(
+        &self,
+        opt: &GetOption,
+        range: R,
+        super_version: &'a SuperVersion,
+        snapshot: Snapshot,
+        reverse: bool,
+        overlay: ScanIter<'a, (InternalKey, Value)>,
+    ) -> ScanIter<'a, (Bytes, Value)> {
+        let status = ScanStatus::default();
+        let tombstones =
+            self.range_tombstones(opt, range.clone(), super_version, &snapshot, &status);
+        let mut iters = vec![overlay];
+        iters.extend(self.table_iters(
+            opt,
+            range,
+            super_version,
+            &snapshot,
+            reverse,
+            None,
+            &status,
+        ));
+        self.merge_iters(iters, tombstones, snapshot, reverse, &status)
+    }
+
+    /// visible range tombstones of all tables overlapping with the range
+    fn range_tombstones<R: RangeBounds<Bytes> + Clone>(
+        &self,
+        opt: &GetOption,
+        range: R,
+        super_version: &SuperVersion,
+        snapshot: &Snapshot,
+        status: &ScanStatus,
+    ) -> RangeTombstones {
+        let inner = self.inner.as_ref();
+        let tables = &super_version.cf_tables;
+        let config = inner.info.borrow_config();
+        let sst = SnapshotTable::new(
+            snapshot.clone(),
+            super_version.sst_version.clone(),
+            &inner.cache,
+        );
+        let ver = opt.snapshot().map(|v| v.sequence()).unwrap_or(u64::MAX);
+
+        let mut tombstones = tables.memtable.range_tombstones();
+        tombstones.extend(tables.imemtables.range_tombstones());
+        tombstones.extend(sst.range_tombstones(config, range, inner.info.borrow_backend(), status));
+        tombstones.retain(|t| t.seq <= ver);
+        RangeTombstones::new(tombstones, config.comparator.clone())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn table_iters<'a, R: RangeBounds<Bytes> + Clone>(
+        &self,
+        opt: &GetOption,
+        range: R,
+        super_version: &'a SuperVersion,
+        snapshot: &Snapshot,
+        reverse: bool,
+        prefix: Option<&[u8]>,
+        status: &ScanStatus,
+    ) -> Vec<ScanIter<'a, (InternalKey, Value)>> {
         let mut iters = Vec::new();
         let lifetime = super_version.lifetime();
         let inner = self.inner.as_ref();
-        let tables = inner.tables.load();
+        let tables = &super_version.cf_tables;
+        let config = inner.info.borrow_config();
+        let sst = SnapshotTable::new(
+            snapshot.clone(),
+            super_version.sst_version.clone(),
+            &inner.cache,
+        );
+        let backend = inner.info.borrow_backend();
 
-        iters.push(tables.memtable.scan(opt, range.clone(), &lifetime));
-        iters.push(tables.imemtables.scan(opt, range.clone(), &lifetime));
+        if reverse {
+            iters.push(tables.memtable.scan_rev(opt, range.clone(), &lifetime));
+            iters.push(tables.imemtables.scan_rev(opt, range.clone(), &lifetime));
+            iters.push(sst.scan_rev(opt, config, range, backend, &lifetime, status));
+        } else {
+            iters.push(tables.memtable.scan(opt, range.clone(), &lifetime));
+            iters.push(tables.imemtables.scan(opt, range.clone(), &lifetime));
+            match prefix {
+                Some(prefix) => iters
+                    .push(sst.scan_prefix(opt, config, range, prefix, backend, &lifetime, status)),
+                None => iters.push(sst.scan(opt, config, range, backend, &lifetime, status)),
+            }
+        }
+        iters
+    }
 
-        iters.push(
-            SnapshotTable::new(snapshot, super_version.sst_version.clone(), &inner.cache).scan(
-                opt,
-                inner.info.borrow_config(),
-                range,
-                self.inner.info.borrow_backend(),
-                &lifetime,
-            ),
-        );
+    fn merge_iters<'a>(
+        &self,
+        iters: Vec<ScanIter<'a, (InternalKey, Value)>>,
+        tombstones: RangeTombstones,
+        snapshot: Snapshot,
+        reverse: bool,
+        status: &ScanStatus,
+    ) -> ScanIter<'a, (Bytes, Value)> {
+        let comparator = self.inner.info.borrow_config().comparator.clone();
+        let iter: ScanIter<'a, (InternalKey, Value)> = if reverse {
+            ScanIter::new(MergedIter::new_reverse(iters, comparator))
+        } else {
+            ScanIter::new(MergedIter::new(iters, comparator))
+        };
+        self.fold_versions(iter, Arc::new(tombstones), snapshot, status)
+    }
 
+    /// visible values of merged versions, which are from new to old for each user key
+    pub(crate) fn fold_versions<'a>(
+        &self,
+        iter: ScanIter<'a, (InternalKey, Value)>,
+        tombstones: Arc<RangeTombstones>,
+        snapshot: Snapshot,
+        status: &ScanStatus,
+    ) -> ScanIter<'a, (Bytes, Value)> {
+        let config = self.inner.info.borrow_config();
+        // versions deleted by range tombstones are skipped before merging operands
+        let iter = if tombstones.is_empty() {
+            iter
+        } else {
+            ScanIter::new(
+                iter.filter(move |(key, _)| !tombstones.covered(key.user_key_slice(), key.seq())),
+            )
+        };
+        let iter = MergeFold::new(
+            iter,
+            config.merge_operator.clone(),
+            config.comparator.clone(),
+            status.clone(),
+        );
         ScanIter::<'a, (Bytes, Value)>::new(
-            MergedIter::new(iters)
-                .filter(|(key, _)| key.key_type() != KeyType::Del)
-                .map(|(key, value)| (key.user_key(), value)),
+            iter.filter(|(key, _)| key.key_type() != KeyType::Del)
+                .map(move |(key, value)| {
+                    // the snapshot is released after the iterator
+                    let _ = &snapshot;
+                    (key.user_key(), value)
+                }),
+        )
+        .with_status(status.clone())
+    }
+
+    /// cursor over a snapshot of the storage, the snapshot of option is used if it is set
+    pub fn cursor<'a>(&'a self, opt: &GetOption, super_version: &'a SuperVersion) -> Cursor<'a> {
+        let snapshot = match opt.snapshot() {
+            Some(snapshot) => snapshot.clone(),
+            None => self.snapshot(),
+        };
+        let opt =
+            GetOption::with_snapshot(snapshot.clone()).set_skip_checksum(!opt.verify_checksum());
+        let status = ScanStatus::default();
+        let tombstones = self.range_tombstones(
+            &opt,
+            (Bound::<Bytes>::Unbounded, Bound::Unbounded),
+            super_version,
+            &snapshot,
+            &status,
+        );
+        let comparator = self.inner.info.borrow_config().comparator.clone();
+        let seek = {
+            let (snapshot, status) = (snapshot.clone(), status.clone());
+            move |range: (Bound<Bytes>, Bound<Bytes>), reverse| {
+                self.table_iters(
+                    &opt,
+                    range,
+                    super_version,
+                    &snapshot,
+                    reverse,
+                    None,
+                    &status,
+                )
+            }
+        };
+        let merged = MergedIter::new_seekable(Box::new(seek), comparator);
+        Cursor::new(self, merged, Arc::new(tombstones), snapshot, status)
+    }
+
+    /// optimistic transaction, it is rolled back if it is dropped without commit
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// write batch readable before commit, ordered by the comparator of the storage
+    pub fn indexed_batch(&self) -> IndexedWriteBatch {
+        IndexedWriteBatch::new(
+            WriteOption::default(),
+            self.inner.info.borrow_config().comparator.clone(),
         )
     }
 
+    /// begin a pessimistic transaction, keys written or read for update are locked until it ends
+    pub fn begin(&self) -> PessimisticTransaction<'_> {
+        PessimisticTransaction::new(self, self.inner.lock_manager.new_txn_id())
+    }
+
+    pub(crate) fn lock_key(&self, txn: u64, key: &Bytes) -> Result<()> {
+        let timeout = Duration::from_millis(self.inner.info.borrow_config().lock_timeout_ms);
+        self.inner.lock_manager.lock(txn, key, timeout)
+    }
+
+    pub(crate) fn unlock_keys<'a, I: IntoIterator<Item = &'a Bytes>>(&self, txn: u64, keys: I) {
+        self.inner.lock_manager.unlock(txn, keys)
+    }
+
+    /// snapshot of the latest write, it is released when all its clones are dropped,
+    /// versions visible to live snapshots are kept by compaction
+    pub fn snapshot(&self) -> Snapshot {
+        let inner = self.inner.clone();
+        let seq = inner.info.with_manifest(|m| m.snapshot()).sequence();
+        Snapshot::with_release(seq, move |seq| {
+            inner.info.with_manifest(|m| m.release_snapshot(seq))
+        })
+    }
+
+    /// sequence of the latest write for a read without snapshot, it is not registered.
+    /// files of the read are kept by its super version
+    pub(crate) fn read_snapshot(&self) -> Snapshot {
+        Snapshot::new(self.inner.info.with_manifest(|m| m.last_seq()))
+    }
+
     pub fn set<K: AsRef<[u8]>, V: AsRef<[u8]>>(
         &self,
         opt: &WriteOption,
@@ -372,25 +960,93 @@ impl Storage {
         self.set_batch(opt, batch.build())
     }
 
+    /// delete all keys in [start, end) by one range tombstone, keys are removed from sst files
+    /// by compaction
+    pub fn delete_range<K: AsRef<[u8]>, E: AsRef<[u8]>>(
+        &self,
+        opt: &WriteOption,
+        start: K,
+        end: E,
+    ) -> Result<u64> {
+        let mut batch = WriteBatchBuilder::default();
+        batch.delete_range(start, end)?;
+
+        self.set_batch(opt, batch.build())
+    }
+
+    /// write a merge operand of the key without reading it, operands are combined with
+    /// the value by `Config::merge_operator` when they are read or compacted
+    pub fn merge<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        opt: &WriteOption,
+        key: K,
+        operand: V,
+    ) -> Result<u64> {
+        if self.inner.info.borrow_config().merge_operator.is_none() {
+            return Err(StorageError::MergeOperatorNotSet);
+        }
+        let mut batch = WriteBatchBuilder::default();
+        batch.merge(key, operand)?;
+
+        self.set_batch(opt, batch.build())
+    }
+
     pub fn set_batch(&self, opt: &WriteOption, batch: WriteBatch) -> Result<u64> {
+        let inner = self.inner.as_ref();
+        // concurrent batches are merged by the leader, they are written to wal with one sync
+        inner
+            .write_queue
+            .write(opt, batch, |batch, sync| self.write_group(batch, sync))
+    }
+
+    /// the batch is written if no tracked key has a version newer than its sequence,
+    /// otherwise `StorageError::Conflict` is returned
+    pub(crate) fn set_batch_checked(
+        &self,
+        opt: &WriteOption,
+        batch: WriteBatch,
+        tracked: Vec<(Bytes, u64)>,
+    ) -> Result<u64> {
+        let inner = self.inner.clone();
+        let check = move || {
+            for (key, seq) in &tracked {
+                if inner.latest_seq(key)?.is_some_and(|latest| latest > *seq) {
+                    return Err(StorageError::Conflict);
+                }
+            }
+            Ok(())
+        };
+        self.inner
+            .write_queue
+            .write_checked(opt, batch, check, |batch, sync| {
+                self.write_group(batch, sync)
+            })
+    }
+
+    fn write_group(&self, mut batch: WriteBatch, sync: bool) -> Result<u64> {
         let inner = self.inner.as_ref();
         let tables = inner.tables.load();
 
-        let cur_seq = inner
-            .info
-            .with_manifest(|m| m.allocate_seq(batch.count() as u64));
+        let count = batch.count() as u64;
+        let cur_seq = inner.info.with_manifest(|m| m.allocate_seq(count));
+        batch.set_seq(cur_seq);
 
         inner.info.with_wal(|wal| -> Result<()> {
             if let Some(wal) = &wal {
                 wal.append(&batch)?;
-                if opt.fsync() {
-                    wal.sync()?;
+                // the policy may have synced it in append
+                if sync {
+                    wal.sync_dirty()?;
                 }
             }
             Ok(())
         })?;
 
         tables.memtable.set_batch(batch, cur_seq)?;
+        // the batch is visible to reads once it's in the memtable
+        inner
+            .info
+            .with_manifest(|m| m.publish_seq(cur_seq + count - 1));
         if tables.memtable.full() {
             self.flush_memtable();
         }
@@ -400,31 +1056,74 @@ impl Storage {
     pub fn super_version(&self) -> Arc<SuperVersion> {
         self.inner.super_version()
     }
+
+    pub fn stats(&self) -> Statistics {
+        let block_cache = self.inner.cache.block_cache();
+        let (table_cache_usage, table_cache_capacity) = self.inner.cache.opened_sst_usage();
+        Statistics {
+            block_cache_hits: block_cache.hits(),
+            block_cache_misses: block_cache.misses(),
+            block_cache_usage: block_cache.usage(),
+            block_cache_capacity: block_cache.capacity(),
+            table_cache_usage,
+            table_cache_capacity,
+        }
+    }
 }
 
 impl Storage {
-    fn restore(&mut self) {
-        let config = self.inner.info.borrow_config();
-        let tables = self.inner.tables.load();
-        let replayer = LogReplayer::new(self.inner.info.borrow_backend(), BatchLogSerializer);
-        let iter = match replayer.iter(wal_name(config, tables.memtable.number())) {
-            Ok(e) => e,
-            Err(e) => {
-                if e.is_io_not_found() {
-                    return;
-                } else {
-                    panic!("{:?}", e);
+    /// replay wal of memtables in [log_number, active_number), each wal is replayed into
+    /// its own imemtable with the original sequences, then flushed
+    fn restore(&mut self, log_number: u64, active_number: u64) {
+        let inner = self.inner.as_ref();
+        let config = inner.info.borrow_config();
+        let backend = inner.info.borrow_backend();
+        let replayer = LogReplayer::new(backend, BatchLogSerializer);
+
+        for number in log_number..active_number {
+            let iter = match replayer.iter(wal_name(config, number)) {
+                Ok(e) => e,
+                Err(e) => {
+                    if e.is_io_not_found() {
+                        continue;
+                    } else {
+                        panic!("{:?}", e);
+                    }
                 }
+            };
+
+            let memtable = Arc::new(Memtable::new(number, config.comparator.clone()));
+            let mut max_seq = 0;
+            for batch in iter {
+                let batch = match batch {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        // tail of wal is not completely written
+                        error!("wal {} replay stopped {:?}", number, e);
+                        break;
+                    }
+                };
+                let seq = batch.seq();
+                max_seq = max_seq.max(seq + batch.count() as u64);
+                memtable.set_batch(batch, seq).unwrap();
             }
-        };
+            inner.info.with_manifest(|m| m.set_latest_seq(max_seq));
+            info!("wal {} restore to seq {}", number, max_seq);
 
-        for batch in iter {
-            self.set_batch(&WriteOption::default(), batch.unwrap())
-                .unwrap();
+            if memtable.is_empty() {
+                let _ = backend.fs.remove(&wal_name(config, number));
+                continue;
+            }
+            inner.modify_super_version(|sv| SuperVersion {
+                cf_tables: Arc::new(ColumnFamilyTables {
+                    memtable: sv.cf_tables.memtable.clone(),
+                    imemtables: sv.cf_tables.imemtables.push(memtable.clone()),
+                }),
+                sst_version: sv.sst_version.clone(),
+                step_version: sv.step_version + 1,
+            });
+            self.minor_pool.compact_async(memtable);
         }
-
-        info!("wal restore from {}", tables.memtable.max_seq());
-        self.flush_memtable();
     }
 
     /// flush memtable into imemtable
@@ -434,7 +1133,18 @@ impl Storage {
             let old_table = sv.cf_tables.memtable.clone();
             if !old_table.is_empty() {
                 let new_number = inner.info.with_manifest(|m| m.allocate_sst_number());
-                let memtable = Arc::new(Memtable::new(new_number));
+                let memtable = Arc::new(Memtable::new(
+                    new_number,
+                    inner.info.borrow_config().comparator.clone(),
+                ));
+                // wal of old memtable is kept until it's flushed
+                let rotated = inner.info.with_wal(|wal| match wal {
+                    Some(wal) => wal.rotate(wal_name(inner.info.borrow_config(), new_number)),
+                    None => Ok(()),
+                });
+                if let Err(e) = rotated {
+                    error!("rotate wal {} fail {:?}", new_number, e);
+                }
                 let current = sv.sst_version.clone();
                 self.minor_pool.compact_async(old_table.clone());
 
@@ -452,7 +1162,7 @@ impl Storage {
         });
     }
 
-    pub(crate) fn flush_wait_imemtables(&mut self) {
+    pub(crate) fn flush_wait_imemtables(&self) {
         let inner = self.inner.as_ref();
         loop {
             if inner.tables.load().imemtables.empty() {
@@ -470,6 +1180,12 @@ impl Storage {
         self.flush_wait_imemtables();
 
         self.minor_pool.stop();
+        self.major_pool.stop();
+        if let Some((tx, handle)) = self.wal_sync_thread.take() {
+            drop(tx);
+            let _ = handle.join();
+        }
+        self.inner.remove_obsolete_files();
         let e = self.inner.info.with_manifest(|m| m.flush());
         if let Err(e) = e {
             error!("flush {}", e);
@@ -484,3 +1200,971 @@ impl Drop for Storage {
         self.shutdown();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::backend::fs::local::LocalFileBasedPersistBackend;
+
+    use super::*;
+
+    fn open(config: &Config) -> Storage {
+        Storage::new(config.clone(), Backend::new(LocalFileBasedPersistBackend))
+    }
+
+    #[test]
+    pub fn restore_from_wal() {
+        let mut config = crate::config::test_config();
+        config.path = config.path.join("nanokv_restore_from_wal");
+        config.no_wal = false;
+        config.wal_sync = WalSyncPolicy::IntervalMs(10);
+        let _ = std::fs::remove_dir_all(&config.path);
+
+        for round in 0..3 {
+            let storage = open(&config);
+            // the first memtable is flushed, the second stays in wal only
+            for i in 0..20000 {
+                let key = format!("key{}_{}", round, i);
+                storage.set(&WriteOption::default(), key, "value").unwrap();
+            }
+            storage.del(&WriteOption::default(), "key0_1").unwrap();
+
+            for r in 0..=round {
+                let key = format!("key{}_{}", r, 19999);
+                assert_eq!(
+                    storage.get(&GetOption::default(), key).unwrap().data(),
+                    b"value"
+                );
+            }
+            assert!(storage.get(&GetOption::default(), "key0_1").is_err());
+            if round == 1 {
+                // crash without flushing the active memtable
+                storage.flush_wait_imemtables();
+                std::mem::forget(storage);
+            }
+        }
+        let _ = std::fs::remove_dir_all(&config.path);
+    }
+
+    #[test]
+    pub fn imemtables_flushed_out_of_order() {
+        use crate::kv::sst::{format::FormatWriter, SSTWriter};
+
+        let mut config = crate::config::test_config();
+        config.path = config.path.join("nanokv_imemtables_flushed_out_of_order");
+        config.no_wal = false;
+        config.wal_sync = WalSyncPolicy::IntervalMs(10);
+        let _ = std::fs::remove_dir_all(&config.path);
+
+        // rotate the memtable as `flush_memtable` without flushing it
+        let rotate = |storage: &Storage| -> Arc<Memtable> {
+            let inner = storage.inner.as_ref();
+            let old = inner.tables.load().memtable.clone();
+            let number = inner.info.with_manifest(|m| m.allocate_sst_number());
+            inner
+                .info
+                .with_wal(|wal| match wal {
+                    Some(wal) => wal.rotate(wal_name(&config, number)),
+                    None => Ok(()),
+                })
+                .unwrap();
+            inner.modify_super_version(|sv| SuperVersion {
+                cf_tables: Arc::new(ColumnFamilyTables {
+                    memtable: Arc::new(Memtable::new(number, config.comparator.clone())),
+                    imemtables: sv.cf_tables.imemtables.push(old.clone()),
+                }),
+                sst_version: sv.sst_version.clone(),
+                step_version: sv.step_version + 1,
+            });
+            old
+        };
+        let flush = |storage: &Storage, table: &Memtable| -> u64 {
+            let inner = storage.inner.as_ref();
+            let name = sst_name(&config, table.number());
+            let mut writer = FormatWriter::new(&config, inner.info.borrow_backend(), name, 0);
+            let meta = writer.write(0, table.number(), table.entries()).unwrap();
+            inner.memtable_flushed(meta).unwrap();
+            inner.info.with_manifest(|m| m.log_number())
+        };
+
+        let storage = open(&config);
+        storage.set(&WriteOption::default(), "a", "1").unwrap();
+        let first = rotate(&storage);
+        storage.set(&WriteOption::default(), "b", "2").unwrap();
+        let second = rotate(&storage);
+        storage.set(&WriteOption::default(), "c", "3").unwrap();
+        let active = storage.inner.tables.load().memtable.number();
+
+        // wal of the first imemtable is replayed until it's flushed
+        assert_eq!(flush(&storage, &second), first.number());
+        assert_eq!(flush(&storage, &first), active);
+        storage
+            .inner
+            .info
+            .with_manifest(|m| m.set_log_number(first.number()))
+            .unwrap();
+        assert_eq!(storage.inner.info.with_manifest(|m| m.log_number()), active);
+
+        // crash, the active memtable is restored from its wal
+        std::mem::forget(storage);
+        let storage = open(&config);
+        for (key, value) in [("a", b"1"), ("b", b"2"), ("c", b"3")] {
+            assert_eq!(
+                storage.get(&GetOption::default(), key).unwrap().data(),
+                value
+            );
+        }
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&config.path);
+    }
+
+    #[test]
+    pub fn snapshot_release() {
+        let mut config = crate::config::test_config();
+        config.path = config.path.join("nanokv_snapshot_release");
+        let _ = std::fs::remove_dir_all(&config.path);
+
+        let storage = open(&config);
+        let snapshot_versions = |storage: &Storage| {
+            storage
+                .inner
+                .info
+                .with_manifest(|m| (m.snapshot_versions(), m.oldest_snapshot_version()))
+        };
+        for i in 0..100 {
+            storage
+                .set(&WriteOption::default(), format!("key{:03}", i), "old")
+                .unwrap();
+        }
+
+        // reads without snapshot register none, the cursor releases its snapshot
+        let su_version = storage.super_version();
+        for i in 0..100 {
+            storage
+                .get(&GetOption::default(), format!("key{:03}", i))
+                .unwrap();
+        }
+        storage.multi_get(&GetOption::default(), &["key001", "key002"]);
+        assert_eq!(
+            storage.scan(&GetOption::default(), .., &su_version).count(),
+            100
+        );
+        let mut cursor = storage.cursor(&GetOption::default(), &su_version);
+        cursor.seek_to_first();
+        assert!(cursor.valid());
+        assert_eq!(snapshot_versions(&storage).0.len(), 1);
+        drop(cursor);
+        drop(su_version);
+        assert_eq!(snapshot_versions(&storage), (vec![], u64::MAX));
+
+        let snapshot = storage.snapshot();
+        let seq = snapshot.sequence();
+        assert_eq!(snapshot_versions(&storage), (vec![seq], seq));
+        // clones keep the snapshot alive
+        let opt = GetOption::default().set_snapshot(snapshot.clone());
+        drop(snapshot);
+        assert_eq!(snapshot_versions(&storage), (vec![seq], seq));
+
+        for i in 0..100 {
+            storage
+                .set(&WriteOption::default(), format!("key{:03}", i), "new")
+                .unwrap();
+        }
+        storage.flush_memtable();
+        storage.flush_wait_imemtables();
+        assert_eq!(storage.get(&opt, "key050").unwrap().data(), b"old");
+        assert_eq!(
+            storage.get(&GetOption::default(), "key050").unwrap().data(),
+            b"new"
+        );
+        let su_version = storage.super_version();
+        let values: Vec<_> = storage
+            .scan(&opt, .., &su_version)
+            .map(|(_, value)| value)
+            .collect();
+        assert!(values.iter().all(|value| value.data() == b"old"));
+        drop(su_version);
+
+        drop(opt);
+        assert_eq!(snapshot_versions(&storage), (vec![], u64::MAX));
+
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&config.path);
+    }
+
+    #[test]
+    pub fn binary_keys() {
+        use crate::kv::sst::format::Format;
+
+        for format in [Format::RawSST, Format::BlockSST] {
+            let mut config = crate::config::test_config();
+            config.path = config.path.join(format!("nanokv_binary_keys_{:?}", format));
+            config.sst_format = format;
+            let _ = std::fs::remove_dir_all(&config.path);
+
+            // big endian integers and keys with 0x00 and 0xff bytes
+            let mut keys: Vec<Vec<u8>> = (0u32..20000)
+                .map(|i| (i * 3).to_be_bytes().to_vec())
+                .collect();
+            keys.extend([
+                vec![],
+                vec![0x00],
+                vec![0xff],
+                vec![0x00, 0x00, 0xff],
+                vec![0xff, 0xff, 0x00],
+                vec![0xff; 8],
+            ]);
+            let storage = open(&config);
+            for key in &keys {
+                storage.set(&WriteOption::default(), key, key).unwrap();
+            }
+            // read from sst
+            storage.flush_memtable();
+            storage.flush_wait_imemtables();
+
+            for key in &keys {
+                let value = storage
+                    .get(&GetOption::default(), key.clone())
+                    .unwrap_or_else(|e| panic!("{:?} {:?}", key, e));
+                assert_eq!(value.data(), &key[..]);
+            }
+            for i in 0u32..100 {
+                let key = (i * 3 + 1).to_be_bytes().to_vec();
+                assert!(storage.get(&GetOption::default(), key).is_err());
+            }
+            if format == Format::BlockSST {
+                assert!(storage.stats().block_cache_hits > 0);
+            }
+
+            let mut sorted = keys.clone();
+            sorted.sort();
+            sorted.dedup();
+            let su_version = storage.super_version();
+            let scanned: Vec<_> = storage
+                .scan(&GetOption::default(), .., &su_version)
+                .map(|(key, _)| key.to_vec())
+                .collect();
+            assert_eq!(scanned, sorted);
+
+            let beg = Bytes::from(vec![0x00, 0x00]);
+            let end = Bytes::from(vec![0x00, 0x00, 0xff]);
+            let scanned: Vec<_> = storage
+                .scan(&GetOption::default(), beg..=end, &su_version)
+                .map(|(key, _)| key.to_vec())
+                .collect();
+            let expected: Vec<_> = sorted
+                .iter()
+                .filter(|k| k[..] >= [0x00, 0x00][..] && k[..] <= [0x00, 0x00, 0xff][..])
+                .cloned()
+                .collect();
+            assert_eq!(scanned, expected);
+            drop(su_version);
+            drop(storage);
+            let _ = std::fs::remove_dir_all(&config.path);
+        }
+    }
+
+    #[test]
+    pub fn cursor() {
+        use crate::kv::sst::format::Format;
+
+        for format in [Format::RawSST, Format::BlockSST] {
+            let mut config = crate::config::test_config();
+            config.path = config.path.join(format!("nanokv_cursor_{:?}", format));
+            config.sst_format = format;
+            let _ = std::fs::remove_dir_all(&config.path);
+
+            let key = |i: u32| format!("key{:05}", i);
+            let storage = open(&config);
+            // even keys in sst
+            for i in (0..20000).step_by(2) {
+                storage.set(&WriteOption::default(), key(i), "sst").unwrap();
+            }
+            storage.flush_memtable();
+            storage.flush_wait_imemtables();
+            // overwrite and delete some of them in imemtables
+            for i in (0..20000).step_by(10) {
+                storage.set(&WriteOption::default(), key(i), "imm").unwrap();
+            }
+            for i in (0..20000).step_by(6) {
+                storage.del(&WriteOption::default(), key(i)).unwrap();
+            }
+            storage.flush_memtable();
+            // odd keys in memtable
+            for i in (1..20000).step_by(4) {
+                storage.set(&WriteOption::default(), key(i), "mem").unwrap();
+            }
+
+            let mut expected: Vec<(String, &str)> = (0..20000)
+                .filter_map(|i| match i {
+                    i if i % 4 == 1 => Some((key(i), "mem")),
+                    i if i % 6 == 0 => None,
+                    i if i % 10 == 0 => Some((key(i), "imm")),
+                    i if i % 2 == 0 => Some((key(i), "sst")),
+                    _ => None,
+                })
+                .collect();
+
+            let su_version = storage.super_version();
+            let mut cursor = storage.cursor(&GetOption::default(), &su_version);
+            // later writes are not visible
+            storage.set(&WriteOption::default(), key(3), "new").unwrap();
+
+            let mut scanned = Vec::new();
+            cursor.seek_to_first();
+            while cursor.valid() {
+                scanned.push((
+                    cursor.key().clone(),
+                    Bytes::copy_from_slice(cursor.value().data()),
+                ));
+                cursor.next();
+            }
+            let to_bytes = |v: &[(String, &str)]| -> Vec<(Bytes, Bytes)> {
+                v.iter()
+                    .map(|(k, v)| (Bytes::from(k.clone()), Bytes::from(v.to_string())))
+                    .collect()
+            };
+            assert_eq!(scanned, to_bytes(&expected));
+
+            scanned.clear();
+            cursor.seek_to_last();
+            while cursor.valid() {
+                scanned.push((
+                    cursor.key().clone(),
+                    Bytes::copy_from_slice(cursor.value().data()),
+                ));
+                cursor.prev();
+            }
+            expected.reverse();
+            assert_eq!(scanned, to_bytes(&expected));
+
+            // 11 is not in storage, 12 is deleted
+            cursor.seek(key(11));
+            assert_eq!(cursor.key(), &key(13));
+            cursor.next();
+            assert_eq!(cursor.key(), &key(14));
+            assert_eq!(cursor.value().data(), b"sst");
+            cursor.prev();
+            assert_eq!(cursor.key(), &key(13));
+            cursor.prev();
+            assert_eq!(cursor.key(), &key(10));
+            assert_eq!(cursor.value().data(), b"imm");
+            cursor.next();
+            assert_eq!(cursor.key(), &key(13));
+
+            cursor.seek_for_prev(key(12));
+            assert_eq!(cursor.key(), &key(10));
+            cursor.seek_for_prev(key(9));
+            assert_eq!(cursor.key(), &key(9));
+
+            // latest 3 keys before a key
+            cursor.seek_for_prev(key(19999));
+            let mut latest = Vec::new();
+            for _ in 0..3 {
+                latest.push(cursor.key().clone());
+                cursor.prev();
+            }
+            assert_eq!(latest, vec![key(19997), key(19996), key(19994)]);
+
+            cursor.seek(key(20000));
+            assert!(!cursor.valid());
+            cursor.seek_for_prev("a");
+            assert!(!cursor.valid());
+            cursor.seek_to_first();
+            cursor.prev();
+            assert!(!cursor.valid());
+
+            // zigzag over keys from the memtable, imemtables and sst, each step switches direction
+            expected.reverse();
+            for start in [0, expected.len() - 300] {
+                cursor.seek(expected[start].0.clone());
+                for pos in start..start + 298 {
+                    cursor.next();
+                    assert_eq!(cursor.key(), &expected[pos + 1].0);
+                    cursor.next();
+                    assert_eq!(cursor.key(), &expected[pos + 2].0);
+                    cursor.prev();
+                    assert_eq!(cursor.key(), &expected[pos + 1].0);
+                    assert_eq!(cursor.value().data(), expected[pos + 1].1.as_bytes());
+                }
+            }
+            cursor.seek_to_last();
+            cursor.prev();
+            cursor.next();
+            assert_eq!(cursor.key(), &expected[expected.len() - 1].0);
+            cursor.next();
+            assert!(!cursor.valid());
+            assert!(cursor.status().is_ok());
+
+            drop(cursor);
+            // without snapshot, the later write is visible
+            let scanned: Vec<_> = storage
+                .scan_rev(
+                    &GetOption::default(),
+                    Bytes::from(key(1))..Bytes::from(key(6)),
+                    &su_version,
+                )
+                .map(|(key, _)| key)
+                .collect();
+            assert_eq!(scanned, vec![key(5), key(4), key(3), key(2), key(1)]);
+
+            drop(su_version);
+            drop(storage);
+            let _ = std::fs::remove_dir_all(&config.path);
+        }
+    }
+
+    #[test]
+    pub fn multi_get() {
+        use crate::kv::sst::format::Format;
+        use rand::seq::SliceRandom;
+
+        for format in [Format::RawSST, Format::BlockSST] {
+            let mut config = crate::config::test_config();
+            config.path = config.path.join(format!("nanokv_multi_get_{:?}", format));
+            config.sst_format = format;
+            let _ = std::fs::remove_dir_all(&config.path);
+
+            let key = |i: u32| format!("key{:05}", i);
+            let storage = open(&config);
+            // several sst files, then imemtables and memtable
+            for part in 0..3 {
+                for i in (part..3000).step_by(3) {
+                    storage.set(&WriteOption::default(), key(i), "sst").unwrap();
+                }
+                storage.flush_memtable();
+                storage.flush_wait_imemtables();
+            }
+            for i in (0..3000).step_by(5) {
+                storage.set(&WriteOption::default(), key(i), "imm").unwrap();
+            }
+            for i in (0..3000).step_by(7) {
+                storage.del(&WriteOption::default(), key(i)).unwrap();
+            }
+            storage.flush_memtable();
+            let seq = storage.set(&WriteOption::default(), key(1), "mem").unwrap();
+            storage.set(&WriteOption::default(), key(2), "mem").unwrap();
+
+            // unsorted, duplicated and missing keys
+            let mut keys: Vec<String> = (0..3200).map(key).collect();
+            keys.extend((0..100).map(key));
+            keys.shuffle(&mut rand::thread_rng());
+
+            for opt in [GetOption::default(), GetOption::with_snapshot(seq)] {
+                let values = storage.multi_get(&opt, &keys);
+                assert_eq!(values.len(), keys.len());
+                for (key, value) in keys.iter().zip(values) {
+                    match storage.get(&opt, key.clone()) {
+                        Ok(expected) => assert_eq!(value.unwrap().data(), expected.data()),
+                        Err(e) => assert_eq!(value.unwrap_err(), e),
+                    }
+                }
+            }
+            let values = storage.multi_get(&GetOption::with_snapshot(seq), &[key(1), key(2)]);
+            assert_eq!(values[0].as_ref().unwrap().data(), b"mem");
+            assert_eq!(values[1].as_ref().unwrap().data(), b"sst");
+            let values = storage.multi_get(&GetOption::default(), &[key(7), key(3000)]);
+            assert!(values
+                .iter()
+                .all(|v| v.as_ref().err() == Some(&StorageError::KeyNotExist)));
+
+            drop(storage);
+            let _ = std::fs::remove_dir_all(&config.path);
+        }
+    }
+
+    #[test]
+    pub fn scan_corrupted_sst() {
+        use crate::kv::sst::format::Format;
+
+        for format in [Format::RawSST, Format::BlockSST] {
+            let mut config = crate::config::test_config();
+            config.path = config
+                .path
+                .join(format!("nanokv_scan_corrupted_sst_{:?}", format));
+            config.sst_format = format;
+            let _ = std::fs::remove_dir_all(&config.path);
+
+            let key = |i: u32| format!("key{:05}", i);
+            let storage = open(&config);
+            for i in 0..2000 {
+                storage
+                    .set(&WriteOption::default(), key(i), "value")
+                    .unwrap();
+            }
+            storage.flush_memtable();
+            storage.flush_wait_imemtables();
+            drop(storage);
+
+            // flip a byte in the middle of the only sst
+            let files = std::fs::read_dir(crate::util::fname::sst_dir(&config))
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .filter(|p| p.extension().is_some_and(|e| e == "sst"))
+                .collect::<Vec<_>>();
+            assert_eq!(files.len(), 1);
+            let number: u64 = files[0]
+                .file_stem()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut data = std::fs::read(&files[0]).unwrap();
+            let mid = data.len() / 2;
+            data[mid] ^= 0xff;
+            std::fs::write(&files[0], data).unwrap();
+
+            let storage = open(&config);
+            let opt = GetOption::default();
+            {
+                let su_version = storage.super_version();
+                let mut iter = storage.scan(&opt, .., &su_version);
+                assert!(iter.by_ref().count() < 2000);
+                assert_eq!(iter.status(), Err(StorageError::SSTDataCorrupt(number)));
+
+                let mut iter = storage.scan_rev(&opt, .., &su_version);
+                assert!(iter.by_ref().count() < 2000);
+                assert_eq!(iter.status(), Err(StorageError::SSTDataCorrupt(number)));
+
+                let mut cursor = storage.cursor(&opt, &su_version);
+                cursor.seek_to_first();
+                while cursor.valid() {
+                    cursor.next();
+                }
+                assert_eq!(cursor.status(), Err(StorageError::SSTDataCorrupt(number)));
+            }
+
+            // a range only in the memtable is ok
+            storage.set(&WriteOption::default(), "z", "value").unwrap();
+            {
+                let su_version = storage.super_version();
+                let mut iter = storage.scan(&opt, Bytes::from("z").., &su_version);
+                assert_eq!(iter.by_ref().count(), 1);
+                assert!(iter.status().is_ok());
+            }
+
+            drop(storage);
+            let _ = std::fs::remove_dir_all(&config.path);
+        }
+    }
+
+    #[test]
+    pub fn bloom_statistics() {
+        use crate::kv::sst::format::Format;
+
+        for format in [Format::RawSST, Format::BlockSST] {
+            for bits in [0, 10] {
+                let mut config = crate::config::test_config();
+                config.path = config
+                    .path
+                    .join(format!("nanokv_bloom_statistics_{:?}_{}", format, bits));
+                config.sst_format = format;
+                config.bloom_bits_per_key = bits;
+                let _ = std::fs::remove_dir_all(&config.path);
+
+                let key = |i: u32| format!("key{:05}", i);
+                let wopt = WriteOption::default();
+                let opt = GetOption::default();
+                let storage = open(&config);
+                for i in (0..2000).step_by(2) {
+                    storage.set(&wopt, key(i), "v").unwrap();
+                }
+                storage.flush_memtable();
+                storage.flush_wait_imemtables();
+
+                // (useful, false positive) of all files
+                let counts = || {
+                    let su_version = storage.super_version();
+                    let mut counts = (0, 0);
+                    for level in 0..crate::kv::manifest::MAX_LEVEL {
+                        for run in su_version.sst_version.level_n(level) {
+                            for fs in run.files() {
+                                counts.0 += fs.bloom_useful_count();
+                                counts.1 += fs.bloom_false_positive_count();
+                            }
+                        }
+                    }
+                    counts
+                };
+
+                for i in (0..2000).step_by(2) {
+                    assert!(storage.get(&opt, key(i)).is_ok());
+                }
+                assert_eq!(counts(), (0, 0));
+
+                // missing keys in the key range of the file
+                for i in (1..1998).step_by(2) {
+                    assert!(storage.get(&opt, key(i)).is_err());
+                }
+                let (useful, false_positive) = counts();
+                if bits == 0 {
+                    // no filter is consulted
+                    assert_eq!((useful, false_positive), (0, 0));
+                } else {
+                    assert_eq!(useful + false_positive, 999);
+                    assert!(useful > 900);
+                }
+
+                // keys covered by a range tombstone are not filtered
+                storage.delete_range(&wopt, key(0), key(2000)).unwrap();
+                storage.flush_memtable();
+                storage.flush_wait_imemtables();
+                let before = counts();
+                for i in 0..2000 {
+                    assert!(storage.get(&opt, key(i)).is_err());
+                }
+                assert_eq!(counts(), before);
+
+                drop(storage);
+                let _ = std::fs::remove_dir_all(&config.path);
+            }
+        }
+    }
+
+    #[test]
+    pub fn merge() {
+        use crate::merge::{MergeOperatorRef, UInt64AddOperator};
+
+        let mut config = crate::config::test_config();
+        config.path = config.path.join("nanokv_merge");
+        let _ = std::fs::remove_dir_all(&config.path);
+
+        let storage = open(&config);
+        assert_eq!(
+            storage.merge(&WriteOption::default(), "a", "1"),
+            Err(StorageError::MergeOperatorNotSet)
+        );
+        drop(storage);
+
+        config.merge_operator = Some(MergeOperatorRef::new(UInt64AddOperator));
+        let storage = open(&config);
+        let wopt = WriteOption::default();
+        let n = |v: u64| v.to_le_bytes();
+        let value = |v: Result<Value>| u64::from_le_bytes(v.unwrap().data().try_into().unwrap());
+
+        // operands in sst, imemtables and memtable
+        storage.set(&wopt, "a", n(10)).unwrap();
+        storage.merge(&wopt, "a", n(1)).unwrap();
+        storage.merge(&wopt, "b", n(1)).unwrap();
+        storage.set(&wopt, "c", n(10)).unwrap();
+        storage.flush_memtable();
+        storage.flush_wait_imemtables();
+        storage.merge(&wopt, "a", n(2)).unwrap();
+        storage.merge(&wopt, "b", n(2)).unwrap();
+        storage.del(&wopt, "c").unwrap();
+        storage.flush_memtable();
+        let snapshot = storage.snapshot();
+        storage.merge(&wopt, "a", n(3)).unwrap();
+        storage.merge(&wopt, "b", n(3)).unwrap();
+        storage.merge(&wopt, "c", n(3)).unwrap();
+
+        let opt = GetOption::default();
+        assert_eq!(value(storage.get(&opt, "a")), 16);
+        assert_eq!(value(storage.get(&opt, "b")), 6);
+        assert_eq!(value(storage.get(&opt, "c")), 3);
+        let values = storage.multi_get(&opt, &["c", "a", "b"]);
+        assert_eq!(
+            values.into_iter().map(value).collect::<Vec<_>>(),
+            vec![3, 16, 6]
+        );
+
+        let super_version = storage.super_version();
+        let scan = |iter: ScanIter<'_, (Bytes, Value)>| -> Vec<(Bytes, u64)> {
+            iter.map(|(k, v)| (k, value(Ok(v)))).collect()
+        };
+        let expect = vec![
+            (Bytes::from("a"), 16),
+            (Bytes::from("b"), 6),
+            (Bytes::from("c"), 3),
+        ];
+        assert_eq!(scan(storage.scan(&opt, .., &super_version)), expect);
+        let mut rev = expect.clone();
+        rev.reverse();
+        assert_eq!(scan(storage.scan_rev(&opt, .., &super_version)), rev);
+
+        // older operands are seen by the snapshot
+        let opt = GetOption::with_snapshot(snapshot.clone());
+        assert_eq!(value(storage.get(&opt, "a")), 13);
+        assert_eq!(
+            storage.get(&opt, "c").unwrap_err(),
+            StorageError::KeyNotExist
+        );
+        assert_eq!(
+            scan(storage.scan_ex(&opt, .., &super_version, snapshot)),
+            vec![(Bytes::from("a"), 13), (Bytes::from("b"), 3)]
+        );
+        drop(super_version);
+        storage.set(&wopt, "d", n(1)).unwrap();
+        drop(storage);
+
+        // operands can't be read without the operator, scans fail as get does
+        config.merge_operator = None;
+        let storage = open(&config);
+        let opt = GetOption::default();
+        assert_eq!(
+            storage.get(&opt, "a").unwrap_err(),
+            StorageError::MergeOperatorNotSet
+        );
+        {
+            let super_version = storage.super_version();
+            let mut iter = storage.scan(&opt, .., &super_version);
+            assert!(iter.next().is_none());
+            assert_eq!(iter.status(), Err(StorageError::MergeOperatorNotSet));
+            let mut iter = storage.scan_rev(&opt, .., &super_version);
+            assert_eq!(iter.next().unwrap().0, Bytes::from("d"));
+            assert!(iter.next().is_none());
+            assert_eq!(iter.status(), Err(StorageError::MergeOperatorNotSet));
+            let mut iter = storage.scan(&opt, Bytes::from("d").., &super_version);
+            assert_eq!(iter.next().unwrap().0, Bytes::from("d"));
+            assert!(iter.next().is_none());
+            assert!(iter.status().is_ok());
+        }
+
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&config.path);
+    }
+
+    #[test]
+    pub fn scan_prefix() {
+        use crate::kv::sst::format::Format;
+        use crate::prefix::{DelimiterPrefix, PrefixExtractorRef};
+
+        for format in [Format::RawSST, Format::BlockSST] {
+            let mut config = crate::config::test_config();
+            config.path = config.path.join(format!("nanokv_scan_prefix_{:?}", format));
+            config.sst_format = format;
+            config.prefix_extractor = Some(PrefixExtractorRef::new(DelimiterPrefix::new(b'/')));
+            let _ = std::fs::remove_dir_all(&config.path);
+
+            let key = |tenant: u32, i: u32| format!("tenant{}/key{:04}", tenant, i);
+            let storage = open(&config);
+            // even tenants and odd tenants are in different files
+            for parity in 0..2 {
+                for tenant in (parity..12).step_by(2) {
+                    for i in 0..1000 {
+                        storage
+                            .set(&WriteOption::default(), key(tenant, i), "v")
+                            .unwrap();
+                    }
+                }
+                storage.flush_memtable();
+                storage.flush_wait_imemtables();
+            }
+            storage
+                .set(&WriteOption::default(), key(3, 1000), "v")
+                .unwrap();
+            storage.del(&WriteOption::default(), key(3, 5)).unwrap();
+
+            let su_version = storage.super_version();
+            let bloom_useful = || {
+                let mut skipped = 0;
+                for level in 0..crate::kv::manifest::MAX_LEVEL {
+                    for run in su_version.sst_version.level_n(level) {
+                        skipped += run
+                            .files()
+                            .iter()
+                            .map(|fs| fs.bloom_useful_count())
+                            .sum::<u32>();
+                    }
+                }
+                skipped
+            };
+
+            let skipped = bloom_useful();
+            let scanned: Vec<_> = storage
+                .scan_prefix(
+                    &GetOption::default(),
+                    key(3, 0)[..8].to_owned(),
+                    &su_version,
+                )
+                .map(|(key, _)| key)
+                .collect();
+            let expected: Vec<_> = (0..=1000).filter(|i| *i != 5).map(|i| key(3, i)).collect();
+            assert_eq!(scanned, expected);
+            // the file of even tenants is skipped
+            assert!(bloom_useful() > skipped);
+
+            assert_eq!(
+                storage
+                    .scan_prefix(&GetOption::default(), "tenant99/", &su_version)
+                    .count(),
+                0
+            );
+            // not a whole prefix of the extractor, no file is skipped
+            let skipped = bloom_useful();
+            let scanned: Vec<_> = storage
+                .scan_prefix(&GetOption::default(), "tenant1", &su_version)
+                .map(|(key, _)| key)
+                .collect();
+            assert_eq!(scanned.len(), 3000);
+            assert!(scanned.iter().all(|key| key.starts_with(b"tenant1")));
+            assert_eq!(bloom_useful(), skipped);
+
+            drop(su_version);
+            drop(storage);
+            let _ = std::fs::remove_dir_all(&config.path);
+        }
+    }
+
+    #[test]
+    pub fn reverse_comparator() {
+        use crate::comparator::ComparatorRef;
+        use crate::kv::sst::format::Format;
+
+        for format in [Format::RawSST, Format::BlockSST] {
+            let mut config = crate::config::test_config();
+            config.path = config
+                .path
+                .join(format!("nanokv_reverse_comparator_{:?}", format));
+            config.sst_format = format;
+            config.comparator = ComparatorRef::new(crate::test::ReverseComparator);
+            let _ = std::fs::remove_dir_all(&config.path);
+
+            let keys: Vec<Vec<u8>> = (0u32..20000)
+                .map(|i| (i * 3).to_be_bytes().to_vec())
+                .collect();
+            let storage = open(&config);
+            for key in &keys {
+                storage.set(&WriteOption::default(), key, key).unwrap();
+            }
+            storage.flush_memtable();
+            storage.flush_wait_imemtables();
+            // newer versions in memtable
+            for key in keys.iter().step_by(100) {
+                storage.set(&WriteOption::default(), key, b"new").unwrap();
+            }
+
+            for (i, key) in keys.iter().enumerate() {
+                let value = storage.get(&GetOption::default(), key.clone()).unwrap();
+                if i % 100 == 0 {
+                    assert_eq!(value.data(), b"new");
+                } else {
+                    assert_eq!(value.data(), &key[..]);
+                }
+            }
+            for i in 0u32..100 {
+                let key = (i * 3 + 1).to_be_bytes().to_vec();
+                assert!(storage.get(&GetOption::default(), key).is_err());
+            }
+
+            let mut sorted = keys.clone();
+            sorted.reverse();
+            let su_version = storage.super_version();
+            let scanned: Vec<_> = storage
+                .scan(&GetOption::default(), .., &su_version)
+                .map(|(key, _)| key.to_vec())
+                .collect();
+            assert_eq!(scanned, sorted);
+
+            // range bounds follow the comparator
+            let beg = Bytes::from(300u32.to_be_bytes().to_vec());
+            let end = Bytes::from(150u32.to_be_bytes().to_vec());
+            let scanned: Vec<_> = storage
+                .scan(&GetOption::default(), beg..end, &su_version)
+                .map(|(key, _)| u32::from_be_bytes(key[..].try_into().unwrap()))
+                .collect();
+            let expected: Vec<_> = (51u32..=100).rev().map(|i| i * 3).collect();
+            assert_eq!(scanned, expected);
+            drop(su_version);
+            drop(storage);
+
+            // db can not be opened with another comparator
+            config.comparator = ComparatorRef::default();
+            let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| open(&config)));
+            assert!(res.is_err());
+            let _ = std::fs::remove_dir_all(&config.path);
+        }
+    }
+
+    #[test]
+    pub fn delete_range() {
+        use crate::kv::sst::format::Format;
+
+        for format in [Format::RawSST, Format::BlockSST] {
+            let mut config = crate::config::test_config();
+            config.path = config
+                .path
+                .join(format!("nanokv_delete_range_{:?}", format));
+            config.sst_format = format;
+            let _ = std::fs::remove_dir_all(&config.path);
+
+            let key = |i: u32| format!("key{:05}", i);
+            let wopt = WriteOption::default();
+            let storage = open(&config);
+            for i in 0..1000 {
+                storage.set(&wopt, key(i), "sst").unwrap();
+            }
+            storage.flush_memtable();
+            storage.flush_wait_imemtables();
+            let before = storage.snapshot();
+
+            // tombstones in sst, imemtables and memtable
+            storage.delete_range(&wopt, key(100), key(200)).unwrap();
+            storage.flush_memtable();
+            storage.flush_wait_imemtables();
+            storage.set(&wopt, key(150), "new").unwrap();
+            let after = storage.snapshot();
+            storage.delete_range(&wopt, key(300), key(400)).unwrap();
+            storage.flush_memtable();
+            storage.delete_range(&wopt, key(500), key(600)).unwrap();
+
+            let deleted = |i: u32| {
+                ((100..200).contains(&i) && i != 150)
+                    || (300..400).contains(&i)
+                    || (500..600).contains(&i)
+            };
+            let check = |storage: &Storage| {
+                let opt = GetOption::default();
+                let keys: Vec<String> = (0..1000).map(key).collect();
+                let values = storage.multi_get(&opt, &keys);
+                for (i, value) in (0..1000).zip(values) {
+                    if deleted(i) {
+                        let err = storage.get(&opt, key(i)).unwrap_err();
+                        assert_eq!(err, StorageError::KeyNotExist);
+                        assert_eq!(value.unwrap_err(), StorageError::KeyNotExist);
+                    } else {
+                        let expected: &[u8] = if i == 150 { b"new" } else { b"sst" };
+                        assert_eq!(storage.get(&opt, key(i)).unwrap().data(), expected);
+                        assert_eq!(value.unwrap().data(), expected);
+                    }
+                }
+
+                let expected: Vec<Bytes> = (0..1000)
+                    .filter(|i| !deleted(*i))
+                    .map(|i| Bytes::from(key(i)))
+                    .collect();
+                let super_version = storage.super_version();
+                let scanned: Vec<Bytes> = storage
+                    .scan(&opt, .., &super_version)
+                    .map(|(key, _)| key)
+                    .collect();
+                assert_eq!(scanned, expected);
+                let mut scanned: Vec<Bytes> = storage
+                    .scan_rev(&opt, .., &super_version)
+                    .map(|(key, _)| key)
+                    .collect();
+                scanned.reverse();
+                assert_eq!(scanned, expected);
+            };
+            check(&storage);
+
+            // tombstones are invisible to older snapshots
+            let super_version = storage.super_version();
+            for (snapshot, count) in [(before, 1000), (after, 901)] {
+                let opt = GetOption::with_snapshot(snapshot.clone());
+                assert_eq!(storage.get(&opt, key(350)).unwrap().data(), b"sst");
+                let scanned = storage.scan_ex(&opt, .., &super_version, snapshot);
+                assert_eq!(scanned.count(), count);
+            }
+            drop(super_version);
+
+            // tombstones of memtable are restored from wal
+            drop(storage);
+            let storage = open(&config);
+            check(&storage);
+
+            drop(storage);
+            let _ = std::fs::remove_dir_all(&config.path);
+        }
+    }
+}
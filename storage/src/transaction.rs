@@ -0,0 +1,423 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use bytes::Bytes;
+
+use crate::{
+    err::{Result, StorageError},
+    iterator::KvIteratorItem,
+    key::{KeyType, Value, WriteBatchBuilder},
+    snapshot::Snapshot,
+    GetOption, Storage, WriteOption,
+};
+
+/// optimistic transaction, it reads from a snapshot taken at begin and its own writes.
+/// commit fails with `StorageError::Conflict` if a key read or written by the transaction
+/// has a newer version than the one it observed
+pub struct Transaction<'a> {
+    storage: &'a Storage,
+    snapshot: Snapshot,
+    // sequence of the version observed by the first read or write of keys, 0 if there was none
+    tracked: BTreeMap<Bytes, u64>,
+    // latest write of keys, None is deletion
+    writes: BTreeMap<Bytes, Option<Bytes>>,
+    batch: WriteBatchBuilder,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(storage: &'a Storage) -> Self {
+        Self {
+            storage,
+            snapshot: storage.snapshot(),
+            tracked: BTreeMap::new(),
+            writes: BTreeMap::new(),
+            batch: WriteBatchBuilder::default(),
+        }
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    pub fn get<K: Into<Bytes>>(&mut self, key: K) -> Result<Value> {
+        let key = key.into();
+        if let Some(write) = self.writes.get(&key) {
+            return write
+                .clone()
+                .map(Value::from)
+                .ok_or(StorageError::KeyNotExist);
+        }
+
+        let opt = GetOption::with_snapshot(self.snapshot.clone());
+        let super_version = self.storage.super_version();
+        let (seq, value) =
+            match self
+                .storage
+                .get_entry(&opt, key.clone(), &super_version, self.snapshot.clone())
+            {
+                Ok((internal_key, value)) => (
+                    internal_key.seq(),
+                    (internal_key.key_type() != KeyType::Del).then_some(value),
+                ),
+                Err(StorageError::KeyNotExist) => (0, None),
+                Err(e) => return Err(e),
+            };
+        self.tracked.entry(key).or_insert(seq);
+        value.ok_or(StorageError::KeyNotExist)
+    }
+
+    pub fn set<K: Into<Bytes>, V: Into<Bytes>>(&mut self, key: K, value: V) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        self.batch.set(&key, &value)?;
+        self.track_write(key, Some(value));
+        Ok(())
+    }
+
+    pub fn del<K: Into<Bytes>>(&mut self, key: K) -> Result<()> {
+        let key = key.into();
+        self.batch.del(&key)?;
+        self.track_write(key, None);
+        Ok(())
+    }
+
+    fn track_write(&mut self, key: Bytes, value: Option<Bytes>) {
+        // blind writes conflict with writes after the snapshot
+        self.tracked
+            .entry(key.clone())
+            .or_insert(self.snapshot.sequence());
+        self.writes.insert(key, value);
+    }
+
+    /// returns the sequence of the first write, or the snapshot sequence if nothing is written
+    pub fn commit(self, opt: &WriteOption) -> Result<u64> {
+        // reads from the snapshot are consistent
+        if self.writes.is_empty() {
+            return Ok(self.snapshot.sequence());
+        }
+        self.storage
+            .set_batch_checked(opt, self.batch.build(), self.tracked.into_iter().collect())
+    }
+}
+
+/// pessimistic transaction with strict two-phase locking, keys are locked by
+/// `get_for_update`, `put` and `delete` and released when it commits or rolls back.
+/// the writes are committed as one batch
+pub struct PessimisticTransaction<'a> {
+    storage: &'a Storage,
+    id: u64,
+    locked: BTreeSet<Bytes>,
+    // latest write of keys, None is deletion
+    writes: BTreeMap<Bytes, Option<Bytes>>,
+    batch: WriteBatchBuilder,
+}
+
+impl<'a> PessimisticTransaction<'a> {
+    pub(crate) fn new(storage: &'a Storage, id: u64) -> Self {
+        Self {
+            storage,
+            id,
+            locked: BTreeSet::new(),
+            writes: BTreeMap::new(),
+            batch: WriteBatchBuilder::default(),
+        }
+    }
+
+    /// reads its own writes or the latest committed value, the key is not locked
+    pub fn get<K: Into<Bytes>>(&self, key: K) -> Result<Value> {
+        let key = key.into();
+        if let Some(write) = self.writes.get(&key) {
+            return write
+                .clone()
+                .map(Value::from)
+                .ok_or(StorageError::KeyNotExist);
+        }
+        self.storage.get(&GetOption::default(), key)
+    }
+
+    /// locks the key, then reads it as `get`
+    pub fn get_for_update<K: Into<Bytes>>(&mut self, key: K) -> Result<Value> {
+        let key = key.into();
+        self.lock(&key)?;
+        self.get(key)
+    }
+
+    pub fn put<K: Into<Bytes>, V: Into<Bytes>>(&mut self, key: K, value: V) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        self.lock(&key)?;
+        self.batch.set(&key, &value)?;
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    pub fn delete<K: Into<Bytes>>(&mut self, key: K) -> Result<()> {
+        let key = key.into();
+        self.lock(&key)?;
+        self.batch.del(&key)?;
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn lock(&mut self, key: &Bytes) -> Result<()> {
+        if !self.locked.contains(key) {
+            self.storage.lock_key(self.id, key)?;
+            self.locked.insert(key.clone());
+        }
+        Ok(())
+    }
+
+    /// returns the sequence of the first write, or 0 if nothing is written
+    pub fn commit(mut self, opt: &WriteOption) -> Result<u64> {
+        if self.writes.is_empty() {
+            return Ok(0);
+        }
+        let batch = std::mem::take(&mut self.batch).build();
+        // locks are released on drop, after the batch is visible
+        self.storage.set_batch(opt, batch)
+    }
+
+    /// discard writes and release locks
+    pub fn rollback(self) {}
+}
+
+impl<'a> Drop for PessimisticTransaction<'a> {
+    fn drop(&mut self) {
+        self.storage.unlock_keys(self.id, &self.locked);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backend::{fs::local::LocalFileBasedPersistBackend, Backend};
+    use crate::Config;
+
+    use super::*;
+
+    fn open(name: &str) -> (Storage, Config) {
+        let mut config = crate::config::test_config();
+        config.path = config.path.join(name);
+        let _ = std::fs::remove_dir_all(&config.path);
+        let storage = Storage::new(config.clone(), Backend::new(LocalFileBasedPersistBackend));
+        (storage, config)
+    }
+
+    #[test]
+    pub fn transaction_conflict() {
+        let (storage, config) = open("nanokv_transaction_conflict");
+        let wopt = WriteOption::default();
+        storage.set(&wopt, "a", "1").unwrap();
+
+        // read-modify-write of the same key
+        let mut txn1 = storage.transaction();
+        let mut txn2 = storage.transaction();
+        assert_eq!(txn1.get("a").unwrap().data(), b"1");
+        assert_eq!(txn2.get("a").unwrap().data(), b"1");
+        txn1.set("a", "2").unwrap();
+        txn2.set("a", "3").unwrap();
+        txn1.commit(&wopt).unwrap();
+        assert_eq!(txn2.commit(&wopt).unwrap_err(), StorageError::Conflict);
+        assert_eq!(
+            storage.get(&GetOption::default(), "a").unwrap().data(),
+            b"2"
+        );
+
+        // own writes are visible, the snapshot hides others
+        let mut txn = storage.transaction();
+        storage.set(&wopt, "b", "1").unwrap();
+        assert_eq!(txn.get("b").unwrap_err(), StorageError::KeyNotExist);
+        txn.set("c", "1").unwrap();
+        assert_eq!(txn.get("c").unwrap().data(), b"1");
+        txn.del("c").unwrap();
+        assert_eq!(txn.get("c").unwrap_err(), StorageError::KeyNotExist);
+        // the absent key read is created by another write
+        assert_eq!(txn.commit(&wopt).unwrap_err(), StorageError::Conflict);
+        assert!(storage.get(&GetOption::default(), "c").is_err());
+
+        // disjoint keys and blind writes
+        let mut txn1 = storage.transaction();
+        let mut txn2 = storage.transaction();
+        txn1.get("a").unwrap();
+        txn1.set("d", "1").unwrap();
+        txn2.set("e", "1").unwrap();
+        txn2.commit(&wopt).unwrap();
+        txn1.commit(&wopt).unwrap();
+        let mut txn = storage.transaction();
+        txn.set("d", "2").unwrap();
+        storage.del(&wopt, "d").unwrap();
+        assert_eq!(txn.commit(&wopt).unwrap_err(), StorageError::Conflict);
+
+        // newer versions are found after they are flushed to sst
+        let mut txn = storage.transaction();
+        txn.get("a").unwrap();
+        txn.set("a", "3").unwrap();
+        storage.set(&wopt, "a", "4").unwrap();
+        storage.flush_memtable();
+        storage.flush_wait_imemtables();
+        assert_eq!(txn.commit(&wopt).unwrap_err(), StorageError::Conflict);
+        assert_eq!(
+            storage.get(&GetOption::default(), "a").unwrap().data(),
+            b"4"
+        );
+
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&config.path);
+    }
+
+    #[test]
+    pub fn transaction_counter() {
+        let (storage, config) = open("nanokv_transaction_counter");
+        let wopt = WriteOption::default();
+        storage.set(&wopt, "counter", 0u64.to_le_bytes()).unwrap();
+
+        let conflicts: u64 = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut conflicts = 0;
+                        for _ in 0..200 {
+                            loop {
+                                let mut txn = storage.transaction();
+                                let value = txn.get("counter").unwrap();
+                                let n = u64::from_le_bytes(value.data().try_into().unwrap());
+                                txn.set("counter", Bytes::copy_from_slice(&(n + 1).to_le_bytes()))
+                                    .unwrap();
+                                match txn.commit(&wopt) {
+                                    Ok(_) => break,
+                                    Err(StorageError::Conflict) => conflicts += 1,
+                                    Err(e) => panic!("{:?}", e),
+                                }
+                            }
+                        }
+                        conflicts
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        log::info!("counter conflicts {}", conflicts);
+
+        let value = storage.get(&GetOption::default(), "counter").unwrap();
+        assert_eq!(u64::from_le_bytes(value.data().try_into().unwrap()), 800);
+
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&config.path);
+    }
+
+    #[test]
+    pub fn pessimistic_transaction() {
+        let mut config = crate::config::test_config();
+        config.path = config.path.join("nanokv_pessimistic_transaction");
+        config.lock_timeout_ms = 20;
+        let _ = std::fs::remove_dir_all(&config.path);
+        let storage = Storage::new(config.clone(), Backend::new(LocalFileBasedPersistBackend));
+        let wopt = WriteOption::default();
+        storage.set(&wopt, "a", "1").unwrap();
+
+        // uncommitted writes are only visible to the transaction
+        let mut txn = storage.begin();
+        txn.put("a", "2").unwrap();
+        txn.delete("b").unwrap();
+        assert_eq!(txn.get("a").unwrap().data(), b"2");
+        assert_eq!(txn.get("b").unwrap_err(), StorageError::KeyNotExist);
+        assert_eq!(
+            storage.get(&GetOption::default(), "a").unwrap().data(),
+            b"1"
+        );
+
+        // locked keys wait until timeout, reads without lock don't
+        let mut txn2 = storage.begin();
+        assert_eq!(txn2.get("a").unwrap().data(), b"1");
+        assert_eq!(
+            txn2.get_for_update("a").unwrap_err(),
+            StorageError::LockTimeout
+        );
+        txn2.put("c", "1").unwrap();
+        txn.commit(&wopt).unwrap();
+        assert_eq!(txn2.get_for_update("a").unwrap().data(), b"2");
+        txn2.rollback();
+        assert!(storage.get(&GetOption::default(), "c").is_err());
+
+        // dropping releases locks
+        let mut txn = storage.begin();
+        txn.put("a", "3").unwrap();
+        drop(txn);
+        let mut txn = storage.begin();
+        txn.put("a", "4").unwrap();
+        txn.commit(&wopt).unwrap();
+        assert_eq!(
+            storage.get(&GetOption::default(), "a").unwrap().data(),
+            b"4"
+        );
+
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&config.path);
+    }
+
+    #[test]
+    pub fn pessimistic_transfer() {
+        let (storage, config) = open("nanokv_pessimistic_transfer");
+        let wopt = WriteOption::default();
+        let accounts = 5u64;
+        for i in 0..accounts {
+            storage
+                .set(&wopt, format!("account{}", i), 100u64.to_le_bytes())
+                .unwrap();
+        }
+        let balance = |txn: &mut PessimisticTransaction, key: &str| -> Result<u64> {
+            let value = txn.get_for_update(key.to_owned())?;
+            Ok(u64::from_le_bytes(value.data().try_into().unwrap()))
+        };
+
+        // transfers in both directions lock keys in different orders
+        let aborts: usize = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4u64)
+                .map(|t| {
+                    let (storage, wopt) = (&storage, &wopt);
+                    s.spawn(move || {
+                        let mut aborts = 0;
+                        for i in 0..100u64 {
+                            let from = format!("account{}", (i + t) % accounts);
+                            let to = format!("account{}", (i * 3 + t + 1) % accounts);
+                            if from == to {
+                                continue;
+                            }
+                            loop {
+                                let mut txn = storage.begin();
+                                let res = balance(&mut txn, &from).and_then(|a| {
+                                    let b = balance(&mut txn, &to)?;
+                                    let amount = a.min(7);
+                                    txn.put(from.clone(), (a - amount).to_le_bytes().to_vec())?;
+                                    txn.put(to.clone(), (b + amount).to_le_bytes().to_vec())
+                                });
+                                match res {
+                                    Ok(()) => {
+                                        txn.commit(wopt).unwrap();
+                                        break;
+                                    }
+                                    Err(StorageError::Deadlock | StorageError::LockTimeout) => {
+                                        aborts += 1;
+                                    }
+                                    Err(e) => panic!("{:?}", e),
+                                }
+                            }
+                        }
+                        aborts
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        log::info!("transfer aborts {}", aborts);
+
+        let total: u64 = (0..accounts)
+            .map(|i| {
+                let value = storage
+                    .get(&GetOption::default(), format!("account{}", i))
+                    .unwrap();
+                u64::from_le_bytes(value.data().try_into().unwrap())
+            })
+            .sum();
+        assert_eq!(total, accounts * 100);
+
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&config.path);
+    }
+}
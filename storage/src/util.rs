@@ -1,2 +1,3 @@
+pub mod bloom;
 pub mod crc;
 pub mod fname;
@@ -0,0 +1,134 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// hash of leveldb, it's stable across platforms
+fn bloom_hash(data: &[u8]) -> u32 {
+    const SEED: u32 = 0xbc9f1d34;
+    const M: u32 = 0xc6a4a793;
+
+    let mut h = SEED ^ (data.len() as u32).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(4);
+    for chunk in chunks.by_ref() {
+        let w = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        h = h.wrapping_add(w).wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, b) in rest.iter().enumerate() {
+            h = h.wrapping_add((*b as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h
+}
+
+// filter data
+// bits
+// probes u8
+pub struct BloomFilterBuilder {
+    bits_per_key: u32,
+    hashes: Vec<u32>,
+    last_key: Option<u32>,
+    last_prefix: Option<u32>,
+}
+
+impl BloomFilterBuilder {
+    pub fn new(bits_per_key: u32) -> Self {
+        Self {
+            bits_per_key,
+            hashes: Vec::new(),
+            last_key: None,
+            last_prefix: None,
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8]) {
+        let h = bloom_hash(key);
+        // keys are sorted, versions of the same key are added once
+        if self.last_key != Some(h) {
+            self.hashes.push(h);
+            self.last_key = Some(h);
+        }
+    }
+
+    /// prefixes share the filter with keys, keys of the same prefix add it once
+    pub fn add_prefix(&mut self, prefix: &[u8]) {
+        let h = bloom_hash(prefix);
+        if self.last_prefix != Some(h) {
+            self.hashes.push(h);
+            self.last_prefix = Some(h);
+        }
+    }
+
+    pub fn build(self) -> Bytes {
+        // round down ln(2) * bits_per_key to reduce probing cost
+        let probes = ((self.bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        let bits = (self.hashes.len() as u64 * self.bits_per_key as u64).max(64);
+        let bytes = bits.div_ceil(8) as usize;
+        let bits = bytes as u64 * 8;
+
+        let mut data = BytesMut::zeroed(bytes);
+        for h in self.hashes {
+            let mut h = h;
+            let delta = h.rotate_right(17);
+            for _ in 0..probes {
+                let pos = (h as u64 % bits) as usize;
+                data[pos / 8] |= 1 << (pos % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+        data.put_u8(probes as u8);
+        data.freeze()
+    }
+}
+
+/// false means the key is absolutely not in the filter
+pub fn may_contain(filter: &[u8], key: &[u8]) -> bool {
+    if filter.len() < 2 {
+        return true;
+    }
+    let probes = filter[filter.len() - 1] as u32;
+    if probes > 30 {
+        // reserved for new encodings
+        return true;
+    }
+    let data = &filter[..filter.len() - 1];
+    let bits = data.len() as u64 * 8;
+
+    let mut h = bloom_hash(key);
+    let delta = h.rotate_right(17);
+    for _ in 0..probes {
+        let pos = (h as u64 % bits) as usize;
+        if data[pos / 8] & (1 << (pos % 8)) == 0 {
+            return false;
+        }
+        h = h.wrapping_add(delta);
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn bloom_filter() {
+        let mut builder = BloomFilterBuilder::new(10);
+        for i in 0..10000 {
+            builder.add(format!("key{}", i).as_bytes());
+        }
+        let filter = builder.build();
+
+        for i in 0..10000 {
+            assert!(may_contain(&filter, format!("key{}", i).as_bytes()));
+        }
+        let false_positive = (10000..20000)
+            .filter(|i| may_contain(&filter, format!("key{}", i).as_bytes()))
+            .count();
+        // about 1% with 10 bits per key
+        assert!(false_positive < 300, "false positive {}", false_positive);
+
+        assert!(may_contain(&[], b"key"));
+    }
+}
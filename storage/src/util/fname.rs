@@ -2,8 +2,12 @@ use std::path::PathBuf;
 
 use crate::Config;
 
+pub fn sst_dir(config: &Config) -> PathBuf {
+    config.path.join("sst")
+}
+
 pub fn sst_name(config: &Config, seq: u64) -> PathBuf {
-    let mut base = config.path.join("sst").join(seq.to_string());
+    let mut base = sst_dir(config).join(seq.to_string());
     base.set_extension("sst");
     base
 }